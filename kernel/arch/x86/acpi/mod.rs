@@ -16,10 +16,14 @@
 
 pub mod sdt;
 
-use crate::arch::vas::{ACPI_PGTBL, KERNEL_VAS};
+use core::mem::size_of;
+use core::slice;
+use core::str;
+
+use crate::arch::dev::acpi::hpet;
+use crate::arch::vas::{KERNEL_VAS, KERNEL_VIRT_BASE};
 use crate::KERNEL_INFO;
 
-use crate::arch::vas::Table;
 use crate::memory_region::Region;
 
 #[derive(Clone, Copy, Debug)]
@@ -32,10 +36,105 @@ pub struct AcpiAddr {
     pub address: u64,
 }
 
-/// Maps the HPET ACPI memory range if an HPET DT was found in the RSDT/XSDT,
-/// i.e. if [`ArchInitInfo::hpet_dt`](crate::arch::ArchInitInfo::hpet_dt) is
-/// `Some`.
+/// Calls `f` for each table listed in the XSDT, or in the RSDT if there is no
+/// XSDT.
+///
+/// The tables are accessed at their physical addresses, so this function must
+/// be called while the boot page directory is loaded.
+unsafe fn for_each_sdt<F: FnMut(*const sdt::Sdt)>(
+    rsdt_phys: Option<u32>,
+    xsdt_phys: Option<u32>,
+    mut f: F,
+) {
+    if let Some(xsdt_phys) = xsdt_phys {
+        let xsdt = (xsdt_phys as *const sdt::Sdt).read_unaligned();
+        let num_sdts = (xsdt.length as usize - size_of::<sdt::Sdt>()) / 8;
+        let sdt_ptrs = slice::from_raw_parts(
+            (xsdt_phys as usize + size_of::<sdt::Sdt>()) as *const u64,
+            num_sdts,
+        );
+
+        let xsdt_sum = xsdt.sum_fields()
+            + sdt_ptrs.iter().fold(0, |acc, x| {
+                acc + ((*x >> 0) & 0xFF) as usize
+                    + ((*x >> 8) & 0xFF) as usize
+                    + ((*x >> 16) & 0xFF) as usize
+                    + ((*x >> 24) & 0xFF) as usize
+                    + ((*x >> 32) & 0xFF) as usize
+                    + ((*x >> 40) & 0xFF) as usize
+                    + ((*x >> 48) & 0xFF) as usize
+                    + ((*x >> 56) & 0xFF) as usize
+            });
+        assert_eq!(xsdt_sum as u8, 0, "invalid XSDT");
+
+        for sdt_ptr in sdt_ptrs {
+            if *sdt_ptr >> 32 != 0 {
+                println!("[ACPI] Ignoring a table above 4 GiB.");
+                continue;
+            }
+            f(*sdt_ptr as u32 as *const sdt::Sdt);
+        }
+    } else if let Some(rsdt_phys) = rsdt_phys {
+        let rsdt = (rsdt_phys as *const sdt::Sdt).read_unaligned();
+        let num_sdts = (rsdt.length as usize - size_of::<sdt::Sdt>()) / 4;
+        let sdt_ptrs = slice::from_raw_parts(
+            (rsdt_phys as usize + size_of::<sdt::Sdt>()) as *const u32,
+            num_sdts,
+        );
+
+        let rsdt_sum = rsdt.sum_fields()
+            + sdt_ptrs.iter().fold(0, |acc, x| {
+                acc + ((*x >> 0) & 0xFF) as usize
+                    + ((*x >> 8) & 0xFF) as usize
+                    + ((*x >> 16) & 0xFF) as usize
+                    + ((*x >> 24) & 0xFF) as usize
+            });
+        assert_eq!(rsdt_sum as u8, 0, "invalid RSDT");
+
+        for sdt_ptr in sdt_ptrs {
+            f(*sdt_ptr as *const sdt::Sdt);
+        }
+    } else {
+        println!("[ACPI] There is no RSDP.");
+    }
+}
+
+/// Finds the HPET DT in the tables listed in the XSDT or RSDT, and saves it to
+/// [`ArchInitInfo::hpet_dt`](crate::arch::ArchInitInfo::hpet_dt).
+fn find_hpet_dt() {
+    let aif = unsafe { &mut KERNEL_INFO.arch };
+    unsafe {
+        for_each_sdt(aif.rsdt_phys, aif.xsdt_phys, |sdt_ptr| {
+            let sdt = sdt_ptr.read_unaligned();
+            let name = str::from_utf8(&sdt.signature).unwrap_or("????");
+            println!(
+                "[ACPI] {} at 0x{:08X}, length: {} bytes",
+                name,
+                sdt_ptr as usize,
+                { sdt.length },
+            );
+
+            if name == "HPET" {
+                if aif.hpet_dt.is_none() {
+                    let hpet_dt =
+                        sdt_ptr.add(1).cast::<hpet::HpetDt>().read_unaligned();
+                    aif.hpet_dt = Some(hpet_dt);
+                } else {
+                    println!("[ACPI] Another HPET timer, ignoring.");
+                }
+            }
+        });
+    }
+}
+
+/// Finds the ACPI tables that the kernel uses and maps the HPET ACPI memory
+/// range if an HPET DT was found in the RSDT/XSDT, i.e. if
+/// [`ArchInitInfo::hpet_dt`](crate::arch::ArchInitInfo::hpet_dt) is `Some`.
+///
+/// The XSDT is preferred over the RSDT if the bootloader has passed both.
 pub fn init() {
+    find_hpet_dt();
+
     let aif = unsafe { &mut KERNEL_INFO.arch };
     let hpet_region = &mut aif.hpet_region;
 
@@ -58,31 +157,25 @@ pub fn init() {
         "HPET physical memory region spans across at least one 4 MiB boundary",
     );
 
-    // Place the ACPI region right after the kernel's page table.
+    // Place the ACPI region right after the 4 MiB page of the kernel image.
+    let kernel_end = KERNEL_VIRT_BASE + aif.kernel_region.end;
     *hpet_region = Some(Region {
-        start: (aif.kernel_region.end + 0x400_000 - 1) & !(0x400_000 - 1),
-        end: ((aif.kernel_region.end + 0x400_000 - 1) & !(0x400_000 - 1))
-            + 0x400_000,
+        start: (kernel_end + 0x400_000 - 1) & !(0x400_000 - 1),
+        end: ((kernel_end + 0x400_000 - 1) & !(0x400_000 - 1)) + 0x400_000,
     });
     println!("[ACPI] ACPI region: {:?}", hpet_region.unwrap());
 
-    let kvas = KERNEL_VAS.lock();
-
+    // The HPET memory is within a single 4 MiB frame, so map it with a 4 MiB
+    // page.
+    let frame = hpet_phys_region.start & !0x3FFFFF;
+    println!(
+        "[ACPI] Mapping 4 MiB page 0x{:08X} -> 0x{:08X}.",
+        hpet_region.unwrap().start,
+        frame,
+    );
     unsafe {
-        let pde_idx = (hpet_region.unwrap().start / 4096 / 1024) as usize;
-        let pgtbl_virt = &mut *ACPI_PGTBL.lock() as *mut Table;
-        kvas.set_pde_virt(pde_idx, pgtbl_virt);
-    }
-
-    let start_page = hpet_phys_region.start / 4096;
-    let end_page = (hpet_phys_region.end - 1) / 4096 + 1;
-
-    for (i, page) in (start_page..end_page).enumerate() {
-        let virt = hpet_region.unwrap().start + i * 4096;
-        let phys = page << 12;
-        println!("[ACPI] Mapping page 0x{:08X} -> 0x{:08X}.", virt, phys);
-        unsafe {
-            kvas.map_page(virt as u32, phys as u32);
-        }
+        KERNEL_VAS
+            .lock()
+            .map_large_page(hpet_region.unwrap().start as u32, frame as u32);
     }
 }
@@ -19,6 +19,11 @@
 .set HEADER_LEN, header_end - header_start
 .set CHECKSUM,   -(MAGIC + ARCH + HEADER_LEN)
 
+// Keep in sync with KERNEL_VIRT_BASE in linker.ld and vas.rs.
+.set KERNEL_VIRT_BASE, 0xC0000000
+.set KERNEL_PDE_IDX,   KERNEL_VIRT_BASE >> 22
+.set LARGE_PDE_FLAGS,  0x83 // PRESENT, READ_WRITE, PAGE_SIZE_IS_4_MIB
+
 .section .multiboot
 .align 8
 header_start:
@@ -48,11 +53,52 @@ stack_bottom:
 .global stack_top
 stack_top:
 
-.section .text
+// Page directory used until the kernel VAS is loaded in arch::init().
+.section .multiboot.bss, "aw", @nobits
+.align 4096
+boot_pgdir:
+.skip 4096
+
+.section .multiboot.text, "ax"
 .global _entry
 .type _entry, @function
 _entry:
     cli
+
+    // Identity map the whole physical memory with 4 MiB pages, so that the
+    // Multiboot information and the ACPI tables can be read before the kernel
+    // VAS is set up.
+    movl $boot_pgdir, %edi
+    xorl %ecx, %ecx
+1:  movl %ecx, %edx
+    shll $22, %edx
+    orl $LARGE_PDE_FLAGS, %edx
+    movl %edx, (%edi, %ecx, 4)
+    incl %ecx
+    cmpl $1024, %ecx
+    jne 1b
+
+    // Map the first 8 MiB at KERNEL_VIRT_BASE.
+    movl $(0x00000000 | LARGE_PDE_FLAGS), (boot_pgdir + KERNEL_PDE_IDX * 4)
+    movl $(0x00400000 | LARGE_PDE_FLAGS), (boot_pgdir + KERNEL_PDE_IDX * 4 + 4)
+
+    // Enable 4 MiB pages and paging.  EAX and EBX are preserved for main().
+    movl %cr4, %ecx
+    orl $0x00000010, %ecx
+    movl %ecx, %cr4
+    movl %edi, %cr3
+    movl %cr0, %ecx
+    orl $0x80000001, %ecx
+    movl %ecx, %cr0
+
+    // Jump to the higher half.
+    movl $higher_half_entry, %ecx
+    jmp *%ecx
+.size _entry, . - _entry
+
+.section .text
+.type higher_half_entry, @function
+higher_half_entry:
     movl $stack_top, %esp
 
     pushl %ebx
@@ -62,7 +108,7 @@ _entry:
 
     // Hang if main() returns.
     jmp halt
-.size _entry, . - _entry
+.size higher_half_entry, . - higher_half_entry
 
 .global halt
 .type halt, @function
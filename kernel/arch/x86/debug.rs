@@ -0,0 +1,165 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! In-situ debugging helpers: breakpoints and single-stepping.
+//!
+//! Use [breakpoint!] to stop at an arbitrary point in the kernel code: the
+//! breakpoint handler prints the location, the registers and the stack trace,
+//! then the execution continues.  [step_through] runs a closure with the trap
+//! flag set and logs the address of each executed instruction.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::arch::interrupts::{InterruptStackFrame, EXCEPTION_EXIT_STATUS};
+use crate::arch::stack_trace::StackTrace;
+use crate::arch::syscall::GpRegs;
+use crate::arch::sysenter;
+use crate::dev::vga;
+use crate::task_manager::{self, TASK_MANAGER};
+
+/// Triggers the breakpoint exception (`int3`) at this point.
+#[macro_export]
+macro_rules! breakpoint {
+    () => {
+        unsafe {
+            asm!("int3");
+        }
+    };
+}
+
+pub use crate::breakpoint;
+
+const EFLAGS_TF: u32 = 1 << 8;
+const DR6_BS: u32 = 1 << 14;
+
+static STEPPING: AtomicBool = AtomicBool::new(false);
+static STEPS: AtomicU32 = AtomicU32::new(0);
+
+/// Runs `f` with EFLAGS.TF set, logging the EIP of each executed instruction.
+///
+/// The interrupt handlers are not stepped through, since the interrupt gates
+/// clear TF.  The steps taken while the screen is locked (e.g. when `f` prints
+/// something) are logged and printed once it is unlocked.
+///
+/// # Panics
+/// Panics if called inside another `step_through`.
+pub fn step_through<F: FnOnce()>(f: F) {
+    assert!(
+        !STEPPING.swap(true, Ordering::SeqCst),
+        "nested step_through() calls are not supported",
+    );
+    STEPS.store(0, Ordering::SeqCst);
+    println!("[DEBUG] Single-stepping.");
+
+    unsafe {
+        asm!("pushfl
+              orl ${}, (%esp)
+              popfl",
+             const EFLAGS_TF,
+             options(att_syntax));
+    }
+    f();
+    unsafe {
+        asm!("pushfl
+              andl ${}, (%esp)
+              popfl",
+             const !EFLAGS_TF,
+             options(att_syntax));
+    }
+
+    STEPPING.store(false, Ordering::SeqCst);
+    println!(
+        "[DEBUG] Stepped through {} instructions.",
+        STEPS.load(Ordering::SeqCst),
+    );
+}
+
+/// Prints the registers of the interrupted code.
+pub fn print_regs(stack_frame: &InterruptStackFrame, gp_regs: &GpRegs) {
+    let regs = *gp_regs;
+    let eip = stack_frame.eip;
+    let cs = stack_frame.cs;
+    let eflags = stack_frame.eflags;
+    let (eax, ebx, ecx, edx) = (regs.eax, regs.ebx, regs.ecx, regs.edx);
+    let (esi, edi, ebp, esp) = (regs.esi, regs.edi, regs.ebp, regs.esp);
+    println!(
+        " eax: 0x{:08X}  ebx: 0x{:08X}  ecx: 0x{:08X}  edx: 0x{:08X}",
+        eax, ebx, ecx, edx,
+    );
+    println!(
+        " esi: 0x{:08X}  edi: 0x{:08X}  ebp: 0x{:08X}  esp: 0x{:08X}",
+        esi, edi, ebp, esp,
+    );
+    println!(
+        " eip: 0x{:08X}  cs: 0x{:04X}  eflags: 0x{:08X}",
+        eip, cs, eflags,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn breakpoint_handler(
+    stack_frame: &InterruptStackFrame,
+    gp_regs: &GpRegs,
+) {
+    // The saved EIP points to the instruction following the one-byte int3.
+    let eip = { stack_frame.eip }.wrapping_sub(1);
+    println!("[DEBUG] Breakpoint at 0x{:08X}.", eip);
+    print_regs(stack_frame, gp_regs);
+
+    StackTrace::walk_and_get().print();
+    println!("[DEBUG] Continuing.");
+}
+
+#[no_mangle]
+pub extern "C" fn debug_exception_handler(
+    stack_frame: &mut InterruptStackFrame,
+    gp_regs: &GpRegs,
+) {
+    let dr6: u32;
+    unsafe {
+        asm!("movl %dr6, {}", out(reg) dr6, options(att_syntax));
+        // The DR6 bits are sticky.
+        asm!("movl {}, %dr6", in(reg) 0u32, options(att_syntax));
+    }
+
+    // A usermode program may set TF itself, there is no debugger to pass the
+    // trap to.
+    if stack_frame.cs & 3 == 3 {
+        let task = unsafe { TASK_MANAGER.this_task() };
+        println!("[DEBUG] Killing task ID {} due to a debug trap.", task.id);
+        task_manager::task_exit(EXCEPTION_EXIT_STATUS);
+    }
+    if dr6 & DR6_BS != 0 && sysenter::is_before_flags_reset(stack_frame.eip) {
+        stack_frame.eflags &= !EFLAGS_TF;
+        return;
+    }
+
+    if dr6 & DR6_BS != 0 && STEPPING.load(Ordering::SeqCst) {
+        let eip = stack_frame.eip;
+        let step = STEPS.fetch_add(1, Ordering::SeqCst);
+        // The stepped code may hold the screen lock, so we must not wait for it
+        // here.
+        vga::_try_print(format_args!(
+            "[DEBUG] step {}: eip 0x{:08X}\n",
+            step, eip,
+        ));
+        return;
+    }
+
+    println!("[DEBUG] Unexpected debug exception, DR6 = 0x{:08X}.", dr6);
+    print_regs(stack_frame, gp_regs);
+    panic!("Unhandled debug exception.");
+}
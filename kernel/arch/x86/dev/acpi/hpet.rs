@@ -18,12 +18,13 @@ use core::fmt;
 
 use crate::arch::dev::pic::PIC;
 use crate::arch::interrupts::{IDT, IRQ0_RUST_HANDLER};
-use crate::dev::timer::TIMER;
 use crate::KERNEL_INFO;
 
 use crate::arch::acpi::AcpiAddr;
-use crate::dev::timer::{Timer, TimerCallback};
+use crate::dev::timer;
+use crate::dev::timer::Timer;
 use crate::memory_region::Region;
+use crate::mmio::MmioRegion;
 
 extern "C" {
     fn irq0_handler(); // interrupts.s
@@ -31,6 +32,26 @@ extern "C" {
 
 const IRQ: u8 = 0;
 
+// Offsets of the registers in the HPET register block.
+const GEN_CAPS_AND_ID_REG: usize = 0x000;
+const GEN_CONF_REG: usize = 0x010;
+const GEN_INT_STATUS_REG: usize = 0x020;
+const MAIN_COUNTER_VALUE_REG: usize = 0x0F0;
+/// Offset of the registers of timer 0, see [timer_reg].
+const TIMERS: usize = 0x100;
+/// Distance between the registers of the neighbouring timers.
+const TIMER_STRIDE: usize = 0x20;
+// Offsets of the registers in the block of a timer.
+const TIMER_CONF_AND_CAP_REG: usize = 0x00;
+const TIMER_COMPARATOR_VALUE_REG: usize = 0x08;
+/// Length of a timer's registers, the last one is the FSB interrupt route.
+const TIMER_REGS_LEN: usize = 0x18;
+
+/// Returns the offset of the register `reg` of timer `timer_n`.
+fn timer_reg(timer_n: usize, reg: usize) -> usize {
+    TIMERS + TIMER_STRIDE * timer_n + reg
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
 pub struct HpetDt {
@@ -53,10 +74,16 @@ impl HpetDt {
         assert_eq!(self.base_addr.address >> 32, 0);
 
         let start = self.base_addr.address as usize;
-        let len = 0x117 + 0x20 * self.num_comparators();
+        let len = self.registers_len();
         Region::from_start_len(start, (len + 4095) & !4095)
     }
 
+    /// Returns the length of the register block, which ends with the registers
+    /// of the last timer.
+    pub fn registers_len(&self) -> usize {
+        timer_reg(self.num_comparators(), TIMER_REGS_LEN)
+    }
+
     pub fn hardware_rev_id(&self) -> u8 {
         self.event_timer_block_id as u8
     }
@@ -71,9 +98,8 @@ impl HpetDt {
 }
 
 pub struct Hpet {
-    base_addr: u32,
+    registers: MmioRegion,
     period_ms: u32,
-    callback: Option<TimerCallback>,
 }
 
 impl Hpet {
@@ -86,12 +112,17 @@ impl Hpet {
         );
         println!("[HPET] PCI vendor ID: 0x{:04X}", hpet_dt.pci_vendor_id());
 
+        // The ACPI region maps the whole 4 MiB frame containing the HPET
+        // registers.
+        let offset = hpet_dt.region_to_map().start & 0x3FFFFF;
         Hpet {
-            base_addr: unsafe {
-                KERNEL_INFO.arch.hpet_region.unwrap().start as u32
+            registers: unsafe {
+                MmioRegion::new(
+                    KERNEL_INFO.arch.hpet_region.unwrap().start + offset,
+                    hpet_dt.registers_len(),
+                )
             },
             period_ms,
-            callback: None,
         }
     }
 
@@ -111,15 +142,11 @@ impl Hpet {
     }
 
     pub fn gen_caps_and_id_reg(&self) -> GenCapsAndIdReg {
-        let reg_addr = self.base_addr;
-        let reg_ptr = reg_addr as *const GenCapsAndIdReg;
-        unsafe { reg_ptr.read_volatile() }
+        self.registers.read(GEN_CAPS_AND_ID_REG)
     }
 
     pub fn gen_conf_reg(&self) -> GenConfReg {
-        let reg_addr = self.base_addr + 0x10;
-        let reg_ptr = reg_addr as *const GenConfReg;
-        unsafe { reg_ptr.read_volatile() }
+        self.registers.read(GEN_CONF_REG)
     }
 
     pub fn write_gen_conf_reg(&self, new_value: GenConfReg) {
@@ -127,39 +154,28 @@ impl Hpet {
             assert!(self.gen_caps_and_id_reg().capable_of_legacy_routing());
         }
 
-        let reg_addr = self.base_addr + 0x10;
-        let reg_ptr = reg_addr as *mut GenConfReg;
-        unsafe {
-            reg_ptr.write_volatile(new_value);
-        }
+        self.registers.write(GEN_CONF_REG, new_value);
     }
 
     pub fn gen_int_status_reg(&self) -> GenIntStatusReg {
-        let reg_addr = self.base_addr + 0x20;
-        let reg_ptr = reg_addr as *const GenIntStatusReg;
-        unsafe { reg_ptr.read_volatile() }
+        self.registers.read(GEN_INT_STATUS_REG)
     }
 
     pub fn main_counter_value(&self) -> u64 {
-        let reg_addr = self.base_addr + 0xF0;
-        let reg_ptr = reg_addr as *const u64;
-        unsafe { reg_ptr.read_volatile() }
+        self.registers.read64(MAIN_COUNTER_VALUE_REG)
     }
 
     pub fn write_main_counter_value(&self, new_value: u64) {
         assert!(!self.gen_conf_reg().is_enabled());
         // FIXME: also check whether the timer is operating in 32-bit mode.
 
-        let reg_addr = self.base_addr + 0xF0;
-        let reg_ptr = reg_addr as *mut u64;
-        unsafe { reg_ptr.write_volatile(new_value) }
+        self.registers.write64(MAIN_COUNTER_VALUE_REG, new_value);
     }
 
     pub fn timer_conf_and_cap_reg(&self, timer_n: usize) -> TimerConfAndCapReg {
         assert!(timer_n <= self.gen_caps_and_id_reg().num_timers());
-        let reg_addr = self.base_addr + 0x100 + 0x20 * (timer_n as u32);
-        let reg_ptr = reg_addr as *const TimerConfAndCapReg;
-        unsafe { reg_ptr.read_volatile() }
+        self.registers
+            .read(timer_reg(timer_n, TIMER_CONF_AND_CAP_REG))
     }
 
     pub fn write_timer_conf_and_cap_reg(
@@ -169,26 +185,46 @@ impl Hpet {
     ) {
         // FIXME: no checks needed?
         assert!(timer_n <= self.gen_caps_and_id_reg().num_timers());
-        let reg_addr = self.base_addr + 0x100 + 0x20 * (timer_n as u32);
-        let reg_ptr = reg_addr as *mut TimerConfAndCapReg;
-        unsafe { reg_ptr.write_volatile(new_value) }
+        self.registers
+            .write(timer_reg(timer_n, TIMER_CONF_AND_CAP_REG), new_value);
     }
 
     pub fn timer_comparator_value(&self, timer_n: usize) -> u64 {
         assert!(timer_n <= self.gen_caps_and_id_reg().num_timers());
-        let reg_addr = self.base_addr + 0x108 + 0x20 * (timer_n as u32);
-        let reg_ptr = reg_addr as *const u64;
-        unsafe { reg_ptr.read_volatile() }
+        self.registers
+            .read64(timer_reg(timer_n, TIMER_COMPARATOR_VALUE_REG))
     }
 
     pub fn write_timer_comparator_value(&self, timer_n: usize, new_value: u64) {
         assert!(timer_n <= self.gen_caps_and_id_reg().num_timers());
-        let reg_addr = self.base_addr + 0x108 + 0x20 * (timer_n as u32);
-        let reg_ptr = reg_addr as *mut u64;
-        unsafe { reg_ptr.write_volatile(new_value) }
+        self.registers
+            .write64(timer_reg(timer_n, TIMER_COMPARATOR_VALUE_REG), new_value);
+    }
+
+    /// Sets the next interrupt of the periodic timer 0 to be in `period_ms`
+    /// and its period to `period_ms`.
+    fn program_period(&self, hpet_dt: &HpetDt) {
+        // Calculate the period in ticks.
+        let tick_fs =
+            self.gen_caps_and_id_reg().main_counter_tick_period() as u64;
+        let period_fs = self.period_ms as u64 * 1_000_000_000_000; // 1e12
+        let period_ticks = period_fs / tick_fs;
+        assert_ne!(period_ticks, 0);
+        assert!(period_ticks >= hpet_dt.main_counter_min_tick as u64);
+
+        // With VAL_SET_CNF, the first write sets the comparator and the
+        // second one sets the period.  The bit is cleared by the write.
+        let mut t0_conf = self.timer_conf_and_cap_reg(0);
+        t0_conf.allow_setting_acc_value(true);
+        self.write_timer_conf_and_cap_reg(0, t0_conf);
+
+        let main_counter = self.main_counter_value();
+        self.write_timer_comparator_value(0, main_counter + period_ticks);
+        self.write_timer_comparator_value(0, period_ticks);
     }
 }
 
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct GenCapsAndIdReg(u64);
 
@@ -234,6 +270,7 @@ impl fmt::Debug for GenCapsAndIdReg {
     }
 }
 
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct GenConfReg(u64);
 
@@ -272,6 +309,7 @@ impl fmt::Debug for GenConfReg {
     }
 }
 
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct GenIntStatusReg(u64);
 
@@ -293,6 +331,7 @@ impl fmt::Debug for GenIntStatusReg {
     }
 }
 
+#[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct TimerConfAndCapReg(u64);
 
@@ -472,21 +511,9 @@ impl Timer for Hpet {
         let mut t0_conf = hpet.timer_conf_and_cap_reg(0);
         t0_conf.set_int_enabled(true);
         t0_conf.set_type(TimerType::Periodic);
-        t0_conf.allow_setting_acc_value(true);
         t0_conf.set_32bit_mode(true);
         hpet.write_timer_conf_and_cap_reg(0, t0_conf);
-
-        // Calculate the period in ticks.
-        let tick_fs =
-            hpet.gen_caps_and_id_reg().main_counter_tick_period() as u64;
-        let period_fs = period_ms as u64 * 1_000_000_000_000; // 1e12
-        let period_ticks = period_fs / tick_fs;
-        assert_ne!(period_ticks, 0);
-        assert!(period_ticks >= hpet_dt.main_counter_min_tick as u64);
-
-        let main_counter = hpet.main_counter_value();
-        hpet.write_timer_comparator_value(0, main_counter + period_ticks);
-        hpet.write_timer_comparator_value(0, period_ticks);
+        hpet.program_period(&hpet_dt);
 
         println!("[HPET] Registers dump:");
         hpet.dump_registers();
@@ -505,12 +532,19 @@ impl Timer for Hpet {
         self.period_ms as usize
     }
 
-    fn set_callback(&mut self, callback: TimerCallback) {
-        self.callback = Some(callback);
-    }
-
-    fn callback(&self) -> Option<TimerCallback> {
-        self.callback
+    fn set_period_ms(&mut self, period_ms: usize) {
+        let hpet_dt = unsafe { KERNEL_INFO.arch.hpet_dt.unwrap() };
+        self.period_ms = period_ms as u32;
+
+        // Stop the main counter, so that it does not pass the new comparator
+        // value before the period is set.
+        let mut gen_conf = self.gen_conf_reg();
+        gen_conf.set_enabled(false);
+        self.write_gen_conf_reg(gen_conf);
+        self.program_period(&hpet_dt);
+        let mut gen_conf = self.gen_conf_reg();
+        gen_conf.set_enabled(true);
+        self.write_gen_conf_reg(gen_conf);
     }
 }
 
@@ -519,10 +553,6 @@ pub extern "C" fn hpet_irq_handler() {
     unsafe {
         PIC.send_eoi(0);
 
-        if let Some(timer) = TIMER.as_ref() {
-            if let Some(callback) = timer.callback() {
-                callback();
-            }
-        }
+        timer::tick();
     }
 }
@@ -0,0 +1,293 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! PS/2 controller (Intel 8042).
+//!
+//! The controller is initialized before the keyboard driver so that the
+//! keyboard is known to work and sends the scancodes of set 1, which the
+//! controller translates from set 2 that every keyboard starts with.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::dev::pit::Pit;
+use crate::arch::port_io;
+
+const PORT_DATA: u16 = 0x60;
+const PORT_CMD: u16 = 0x64;
+const PORT_STATUS: u16 = 0x64;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xA7;
+const CMD_ENABLE_PORT2: u8 = 0xA8;
+const CMD_TEST_PORT2: u8 = 0xA9;
+const CMD_SELF_TEST: u8 = 0xAA;
+const CMD_TEST_PORT1: u8 = 0xAB;
+const CMD_DISABLE_PORT1: u8 = 0xAD;
+const CMD_ENABLE_PORT1: u8 = 0xAE;
+const CMD_WRITE_PORT2: u8 = 0xD4;
+const CMD_PULSE_RESET: u8 = 0xFE;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const DEV_CMD_RESET: u8 = 0xFF;
+const DEV_ACK: u8 = 0xFA;
+const DEV_RESEND: u8 = 0xFE;
+const DEV_SELF_TEST_PASSED: u8 = 0xAA;
+
+/// Time to wait for the controller to take or send a byte.
+const TIMEOUT_US: u32 = 100_000;
+
+/// Same as [TIMEOUT_US], but for the device self-test that follows a reset
+/// and may take hundreds of milliseconds.
+const RESET_TIMEOUT_US: u32 = 1_000_000;
+
+/// Maximum number of stale bytes read out of the output buffer.
+const MAX_FLUSHED_BYTES: usize = 64;
+
+/// Number of times a byte is resent to a device on its request.
+const MAX_RESENDS: usize = 3;
+
+bitflags_new! {
+    struct Status: u8 {
+        const OUTPUT_FULL = 1 << 0;
+        const INPUT_FULL = 1 << 1;
+        const PORT2_OUTPUT_FULL = 1 << 5;
+    }
+}
+
+bitflags_new! {
+    struct Config: u8 {
+        const PORT1_IRQ = 1 << 0;
+        const PORT2_IRQ = 1 << 1;
+        const SYSTEM_FLAG = 1 << 2;
+        const PORT1_CLOCK_DISABLED = 1 << 4;
+        const PORT2_CLOCK_DISABLED = 1 << 5;
+        const PORT1_TRANSLATION = 1 << 6;
+    }
+}
+
+static HAS_SECOND_PORT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum InitErr {
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(u8),
+    NoAckOnReset(u8),
+    NoAck(u8),
+    DeviceSelfTestFailed(u8),
+}
+
+fn status() -> Status {
+    unsafe { Status::from_bits_unchecked(port_io::inb(PORT_STATUS)) }
+}
+
+fn write_port(port: u16, value: u8) -> Result<(), InitErr> {
+    let mut countdown = Pit::start_countdown_us(TIMEOUT_US);
+    while !countdown.is_expired() {
+        if !status().contains(Status::INPUT_FULL) {
+            unsafe {
+                port_io::outb(port, value);
+            }
+            return Ok(());
+        }
+    }
+    Err(InitErr::Timeout)
+}
+
+fn read_data_within(timeout_us: u32) -> Result<u8, InitErr> {
+    let mut countdown = Pit::start_countdown_us(timeout_us);
+    while !countdown.is_expired() {
+        if status().contains(Status::OUTPUT_FULL) {
+            return Ok(unsafe { port_io::inb(PORT_DATA) });
+        }
+    }
+    Err(InitErr::Timeout)
+}
+
+fn read_data() -> Result<u8, InitErr> {
+    read_data_within(TIMEOUT_US)
+}
+
+fn send_command(cmd: u8) -> Result<(), InitErr> {
+    write_port(PORT_CMD, cmd)
+}
+
+fn flush_output() {
+    for _ in 0..MAX_FLUSHED_BYTES {
+        if !status().contains(Status::OUTPUT_FULL) {
+            break;
+        }
+        unsafe {
+            port_io::inb(PORT_DATA);
+        }
+    }
+}
+
+fn read_config() -> Result<Config, InitErr> {
+    send_command(CMD_READ_CONFIG)?;
+    Ok(Config::from_bits_unchecked(read_data()?))
+}
+
+fn write_config(config: Config) -> Result<(), InitErr> {
+    send_command(CMD_WRITE_CONFIG)?;
+    write_port(PORT_DATA, config.bits())
+}
+
+fn test_port(cmd: u8) -> Result<(), InitErr> {
+    send_command(cmd)?;
+    match read_data()? {
+        PORT_TEST_PASSED => Ok(()),
+        result => Err(InitErr::PortTestFailed(result)),
+    }
+}
+
+/// Resets the keyboard and waits for its self-test to pass.
+fn reset_keyboard() -> Result<(), InitErr> {
+    write_port(PORT_DATA, DEV_CMD_RESET)?;
+    match read_data()? {
+        DEV_ACK => {}
+        response => return Err(InitErr::NoAckOnReset(response)),
+    }
+    match read_data_within(RESET_TIMEOUT_US)? {
+        DEV_SELF_TEST_PASSED => Ok(()),
+        response => Err(InitErr::DeviceSelfTestFailed(response)),
+    }
+}
+
+fn try_init() -> Result<(), InitErr> {
+    // Keep the devices quiet during the initialization.
+    send_command(CMD_DISABLE_PORT1)?;
+    send_command(CMD_DISABLE_PORT2)?;
+    flush_output();
+
+    let mut config = read_config()?;
+    config.remove(Config::PORT1_IRQ);
+    config.remove(Config::PORT2_IRQ);
+    config.remove(Config::PORT1_TRANSLATION);
+    write_config(config)?;
+
+    send_command(CMD_SELF_TEST)?;
+    match read_data()? {
+        SELF_TEST_PASSED => {}
+        result => return Err(InitErr::SelfTestFailed(result)),
+    }
+    // The self-test may reset the controller.
+    write_config(config)?;
+
+    // If the second port clock gets enabled, there is a second port.
+    send_command(CMD_ENABLE_PORT2)?;
+    let has_second_port =
+        !read_config()?.contains(Config::PORT2_CLOCK_DISABLED);
+    if has_second_port {
+        send_command(CMD_DISABLE_PORT2)?;
+        if let Err(err) = test_port(CMD_TEST_PORT2) {
+            println!("[I8042] Second port test failed: {:?}.", err);
+        } else {
+            HAS_SECOND_PORT.store(true, Ordering::SeqCst);
+        }
+    }
+
+    test_port(CMD_TEST_PORT1)?;
+    send_command(CMD_ENABLE_PORT1)?;
+    reset_keyboard()?;
+    flush_output();
+
+    config.insert(Config::PORT1_IRQ);
+    config.insert(Config::PORT1_TRANSLATION);
+    config.remove(Config::PORT1_CLOCK_DISABLED);
+    write_config(config)
+}
+
+/// Initializes the controller and the keyboard on its first port.  The
+/// keyboard must not be used if this function fails.
+pub fn init() -> Result<(), InitErr> {
+    println!("[I8042] Initializing PS/2 controller.");
+    let res = try_init();
+    match res {
+        Ok(()) => println!(
+            "[I8042] Second port: {}.",
+            if has_second_port() { "yes" } else { "no" },
+        ),
+        Err(ref err) => {
+            log_err!("[I8042] Initialization failed: {:?}.", err);
+            let _ = send_command(CMD_DISABLE_PORT1);
+        }
+    }
+    res
+}
+
+/// Checks if the controller has a working second (mouse) port.
+pub fn has_second_port() -> bool {
+    HAS_SECOND_PORT.load(Ordering::SeqCst)
+}
+
+/// Resets the CPU by pulsing the controller's reset line.  Returns only if
+/// the reset does not happen.
+pub fn reset_cpu() {
+    let _ = send_command(CMD_PULSE_RESET);
+    Pit::one_shot_poll_us(TIMEOUT_US);
+}
+
+/// Reads the data port without waiting, for the IRQ handlers.
+pub unsafe fn read_data_now() -> u8 {
+    port_io::inb(PORT_DATA)
+}
+
+/// Enables the second port and its IRQ.
+pub fn enable_second_port() -> Result<(), InitErr> {
+    send_command(CMD_ENABLE_PORT2)?;
+    let mut config = read_config()?;
+    config.insert(Config::PORT2_IRQ);
+    config.remove(Config::PORT2_CLOCK_DISABLED);
+    write_config(config)
+}
+
+/// Reads a byte sent by the device on the second port, skipping the bytes
+/// from the keyboard.
+///
+/// Must be called with the interrupts disabled, before the IRQ of the second
+/// port is unmasked.
+pub fn read_second_port() -> Result<u8, InitErr> {
+    let mut countdown = Pit::start_countdown_us(TIMEOUT_US);
+    while !countdown.is_expired() {
+        let status = status();
+        if status.contains(Status::OUTPUT_FULL) {
+            let byte = unsafe { port_io::inb(PORT_DATA) };
+            if status.contains(Status::PORT2_OUTPUT_FULL) {
+                return Ok(byte);
+            }
+        }
+    }
+    Err(InitErr::Timeout)
+}
+
+/// Sends a byte to the device on the second port and waits for it to be
+/// acknowledged.  See [read_second_port] for when it may be called.
+pub fn write_second_port(byte: u8) -> Result<(), InitErr> {
+    for _ in 0..=MAX_RESENDS {
+        send_command(CMD_WRITE_PORT2)?;
+        write_port(PORT_DATA, byte)?;
+        match read_second_port()? {
+            DEV_ACK => return Ok(()),
+            DEV_RESEND => continue,
+            response => return Err(InitErr::NoAck(response)),
+        }
+    }
+    Err(InitErr::NoAck(DEV_RESEND))
+}
@@ -14,12 +14,17 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use alloc::collections::vec_deque::VecDeque;
 use alloc::rc::Rc;
 use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
 
 use crate::arch::dev::pic::PIC;
 use crate::arch::interrupts::IDT;
+use crate::dev::sysrq::SysRq;
+use crate::dev::timer;
+use crate::kernel_static::Mutex;
 use crate::port::{Port, PortBuilder};
 
 extern "C" {
@@ -36,6 +41,58 @@ const PORT_STATUS: u16 = 0x64;
 // const RSP_RESEND: u8 = 0xFE;
 // const RSP_ECHO: u8 = 0xEE;
 
+const CMD_SET_LEDS: u8 = 0xED;
+
+/// Status register bit that is set while the controller has not taken the
+/// previous byte written to the data port.
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+/// Number of times a command byte is resent on the keyboard's request before
+/// the command is dropped.
+const MAX_RESENDS: usize = 3;
+
+/// Time to wait for the keyboard to acknowledge a command byte before the
+/// command is dropped.
+const COMMAND_TIMEOUT_MS: u64 = 100;
+
+bitflags_new! {
+    struct Leds: u8 {
+        const SCROLL_LOCK = 1 << 0;
+        const NUM_LOCK = 1 << 1;
+        const CAPS_LOCK = 1 << 2;
+    }
+}
+
+/// Number of events kept while there are no listeners.
+const MAX_PENDING_EVENTS: usize = 32;
+
+/// LED state to be sent to the keyboard, see [set_leds].
+static NEW_LEDS: AtomicU8 = AtomicU8::new(NO_NEW_LEDS);
+const NO_NEW_LEDS: u8 = 0xFF;
+
+/// Command to the keyboard with an optional data byte.  Each byte must be
+/// acknowledged before the next one is sent.
+struct Command {
+    bytes: [u8; 2],
+    len: usize,
+    num_acked: usize,
+    num_resends: usize,
+    /// Uptime when the current byte was sent.
+    sent_at_ms: u64,
+}
+
+impl Command {
+    fn new(cmd: u8, data: Option<u8>) -> Self {
+        Command {
+            bytes: [cmd, data.unwrap_or(0)],
+            len: if data.is_some() { 2 } else { 1 },
+            num_acked: 0,
+            num_resends: 0,
+            sent_at_ms: 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(u8)]
 enum Response {
@@ -59,10 +116,21 @@ impl From<u8> for Response {
 pub struct Keyboard {
     data: Port,
     _cmd: Port,
-    _status: Port,
+    status: Port,
 
     scseq: Vec<u8>, // current scancode sequence
-    listener: Option<Rc<RefCell<dyn EventListener>>>,
+    listeners: Vec<Rc<RefCell<dyn EventListener>>>,
+    /// Events received while there were no listeners, the oldest first.
+    pending_events: VecDeque<Event>,
+    /// Number of pending events dropped because there were too many.
+    num_dropped_events: usize,
+
+    /// Commands to send, the first one is being sent.
+    commands: VecDeque<Command>,
+    /// LED state sent last, `None` if it is not known.
+    sent_leds: Option<u8>,
+
+    sysrq: SysRq,
 }
 
 impl Keyboard {
@@ -70,30 +138,141 @@ impl Keyboard {
         Keyboard {
             data: PortBuilder::port(PORT_DATA).size(8).done(),
             _cmd: PortBuilder::port(PORT_CMD).write_size(8).done(),
-            _status: PortBuilder::port(PORT_STATUS).read_size(8).done(),
+            status: PortBuilder::port(PORT_STATUS).read_size(8).done(),
 
             scseq: Vec::new(),
-            listener: None,
+            listeners: Vec::new(),
+            pending_events: VecDeque::with_capacity(MAX_PENDING_EVENTS),
+            num_dropped_events: 0,
+
+            commands: VecDeque::new(),
+            sent_leds: None,
+
+            sysrq: SysRq::new(),
+        }
+    }
+
+    /// Queues a command with an optional data byte.  It is sent once the
+    /// previous commands are done.
+    ///
+    /// Must be called with the interrupts disabled.
+    pub fn send_command(&mut self, cmd: u8, data: Option<u8>) {
+        self.commands.push_back(Command::new(cmd, data));
+        if self.commands.len() == 1 {
+            self.send_next_byte();
+        }
+    }
+
+    /// Sends the first unacknowledged byte of the current command.
+    fn send_next_byte(&mut self) {
+        let command = match self.commands.front_mut() {
+            Some(command) => command,
+            None => return,
+        };
+        command.sent_at_ms = timer::uptime_ms();
+        unsafe {
+            // If the controller does not take the previous byte, the command
+            // times out.
+            for _ in 0..1000 {
+                if self.status.read::<u8>() & STATUS_INPUT_FULL == 0 {
+                    break;
+                }
+            }
+            self.data.write(command.bytes[command.num_acked]);
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        let done = match self.commands.front_mut() {
+            Some(command) => {
+                command.num_acked += 1;
+                command.num_resends = 0;
+                command.num_acked == command.len
+            }
+            None => return,
+        };
+        if done {
+            self.commands.pop_front();
+        }
+        self.send_next_byte();
+    }
+
+    fn resend(&mut self) {
+        let give_up = match self.commands.front_mut() {
+            Some(command) => {
+                command.num_resends += 1;
+                command.num_resends > MAX_RESENDS
+            }
+            None => return,
+        };
+        if give_up {
+            self.drop_command("keeps being asked to be resent");
+        }
+        self.send_next_byte();
+    }
+
+    /// Drops the current command if it has not been acknowledged in time, so
+    /// that a dead keyboard does not block the queue.
+    fn check_timeout(&mut self) {
+        let timed_out = match self.commands.front() {
+            Some(command) => {
+                timer::uptime_ms().saturating_sub(command.sent_at_ms)
+                    > COMMAND_TIMEOUT_MS
+            }
+            None => return,
+        };
+        if timed_out {
+            self.drop_command("has timed out");
+            self.send_next_byte();
+        }
+    }
+
+    fn drop_command(&mut self, reason: &str) {
+        if let Some(command) = self.commands.pop_front() {
+            if command.bytes[0] == CMD_SET_LEDS {
+                self.sent_leds = None;
+            }
+            try_println!(
+                "[KBD] Dropping command 0x{:02X}, it {}.",
+                command.bytes[0],
+                reason,
+            );
+        }
+    }
+
+    /// Sends the LED state requested with [set_leds], if any and if it
+    /// differs from the one sent last.
+    fn update_leds(&mut self) {
+        let leds = NEW_LEDS.swap(NO_NEW_LEDS, Ordering::SeqCst);
+        if leds != NO_NEW_LEDS && self.sent_leds != Some(leds) {
+            self.sent_leds = Some(leds);
+            self.send_command(CMD_SET_LEDS, Some(leds));
         }
     }
 
     unsafe fn feed(&mut self) {
         let sc = self.data.read::<u8>();
+        match Response::from(sc) {
+            // These are not scancodes.
+            Response::Ack => return self.acknowledge(),
+            Response::Resend => return self.resend(),
+            Response::Error => {
+                try_println!("[KBD] Keyboard error or buffer overrun.");
+                self.scseq.truncate(0);
+                return;
+            }
+            Response::Unknown => {}
+        }
         self.scseq.push(sc);
         // println!("[KBD] scseq = {:02X?}", self.scseq);
         let maybe_event = self.try_resolve();
         if let Some(event) = maybe_event {
             // println!("[KBD] event = {:?}", event);
-            if self.listener.is_some() {
-                self.listener
-                    .as_ref()
-                    .unwrap()
-                    .borrow_mut()
-                    .receive_event(event);
-            } else {
-                println!("[KBD] There is no event listener set.");
+            if !self.sysrq.handle_event(&event) {
+                self.dispatch(event);
             }
         }
+        self.update_leds();
     }
 
     fn try_resolve(&mut self) -> Option<Event> {
@@ -134,6 +313,8 @@ impl Keyboard {
 
                 0x45 => Some(Key::NumLock),
                 0x46 => Some(Key::ScrollLock),
+                // Alt+PrintScreen.
+                0x54 => Some(Key::PrintScreenSysRq),
 
                 0x02 => Some(Key::One),
                 0x03 => Some(Key::Two),
@@ -185,6 +366,7 @@ impl Keyboard {
                 0x33 => Some(Key::Comma),
                 0x34 => Some(Key::Period),
                 0x35 => Some(Key::Slash),
+                0x56 => Some(Key::NonUsBackslash),
 
                 0x37 => Some(Key::NumpadAsterisk),
                 0x4A => Some(Key::NumpadMinus),
@@ -289,21 +471,51 @@ impl Keyboard {
         None
     }
 
-    pub fn set_listener(
+    /// Passes the event to all listeners or keeps it until there is one.
+    fn dispatch(&mut self, event: Event) {
+        if self.listeners.is_empty() {
+            if self.pending_events.len() == MAX_PENDING_EVENTS {
+                self.pending_events.pop_front();
+                self.num_dropped_events += 1;
+            }
+            self.pending_events.push_back(event);
+            return;
+        }
+        for listener in self.listeners.iter() {
+            listener.borrow_mut().receive_event(event);
+        }
+    }
+
+    /// Adds a listener that receives every following event.  The first
+    /// listener also receives the events that have been kept until then.
+    pub fn add_listener(
         &mut self,
         new_listener: Rc<RefCell<dyn EventListener>>,
     ) {
-        self.listener = Some(new_listener);
+        if self.num_dropped_events != 0 {
+            try_println!(
+                "[KBD] Dropped {} events while there were no listeners.",
+                self.num_dropped_events,
+            );
+            self.num_dropped_events = 0;
+        }
+        while let Some(event) = self.pending_events.pop_front() {
+            new_listener.borrow_mut().receive_event(event);
+        }
+        self.listeners.push(new_listener);
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct Event {
     pub key: Key,
     pub pressed: bool,
 }
 
-#[derive(PartialEq, Debug)]
+/// Key of a PC keyboard.
+///
+/// `NumpadZero` must stay the last variant, see [NUM_KEYS].
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum Key {
     Escape,
     Backtick,
@@ -395,6 +607,8 @@ pub enum Key {
     Comma,
     Period,
     Slash,
+    /// Key between the left Shift and Z on the ISO keyboards.
+    NonUsBackslash,
 
     LeftArrow,
     UpArrow,
@@ -420,17 +634,52 @@ pub enum Key {
     NumpadZero,
 }
 
+/// Number of the [Key] variants.
+pub const NUM_KEYS: usize = Key::NumpadZero as usize + 1;
+
 pub trait EventListener {
     fn receive_event(&mut self, event: Event);
 }
 
-pub static mut KEYBOARD: Option<Keyboard> = None;
+// Locked by the keyboard IRQ handler and the timer event, the other users must
+// disable the interrupts to lock it.
+kernel_static! {
+    pub static ref KEYBOARD: Mutex<Option<Keyboard>> = Mutex::new(None);
+}
+
+/// Requests the keyboard LEDs to be set.  They are updated from the keyboard
+/// or the timer IRQ handler.
+pub fn set_leds(scroll_lock: bool, num_lock: bool, caps_lock: bool) {
+    let mut leds = Leds::empty();
+    for &(on, led) in &[
+        (scroll_lock, Leds::SCROLL_LOCK),
+        (num_lock, Leds::NUM_LOCK),
+        (caps_lock, Leds::CAPS_LOCK),
+    ] {
+        if on {
+            leds.insert(led);
+        }
+    }
+    NEW_LEDS.store(leds.bits(), Ordering::SeqCst);
+}
+
+/// Sends the requested LED state and drops the timed out commands, called on
+/// every timer tick.
+fn keyboard_tick(_: usize) {
+    // The tick may come while a task is locking the keyboard to add a
+    // listener, then it is done on the next tick.
+    if let Some(mut keyboard) = KEYBOARD.try_lock() {
+        if let Some(keyboard) = keyboard.as_mut() {
+            keyboard.check_timeout();
+            keyboard.update_leds();
+        }
+    }
+}
 
 pub fn init() {
     println!("[KBD] Initializing keyboard.");
-    unsafe {
-        KEYBOARD = Some(Keyboard::new());
-    }
+    *KEYBOARD.lock() = Some(Keyboard::new());
+    timer::every_tick(keyboard_tick, 0);
     IDT.lock().interrupts[IRQ as usize].set_handler(irq1_handler);
     unsafe {
         PIC.set_irq_mask(IRQ, false);
@@ -440,7 +689,7 @@ pub fn init() {
 #[no_mangle]
 pub extern "C" fn keyboard_irq_handler() {
     unsafe {
-        KEYBOARD.as_mut().unwrap().feed();
+        KEYBOARD.lock().as_mut().unwrap().feed();
         PIC.send_eoi(IRQ);
     }
 }
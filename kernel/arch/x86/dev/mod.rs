@@ -15,6 +15,9 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 pub mod acpi;
+pub mod i8042;
 pub mod keyboard;
+pub mod mouse;
 pub mod pic;
 pub mod pit;
+pub mod serial;
@@ -0,0 +1,371 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! PS/2 mouse driver.
+//!
+//! The mouse is on the second port of the [i8042](super::i8042) controller.
+//! Its IRQ 12 handler assembles the packets into [MouseEvent]s, which are
+//! passed to the listener and kept in a ring that is read through
+//! `/dev/mouse`.  Mice that pass the IntelliMouse detection send 4-byte
+//! packets with the wheel movement.
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+use crate::arch::dev::i8042::{self, InitErr};
+use crate::arch::dev::pic::PIC;
+use crate::arch::interrupts::{self, IDT};
+use crate::dev::char_device::{CharDevice, ReadErr, WriteErr};
+use crate::dev::timer;
+use crate::kernel_static::Mutex;
+
+extern "C" {
+    fn irq12_handler();
+}
+
+const IRQ: u8 = 12;
+
+const CMD_SET_RESOLUTION: u8 = 0xE8;
+const CMD_GET_DEVICE_ID: u8 = 0xF2;
+const CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const CMD_ENABLE_REPORTING: u8 = 0xF4;
+const CMD_SET_DEFAULTS: u8 = 0xF6;
+
+/// Device ID of the mice with a wheel.
+const ID_INTELLIMOUSE: u8 = 3;
+
+/// Sample rates that make an IntelliMouse report its own device ID.
+const INTELLIMOUSE_KNOCK: [u8; 3] = [200, 100, 80];
+
+const SAMPLE_RATE: u8 = 100; // samples per second
+const RESOLUTION: u8 = 2; // 4 counts per mm
+
+/// Time between the bytes of a packet after which the packet is considered
+/// broken and a new one is started.
+const PACKET_TIMEOUT_MS: u64 = 50;
+
+bitflags_new! {
+    struct PacketFlags: u8 {
+        const LEFT_BUTTON = 1 << 0;
+        const RIGHT_BUTTON = 1 << 1;
+        const MIDDLE_BUTTON = 1 << 2;
+        const ALWAYS_ONE = 1 << 3;
+        const X_SIGN = 1 << 4;
+        const Y_SIGN = 1 << 5;
+        const X_OVERFLOW = 1 << 6;
+        const Y_OVERFLOW = 1 << 7;
+    }
+}
+
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_RIGHT: u8 = 1 << 1;
+pub const BUTTON_MIDDLE: u8 = 1 << 2;
+
+/// Mouse movement since the previous event and the pressed buttons.
+///
+/// `dy` is positive when the mouse moves away from the user, `dz` is positive
+/// when the wheel is rolled towards the user.
+#[derive(Clone, Copy, Debug)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub dz: i8,
+    /// `BUTTON_*` bits.
+    pub buttons: u8,
+}
+
+impl MouseEvent {
+    /// Size of an event read from `/dev/mouse`.
+    pub const SIZE: usize = 6;
+
+    /// Returns the event as it is read from `/dev/mouse`: `dx` and `dy` in
+    /// little endian, then `dz` and `buttons`.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let dx = self.dx.to_le_bytes();
+        let dy = self.dy.to_le_bytes();
+        [dx[0], dx[1], dy[0], dy[1], self.dz as u8, self.buttons]
+    }
+
+    fn from_packet(packet: &[u8]) -> Self {
+        let flags = PacketFlags::from_bits_unchecked(packet[0]);
+
+        // The movement is a 9-bit two's complement number.
+        let delta = |value: u8, sign: PacketFlags, overflow: PacketFlags| {
+            if flags.contains(overflow) {
+                0
+            } else if flags.contains(sign) {
+                value as i16 - 0x100
+            } else {
+                value as i16
+            }
+        };
+
+        let mut buttons = 0;
+        for &(flag, button) in &[
+            (PacketFlags::LEFT_BUTTON, BUTTON_LEFT),
+            (PacketFlags::RIGHT_BUTTON, BUTTON_RIGHT),
+            (PacketFlags::MIDDLE_BUTTON, BUTTON_MIDDLE),
+        ] {
+            if flags.contains(flag) {
+                buttons |= button;
+            }
+        }
+
+        MouseEvent {
+            dx: delta(packet[1], PacketFlags::X_SIGN, PacketFlags::X_OVERFLOW),
+            dy: delta(packet[2], PacketFlags::Y_SIGN, PacketFlags::Y_OVERFLOW),
+            // The low nibble is the 4-bit two's complement wheel movement.
+            dz: packet.get(3).map_or(0, |&z| ((z << 4) as i8) >> 4),
+            buttons,
+        }
+    }
+}
+
+pub trait MouseListener {
+    fn receive_mouse_event(&mut self, event: MouseEvent);
+}
+
+const RING_SIZE: usize = 256;
+
+/// Event ring buffer that overwrites the oldest events when it is full.
+struct EventRing {
+    events: [MouseEvent; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl EventRing {
+    const fn new() -> Self {
+        EventRing {
+            events: [MouseEvent {
+                dx: 0,
+                dy: 0,
+                dz: 0,
+                buttons: 0,
+            }; RING_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, event: MouseEvent) {
+        self.events[(self.start + self.len) % RING_SIZE] = event;
+        if self.len < RING_SIZE {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % RING_SIZE;
+        }
+    }
+
+    fn pop(&mut self) -> Option<MouseEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.start];
+        self.start = (self.start + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+kernel_static! {
+    static ref EVENTS: Mutex<EventRing> = Mutex::new(EventRing::new());
+}
+
+pub struct Mouse {
+    packet: [u8; 4],
+    packet_len: usize,
+    has_wheel: bool,
+    /// Uptime when the last byte was received.
+    last_byte_ms: u64,
+
+    listeners: Vec<Rc<RefCell<dyn MouseListener>>>,
+}
+
+impl Mouse {
+    fn new(has_wheel: bool) -> Self {
+        Mouse {
+            packet: [0; 4],
+            packet_len: 0,
+            has_wheel,
+            last_byte_ms: 0,
+
+            listeners: Vec::new(),
+        }
+    }
+
+    fn full_packet_len(&self) -> usize {
+        if self.has_wheel {
+            4
+        } else {
+            3
+        }
+    }
+
+    fn feed(&mut self, byte: u8) {
+        let now = timer::uptime_ms();
+        if self.packet_len != 0
+            && now.saturating_sub(self.last_byte_ms) > PACKET_TIMEOUT_MS
+        {
+            // The rest of the packet has been lost.
+            self.packet_len = 0;
+        }
+        self.last_byte_ms = now;
+
+        if self.packet_len == 0
+            && !PacketFlags::from_bits_unchecked(byte)
+                .contains(PacketFlags::ALWAYS_ONE)
+        {
+            // This is not the first byte of a packet, skip bytes until the
+            // packets are in sync again.
+            return;
+        }
+        self.packet[self.packet_len] = byte;
+        self.packet_len += 1;
+
+        if self.packet_len == self.full_packet_len() {
+            self.packet_len = 0;
+            let event =
+                MouseEvent::from_packet(&self.packet[..self.full_packet_len()]);
+            EVENTS.lock().push(event);
+            for listener in self.listeners.iter() {
+                listener.borrow_mut().receive_mouse_event(event);
+            }
+        }
+    }
+
+    /// Adds a listener that receives every following event.
+    pub fn add_listener(
+        &mut self,
+        new_listener: Rc<RefCell<dyn MouseListener>>,
+    ) {
+        self.listeners.push(new_listener);
+    }
+}
+
+pub static mut MOUSE: Option<Mouse> = None;
+
+fn set_sample_rate(rate: u8) -> Result<(), InitErr> {
+    i8042::write_second_port(CMD_SET_SAMPLE_RATE)?;
+    i8042::write_second_port(rate)
+}
+
+fn device_id() -> Result<u8, InitErr> {
+    i8042::write_second_port(CMD_GET_DEVICE_ID)?;
+    i8042::read_second_port()
+}
+
+/// Sets up the mouse, returns whether it has a wheel.
+fn try_init() -> Result<bool, InitErr> {
+    i8042::enable_second_port()?;
+    i8042::write_second_port(CMD_SET_DEFAULTS)?;
+
+    for &rate in INTELLIMOUSE_KNOCK.iter() {
+        set_sample_rate(rate)?;
+    }
+    let has_wheel = device_id()? == ID_INTELLIMOUSE;
+
+    set_sample_rate(SAMPLE_RATE)?;
+    i8042::write_second_port(CMD_SET_RESOLUTION)?;
+    i8042::write_second_port(RESOLUTION)?;
+    i8042::write_second_port(CMD_ENABLE_REPORTING)?;
+    Ok(has_wheel)
+}
+
+/// Initializes the mouse on the second PS/2 port, if there is one.
+pub fn init() {
+    if !i8042::has_second_port() {
+        println!("[MOUSE] There is no second PS/2 port.");
+        return;
+    }
+    println!("[MOUSE] Initializing mouse.");
+    match interrupts::with_disabled(try_init) {
+        Ok(has_wheel) => {
+            println!(
+                "[MOUSE] Wheel: {}.",
+                if has_wheel { "yes" } else { "no" }
+            );
+            unsafe {
+                MOUSE = Some(Mouse::new(has_wheel));
+            }
+        }
+        Err(err) => {
+            log_err!("[MOUSE] Initialization failed: {:?}.", err);
+            return;
+        }
+    }
+    IDT.lock().interrupts[IRQ as usize].set_handler(irq12_handler);
+    unsafe {
+        PIC.set_irq_mask(IRQ, false);
+    }
+}
+
+/// Checks if the mouse has been initialized by [init].
+pub fn is_present() -> bool {
+    unsafe { MOUSE.is_some() }
+}
+
+#[no_mangle]
+pub extern "C" fn mouse_irq_handler() {
+    unsafe {
+        let byte = i8042::read_data_now();
+        MOUSE.as_mut().unwrap().feed(byte);
+        PIC.send_eoi(IRQ);
+    }
+}
+
+/// The mouse as a char device, `/dev/mouse`.
+///
+/// Reads never block and return whole [events](MouseEvent::to_bytes) only,
+/// as many as fit in the buffer.
+pub struct MouseDevice;
+
+impl CharDevice for MouseDevice {
+    fn read(&mut self) -> Result<u8, ReadErr> {
+        Err(ReadErr::InvalidLen)
+    }
+
+    fn read_many(&mut self, buf: &mut [u8]) -> Result<usize, ReadErr> {
+        if buf.len() < MouseEvent::SIZE {
+            return Err(ReadErr::InvalidLen);
+        }
+        interrupts::with_disabled(|| {
+            let mut events = EVENTS.lock();
+            let mut num_read = 0;
+            for chunk in buf.chunks_exact_mut(MouseEvent::SIZE) {
+                match events.pop() {
+                    Some(event) => chunk.copy_from_slice(&event.to_bytes()),
+                    None => break,
+                }
+                num_read += MouseEvent::SIZE;
+            }
+            Ok(num_read)
+        })
+    }
+
+    fn write(&mut self, _byte: u8) -> Result<(), WriteErr> {
+        Err(WriteErr::NotWritable)
+    }
+
+    fn write_many(&mut self, _bytes: &[u8]) -> Result<(), WriteErr> {
+        Err(WriteErr::NotWritable)
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("mouse")
+    }
+}
@@ -118,6 +118,9 @@ impl Pic {
     }
 
     pub fn send_eoi(&self, irq_num: u8) {
+        unsafe {
+            IRQ_COUNTS[irq_num as usize] += 1;
+        }
         if irq_num >= 8 {
             self.send_slave_command(EOI);
         }
@@ -160,6 +163,15 @@ impl Pic {
     }
 }
 
+/// Number of times each IRQ has been handled, counted on its EOI.  Spurious
+/// IRQs are not counted.
+static mut IRQ_COUNTS: [usize; 16] = [0; 16];
+
+/// Returns the number of times the IRQ `irq_num` has been handled.
+pub fn irq_count(irq_num: u8) -> usize {
+    unsafe { IRQ_COUNTS[irq_num as usize] }
+}
+
 pub static mut PIC: Pic = Pic {
     master_vector_offset: 32,
     slave_vector_offset: 40,
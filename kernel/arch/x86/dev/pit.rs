@@ -16,10 +16,10 @@
 
 use crate::arch::dev::pic::PIC;
 use crate::arch::interrupts::{IDT, IRQ0_RUST_HANDLER};
-use crate::dev::timer::TIMER;
 
 use crate::arch::port_io;
-use crate::dev::timer::{Timer, TimerCallback};
+use crate::dev::timer;
+use crate::dev::timer::Timer;
 
 extern "C" {
     fn irq0_handler(); // interrupts.s
@@ -71,15 +71,98 @@ enum OperatingMode {
 pub const IRQ: u8 = 0;
 const BASE_FREQUENCY: f64 = 1.193182e+6; // Hz
 
+/// Port B of the keyboard controller, which gates channel 2.
+const PORT_SPEAKER_CONTROL: u16 = 0x61;
+
+bitflags_new! {
+    struct SpeakerControl: u8 {
+        const CH2_GATE = 1 << 0;
+        const SPEAKER_ENABLE = 1 << 1;
+        const CH2_OUTPUT = 1 << 5;              // read-only
+    }
+}
+
+/// Longest countdown of channel 2 in microseconds, a bit less than 65535
+/// ticks.
+const MAX_COUNTDOWN_US: u32 = 54_000;
+
+/// Countdown on channel 2, which is not connected to an IRQ, so it can be
+/// used with the interrupts disabled and whatever timer drives IRQ 0.
+///
+/// Longer countdowns are split into several ones, each of them is started
+/// when the previous one is checked to be expired.
+pub struct Countdown {
+    remaining_us: u32,
+}
+
+impl Countdown {
+    fn start_part(&mut self) {
+        let part_us = self.remaining_us.min(MAX_COUNTDOWN_US);
+        self.remaining_us -= part_us;
+        let ticks = (part_us as u64 * 1_193_182 / 1_000_000).max(1) as u16;
+
+        let mut value: u8 = 0;
+        value |= 0 << 0; // binary mode (not BCD)
+        value |= (OperatingMode::InterruptOnTerminalCount as u8) << 1;
+        value |= (AccessMode::BothBytes as u8) << 4;
+        value |= Channel::Ch2 as u8;
+
+        unsafe {
+            // The counter starts when the gate goes high.
+            let mut control = SpeakerControl::from_bits_unchecked(
+                port_io::inb(PORT_SPEAKER_CONTROL),
+            );
+            control.remove(SpeakerControl::CH2_GATE);
+            control.remove(SpeakerControl::SPEAKER_ENABLE);
+            port_io::outb(PORT_SPEAKER_CONTROL, control.bits());
+
+            port_io::outb(Port::ModeCommandRegister as u16, value);
+            port_io::outb(Port::Channel2Data as u16, ticks as u8);
+            port_io::outb(Port::Channel2Data as u16, (ticks >> 8) as u8);
+
+            control.insert(SpeakerControl::CH2_GATE);
+            port_io::outb(PORT_SPEAKER_CONTROL, control.bits());
+        }
+    }
+
+    /// Checks if the countdown is over.
+    pub fn is_expired(&mut self) -> bool {
+        // The output goes high when the counter reaches zero.
+        let control = SpeakerControl::from_bits_unchecked(unsafe {
+            port_io::inb(PORT_SPEAKER_CONTROL)
+        });
+        if !control.contains(SpeakerControl::CH2_OUTPUT) {
+            return false;
+        }
+        if self.remaining_us == 0 {
+            return true;
+        }
+        self.start_part();
+        false
+    }
+}
+
 pub struct Pit {
     reload_value: u16,
     operating_mode: OperatingMode,
     access_mode: AccessMode,
-
-    callback: Option<TimerCallback>,
 }
 
 impl Pit {
+    /// Starts a countdown of `us` microseconds, see [Countdown].
+    pub fn start_countdown_us(us: u32) -> Countdown {
+        let mut countdown = Countdown { remaining_us: us };
+        countdown.start_part();
+        countdown
+    }
+
+    /// Waits for `us` microseconds by polling a [Countdown].  The resolution
+    /// is about a microsecond.
+    pub fn one_shot_poll_us(us: u32) {
+        let mut countdown = Self::start_countdown_us(us);
+        while !countdown.is_expired() {}
+    }
+
     pub fn init(&self) {
         self.send_register();
         self.send_reload_value();
@@ -163,8 +246,6 @@ impl Timer for Pit {
             reload_value: 0,
             operating_mode: OperatingMode::SquareWaveGenerator,
             access_mode: AccessMode::BothBytes,
-
-            callback: None,
         };
 
         pit.set_period(period_ms as f64 * 1e-3);
@@ -185,19 +266,16 @@ impl Timer for Pit {
         pit
     }
 
+    fn set_period_ms(&mut self, period_ms: usize) {
+        self.set_period(period_ms as f64 * 1e-3);
+        self.init();
+    }
+
     fn period_ms(&self) -> usize {
-        let res = (self.period() * 1e9) as usize;
+        let res = (self.period() * 1e3 + 0.5) as usize;
         assert_ne!(res, 0);
         res
     }
-
-    fn set_callback(&mut self, callback: TimerCallback) {
-        self.callback = Some(callback);
-    }
-
-    fn callback(&self) -> Option<TimerCallback> {
-        self.callback
-    }
 }
 
 #[no_mangle]
@@ -205,10 +283,6 @@ pub extern "C" fn pit_irq_handler() {
     unsafe {
         PIC.send_eoi(IRQ);
 
-        if let Some(timer) = TIMER.as_ref() {
-            if let Some(callback) = timer.callback() {
-                callback();
-            }
-        }
+        timer::tick();
     }
 }
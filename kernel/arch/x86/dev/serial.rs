@@ -0,0 +1,331 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! 16550 UART driver for the first serial port (COM1).
+//!
+//! The port runs at 115200 baud, 8N1.  Writes poll the line status register,
+//! received bytes are put into a ring buffer by the IRQ 4 handler.  With the
+//! `console=serial` command line option everything printed by the kernel is
+//! mirrored to the port.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::dev::pic::PIC;
+use crate::arch::interrupts::{self, IDT};
+use crate::arch::port_io;
+use crate::dev::char_device::{CharDevice, ReadErr, WriteErr};
+use crate::kernel_static::Mutex;
+use crate::task_manager::WaitQueue;
+
+extern "C" {
+    fn irq4_handler();
+}
+
+const IRQ: u8 = 4;
+
+const COM1: u16 = 0x3F8;
+
+// Register offsets.  The divisor latch replaces the data and interrupt enable
+// registers while DLAB is set.
+const REG_DATA: u16 = 0;
+const REG_INT_ENABLE: u16 = 1;
+const REG_DIVISOR_LOW: u16 = 0;
+const REG_DIVISOR_HIGH: u16 = 1;
+const REG_FIFO_CONTROL: u16 = 2;
+const REG_LINE_CONTROL: u16 = 3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+const REG_SCRATCH: u16 = 7;
+
+/// Divisor of the 115200 Hz UART clock.
+const BAUD_DIVISOR: u16 = 1;
+
+bitflags_new! {
+    struct LineControl: u8 {
+        const WORD_LENGTH_8 = 0b11;
+        const TWO_STOP_BITS = 1 << 2;           // not set: one stop bit
+        const PARITY_ENABLE = 1 << 3;
+        const DLAB = 1 << 7;
+    }
+}
+
+bitflags_new! {
+    struct FifoControl: u8 {
+        const ENABLE = 1 << 0;
+        const CLEAR_RECEIVE = 1 << 1;
+        const CLEAR_TRANSMIT = 1 << 2;
+        const TRIGGER_LEVEL_14 = 0b11 << 6;
+    }
+}
+
+bitflags_new! {
+    struct ModemControl: u8 {
+        const DTR = 1 << 0;
+        const RTS = 1 << 1;
+        const OUT2 = 1 << 3;                    // connects the IRQ line
+    }
+}
+
+bitflags_new! {
+    struct IntEnable: u8 {
+        const RECEIVED_DATA = 1 << 0;
+    }
+}
+
+bitflags_new! {
+    struct LineStatus: u8 {
+        const DATA_READY = 1 << 0;
+        const OVERRUN_ERROR = 1 << 1;
+        const THR_EMPTY = 1 << 5;
+        const TRANSMITTER_EMPTY = 1 << 6;
+    }
+}
+
+const RING_SIZE: usize = 4096;
+
+/// Byte ring buffer that overwrites the oldest bytes when it is full.
+struct RingBuffer {
+    buf: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        RingBuffer {
+            buf: [0; RING_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buf[(self.start + self.len) % RING_SIZE] = byte;
+        if self.len < RING_SIZE {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % RING_SIZE;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.start];
+        self.start = (self.start + 1) % RING_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+kernel_static! {
+    static ref RECEIVED: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+}
+
+// Tasks waiting for a byte to be received.
+kernel_static! {
+    static ref READERS: WaitQueue = WaitQueue::new();
+}
+
+// Kernel output that has not been mirrored to the port, for dumping it on a
+// panic.
+kernel_static! {
+    static ref BACKLOG: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
+}
+
+/// Whether COM1 has been found and initialized.
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Whether the kernel output is [mirrored](mirror) to COM1.
+static MIRROR: AtomicBool = AtomicBool::new(false);
+
+unsafe fn read_reg(reg: u16) -> u8 {
+    port_io::inb(COM1 + reg)
+}
+
+unsafe fn write_reg(reg: u16, value: u8) {
+    port_io::outb(COM1 + reg, value);
+}
+
+fn line_status() -> LineStatus {
+    LineStatus::from_bits_unchecked(unsafe { read_reg(REG_LINE_STATUS) })
+}
+
+/// Sets up COM1 if there is a UART, with its interrupts disabled.
+pub fn init() {
+    unsafe {
+        // There is no UART if the scratch register does not keep its value.
+        write_reg(REG_SCRATCH, 0xA5);
+        if read_reg(REG_SCRATCH) != 0xA5 {
+            println!("[SERIAL] There is no UART on COM1.");
+            return;
+        }
+
+        write_reg(REG_INT_ENABLE, 0);
+        write_reg(REG_LINE_CONTROL, LineControl::DLAB.bits());
+        write_reg(REG_DIVISOR_LOW, BAUD_DIVISOR as u8);
+        write_reg(REG_DIVISOR_HIGH, (BAUD_DIVISOR >> 8) as u8);
+        write_reg(REG_LINE_CONTROL, LineControl::WORD_LENGTH_8.bits());
+        write_reg(
+            REG_FIFO_CONTROL,
+            (FifoControl::ENABLE
+                | FifoControl::CLEAR_RECEIVE
+                | FifoControl::CLEAR_TRANSMIT
+                | FifoControl::TRIGGER_LEVEL_14)
+                .bits(),
+        );
+        write_reg(
+            REG_MODEM_CONTROL,
+            (ModemControl::DTR | ModemControl::RTS | ModemControl::OUT2).bits(),
+        );
+    }
+    PRESENT.store(true, Ordering::SeqCst);
+    println!("[SERIAL] Initialized COM1 at 115200 baud.");
+}
+
+/// Checks if COM1 has been found by [init].
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::SeqCst)
+}
+
+/// Enables the receive interrupt.  Must be called after the IDT and the PIC
+/// are initialized.
+pub fn init_irq() {
+    if !is_present() {
+        return;
+    }
+    IDT.lock().interrupts[IRQ as usize].set_handler(irq4_handler);
+    unsafe {
+        PIC.set_irq_mask(IRQ, false);
+        write_reg(REG_INT_ENABLE, IntEnable::RECEIVED_DATA.bits());
+    }
+}
+
+/// Writes a byte to COM1, waiting until the transmitter can take it.
+pub fn write_byte(byte: u8) {
+    if !is_present() {
+        return;
+    }
+    while !line_status().contains(LineStatus::THR_EMPTY) {}
+    unsafe {
+        write_reg(REG_DATA, byte);
+    }
+}
+
+/// Writes a character, translating a newline into CR LF for terminals.
+fn put_char(ch: u8) {
+    if ch == b'\n' {
+        write_byte(b'\r');
+    }
+    write_byte(ch);
+}
+
+/// Waits until all written bytes are sent out.
+pub fn flush() {
+    if !is_present() {
+        return;
+    }
+    while !line_status().contains(LineStatus::TRANSMITTER_EMPTY) {}
+}
+
+/// Starts or stops mirroring the kernel output to COM1.  Starting it sends
+/// the output that has been kept in the backlog.
+pub fn set_mirror(enable: bool) {
+    if enable && !MIRROR.load(Ordering::SeqCst) {
+        // The backlog may be locked if the panic happened while printing.
+        if let Some(mut backlog) = BACKLOG.try_lock() {
+            while let Some(ch) = backlog.pop() {
+                put_char(ch);
+            }
+        }
+    }
+    MIRROR.store(enable, Ordering::SeqCst);
+}
+
+/// Handles a character of the kernel output: writes it to COM1 if the output
+/// is mirrored or keeps it in the backlog otherwise.
+pub fn mirror(ch: u8) {
+    if MIRROR.load(Ordering::SeqCst) {
+        put_char(ch);
+    } else {
+        interrupts::with_disabled(|| {
+            BACKLOG.lock().push(ch);
+        });
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn serial_irq_handler() {
+    let mut received = RECEIVED.lock();
+    while line_status().contains(LineStatus::DATA_READY) {
+        let byte = unsafe { read_reg(REG_DATA) };
+        received.push(byte);
+    }
+    drop(received);
+    READERS.wake_all();
+    unsafe {
+        PIC.send_eoi(IRQ);
+    }
+}
+
+/// COM1 as a char device, `/dev/ttyS0`.
+pub struct SerialPort;
+
+impl CharDevice for SerialPort {
+    fn read(&mut self) -> Result<u8, ReadErr> {
+        interrupts::with_disabled(|| match RECEIVED.lock().pop() {
+            Some(byte) => Ok(byte),
+            None => Err(ReadErr::Block(&READERS)),
+        })
+    }
+
+    /// Reads the received bytes, blocks if there are none.
+    fn read_many(&mut self, buf: &mut [u8]) -> Result<usize, ReadErr> {
+        if buf.is_empty() {
+            return Err(ReadErr::InvalidLen);
+        }
+        buf[0] = self.read()?;
+        let mut num_read = 1;
+        interrupts::with_disabled(|| {
+            let mut received = RECEIVED.lock();
+            while num_read < buf.len() {
+                match received.pop() {
+                    Some(byte) => buf[num_read] = byte,
+                    None => break,
+                }
+                num_read += 1;
+            }
+        });
+        Ok(num_read)
+    }
+
+    fn write(&mut self, byte: u8) -> Result<(), WriteErr> {
+        put_char(byte);
+        Ok(())
+    }
+
+    fn write_many(&mut self, bytes: &[u8]) -> Result<(), WriteErr> {
+        for byte in bytes {
+            self.write(*byte)?;
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> Option<&str> {
+        Some("ttyS0")
+    }
+}
@@ -0,0 +1,118 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! x87 FPU and SSE state of the tasks.
+//!
+//! The kernel is built without them (see `target.json`), so the FPU registers
+//! always hold the state of the running task.  It is saved and restored
+//! eagerly on every task switch.
+
+use crate::arch::tsc::cpuid;
+
+const CPUID_FEATURES: u32 = 1;
+const FEATURE_EDX_FXSR: u32 = 1 << 24;
+const FEATURE_EDX_SSE: u32 = 1 << 25;
+
+/// Monitor coprocessor, makes WAIT/FWAIT respect CR0.TS.
+const CR0_MP: u32 = 1 << 1;
+/// x87 emulation, FPU instructions raise #NM.
+const CR0_EM: u32 = 1 << 2;
+/// Task switched, FPU instructions raise #NM.
+const CR0_TS: u32 = 1 << 3;
+/// Native x87 exceptions instead of IRQ 13.
+const CR0_NE: u32 = 1 << 5;
+/// FXSAVE, FXRSTOR and SSE instructions are enabled.
+const CR4_OSFXSR: u32 = 1 << 9;
+/// Unmasked SSE exceptions raise #XM.
+const CR4_OSXMMEXCPT: u32 = 1 << 10;
+
+/// x87 control word after FNINIT, all the exceptions are masked.
+const DEFAULT_FCW: u16 = 0x037F;
+/// MXCSR after reset, all the exceptions are masked.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+/// Area for FXSAVE and FXRSTOR.
+#[derive(Clone)]
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    /// Returns the state the x87 FPU and SSE have after FNINIT and reset.
+    pub fn new() -> Self {
+        let mut area = [0; 512];
+        area[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        area[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        FpuState(area)
+    }
+
+    /// Saves the FPU registers here.
+    pub fn save(&mut self) {
+        unsafe {
+            asm!(
+                "fxsave ({})",
+                in(reg) self.0.as_mut_ptr(),
+                options(att_syntax, nostack),
+            );
+        }
+    }
+
+    /// Loads the FPU registers from here.
+    pub fn restore(&self) {
+        unsafe {
+            asm!(
+                "fxrstor ({})",
+                in(reg) self.0.as_ptr(),
+                options(att_syntax, nostack),
+            );
+        }
+    }
+}
+
+/// Enables the x87 FPU and SSE for the usermode.
+///
+/// # Panics
+/// This function panics if the CPU does not support FXSAVE.
+pub fn init() {
+    let features = cpuid(CPUID_FEATURES)[3];
+    assert!(
+        features & FEATURE_EDX_FXSR != 0,
+        "the CPU does not support FXSAVE",
+    );
+    let has_sse = features & FEATURE_EDX_SSE != 0;
+
+    unsafe {
+        let mut cr0: u32;
+        asm!("movl %cr0, {}", out(reg) cr0, options(att_syntax));
+        cr0 &= !(CR0_EM | CR0_TS);
+        cr0 |= CR0_MP | CR0_NE;
+        asm!("movl {}, %cr0", in(reg) cr0, options(att_syntax));
+
+        let mut cr4: u32;
+        asm!("movl %cr4, {}", out(reg) cr4, options(att_syntax));
+        cr4 |= CR4_OSFXSR;
+        if has_sse {
+            cr4 |= CR4_OSXMMEXCPT;
+        }
+        asm!("movl {}, %cr4", in(reg) cr4, options(att_syntax));
+
+        asm!("fninit");
+    }
+
+    println!(
+        "[FPU] Enabled x87{}.",
+        if has_sse { " and SSE" } else { "" }
+    );
+}
@@ -17,7 +17,9 @@
 use core::mem::size_of;
 
 use crate::arch::dev::pic::PIC;
+use crate::arch::syscall::GpRegs;
 use crate::kernel_static::Mutex;
+use crate::task_manager::{self, TASK_MANAGER};
 
 // See interrupts.s
 extern "C" {
@@ -161,6 +163,7 @@ impl Gate<Isr> {
 
 type Isr = unsafe extern "C" fn();
 
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 pub struct InterruptStackFrame {
     pub eip: u32,
@@ -290,6 +293,7 @@ pub extern "C" fn dummy_exception_handler(
     int_num: u32,
     err_code: u32,
     stack_frame: &InterruptStackFrame,
+    gp_regs: &GpRegs,
 ) {
     println!("Dummy exception handler called.");
     println!(" exception number: {}", int_num);
@@ -305,9 +309,40 @@ pub extern "C" fn dummy_exception_handler(
     let eip = stack_frame.eip;
     println!(" eip: 0x{:08X}", eip);
 
+    // An exception in the usermode, e.g. a general protection fault, kills the
+    // task, not the kernel.
+    if stack_frame.cs & 3 == 3 {
+        let task = unsafe { TASK_MANAGER.this_task() };
+        task.print_user_symbol(eip as usize);
+        println!("Killing task ID {} due to the exception.", task.id);
+        task_manager::task_exit(EXCEPTION_EXIT_STATUS);
+    }
+
+    save_panic_regs(stack_frame, gp_regs);
     panic!("Unhandled exception.");
 }
 
+/// Registers of the code interrupted by the exception that the kernel panics
+/// on, see [save_panic_regs].
+static mut PANIC_REGS: Option<(InterruptStackFrame, GpRegs)> = None;
+
+/// Saves the registers of the interrupted code for [crate::arch::panic] to
+/// print.  Must be called by an exception handler right before it panics.
+pub fn save_panic_regs(stack_frame: &InterruptStackFrame, gp_regs: &GpRegs) {
+    unsafe {
+        PANIC_REGS = Some((*stack_frame, *gp_regs));
+    }
+}
+
+/// Returns the registers saved with [save_panic_regs], if any.
+pub fn panic_regs() -> Option<(InterruptStackFrame, GpRegs)> {
+    unsafe { PANIC_REGS }
+}
+
+/// Exit status of a task killed by an unhandled exception other than a page
+/// fault.
+pub const EXCEPTION_EXIT_STATUS: i32 = -3;
+
 pub fn init() {
     let idt_descriptor = IdtDescriptor {
         size: (size_of::<InterruptDescriptorTable>() - 1) as u16,
@@ -319,6 +354,77 @@ pub fn init() {
     }
 }
 
+/// Number of IRQ handlers being run, counted by the handlers in interrupts.s.
+///
+/// The timer IRQ handler may switch to a task that has not been interrupted,
+/// so the depth is saved and restored on task switches (see [Task::irq_depth]).
+///
+/// [Task::irq_depth]: crate::task::Task::irq_depth
+#[no_mangle]
+static mut IRQ_DEPTH: u32 = 0;
+
+/// Checks if the CPU is running an IRQ handler, where blocking is not allowed.
+pub fn in_interrupt() -> bool {
+    irq_depth() != 0
+}
+
+pub fn irq_depth() -> u32 {
+    unsafe { IRQ_DEPTH }
+}
+
+pub fn set_irq_depth(depth: u32) {
+    unsafe {
+        IRQ_DEPTH = depth;
+    }
+}
+
+/// Halts the CPU until an interrupt is handled.
+///
+/// The interrupts are enabled while halting, the interrupt flag is restored
+/// afterwards.
+pub fn wait_for_interrupt() {
+    with_disabled(|| unsafe {
+        // STI takes effect after HLT, so an interrupt cannot be missed.
+        asm!("sti", "hlt", "cli", options(att_syntax));
+    });
+}
+
+/// Interrupt flag in EFLAGS.
+const EFLAGS_IF: u32 = 1 << 9;
+
+/// Checks if the interrupts are enabled.
+pub fn are_enabled() -> bool {
+    let eflags: u32;
+    unsafe {
+        asm!("pushfl", "popl {}", out(reg) eflags, options(att_syntax));
+    }
+    eflags & EFLAGS_IF != 0
+}
+
+/// Runs `f` with interrupts disabled.
+///
+/// The interrupt flag is restored afterwards, so that calls to this function
+/// can be nested and it can be used in interrupt handlers.
+pub fn with_disabled<R, F: FnOnce() -> R>(f: F) -> R {
+    let eflags: u32;
+    unsafe {
+        asm!(
+            "pushfl",
+            "popl {}",
+            "cli",
+            out(reg) eflags,
+            options(att_syntax),
+        );
+    }
+    let result = f();
+    if eflags & EFLAGS_IF != 0 {
+        unsafe {
+            asm!("sti");
+        }
+    }
+    result
+}
+
 #[no_mangle]
 pub extern "C" fn common_interrupt_handler(stack_frame: &InterruptStackFrame) {
     println!("Common interrupt handler called.");
@@ -14,13 +14,25 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-.macro CALL_HANDLER handler int_num err_code frame_ptr
+.macro CALL_HANDLER handler int_num err_code frame_ptr regs_ptr
+    pushl \regs_ptr                 // general purpose registers pointer
     pushl \frame_ptr
     pushl \err_code                 // error code
     pushl \int_num                  // interrupt number
     cld
     call \handler
-    addl $12, %esp
+    addl $16, %esp
+.endm
+
+// Patches the %ebp and %esp saved by pusha (see GpRegs in syscall.rs) to hold
+// the interrupted code's values instead of the ones used by the ISR itself.
+// %esp is only valid if there is no privilege switch.  The argument is the
+// offset of the interrupted %esp from the ISR's %ebp.
+.macro PATCH_INTERRUPTED_REGS esp_offset
+    movl (%ebp), %eax
+    movl %eax, 8(%esp)              // interrupted ebp
+    leal \esp_offset(%ebp), %eax
+    movl %eax, 12(%esp)             // interrupted esp
 .endm
 
 .macro EXCEPTION_ISR num handler
@@ -32,9 +44,11 @@ isr_\num:
     movl %esp, %ebp
 
     pusha
+    PATCH_INTERRUPTED_REGS 16
+    movl %esp, %edx                 // general purpose registers pointer
     movl %ebp, %ebx
     addl $4, %ebx                   // interrupt stack frame pointer
-    CALL_HANDLER \handler $\num $0 %ebx
+    CALL_HANDLER \handler $\num $0 %ebx %edx
     popa
 
     popl %ebp
@@ -54,12 +68,14 @@ isr_\num:
     // respected and the stack tracer shows the saved %eip and not the error
     // code.
     pusha
+    PATCH_INTERRUPTED_REGS 20
+    movl %esp, %edx                 // general purpose registers pointer
     movl 8(%ebp), %ebx              // saved eip => ebx
     movl 4(%ebp), %ecx              // error code => ecx
     movl %ebx, 4(%ebp)
     movl %ebp, %ebx
     addl $8, %ebx                   // interrupt stack frame pointer
-    CALL_HANDLER \handler $\num %ecx %ebx
+    CALL_HANDLER \handler $\num %ecx %ebx %edx
     popa
 
     popl %ebp
@@ -68,6 +84,34 @@ isr_\num:
 .size isr_\num, . - isr_\num
 .endm
 
+// Like EXCEPTION_ISR, but the handler receives only the stack frame pointer and
+// the general purpose registers pointer.  Valid only for exceptions that do not
+// push an error code and that happen in the kernel mode.
+.macro EXCEPTION_ISR_REGS num handler
+.global isr_\num
+.type isr_\num, @function
+isr_\num:
+    cli
+    pushl %ebp
+    movl %esp, %ebp
+
+    pusha
+    PATCH_INTERRUPTED_REGS 16
+    movl %esp, %eax
+    movl %ebp, %ebx
+    addl $4, %ebx                   // interrupt stack frame pointer
+    cld
+    pushl %eax                      // general purpose registers pointer
+    pushl %ebx                      // stack frame pointer
+    call \handler
+    addl $8, %esp
+    popa
+
+    popl %ebp
+    iret
+.size isr_\num, . - isr_\num
+.endm
+
 .macro DUMMY_EXCEPTION_ISR num
 EXCEPTION_ISR \num dummy_exception_handler
 .endm
@@ -77,9 +121,9 @@ EXCEPTION_ISR_EC \num dummy_exception_handler
 .endm
 
 DUMMY_EXCEPTION_ISR 0       // divide error
-DUMMY_EXCEPTION_ISR 1       // debug
+EXCEPTION_ISR_REGS 1 debug_exception_handler
 DUMMY_EXCEPTION_ISR 2       // non-maskable interrupt
-DUMMY_EXCEPTION_ISR 3       // breakpoint
+EXCEPTION_ISR_REGS 3 breakpoint_handler
 DUMMY_EXCEPTION_ISR 4       // overflow
 DUMMY_EXCEPTION_ISR 5       // bound range exceeded
 DUMMY_EXCEPTION_ISR 6       // invalid opcode
@@ -156,6 +200,8 @@ common_isr_ec:
 .global IRQ0_RUST_HANDLER
 IRQ0_RUST_HANDLER:      .long 0
 
+// The IRQ handlers below count themselves in IRQ_DEPTH (see interrupts.rs).
+
 .global irq0_handler
 .type irq0_handler, @function
 irq0_handler:
@@ -164,12 +210,19 @@ irq0_handler:
     movl %esp, %ebp
 
     pusha
+    incl IRQ_DEPTH
     movl $IRQ0_RUST_HANDLER, %eax
     cmpl $0, (%eax)
     je 1f
     cld
     call *(%eax)
-1:  popa
+1:  movl %ebp, %ebx
+    addl $4, %ebx
+    pushl %ebx
+    call terminate_if_killed
+    addl $4, %esp
+    decl IRQ_DEPTH
+    popa
 
     popl %ebp
     iret
@@ -183,14 +236,39 @@ irq1_handler:
     movl %esp, %ebp
 
     pusha
+    incl IRQ_DEPTH
     cld
     call keyboard_irq_handler
+    movl %ebp, %ebx
+    addl $4, %ebx
+    pushl %ebx
+    call terminate_if_killed
+    addl $4, %esp
+    decl IRQ_DEPTH
     popa
 
     popl %ebp
     iret
 .size irq1_handler, . - irq1_handler
 
+.global irq4_handler
+.type irq4_handler, @function
+irq4_handler:
+    cli
+    pushl %ebp
+    movl %esp, %ebp
+
+    pusha
+    incl IRQ_DEPTH
+    cld
+    call serial_irq_handler
+    decl IRQ_DEPTH
+    popa
+
+    popl %ebp
+    iret
+.size irq4_handler, . - irq4_handler
+
 // IRQ 7 may be a spurious IRQ.
 .global irq7_handler
 .type irq7_handler, @function
@@ -200,18 +278,38 @@ irq7_handler:
     movl %esp, %ebp
 
     pusha
+    incl IRQ_DEPTH
     movl %ebp, %ebx
     addl $4, %ebx
     cld
     pushl %ebx
     call stage1_irq7_handler
     addl $4, %esp
+    decl IRQ_DEPTH
     popa
 
     popl %ebp
     iret
 .size irq7_handler, . - irq7_handler
 
+.global irq12_handler
+.type irq12_handler, @function
+irq12_handler:
+    cli
+    pushl %ebp
+    movl %esp, %ebp
+
+    pusha
+    incl IRQ_DEPTH
+    cld
+    call mouse_irq_handler
+    decl IRQ_DEPTH
+    popa
+
+    popl %ebp
+    iret
+.size irq12_handler, . - irq12_handler
+
 .global irq14_handler
 .type irq14_handler, @function
 irq14_handler:
@@ -220,12 +318,14 @@ irq14_handler:
     movl %esp, %ebp
 
     pusha
+    incl IRQ_DEPTH
     movl %ebp, %ebx
     addl $4, %ebx
     cld
     pushl %ebx
     call ata_irq14_handler
     addl $4, %esp
+    decl IRQ_DEPTH
     popa
 
     popl %ebp
@@ -242,12 +342,14 @@ irq15_handler:
     movl %esp, %ebp
 
     pusha
+    incl IRQ_DEPTH
     movl %ebp, %ebx
     addl $4, %ebx
     cld
     pushl %ebx
     call stage1_irq15_handler
     addl $4, %esp
+    decl IRQ_DEPTH
     popa
 
     popl %ebp
@@ -277,3 +379,54 @@ int0x88_handler:
     popl %ebp
     iret
 .size int0x88_handler, . - int0x88_handler
+
+// Syscall with sysenter (see sysenter.rs).  The CPU has loaded %esp with the
+// task's kernel stack and disabled the interrupts.  The usermode %eip is in
+// %edi and %esp in %ebp.
+.global sysenter_entry
+.type sysenter_entry, @function
+sysenter_entry:
+    // Make up the stack frame that int 0x88 would push.
+    pushl $0x23                     // ss = USERMODE_DATA_SEG | 3
+    pushl %ebp                      // esp
+    pushfl
+    orl $0x200, (%esp)              // eflags, IF was cleared by sysenter
+    pushl $0x1B                     // cs = USERMODE_CODE_SEG | 3
+    pushl %edi                      // eip
+
+    // sysenter clears only IF, VM and RF, the other usermode flags, e.g. TF,
+    // NT and AC, must not leak into the kernel code.  A single-step trap that
+    // TF causes before this is handled in debug.rs.
+    pushl $2
+    popfl
+.global sysenter_flags_reset
+sysenter_flags_reset:
+
+    pushl %ebp
+    movl %esp, %ebp
+
+    pusha
+    movl %esp, %eax
+    movl %ebp, %ebx
+    addl $4, %ebx
+    cld
+    pushl (%ebp)                    // usermode ebp
+    pushl %eax                      // general purpose registers pointer
+    pushl %ebx                      // stack frame pointer
+    call syscall_handler
+    addl $12, %esp
+    popa
+
+    popl %ebp
+
+    // sysexit jumps to %edx with %esp = %ecx.  The interrupts are enabled
+    // right before it, sti takes effect after the next instruction.
+    movl (%esp), %edx               // eip
+    movl 12(%esp), %ecx             // esp
+    andl $~0x200, 8(%esp)
+    addl $8, %esp
+    popfl
+    addl $8, %esp
+    sti
+    sysexit
+.size sysenter_entry, . - sysenter_entry
@@ -24,6 +24,11 @@ pub mod dev;
 pub mod pmm_stack;
 pub mod port_io;
 pub mod stack_trace;
+pub mod tsc;
+
+pub mod debug;
+
+pub mod fpu;
 
 pub mod task;
 pub mod task_manager;
@@ -31,6 +36,7 @@ pub mod task_manager;
 pub mod pci;
 
 pub mod syscall;
+pub mod sysenter;
 
 use core::ptr;
 
@@ -42,11 +48,19 @@ use crate::KERNEL_INFO;
 use crate::dev::timer::Timer;
 use crate::heap;
 use crate::memory_region::Region;
+use crate::multiboot;
 
 pub struct ArchInitInfo {
+    /// Physical memory occupied by the kernel image, which is mapped at
+    /// [KERNEL_VIRT_BASE](vas::KERNEL_VIRT_BASE) + its physical address.
     pub kernel_region: Region<usize>,
     pub heap_region: Region<usize>,
 
+    /// Physical address of the RSDT from the old RSDP.
+    pub rsdt_phys: Option<u32>,
+    /// Physical address of the XSDT from the new RSDP.
+    pub xsdt_phys: Option<u32>,
+
     pub hpet_dt: Option<dev::acpi::hpet::HpetDt>,
     pub hpet_region: Option<Region<usize>>,
 }
@@ -57,6 +71,9 @@ impl ArchInitInfo {
             kernel_region: Region { start: 0, end: 0 },
             heap_region: Region { start: 0, end: 0 },
 
+            rsdt_phys: None,
+            xsdt_phys: None,
+
             hpet_dt: None,
             hpet_region: None,
         }
@@ -73,14 +90,91 @@ extern "C" {
     static stack_top: u32;
 }
 
+/// Maps the framebuffer, if there is one, into
+/// [FRAMEBUFFER_REGION](vas::FRAMEBUFFER_REGION) with 4 MiB pages and returns
+/// its new address.
+///
+/// The PMM is not initialized yet, so there are no page tables to use.
+fn map_framebuffer() -> Option<usize> {
+    let fb = unsafe { KERNEL_INFO.framebuffer? };
+    let first_frame = fb.phys & !0x3FFFFF;
+    // The framebuffer may end at 4 GiB, so do not compute its end.
+    let len = (fb.phys % 0x400000 + fb.size() + 0x3FFFFF) & !0x3FFFFF;
+    assert!(
+        len <= vas::FRAMEBUFFER_REGION.len(),
+        "the framebuffer does not fit in the framebuffer region",
+    );
+
+    let kvas = vas::KERNEL_VAS.lock();
+    for offset in (0..len).step_by(0x400000) {
+        unsafe {
+            kvas.map_large_page(
+                (vas::FRAMEBUFFER_REGION.start + offset) as u32,
+                (first_frame + offset) as u32,
+            );
+        }
+    }
+    Some(vas::FRAMEBUFFER_REGION.start + fb.phys % 0x400000)
+}
+
+/// Maps the Multiboot modules into [MODULES_REGION](vas::MODULES_REGION).
+fn map_boot_modules() {
+    let mut next_virt = vas::MODULES_REGION.start;
+    for module in unsafe { multiboot::modules_mut() } {
+        let first_frame = module.phys.start & !0xFFF;
+        let frames_end = (module.phys.end + 0xFFF) & !0xFFF;
+        let len = frames_end - first_frame;
+        if next_virt + len > vas::MODULES_REGION.end {
+            log_warn!(
+                "Skipping the boot module {:?}, it does not fit in the \
+                 modules region.",
+                module.cmdline(),
+            );
+            continue;
+        }
+
+        // The modules are only read, e.g. by MemBlockDevice.
+        unsafe {
+            let kvas = vas::KERNEL_VAS.lock();
+            for offset in (0..len).step_by(4096) {
+                kvas.map_page(
+                    (next_virt + offset) as u32,
+                    (first_frame + offset) as u32,
+                );
+            }
+            kvas.set_protection(
+                next_virt as u32,
+                (next_virt + len) as u32,
+                false,
+                false,
+            )
+            .unwrap();
+        }
+
+        let start = next_virt + module.phys.start % 4096;
+        module.virt = Some(Region {
+            start,
+            end: start + module.phys.len(),
+        });
+        println!(
+            "Mapped the boot module {:?} at {:?}.",
+            module.cmdline(),
+            module.virt.unwrap(),
+        );
+        next_virt += len;
+    }
+}
+
 pub fn init() {
     let aif = unsafe { &mut KERNEL_INFO.arch };
 
     gdt::init();
 
     aif.kernel_region = Region {
-        start: unsafe { &kernel_start as *const _ as usize },
-        end: unsafe { &kernel_end as *const _ as usize },
+        start: unsafe { &kernel_start as *const _ as usize }
+            - vas::KERNEL_VIRT_BASE,
+        end: unsafe { &kernel_end as *const _ as usize }
+            - vas::KERNEL_VIRT_BASE,
     };
     println!("Kernel region: {:?}", aif.kernel_region);
 
@@ -93,33 +187,33 @@ pub fn init() {
 
     dev::pic::init();
     interrupts::init();
+    fpu::init();
+    sysenter::init();
 
     // FIXME: check if there is an HPET instead of panicking in multiboot.rs.
 
     acpi::init();
+    let fb_virt = map_framebuffer();
 
-    // Enable paging.
-    unsafe {
+    // Switch from the boot page directory (see boot.s), which has already
+    // enabled paging and 4 MiB pages.
+    interrupts::with_disabled(|| unsafe {
         vas::KERNEL_VAS.lock().load();
-        asm!("movl %cr0, %eax
-              orl $0x80000001, %eax
-              movl %eax, %cr0",
-             out("eax") _,
-             options(att_syntax));
-    }
+        if let Some(fb_virt) = fb_virt {
+            // The framebuffer is not accessible at its old address anymore.
+            crate::dev::vga::move_framebuffer(fb_virt);
+        }
+    });
 
     pmm_stack::init();
 
-    // Place a guard page at 0x00000000 to detect null pointer dereference.
-    unsafe {
-        let mut kvas = vas::KERNEL_VAS.lock();
-        kvas.place_guard_page(0x00000000);
-    }
+    // Null pointer dereferences are caught since nothing is mapped below
+    // USERMODE_REGION.
 
     let last_region_end = if let Some(hpet_region) = aif.hpet_region {
         hpet_region.end
     } else {
-        aif.kernel_region.end
+        vas::KERNEL_VIRT_BASE + aif.kernel_region.end
     };
     aif.heap_region = Region {
         start: (last_region_end + 0x400_000 - 1) & !(0x400_000 - 1),
@@ -127,6 +221,11 @@ pub fn init() {
             + crate::heap::KERNEL_HEAP_SIZE,
     };
     println!("Heap region: {:?}", aif.heap_region);
+    assert!(
+        aif.heap_region.start + crate::heap::KERNEL_HEAP_MAX_SIZE
+            <= vas::FRAMEBUFFER_REGION.start,
+        "the heap cannot grow to its maximum size",
+    );
 
     // Map the heap.
     unsafe {
@@ -138,10 +237,18 @@ pub fn init() {
         kvas.allocate_pages_from_stack(
             aif.heap_region.start as u32,
             aif.heap_region.end as u32,
-        );
+        )
+        .expect("could not allocate the kernel heap");
+
+        // Usermode VASes share the kernel page tables, so all of them must
+        // exist before the first usermode VAS is created.
+        kvas.ensure_kernel_pgtbls();
     }
 
     heap::init();
+    stack_trace::init();
+
+    map_boot_modules();
 
     let timer: Box<dyn Timer> = if aif.hpet_dt.is_some() {
         println!("Using HPET as the system timer.");
@@ -155,16 +262,48 @@ pub fn init() {
         assert!(TIMER.is_none());
         TIMER = Some(timer);
     }
+    tsc::init();
+
+    let pmm_stats = pmm_stack::stats();
+    println!(
+        "Detected {} MiB usable, {} MiB free after kernel init.",
+        pmm_stats.total_pages * 4096 / 1024 / 1024,
+        pmm_stats.free_pages * 4096 / 1024 / 1024,
+    );
+    let above_4g = unsafe { KERNEL_INFO.total_memory_above_4g };
+    if above_4g != 0 {
+        println!(
+            "Another {} MiB is installed above 4 GiB and cannot be used.",
+            above_4g / 1024 / 1024,
+        );
+    }
 }
 
+/// Prints the CPU state for the panic report: the registers of the code that
+/// caused the exception if the kernel panics on one, CR2, CR3, the interrupt
+/// nesting depth and the stack trace.
+///
+/// Nothing here locks, so that the report is printed whatever the panicking
+/// code holds.
 #[inline(always)]
 pub fn panic() {
     unsafe {
         asm!("cli");
     }
-    let trace = stack_trace::StackTrace::walk_and_get();
-    println!(" stack trace:");
-    for (i, addr) in trace.iter().enumerate() {
-        print!(" #{:02}: 0x{:08X}    ", trace.length - i, addr);
+    if let Some((stack_frame, gp_regs)) = interrupts::panic_regs() {
+        println!(" exception registers:");
+        debug::print_regs(&stack_frame, &gp_regs);
+    }
+    let (cr2, cr3): (u32, u32);
+    unsafe {
+        asm!("movl %cr2, {:e}", out(reg) cr2, options(att_syntax));
+        asm!("movl %cr3, {:e}", out(reg) cr3, options(att_syntax));
     }
+    println!(
+        " cr2: 0x{:08X}  cr3: 0x{:08X}  IRQ depth: {}",
+        cr2,
+        cr3,
+        interrupts::irq_depth(),
+    );
+    stack_trace::StackTrace::walk_and_get().print();
 }
@@ -22,6 +22,7 @@ use core::marker::PhantomData;
 use crate::arch::port_io;
 use crate::dev::block_device;
 use crate::dev::disk;
+use crate::dev::vga;
 
 #[derive(Clone)]
 struct Pci {
@@ -697,10 +698,12 @@ pub fn init() {
         PCI.enumerate();
     }
 
-    for (host_bus_num, host_bus) in unsafe { &PCI }.host_buses.iter() {
-        print!("Host bus 0x{:02X} : ", host_bus_num);
-        print_bus(16, host_bus);
-    }
+    vga::without_wrap(|| {
+        for (host_bus_num, host_bus) in unsafe { &PCI }.host_buses.iter() {
+            print!("Host bus 0x{:02X} : ", host_bus_num);
+            print_bus(16, host_bus);
+        }
+    });
 
     // Initialize devices.
     for device in unsafe { &PCI }.all_devices() {
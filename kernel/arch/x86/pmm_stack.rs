@@ -14,8 +14,12 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use core::ptr;
+use core::slice;
+
+use crate::arch::vas::{KERNEL_VAS, KERNEL_VIRT_BASE};
 use crate::kernel_static::{Mutex, MutexWrapper};
-use crate::memory_region::OverlappingWith;
+use crate::memory_region::Region;
 use crate::KERNEL_INFO;
 
 extern "C" {
@@ -24,10 +28,39 @@ extern "C" {
     static mut pmm_stack_top: u32;
 }
 
+/// Region of the kernel VAS where the frame reference count table is mapped.
+///
+/// These are the last 4 MiB of the kernel region, which is enough for 4 GiB of
+/// physical memory with one byte per frame.
+pub const REFCOUNTS_REGION: Region<usize> = Region {
+    start: KERNEL_VIRT_BASE + 0x7C0_0000,
+    end: KERNEL_VIRT_BASE + 0x800_0000,
+};
+
 pub struct PmmStack {
     top: *mut u32,
     pointer: *mut u32,
     bottom: *mut u32,
+
+    /// Reference counts of the frames, one byte per frame below `num_frames *
+    /// 4096`.  Null until the table is set up by [init].
+    refcounts: *mut u8,
+    num_frames: usize,
+
+    total_pages: usize,
+    free_pages: usize,
+    max_used_pages: usize,
+}
+
+/// Physical memory usage, in pages.
+#[derive(Clone, Copy, Debug)]
+pub struct PmmStats {
+    /// Number of pages pushed onto the stack at boot.
+    pub total_pages: usize,
+    /// Number of pages currently on the stack.
+    pub free_pages: usize,
+    /// Highest number of pages taken off the stack at the same time.
+    pub max_used_pages: usize,
 }
 
 impl PmmStack {
@@ -36,44 +69,152 @@ impl PmmStack {
             top,
             pointer: top,
             bottom,
+
+            refcounts: ptr::null_mut(),
+            num_frames: 0,
+
+            total_pages: 0,
+            free_pages: 0,
+            max_used_pages: 0,
         }
     }
 
-    unsafe fn fill(&mut self) {
+    /// Pushes the pages of all the available memory regions except those
+    /// overlapping with `reserved`, returns the number of non-empty regions
+    /// that were pushed.
+    unsafe fn fill(&mut self, reserved: &[Region<usize>]) -> usize {
+        let mut num_regions = 0;
         for region in KERNEL_INFO.available_memory_regions.iter() {
-            let mut region = region.clone();
             if region.start == 0 && region.end == 0 {
                 // End of slice.
                 break;
             }
-            match region.overlapping_with(&KERNEL_INFO.arch.kernel_region) {
-                OverlappingWith::Covers => {
-                    unimplemented!("a free region covers the kernel");
+            num_regions += self.push_region(*region, reserved);
+        }
+        self.total_pages = self.free_pages;
+        num_regions
+    }
+
+    /// Pushes the pages of `region` that do not overlap with any of
+    /// `reserved`, returns the number of non-empty pieces it was clipped to.
+    unsafe fn push_region(
+        &mut self,
+        region: Region<usize>,
+        reserved: &[Region<usize>],
+    ) -> usize {
+        match reserved.split_first() {
+            Some((first, rest))
+                if first.start < region.end && region.start < first.end =>
+            {
+                let mut num_regions = 0;
+                if region.start < first.start {
+                    let left = Region {
+                        start: region.start,
+                        end: first.start,
+                    };
+                    num_regions += self.push_region(left, rest);
                 }
-                OverlappingWith::StartsIn => {
-                    region.start = KERNEL_INFO.arch.kernel_region.end;
+                if first.end < region.end {
+                    let right = Region {
+                        start: first.end,
+                        end: region.end,
+                    };
+                    num_regions += self.push_region(right, rest);
                 }
-                OverlappingWith::IsIn => {
-                    continue;
+                num_regions
+            }
+            Some((_, rest)) => self.push_region(region, rest),
+            None => {
+                let start = (region.start + 0xFFF) & !0xFFF;
+                let end = region.end & !0xFFF;
+                if start >= end {
+                    // The region is too small.
+                    return 0;
                 }
-                OverlappingWith::EndsIn => {
-                    region.end = KERNEL_INFO.arch.kernel_region.start;
+                for page_addr in (start..end).step_by(4096) {
+                    self.push_page(page_addr as u32);
                 }
-                OverlappingWith::NoOverlap => {}
-            }
-            region.start = (region.start + 0xFFF) & !0xFFF;
-            region.end &= !0xFFF;
-            if region.start >= region.end {
-                // The region is too small.
-                continue;
+                1
             }
-            for page_addr in (region.start..region.end).step_by(4096) {
-                self.push_page(page_addr as u32);
+        }
+    }
+
+    /// Returns the pages that are currently on the stack.
+    fn pages(&self) -> &[u32] {
+        unsafe {
+            slice::from_raw_parts(
+                self.pointer,
+                (self.top as usize - self.pointer as usize) / 4,
+            )
+        }
+    }
+
+    /// Returns the reference count of the frame at `phys`, or `None` if the
+    /// reference count table has not been set up yet.
+    ///
+    /// # Panics
+    /// This method panics if the frame is outside of the table.
+    fn refcount_mut(&mut self, phys: u32) -> Option<&mut u8> {
+        if self.refcounts.is_null() {
+            return None;
+        }
+        let frame = phys as usize / 4096;
+        assert!(
+            frame < self.num_frames,
+            "frame 0x{:08X} is not managed by the PMM",
+            phys,
+        );
+        unsafe { Some(&mut *self.refcounts.add(frame)) }
+    }
+
+    /// Adds a reference to the allocated frame at `phys`.
+    ///
+    /// This is needed when a frame gets mapped in more than one place, e.g.
+    /// into several VASes.
+    pub fn ref_frame(&mut self, phys: u32) {
+        let refcount = self
+            .refcount_mut(phys)
+            .expect("the reference count table is not set up");
+        debug_assert_ne!(*refcount, 0, "frame 0x{:08X} is free", phys);
+        assert_ne!(
+            *refcount,
+            u8::MAX,
+            "too many references to frame 0x{:08X}",
+            phys,
+        );
+        *refcount += 1;
+    }
+
+    /// Removes a reference to the frame at `phys`.  If there are no references
+    /// left, the frame is pushed onto the stack and `true` is returned.
+    pub fn unref_frame(&mut self, phys: u32) -> bool {
+        let is_free = match self.refcount_mut(phys) {
+            Some(refcount) => {
+                debug_assert_ne!(
+                    *refcount, 0,
+                    "double free of frame 0x{:08X}",
+                    phys,
+                );
+                *refcount -= 1;
+                *refcount == 0
             }
+            None => true,
+        };
+        if is_free {
+            self.push_page(phys);
         }
+        is_free
     }
 
-    fn push_page(&mut self, addr: u32) {
+    pub fn push_page(&mut self, addr: u32) {
+        assert_eq!(addr & 0xFFF, 0, "addr must be page-aligned");
+        if let Some(&mut refcount) = self.refcount_mut(addr) {
+            debug_assert_eq!(
+                refcount, 0,
+                "frame 0x{:08X} is still referenced",
+                addr,
+            );
+        }
         assert!(
             self.bottom <= self.pointer && self.pointer <= self.top,
             "stack pointer is outside the stack",
@@ -83,18 +224,43 @@ impl PmmStack {
             self.pointer = self.pointer.sub(1);
             *self.pointer = addr;
         }
+        self.free_pages += 1;
     }
 
-    pub fn pop_page(&mut self) -> u32 {
+    /// Takes a free page off the stack, returns `None` if there are none left.
+    pub fn pop_page(&mut self) -> Option<u32> {
         assert!(
             self.bottom <= self.pointer && self.pointer <= self.top,
             "stack pointer is outside the stack",
         );
-        assert!(self.pointer < self.top, "pop: stack top reached");
-        unsafe {
+        if self.pointer == self.top {
+            return None;
+        }
+        let addr = unsafe {
             let addr = *self.pointer;
             self.pointer = self.pointer.add(1);
             addr
+        };
+        if let Some(refcount) = self.refcount_mut(addr) {
+            debug_assert_eq!(
+                *refcount, 0,
+                "free frame 0x{:08X} is referenced",
+                addr
+            );
+            *refcount = 1;
+        }
+        self.free_pages -= 1;
+        self.max_used_pages = self
+            .max_used_pages
+            .max(self.total_pages.saturating_sub(self.free_pages));
+        Some(addr)
+    }
+
+    pub fn stats(&self) -> PmmStats {
+        PmmStats {
+            total_pages: self.total_pages,
+            free_pages: self.free_pages,
+            max_used_pages: self.max_used_pages,
         }
     }
 }
@@ -107,11 +273,66 @@ kernel_static! {
     });
 }
 
+pub fn stats() -> PmmStats {
+    PMM_STACK.lock().stats()
+}
+
+/// Same as [stats], but returns `None` if the stack is locked.
+pub fn try_stats() -> Option<PmmStats> {
+    PMM_STACK.try_lock().map(|stack| stack.stats())
+}
+
+/// See [PmmStack::ref_frame].
+pub fn ref_frame(phys: u32) {
+    PMM_STACK.lock().ref_frame(phys);
+}
+
+/// See [PmmStack::unref_frame].
+pub fn unref_frame(phys: u32) -> bool {
+    PMM_STACK.lock().unref_frame(phys)
+}
+
 pub fn init() {
     let mut stack: MutexWrapper<PmmStack> = PMM_STACK.lock();
-    unsafe {
-        stack.fill();
+
+    // The kernel image, the Multiboot information structure, the modules and
+    // the first MiB (BIOS data, VGA memory, etc.) must never be handed out.
+    let mut reserved = [Region { start: 0, end: 0 }; 34];
+    reserved[0] = Region {
+        start: 0,
+        end: 1024 * 1024,
+    };
+    reserved[1] = unsafe { KERNEL_INFO.arch.kernel_region };
+    let mut num_reserved = 2;
+    for region in unsafe { KERNEL_INFO.reserved_memory_regions.iter() } {
+        if region.start == 0 && region.end == 0 {
+            // End of slice.
+            break;
+        }
+        reserved[num_reserved] = *region;
+        num_reserved += 1;
+    }
+    let reserved = &reserved[..num_reserved];
+    for region in reserved {
+        println!("[PMM] Reserved: {:?}", region);
+    }
+
+    let num_regions = unsafe { stack.fill(reserved) };
+    for &page in stack.pages() {
+        assert!(
+            !reserved
+                .iter()
+                .any(|region| region.contains(&(page as usize))),
+            "reserved page 0x{:08X} is on the stack",
+            page,
+        );
     }
+    println!(
+        "[PMM] Usable after reservations: {} MiB in {} regions",
+        stack.free_pages * 4096 / 1024 / 1024,
+        num_regions,
+    );
+
     let num_entries = (stack.top as u32 - stack.pointer as u32) / 4;
     println!(
         "[PMM] Stack: top: 0x{:08X}, ptr: 0x{:08X}, bottom: 0x{:08X}, \
@@ -122,4 +343,56 @@ pub fn init() {
         num_entries,
         num_entries as f64 * 4096.0 / 1024.0 / 1024.0,
     );
+
+    // The table is mapped with the frames from the stack, so it must be
+    // unlocked.
+    drop(stack);
+    init_refcounts();
+}
+
+/// Sets up the frame reference count table.
+///
+/// The frames that are not on the stack at this point, i.e. the reserved ones
+/// and those already taken off the stack, are counted as referenced once.
+fn init_refcounts() {
+    let num_frames = unsafe {
+        KERNEL_INFO
+            .available_memory_regions
+            .iter()
+            .take_while(|region| region.start != 0 || region.end != 0)
+            .map(|region| region.end / 4096)
+            .max()
+            .unwrap_or(0)
+    };
+    let table = Region {
+        start: REFCOUNTS_REGION.start,
+        end: (REFCOUNTS_REGION.start + num_frames + 0xFFF) & !0xFFF,
+    };
+    assert!(
+        table.is_in(&REFCOUNTS_REGION),
+        "the reference count table is too large",
+    );
+
+    unsafe {
+        let kvas = KERNEL_VAS.lock();
+        kvas.ensure_pgtbl(table.start as u32);
+        kvas.allocate_pages_from_stack(table.start as u32, table.end as u32)
+            .expect("could not allocate the reference count table");
+    }
+
+    let refcounts = table.start as *mut u8;
+    let mut stack = PMM_STACK.lock();
+    unsafe {
+        refcounts.write_bytes(1, num_frames);
+        for &page in stack.pages() {
+            *refcounts.add(page as usize / 4096) = 0;
+        }
+    }
+    stack.refcounts = refcounts;
+    stack.num_frames = num_frames;
+
+    println!(
+        "[PMM] Reference count table: {:?}, {} frames",
+        table, num_frames,
+    );
 }
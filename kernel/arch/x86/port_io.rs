@@ -73,3 +73,49 @@ pub unsafe fn inl(port: u16) -> u32 {
     );
     data
 }
+
+pub unsafe fn insw(port: u16, buf: &mut [u16]) {
+    asm!(
+        "rep insw (%dx), %es:(%edi)",
+        inout("edi") buf.as_mut_ptr() => _,
+        inout("ecx") buf.len() => _,
+        in("dx") port,
+        options(att_syntax, nostack),
+    );
+}
+
+pub unsafe fn insl(port: u16, buf: &mut [u32]) {
+    asm!(
+        "rep insl (%dx), %es:(%edi)",
+        inout("edi") buf.as_mut_ptr() => _,
+        inout("ecx") buf.len() => _,
+        in("dx") port,
+        options(att_syntax, nostack),
+    );
+}
+
+// LLVM reserves %esi on x86, so it is swapped with another register around
+// the string output instructions.
+pub unsafe fn outsw(port: u16, data: &[u16]) {
+    asm!(
+        "xchgl {0:e}, %esi",
+        "rep outsw (%esi), %dx",
+        "xchgl {0:e}, %esi",
+        inout(reg) data.as_ptr() => _,
+        inout("ecx") data.len() => _,
+        in("dx") port,
+        options(att_syntax, nostack, readonly),
+    );
+}
+
+pub unsafe fn outsl(port: u16, data: &[u32]) {
+    asm!(
+        "xchgl {0:e}, %esi",
+        "rep outsl (%esi), %dx",
+        "xchgl {0:e}, %esi",
+        inout(reg) data.as_ptr() => _,
+        inout("ecx") data.len() => _,
+        in("dx") port,
+        options(att_syntax, nostack, readonly),
+    );
+}
@@ -14,30 +14,347 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
+use core::slice;
+use core::str;
+
+use crate::arch::vas::{KERNEL_HIGHER_HALF_PDES, KERNEL_VIRT_BASE};
+use crate::memory_region::Region;
+use crate::multiboot::{self, ElfSectionHeader};
+use crate::task::{Task, TaskState};
+use crate::task_manager::TASK_MANAGER;
+
 extern "C" {
-    fn walk_stack(addr_array: *mut u32, max_len: u32) -> u32;
+    // see boot.s
+    static stack_bottom: u32;
+    static stack_top: u32;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ElfSymbol {
+    name: u32,
+    value: u32,
+    size: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+}
+
+const STT_FUNC: u8 = 2;
+
+struct FuncSymbol {
+    start: u32,
+    size: u32,
+    name: &'static str,
+}
+
+/// Kernel function symbols sorted by their addresses.
+///
+/// Set once by [init] and only read afterwards, so it can be used in the panic
+/// handler without locking.
+static mut FUNC_SYMBOLS: Option<Vec<FuncSymbol>> = None;
+
+/// Returns the contents of a section that the bootloader loaded into the low
+/// physical memory mapped at [KERNEL_VIRT_BASE].
+unsafe fn section_data(sh: &ElfSectionHeader) -> Option<&'static [u8]> {
+    let phys = sh.phys_region()?;
+    if phys.end > KERNEL_HIGHER_HALF_PDES * 0x400_000 {
+        return None;
+    }
+    Some(slice::from_raw_parts(
+        (KERNEL_VIRT_BASE + phys.start) as *const u8,
+        phys.len(),
+    ))
+}
+
+/// Collects the kernel function symbols from the symbol table passed by the
+/// bootloader, so that the stack traces can be [symbolized](symbolize).
+pub fn init() {
+    let sections = multiboot::elf_sections();
+    let symtab = sections
+        .iter()
+        .find(|sh| sh.sh_type == ElfSectionHeader::SHT_SYMTAB);
+    let (symtab, strtab) = match symtab {
+        Some(symtab) => unsafe {
+            match (
+                section_data(symtab),
+                sections
+                    .get(symtab.link as usize)
+                    .and_then(|sh| section_data(sh)),
+            ) {
+                (Some(symtab), Some(strtab)) => (symtab, strtab),
+                _ => {
+                    println!("[TRACE] Kernel symbol table is not accessible.");
+                    return;
+                }
+            }
+        },
+        None => {
+            println!("[TRACE] No kernel symbol table.");
+            return;
+        }
+    };
+
+    let num_symbols = symtab.len() / size_of::<ElfSymbol>();
+    let symbols = unsafe {
+        slice::from_raw_parts(symtab.as_ptr() as *const ElfSymbol, num_symbols)
+    };
+    let mut func_symbols: Vec<FuncSymbol> = symbols
+        .iter()
+        .filter(|sym| sym.info & 0xF == STT_FUNC && sym.value != 0)
+        .filter_map(|sym| {
+            let name = strtab.get(sym.name as usize..)?;
+            let len = name.iter().position(|&ch| ch == 0)?;
+            Some(FuncSymbol {
+                start: sym.value,
+                size: sym.size,
+                name: str::from_utf8(&name[..len]).ok()?,
+            })
+        })
+        .collect();
+    func_symbols.sort_unstable_by_key(|sym| sym.start);
+    println!(
+        "[TRACE] Found {} kernel function symbols.",
+        func_symbols.len()
+    );
+
+    unsafe {
+        FUNC_SYMBOLS = Some(func_symbols);
+    }
+}
+
+/// Finds the kernel function containing `addr` and returns its name and the
+/// offset of `addr` in it.
+pub fn symbolize(addr: u32) -> Option<(&'static str, usize)> {
+    let func_symbols = unsafe { FUNC_SYMBOLS.as_ref()? };
+    let idx = match func_symbols.binary_search_by_key(&addr, |sym| sym.start) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+    let sym = &func_symbols[idx];
+    let offset = addr - sym.start;
+    if offset < sym.size || offset == 0 {
+        Some((sym.name, offset as usize))
+    } else {
+        None
+    }
+}
+
+/// Returns the frame pointer of the function it is inlined into.
+#[inline(always)]
+pub fn frame_pointer() -> u32 {
+    let ebp: u32;
+    unsafe {
+        asm!("movl %ebp, {:e}", out(reg) ebp, options(att_syntax));
+    }
+    ebp
+}
+
+/// Returns the kernel stack that `addr` is on: the boot stack or the kernel
+/// stack of the running task.  If it is neither, only the page of `addr` is
+/// known to be mapped.
+fn current_stack(addr: usize) -> Region<usize> {
+    let boot_stack = unsafe {
+        Region {
+            start: &stack_bottom as *const _ as usize,
+            end: &stack_top as *const _ as usize,
+        }
+    };
+    if boot_stack.contains(&addr) {
+        return boot_stack;
+    }
+    if let Some(task) = unsafe { TASK_MANAGER.running_task() } {
+        let task_stack = task.kernel_stack_region();
+        if task_stack.contains(&addr) {
+            return task_stack;
+        }
+    }
+    Region {
+        start: addr & !0xFFF,
+        end: (addr & !0xFFF) + 4096,
+    }
+}
+
+/// Walks the kernel stack of a task that is not running, starting from the
+/// registers that `switch_tasks` in task_manager.s saved on it.  Returns `None`
+/// if the task is running or is in the middle of a switch, since its saved
+/// registers are stale then.
+pub fn of_task(task: &Task) -> Option<StackTrace> {
+    if task.state() == TaskState::Running {
+        return None;
+    }
+    let stack = task.kernel_stack_region();
+
+    // The state is changed before the registers are saved, so a task that is
+    // being switched from is still running on its stack.
+    if stack.contains(&(frame_pointer() as usize)) {
+        return None;
+    }
+
+    // switch_tasks pushes %ebp right after the return address and points %ebp
+    // at it, so the saved registers end with a regular frame.
+    let top = task.kernel_stack.top as usize;
+    if top < stack.start || top >= stack.end {
+        return None;
+    }
+    let frame = top + 6 * size_of::<u32>();
+    Some(unsafe { StackTrace::walk(frame as u32, &stack) })
 }
 
+/// What a stack trace address points to, see [resolve].
+#[derive(Clone, Copy, Debug)]
+pub enum Location {
+    /// In a kernel function, with the offset in it.
+    Symbol(&'static str, usize),
+    /// In the kernel, but the function is not known.
+    Kernel,
+    /// Below [KERNEL_VIRT_BASE], e.g. the usermode return address of a
+    /// syscall.
+    User,
+}
+
+/// Finds what `addr` points to.  The usermode addresses are not looked up,
+/// since the kernel symbols would be meaningless for them.
+pub fn resolve(addr: u32) -> Location {
+    if (addr as usize) < KERNEL_VIRT_BASE {
+        return Location::User;
+    }
+    match symbolize(addr) {
+        Some((name, offset)) => Location::Symbol(name, offset),
+        None => Location::Kernel,
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::Symbol(name, offset) => {
+                write!(f, "{}+0x{:X}", Demangle(name), offset)
+            }
+            Location::Kernel => f.write_str("?"),
+            Location::User => f.write_str("[user]"),
+        }
+    }
+}
+
+/// Formats a Rust symbol name without the mangling, e.g.
+/// `_ZN4ext28read_dir17h0123456789abcdefE` as `ext2::read_dir`.
+///
+/// The names that are not mangled the legacy way are formatted as is.
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = match self.0.strip_prefix("_ZN") {
+            Some(rest) => rest,
+            None => return f.write_str(self.0),
+        };
+
+        let mut first = true;
+        while let Some(len_end) = rest.find(|ch: char| !ch.is_ascii_digit()) {
+            let len: usize = match rest[..len_end].parse() {
+                Ok(len) => len,
+                Err(_) => break,
+            };
+            let ident = match rest.get(len_end..len_end + len) {
+                Some(ident) => ident,
+                None => break,
+            };
+            rest = &rest[len_end + len..];
+
+            // Skip the hash.
+            let is_hash = ident.len() == 17
+                && ident.starts_with('h')
+                && ident[1..].chars().all(|ch| ch.is_ascii_hexdigit());
+            if rest == "E" && is_hash {
+                return Ok(());
+            }
+
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            f.write_str(ident)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum number of frames in a [StackTrace].
+const MAX_DEPTH: usize = 32;
+
 pub struct StackTrace {
-    pub addresses: [u32; 32], // maybe 32 is enough
+    pub addresses: [u32; MAX_DEPTH],
     pub length: usize,
+    /// The frame pointer the walk stopped at because it is invalid, e.g. the
+    /// stack is corrupted.
+    pub bad_frame: Option<u32>,
 }
 
 impl StackTrace {
+    /// Walks the stack of the caller.
+    #[inline(never)]
     pub fn walk_and_get() -> Self {
+        let ebp = frame_pointer();
+        let stack = current_stack(ebp as usize);
+        unsafe { Self::walk(ebp, &stack) }
+    }
+
+    /// Follows the chain of the saved frame pointers starting with `ebp` and
+    /// collects the return addresses.
+    ///
+    /// The walk stops at the 0 frame pointer that boot.s and
+    /// [with_filled_stack](crate::task::Task::with_filled_stack) put at the
+    /// bottom of the stacks, at the frame of an interrupt stub that has
+    /// interrupted the usermode, or at a frame pointer that does not point up
+    /// the `stack`, so that a corrupted stack does not make the walk fault.
+    ///
+    /// # Safety
+    /// `stack` must be mapped.
+    pub unsafe fn walk(mut ebp: u32, stack: &Region<usize>) -> Self {
         let mut ret = Self {
-            addresses: [0; 32],
+            addresses: [0; MAX_DEPTH],
             length: 0,
+            bad_frame: None,
         };
-        unsafe {
-            ret.length = walk_stack(
-                ret.addresses.as_mut_ptr(),
-                ret.addresses.len() as u32,
-            ) as usize;
+        let mut prev_ebp = 0;
+        while ebp != 0 && ret.length < MAX_DEPTH {
+            let frame = ebp as usize;
+            if frame % 4 != 0
+                || ebp <= prev_ebp
+                || frame < stack.start
+                || frame.saturating_add(8) > stack.end
+            {
+                ret.bad_frame = Some(ebp);
+                break;
+            }
+            let frame = frame as *const u32;
+            let ret_addr = *frame.add(1);
+            ret.addresses[ret.length] = ret_addr;
+            ret.length += 1;
+            // The stubs in interrupts.s make a frame out of the interrupted
+            // %eip and %ebp.  If they are usermode ones, e.g. of a syscall,
+            // the rest of the chain is on the user stack.
+            if (ret_addr as usize) < KERNEL_VIRT_BASE {
+                break;
+            }
+            prev_ebp = ebp;
+            ebp = *frame;
         }
         ret
     }
 
+    /// Prints the frames one per line, innermost first, each with its
+    /// [resolved](resolve) location.
+    pub fn print(&self) {
+        println!(" stack trace:");
+        print!("{}", self);
+    }
+
     pub fn iter(&self) -> Iter {
         Iter {
             stack_trace: self,
@@ -46,6 +363,28 @@ impl StackTrace {
     }
 }
 
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, addr) in self.iter().enumerate() {
+            writeln!(
+                f,
+                " #{:02}: 0x{:08X}  {}",
+                self.length - i,
+                addr,
+                resolve(addr),
+            )?;
+        }
+        if let Some(ebp) = self.bad_frame {
+            writeln!(
+                f,
+                " (stopped at an invalid frame pointer 0x{:08X})",
+                ebp
+            )?;
+        }
+        Ok(())
+    }
+}
+
 pub struct Iter<'a> {
     stack_trace: &'a StackTrace,
     index: usize,
@@ -14,17 +14,22 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use alloc::alloc::{alloc, Layout};
-use core::mem::{align_of, size_of};
-use core::slice;
-use core::str;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem::size_of;
 
 use crate::arch::task::jump_into_usermode;
+use crate::arch::vas::USERMODE_REGION;
 use crate::task_manager::TASK_MANAGER;
 
 use crate::arch::gdt;
 use crate::arch::interrupts::InterruptStackFrame;
+use crate::arch::sysenter;
+use crate::errno::Errno;
+use crate::ffi::cstring::CString;
 use crate::syscall;
+use crate::usercopy;
 
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
@@ -40,11 +45,15 @@ pub struct GpRegs {
     pub eax: u32,
 }
 
-const EBADF: i32 = -1;
-const EINVAL: i32 = -2;
-const EMFILE: i32 = -3;
-const ENOENT: i32 = -4;
-const ENOTTY: i32 = -5;
+/// Maximum number of the argv or environ strings passed to execve.
+const MAX_EXEC_STRINGS: usize = 256;
+/// Maximum length of an argv or environ string passed to execve.
+const MAX_EXEC_STRING_LEN: usize = 4096;
+/// Maximum number of bytes copied at once by the read and write syscalls.
+const MAX_IO_CHUNK: usize = 64 * 1024;
+
+/// The syscalls may be made with sysenter, see [sysenter].
+const FEATURE_SYSENTER: u32 = 1 << 0;
 
 #[no_mangle]
 pub extern "C" fn syscall_handler(
@@ -59,98 +68,63 @@ pub extern "C" fn syscall_handler(
     // );
     // println!("{:#010X?}", gp_regs);
     let syscall_num: u32 = { gp_regs.eax };
-    let return_value: i32;
+    let result: Result<u32, Errno>;
 
     // 0 open
     // ebx: pathname, *const u8
     // ecx: pathname len, u32
-    // returns fd or error number, i32
+    // edx: flags, u32
+    // returns fd
     if syscall_num == 0 {
-        let pathname = unsafe {
-            let bytes = slice::from_raw_parts(
-                gp_regs.ebx as *const u8,
-                gp_regs.ecx as usize,
-            );
-            str::from_utf8(&bytes).unwrap()
-        };
-        return_value = match syscall::open(pathname) {
-            Ok(fd) => fd,
-            Err(err) => match err {
-                syscall::OpenErr::NotFound => ENOENT,
-                syscall::OpenErr::MaxOpenedFiles => EMFILE,
-                syscall::OpenErr::UnsupportedFileType => EINVAL,
-            },
-        };
+        let flags = syscall::OpenFlags::from_bits_unchecked(gp_regs.edx);
+        result = user_path(gp_regs.ebx, gp_regs.ecx).and_then(|pathname| {
+            syscall::open(&pathname, flags)
+                .map(|fd| fd as u32)
+                .map_err(Errno::from)
+        });
     }
     // 1 write
     // ebx: fd, i32
     // ecx: buffer pointer, *const u8
     // edx: buffer size in bytes, u32
-    // returns 0 or error number, i32
+    // returns number of bytes written
     else if syscall_num == 1 {
         let fd = gp_regs.ebx as i32;
-        let buf = unsafe {
-            slice::from_raw_parts(
-                gp_regs.ecx as *const u8,
-                gp_regs.edx as usize,
-            )
-        };
-        return_value = match syscall::write(fd, buf) {
-            Ok(n) => n as i32,
-            Err(err) => match err {
-                syscall::WriteErr::BadFd => EBADF,
-            },
-        };
+        result =
+            write_from_user(fd, gp_regs.ecx as usize, gp_regs.edx as usize)
+                .map(|n| n as u32);
     }
     // 2 read
     // ebx: fd, i32
     // ecx: buffer pointer, *mut u8
     // edx: buffer size in bytes, u32
-    // returns 0 or error number, i32
+    // returns number of bytes read, 0 at the end of file
     else if syscall_num == 2 {
         let fd = gp_regs.ebx as i32;
-        let buf = unsafe {
-            slice::from_raw_parts_mut(
-                gp_regs.ecx as *mut u8,
-                gp_regs.edx as usize,
-            )
-        };
-        return_value = match syscall::read(fd, buf) {
-            Ok(n) => n as i32,
-            Err(err) => match err {
-                syscall::ReadErr::BadFd => EBADF,
-                syscall::ReadErr::NotReadable => EINVAL,
-            },
-        };
+        result = read_to_user(fd, gp_regs.ecx as usize, gp_regs.edx as usize)
+            .map(|n| n as u32);
     }
     // 3 seek_abs
     // ebx: fd, i32
     // ecx: new offset, u32
-    // returns 0 or error number, i32
+    // returns the new offset
     else if syscall_num == 3 {
         let fd = gp_regs.ebx as i32;
         let new_offset = gp_regs.ecx as usize;
-        return_value = match syscall::seek(syscall::Seek::Abs, fd, new_offset) {
-            Ok(new_offset) => new_offset as i32,
-            Err(err) => match err {
-                syscall::SeekErr::BadFd => EBADF,
-            },
-        };
+        result = syscall::seek(syscall::Seek::Abs, fd, new_offset)
+            .map(|new_offset| new_offset as u32)
+            .map_err(Errno::from);
     }
     // 4 seek_rel
     // ebx: fd, i32
     // ecx: add to offset, u32
-    // returns 0 or error number, i32
+    // returns the new offset
     else if syscall_num == 4 {
         let fd = gp_regs.ebx as i32;
         let add_to_offset = gp_regs.ecx as usize;
-        return_value =
-            match syscall::seek(syscall::Seek::Rel, fd, add_to_offset) {
-                Ok(new_offset) => new_offset as i32,
-                Err(err) => match err {
-                    syscall::SeekErr::BadFd => EBADF,
-                },
-            };
+        result = syscall::seek(syscall::Seek::Rel, fd, add_to_offset)
+            .map(|new_offset| new_offset as u32)
+            .map_err(Errno::from);
     }
     // 5 mem_map
     // ebx: args, *const struct, where struct is:
@@ -160,31 +134,30 @@ pub extern "C" fn syscall_handler(
     //     flags, u32
     //     fd, i32
     //     offset, u32
-    // return value: FIXME:
+    // returns the mapping address
+    //
+    // The mapping address is page-aligned, so it never looks like an error
+    // number, even though it may be negative as i32.
     else if syscall_num == 5 {
-        let args =
-            unsafe { slice::from_raw_parts(gp_regs.ebx as *const u32, 6) };
-
-        let addr = args[0] as usize;
-        let len = args[1] as usize;
-        let prot = syscall::MemMapProt::from_bits(args[2]);
-        let flags = syscall::MemMapFlags::from_bits(args[3]);
-        let fd = args[4] as i32;
-        let offset = args[5] as usize;
-
-        return_value =
-            match syscall::mem_map(addr, len, prot, flags, fd, offset) {
-                Ok(ptr) => ptr as i32,
-                Err(_) => unimplemented!(),
-            };
+        let args = usercopy::read_from_user::<[u32; 6]>(gp_regs.ebx as usize);
+        result = args.and_then(|args| {
+            let addr = args[0] as usize;
+            let len = args[1] as usize;
+            let prot = syscall::MemMapProt::from_bits_unchecked(args[2]);
+            let flags = syscall::MemMapFlags::from_bits_unchecked(args[3]);
+            let fd = args[4] as i32;
+            let offset = args[5] as usize;
+            syscall::mem_map(addr, len, prot, flags, fd, offset)
+                .map(|ptr| ptr as u32)
+                .map_err(Errno::from)
+        });
     }
     // 6 set_tls
-    // ebx: a pointer to the TLS, u32
+    // ebx: a pointer to the TLS, u32, 0 to clear it
     // returns 0
     else if syscall_num == 6 {
         let ptr = gp_regs.ebx as usize;
-        syscall::set_tls(ptr);
-        return_value = 0;
+        result = syscall::set_tls(ptr).map(|()| 0).map_err(Errno::from);
     }
     // 8 debug_print_num
     // ebx: num, u32
@@ -192,22 +165,17 @@ pub extern "C" fn syscall_handler(
     else if syscall_num == 8 {
         let num = gp_regs.ebx;
         syscall::debug_print_num(num);
-        return_value = 0;
+        result = Ok(0);
     }
     // 9 debug_print_str
     // ebx: string, *const u8
     // ecx: string len, u32
     // returns 0
     else if syscall_num == 9 {
-        let string = unsafe {
-            let bytes = slice::from_raw_parts(
-                gp_regs.ebx as *const u8,
-                gp_regs.ecx as usize,
-            );
-            str::from_utf8(&bytes).unwrap()
-        };
-        syscall::debug_print_str(string);
-        return_value = 0;
+        result = user_str(gp_regs.ebx, gp_regs.ecx).map(|string| {
+            syscall::debug_print_str(&string);
+            0
+        });
     }
     // 10 exit
     // ebx: exit status, i32
@@ -218,28 +186,22 @@ pub extern "C" fn syscall_handler(
     }
     // 11 is_tty
     // ebx: fd, i32
-    // returns 1 or error number
+    // returns 1, or -ENOTTY if the file is not a terminal
     else if syscall_num == 11 {
         let fd = gp_regs.ebx as i32;
-        return_value = match syscall::is_tty(fd) {
-            Ok(res) => {
-                if res {
-                    1
-                } else {
-                    ENOTTY
-                }
-            }
-            Err(err) => match err {
-                syscall::IsTtyErr::BadFd => EBADF,
-            },
-        }
+        result = match syscall::is_tty(fd) {
+            Ok(true) => Ok(1),
+            Ok(false) => Err(Errno::ENOTTY),
+            Err(err) => Err(Errno::from(err)),
+        };
     }
     // 12 get_pid
     // returns process ID
     else if syscall_num == 12 {
-        return_value = syscall::get_pid();
+        result = Ok(syscall::get_pid() as u32);
     }
     // 13 fork
+    // returns the child's process ID in the parent and 0 in the child
     else if syscall_num == 13 {
         unsafe {
             println!(
@@ -247,42 +209,472 @@ pub extern "C" fn syscall_handler(
                 TASK_MANAGER.this_task().id,
             );
 
-            // FIXME: memory leak
-            let p_usermode_regs = alloc(
-                Layout::from_size_align(
-                    size_of::<GpRegs>(),
-                    align_of::<GpRegs>(),
-                )
-                .unwrap(),
-            )
-            .cast::<GpRegs>();
-            *p_usermode_regs = gp_regs.clone();
-            (*p_usermode_regs).eax = 0; // syscall return value for the child process
-            (*p_usermode_regs).ebp = usermode_ebp;
-            (*p_usermode_regs).esp = stack_frame.esp;
-
             let copy_id = TASK_MANAGER.allocate_task_id();
-            let copy = TASK_MANAGER.this_task().clone(
-                copy_id,
+            result = match TASK_MANAGER.this_task().clone(copy_id) {
+                Some(mut copy) => {
+                    // The child resumes right after the syscall with the same
+                    // registers, except that it sees 0 returned.  Its usermode
+                    // stack is an exact copy, nothing below %esp is touched.
+                    let usermode_regs = GpRegs {
+                        ebp: usermode_ebp,
+                        esp: stack_frame.esp,
+                        eax: 0,
+                        ..*gp_regs
+                    };
+                    let p_usermode_regs =
+                        copy.push_usermode_regs(usermode_regs);
+                    copy.fill_kernel_stack(
+                        jump_into_usermode as u32,
+                        &[
+                            gdt::USERMODE_CODE_SEG as u32,
+                            gdt::USERMODE_DATA_SEG as u32,
+                            gdt::TLS_SEG as u32,
+                            stack_frame.eip,
+                            p_usermode_regs as u32,
+                        ],
+                    );
+                    TASK_MANAGER
+                        .this_task()
+                        .process_mut()
+                        .child_ids
+                        .push(copy_id);
+                    TASK_MANAGER.add_runnable_task(copy);
+
+                    println!("[SYS FORK] Cloned task ID: {}.", copy_id);
+                    Ok(copy_id as u32)
+                }
+                None => Err(Errno::ENOMEM),
+            };
+        }
+    }
+    // 14 mem_protect
+    // ebx: addr, u32
+    // ecx: len, u32
+    // edx: prot, u32
+    // returns 0
+    else if syscall_num == 14 {
+        let addr = gp_regs.ebx as usize;
+        let len = gp_regs.ecx as usize;
+        let prot = syscall::MemMapProt::from_bits_unchecked(gp_regs.edx);
+        result = syscall::mem_protect(addr, len, prot)
+            .map(|()| 0)
+            .map_err(Errno::from);
+    }
+    // 15 mem_unmap
+    // ebx: addr, u32
+    // ecx: len, u32
+    // returns 0
+    else if syscall_num == 15 {
+        let addr = gp_regs.ebx as usize;
+        let len = gp_regs.ecx as usize;
+        result = syscall::mem_unmap(addr, len)
+            .map(|()| 0)
+            .map_err(Errno::from);
+    }
+    // 16 brk
+    // ebx: new program break, u32
+    // returns the new program break or the old one on failure, u32
+    else if syscall_num == 16 {
+        result = Ok(syscall::brk(gp_regs.ebx as usize) as u32);
+    }
+    // 17 set_foreground
+    // ebx: task ID, u32
+    // returns 0
+    else if syscall_num == 17 {
+        let task_id = gp_regs.ebx as usize;
+        result = syscall::set_foreground(task_id)
+            .map(|()| 0)
+            .map_err(Errno::from);
+    }
+    // 18 sleep_ms
+    // ebx: number of milliseconds, u32
+    // returns 0
+    else if syscall_num == 18 {
+        syscall::sleep_ms(gp_regs.ebx);
+        result = Ok(0);
+    }
+    // 19 set_priority
+    // ebx: task ID, u32
+    // ecx: priority, 0 (highest) to 7 (idle), u32
+    // returns 0
+    else if syscall_num == 19 {
+        let task_id = gp_regs.ebx as usize;
+        result = syscall::set_priority(task_id, gp_regs.ecx)
+            .map(|()| 0)
+            .map_err(Errno::from);
+    }
+    // 20 wait
+    // ebx: where to store the exit status, *mut i32, may be null
+    // returns the ID of the exited child
+    else if syscall_num == 20 {
+        // The pointer is checked before reaping the child, so that its exit
+        // status is not lost.
+        let status_ptr = gp_regs.ebx as usize;
+        result = check_nullable(status_ptr, size_of::<i32>())
+            .and_then(|()| syscall::wait().map_err(Errno::from))
+            .and_then(|(task_id, status)| {
+                if status_ptr != 0 {
+                    usercopy::write_to_user(status_ptr, status)?;
+                }
+                Ok(task_id as u32)
+            });
+    }
+    // 21 execve
+    // ebx: pathname, *const u8
+    // ecx: pathname len, u32
+    // edx: argv, NULL-terminated array of C strings, may be null
+    // esi: environ, NULL-terminated array of C strings, may be null
+    // returns only on failure
+    else if syscall_num == 21 {
+        result = (|| {
+            let pathname = user_path(gp_regs.ebx, gp_regs.ecx)?;
+            let argv = copy_user_cstrings(gp_regs.edx as usize)?;
+            let environ = copy_user_cstrings(gp_regs.esi as usize)?;
+            Err(Errno::from(syscall::execve(&pathname, argv, environ)))
+        })();
+    }
+    // 22 kill
+    // ebx: task ID, u32
+    // returns 0
+    else if syscall_num == 22 {
+        result = syscall::kill(gp_regs.ebx as usize)
+            .map(|()| 0)
+            .map_err(Errno::from);
+    }
+    // 23 thread_create
+    // ebx: entry point, u32
+    // ecx: top of the thread's usermode stack, u32
+    // edx: argument passed to the entry point, u32
+    // esi: flags, u32
+    // returns the thread's task ID
+    else if syscall_num == 23 {
+        let entry = gp_regs.ebx as usize;
+        let stack_top = gp_regs.ecx as usize;
+        let arg = gp_regs.edx;
+        let flags = gp_regs.esi;
+        // The entry point is called with the argument and a null return address
+        // on the stack.
+        let frame = stack_top.wrapping_sub(8);
+        let is_valid = flags & !syscall::THREAD_DETACHED == 0
+            && USERMODE_REGION.contains(&entry);
+        result = if !is_valid {
+            Err(Errno::EINVAL)
+        } else if stack_top < 8 {
+            Err(Errno::EFAULT)
+        } else {
+            usercopy::write_to_user(frame, [0, arg])
+        }
+        .map(|()| unsafe {
+            let thread_id = TASK_MANAGER.allocate_task_id();
+            let mut thread = TASK_MANAGER.this_task().thread(thread_id);
+            thread.joinable = flags & syscall::THREAD_DETACHED == 0;
+            let usermode_regs = GpRegs {
+                edi: 0,
+                esi: 0,
+                ebp: 0,
+                esp: frame as u32,
+                ebx: 0,
+                edx: 0,
+                ecx: 0,
+                eax: 0,
+            };
+            let p_usermode_regs = thread.push_usermode_regs(usermode_regs);
+            thread.fill_kernel_stack(
                 jump_into_usermode as u32,
                 &[
                     gdt::USERMODE_CODE_SEG as u32,
                     gdt::USERMODE_DATA_SEG as u32,
                     gdt::TLS_SEG as u32,
-                    stack_frame.eip,
+                    entry as u32,
                     p_usermode_regs as u32,
                 ],
             );
-            TASK_MANAGER.add_runnable_task(copy);
+            TASK_MANAGER.add_runnable_task(thread);
+
+            println!(
+                "[SYS THREAD_CREATE] Created thread ID {} at 0x{:08X}.",
+                thread_id, entry,
+            );
+            thread_id as u32
+        });
+    }
+    // 24 thread_join
+    // ebx: thread ID, u32
+    // ecx: where to store the exit value, *mut i32, may be null
+    // returns 0
+    else if syscall_num == 24 {
+        let value_ptr = gp_regs.ecx as usize;
+        result = check_nullable(value_ptr, size_of::<i32>())
+            .and_then(|()| {
+                syscall::thread_join(gp_regs.ebx as usize).map_err(Errno::from)
+            })
+            .and_then(|value| {
+                if value_ptr != 0 {
+                    usercopy::write_to_user(value_ptr, value)?;
+                }
+                Ok(0)
+            });
+    }
+    // 25 thread_exit
+    // ebx: exit value, i32
+    // does not return
+    else if syscall_num == 25 {
+        syscall::thread_exit(gp_regs.ebx as i32);
+    }
+    // 26 set_task_name
+    // ebx: name, *const u8
+    // ecx: name len, u32
+    // returns 0
+    else if syscall_num == 26 {
+        result = user_str(gp_regs.ebx, gp_regs.ecx).map(|name| {
+            syscall::set_task_name(&name);
+            0
+        });
+    }
+    // 27 close
+    // ebx: fd, i32
+    // returns 0
+    else if syscall_num == 27 {
+        result = syscall::close(gp_regs.ebx as i32)
+            .map(|()| 0)
+            .map_err(Errno::from);
+    }
+    // 28 lseek
+    // ebx: fd, i32
+    // ecx: offset, i32
+    // edx: whence, 0 (SEEK_SET), 1 (SEEK_CUR) or 2 (SEEK_END), u32
+    // returns the new offset
+    //
+    // 29 is reserved for a variant with a 64-bit offset.
+    else if syscall_num == 28 {
+        let whence = match gp_regs.edx {
+            0 => Some(syscall::Seek::Abs),
+            1 => Some(syscall::Seek::Rel),
+            2 => Some(syscall::Seek::End),
+            _ => None,
+        };
+        result = whence.ok_or(Errno::EINVAL).and_then(|whence| {
+            let fd = gp_regs.ebx as i32;
+            let offset = gp_regs.ecx as i32 as i64;
+            syscall::lseek(fd, offset, whence)
+                .map(|new_offset| new_offset as u32)
+                .map_err(Errno::from)
+        });
+    }
+    // 30 stat
+    // 32 lstat
+    // ebx: pathname, *const u8
+    // ecx: pathname len, u32
+    // edx: stat buffer, *mut syscall::Stat
+    // returns 0
+    else if syscall_num == 30 || syscall_num == 32 {
+        result = user_path(gp_regs.ebx, gp_regs.ecx).and_then(|pathname| {
+            let stat = if syscall_num == 30 {
+                syscall::stat(&pathname)
+            } else {
+                syscall::lstat(&pathname)
+            };
+            write_stat(gp_regs.edx as usize, stat)
+        });
+    }
+    // 31 fstat
+    // ebx: fd, i32
+    // ecx: stat buffer, *mut syscall::Stat
+    // returns 0
+    else if syscall_num == 31 {
+        let stat = syscall::fstat(gp_regs.ebx as i32);
+        result = write_stat(gp_regs.ecx as usize, stat);
+    }
+    // 33 get_ppid
+    // returns parent process ID or 0 if there is none
+    else if syscall_num == 33 {
+        result = Ok(syscall::get_ppid() as u32);
+    }
+    // 34 get_tid
+    // returns task ID
+    else if syscall_num == 34 {
+        result = Ok(syscall::get_tid() as u32);
+    }
+    // 35 pipe
+    // ebx: fds, *mut [i32; 2], receives the read end and the write end
+    // returns 0
+    else if syscall_num == 35 {
+        let fds_ptr = gp_regs.ebx as usize;
+        result = usercopy::check_writable(fds_ptr, 2 * size_of::<i32>())
+            .and_then(|()| syscall::pipe().map_err(Errno::from))
+            .and_then(|(read_fd, write_fd)| {
+                usercopy::write_to_user(fds_ptr, [read_fd, write_fd]).map_err(
+                    |err| {
+                        let _ = syscall::close(read_fd);
+                        let _ = syscall::close(write_fd);
+                        err
+                    },
+                )?;
+                Ok(0)
+            });
+    }
+    // 36 get_features
+    // returns the FEATURE_* flags
+    else if syscall_num == 36 {
+        let mut features = 0;
+        if sysenter::is_enabled() {
+            features |= FEATURE_SYSENTER;
+        }
+        result = Ok(features);
+    }
+    // 37 nanosleep
+    // ebx: requested time, *const syscall::Timespec
+    // ecx: where to store the remaining time if woken up early,
+    //      *mut syscall::Timespec, may be null
+    // returns 0
+    else if syscall_num == 37 {
+        let rem_ptr = gp_regs.ecx as usize;
+        result = check_nullable(rem_ptr, size_of::<syscall::Timespec>())
+            .and_then(|()| usercopy::read_from_user(gp_regs.ebx as usize))
+            .and_then(|req| match syscall::nanosleep(req) {
+                Ok(()) => Ok(0),
+                Err(syscall::NanosleepErr::Interrupted(rem))
+                    if rem_ptr != 0 =>
+                {
+                    usercopy::write_to_user(rem_ptr, rem)?;
+                    Err(Errno::EINTR)
+                }
+                Err(err) => Err(Errno::from(err)),
+            });
+    }
+    // 38 ioctl
+    // ebx: fd, i32
+    // ecx: request, u32
+    // edx: argument, a value or a pointer depending on the request, u32
+    // returns a value depending on the request
+    else if syscall_num == 38 {
+        let fd = gp_regs.ebx as i32;
+        result = syscall::ioctl(fd, gp_regs.ecx, gp_regs.edx as usize);
+    }
+    // 39 mem_stats
+    // ebx: where to store the stats, *mut syscall::MemStats
+    // returns 0
+    else if syscall_num == 39 {
+        let stats = syscall::mem_stats();
+        result =
+            usercopy::write_to_user(gp_regs.ebx as usize, stats).map(|()| 0);
+    } else {
+        println!("[SYS] Invalid syscall number {}.", syscall_num);
+        result = Err(Errno::ENOSYS);
+    }
+
+    gp_regs.eax = match result {
+        Ok(value) => value,
+        Err(errno) => errno.to_return_value(),
+    };
+
+    unsafe {
+        TASK_MANAGER.reap_terminated_tasks();
+        TASK_MANAGER.terminate_this_task_if_killed();
+    }
+}
 
-            println!("[SYS FORK] Cloned task ID: {}.", copy_id);
+/// Checks that the `len` bytes at `user_ptr` are writable, unless the pointer
+/// is null.
+fn check_nullable(user_ptr: usize, len: usize) -> Result<(), Errno> {
+    if user_ptr == 0 {
+        Ok(())
+    } else {
+        usercopy::check_writable(user_ptr, len)
+    }
+}
 
-            return_value = copy_id as i32;
+/// Reads at most `len` bytes from `fd` to `buf` in the usermode memory of the
+/// running task through a kernel buffer of at most [MAX_IO_CHUNK] bytes.
+fn read_to_user(fd: i32, buf: usize, len: usize) -> Result<usize, Errno> {
+    // The buffer is checked before reading, so that no data is lost.
+    usercopy::check_writable(buf, len)?;
+    let mut chunk = vec![0; len.min(MAX_IO_CHUNK)];
+    let n = syscall::read(fd, &mut chunk)?;
+    usercopy::copy_to_user(buf, &chunk[..n])?;
+    Ok(n)
+}
+
+/// Writes `len` bytes from `buf` in the usermode memory of the running task to
+/// `fd` through a kernel buffer of at most [MAX_IO_CHUNK] bytes.  Stops at the
+/// first short write, or at an error after some bytes are written.
+fn write_from_user(fd: i32, buf: usize, len: usize) -> Result<usize, Errno> {
+    usercopy::check_range(buf, len)?;
+    let mut chunk = vec![0; len.min(MAX_IO_CHUNK)];
+    let mut written = 0;
+    while written < len {
+        let chunk_len = (len - written).min(chunk.len());
+        let res =
+            usercopy::copy_from_user(&mut chunk, buf + written, chunk_len)
+                .and_then(|()| {
+                    syscall::write(fd, &chunk[..chunk_len]).map_err(Errno::from)
+                });
+        match res {
+            Ok(n) => {
+                written += n;
+                if n < chunk_len {
+                    break;
+                }
+            }
+            Err(err) if written == 0 => return Err(err),
+            Err(_) => break,
         }
+    }
+    Ok(written)
+}
+
+/// Copies the string of `len` bytes at `addr` in the usermode memory of the
+/// running task, returns `EINVAL` if it is not valid UTF-8.
+fn user_str(addr: u32, len: u32) -> Result<String, Errno> {
+    let bytes = usercopy::bytes_from_user(addr as usize, len as usize)?;
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+/// Same as [user_str], but also checks the length against
+/// [PATH_MAX](syscall::PATH_MAX).
+fn user_path(addr: u32, len: u32) -> Result<String, Errno> {
+    if len as usize > syscall::PATH_MAX {
+        Err(Errno::ENAMETOOLONG)
     } else {
-        println!("[SYS] Ignoring an invalid syscall number {}.", syscall_num);
-        return_value = 0;
+        user_str(addr, len)
     }
+}
 
-    gp_regs.eax = return_value as u32;
+/// Writes the result of a stat syscall to `buf` in the usermode memory of the
+/// running task.
+fn write_stat(
+    buf: usize,
+    stat: Result<syscall::Stat, syscall::StatErr>,
+) -> Result<u32, Errno> {
+    usercopy::check_writable(buf, size_of::<syscall::Stat>())?;
+    usercopy::write_to_user(buf, stat?)?;
+    Ok(0)
+}
+
+/// Copies a NULL-terminated array of C strings out of the usermode memory of
+/// the running task.  A null `array` is an empty array.
+fn copy_user_cstrings(array: usize) -> Result<Vec<CString>, Errno> {
+    let mut strings = Vec::new();
+    if array == 0 {
+        return Ok(strings);
+    }
+    for idx in 0..=MAX_EXEC_STRINGS {
+        let elem = array
+            .checked_add(idx * size_of::<u32>())
+            .ok_or(Errno::EFAULT)?;
+        let string = usercopy::read_from_user::<u32>(elem)? as usize;
+        if string == 0 {
+            return Ok(strings);
+        }
+        if idx == MAX_EXEC_STRINGS {
+            break;
+        }
+        let string = usercopy::str_from_user(string, MAX_EXEC_STRING_LEN)
+            .map_err(|err| match err {
+                Errno::ENAMETOOLONG => Errno::E2BIG,
+                other => other,
+            })?;
+        strings.push(string);
+    }
+    Err(Errno::E2BIG)
 }
@@ -0,0 +1,111 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Fast syscall entry with `sysenter` and `sysexit`.
+//!
+//! The usermode code may use `sysenter` instead of `int 0x88` if the
+//! get_features syscall reports it.  The syscall number and the arguments are
+//! passed in the same registers, and in addition:
+//!
+//! * `%edi` holds the address to return to,
+//! * `%ebp` holds the usermode `%esp` to return with.
+//!
+//! `sysexit` clobbers `%ecx` and `%edx`, and the original `%ebp` is not
+//! restored, so the caller has to save it on the stack.  `sysenter_entry` in
+//! interrupts.s makes up the same stack frame as `int 0x88`, so the syscalls
+//! are dispatched by the same [syscall_handler](super::syscall::syscall_handler).
+//!
+//! `sysenter` keeps TF set, so a usermode program that sets it gets a
+//! single-step trap at the start of `sysenter_entry`.  The debug exception
+//! handler clears TF and returns there, and the usermode TF is lost.
+//!
+//! The CPU loads `%esp` from the SYSENTER_ESP MSR, which `switch_tasks` in
+//! task_manager.s updates on every task switch along with ESP0 in the TSS.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::arch::gdt;
+use crate::arch::tsc::cpuid;
+
+const CPUID_FEATURES: u32 = 1;
+const FEATURE_EDX_SEP: u32 = 1 << 11;
+
+const MSR_SYSENTER_CS: u32 = 0x174;
+const MSR_SYSENTER_ESP: u32 = 0x175;
+const MSR_SYSENTER_EIP: u32 = 0x176;
+
+extern "C" {
+    fn sysenter_entry();
+    /// Label in `sysenter_entry` right after it resets EFLAGS.
+    fn sysenter_flags_reset();
+}
+
+/// Read by `switch_tasks` in task_manager.s.
+#[no_mangle]
+static SYSENTER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if the usermode code may use `sysenter`.
+pub fn is_enabled() -> bool {
+    SYSENTER_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Checks if `eip` is in `sysenter_entry` before it resets EFLAGS, where the
+/// usermode TF may still be set.
+pub fn is_before_flags_reset(eip: u32) -> bool {
+    (sysenter_entry as u32..sysenter_flags_reset as u32).contains(&eip)
+}
+
+/// Checks if the CPU supports `sysenter`.  Pentium Pro reports the feature
+/// but does not implement it properly.
+fn has_sysenter() -> bool {
+    let [signature, _, _, features] = cpuid(CPUID_FEATURES);
+    let family = (signature >> 8) & 0xF;
+    let model = (signature >> 4) & 0xF;
+    let stepping = signature & 0xF;
+    features & FEATURE_EDX_SEP != 0
+        && !(family == 6 && model < 3 && stepping < 3)
+}
+
+unsafe fn wrmsr(msr: u32, value: u32) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value,
+        in("edx") 0,
+        options(att_syntax, nostack),
+    );
+}
+
+/// Programs the SYSENTER MSRs if the CPU supports `sysenter`.  The GDT and the
+/// TSS must be loaded.
+pub fn init() {
+    if !has_sysenter() {
+        log_warn!("[SYSENTER] Not supported, syscalls use int 0x88 only.");
+        return;
+    }
+    // The CPU takes the kernel data segment and the usermode code and data
+    // segments from their positions relative to the kernel code segment.
+    assert_eq!(gdt::KERNEL_DATA_IDX, gdt::KERNEL_CODE_IDX + 1);
+    assert_eq!(gdt::USERMODE_CODE_IDX, gdt::KERNEL_CODE_IDX + 2);
+    assert_eq!(gdt::USERMODE_DATA_IDX, gdt::KERNEL_CODE_IDX + 3);
+    unsafe {
+        wrmsr(MSR_SYSENTER_CS, gdt::KERNEL_CODE_SEG as u32);
+        wrmsr(MSR_SYSENTER_ESP, gdt::TSS.esp0);
+        wrmsr(MSR_SYSENTER_EIP, sysenter_entry as u32);
+    }
+    SYSENTER_ENABLED.store(true, Ordering::SeqCst);
+    log_info!("[SYSENTER] Enabled.");
+}
@@ -14,24 +14,35 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use alloc::alloc::{alloc, Layout};
 use alloc::vec;
 use alloc::vec::Vec;
+use core::cmp;
 use core::default::Default;
+use core::mem::size_of;
 use core::ptr;
+use core::slice;
 
-use crate::arch::pmm_stack::PMM_STACK;
 use crate::arch::vas::USERMODE_REGION;
-use crate::task::USERMODE_STACK_REGION;
-use crate::task_manager::TASK_MANAGER;
+use crate::task::{
+    USERMODE_STACK_LIMIT_REGION, USERMODE_STACK_MAX_SIZE, USERMODE_STACK_REGION,
+};
+use crate::task_manager::{self, TASK_MANAGER};
 
+use crate::arch::fpu::FpuState;
 use crate::arch::gdt;
 use crate::arch::syscall::GpRegs;
-use crate::arch::vas::{Table, VirtAddrSpace};
+use crate::arch::vas::{VirtAddrSpace, KERNEL_VAS};
+use crate::cmdline;
+use crate::dev::console;
 use crate::ffi::cstring::CString;
+use crate::fs;
 use crate::memory_region::Region;
 use crate::stack::Stack;
-use crate::task::Task;
+use crate::task::{LoadErr, Process, Task};
+
+/// Value the new kernel stacks are filled with, see
+/// [Task::kernel_stack_usage].
+pub const KERNEL_STACK_PATTERN: u32 = 0x57AC_57AC;
 
 extern "C" {
     /// Does an interrupt return with requested privilege level 3 (usermode).
@@ -61,28 +72,122 @@ impl Task {
         entry_args: &[u32],
     ) -> Self {
         let mut task = Self::with_empty_stack(id, vas);
+        task.fill_kernel_stack(entry, entry_args);
+        task
+    }
+
+    /// Creates a kernel thread that runs `f(arg)` in the kernel VAS and exits
+    /// with the status 0 when `f` returns.
+    pub fn kernel_thread(id: usize, f: fn(usize), arg: usize) -> Self {
+        let kvas = KERNEL_VAS.lock().clone();
+        Self::with_filled_stack(
+            id,
+            kvas,
+            kernel_thread_entry as u32,
+            &[f as usize as u32, arg as u32],
+        )
+    }
+
+    /// Checks if the task runs in the kernel VAS, i.e. it is a [kernel
+    /// thread](Self::kernel_thread) or the idle task.
+    pub fn is_kernel_thread(&self) -> bool {
+        !self.process().vas.is_usermode()
+    }
 
-        // Set up an initial stack that will be popped on a task switch (see
-        // task_manager.s).
+    /// Sets up the kernel stack to be popped on the first task switch to the
+    /// task.  See [with_filled_stack](Self::with_filled_stack) for the
+    /// requirements to `entry` and `entry_args`.
+    pub fn fill_kernel_stack(&mut self, entry: u32, entry_args: &[u32]) {
+        // See task_manager.s for the stack layout.
         for arg in entry_args.iter().rev() {
-            task.kernel_stack.push(arg.clone()).unwrap();
+            self.kernel_stack.push(arg.clone()).unwrap();
         }
-        task.kernel_stack.push(0x00000000).unwrap();
+        self.kernel_stack.push(0x00000000).unwrap();
         // Here 0x00000000 is just some value for the stack tracer to print
         // out as EIP instead of some heap garbage after the stack.  Also it
         // may serve as an address to return to from default_entry_point().
-        task.kernel_stack.push(entry).unwrap(); // eip
-        task.kernel_stack.push(0x00000000).unwrap();
+        self.kernel_stack.push(entry).unwrap(); // eip
+        self.kernel_stack.push(0x00000000).unwrap();
         // ebp = 0x00000000 is a magic value that makes the stack tracer to
         // stop.  It is used here the same way as in boot.s.
-        task.kernel_stack.push(0).unwrap(); // eax
-        task.kernel_stack.push(0).unwrap(); // ecx
-        task.kernel_stack.push(0).unwrap(); // edx
-        task.kernel_stack.push(0).unwrap(); // ebx
-        task.kernel_stack.push(0).unwrap(); // esi
-        task.kernel_stack.push(0).unwrap(); // edi
+        self.kernel_stack.push(0).unwrap(); // eax
+        self.kernel_stack.push(0).unwrap(); // ecx
+        self.kernel_stack.push(0).unwrap(); // edx
+        self.kernel_stack.push(0).unwrap(); // ebx
+        self.kernel_stack.push(0).unwrap(); // esi
+        self.kernel_stack.push(0).unwrap(); // edi
+    }
 
-        task
+    /// Pushes the registers for [jump_into_usermode] onto the kernel stack,
+    /// so that they live as long as the task does.  Returns their address.
+    ///
+    /// This must be done before the stack is [filled](Self::fill_kernel_stack).
+    pub fn push_usermode_regs(&mut self, regs: GpRegs) -> *const GpRegs {
+        let words = [
+            regs.edi, regs.esi, regs.ebp, regs.esp, regs.ebx, regs.edx,
+            regs.ecx, regs.eax,
+        ];
+        for &word in words.iter().rev() {
+            self.kernel_stack.push(word).unwrap();
+        }
+        self.kernel_stack.top as *const GpRegs
+    }
+
+    /// Fills the kernel stack with [KERNEL_STACK_PATTERN], so that its usage
+    /// can be [measured](Self::kernel_stack_usage).  This must be done before
+    /// the guard page is placed.
+    pub unsafe fn paint_kernel_stack(&mut self) {
+        let mut addr = self.kernel_stack.max_top;
+        while addr != self.kernel_stack.top {
+            addr.write(KERNEL_STACK_PATTERN);
+            addr = addr.add(1);
+        }
+    }
+
+    /// Returns how deep the kernel stack has ever been used and its size
+    /// without the guard page, in bytes.
+    pub fn kernel_stack_usage(&self) -> (usize, usize) {
+        let start = self.kernel_stack_guard_page() as usize + 4096;
+        let bottom = self.kernel_stack.bottom as usize;
+        let mut addr = start;
+        // The stack grows down, so the first word that does not match the
+        // pattern is the deepest one written to.
+        while addr < bottom
+            && unsafe { (addr as *const u32).read() } == KERNEL_STACK_PATTERN
+        {
+            addr += 4;
+        }
+        (bottom - addr, bottom - start)
+    }
+
+    /// Returns the region of the kernel stack above its guard page.
+    pub fn kernel_stack_region(&self) -> Region<usize> {
+        Region {
+            start: self.kernel_stack_guard_page() as usize + 4096,
+            end: self.kernel_stack.bottom as usize,
+        }
+    }
+
+    /// Returns the address of the guard page below the kernel stack.
+    pub fn kernel_stack_guard_page(&self) -> u32 {
+        self.kernel_stack.max_top as u32
+    }
+
+    /// Places a guard page below the kernel stack, so that a kernel stack
+    /// overflow page faults instead of overwriting the preceding heap memory.
+    ///
+    /// The guard page is placed in the kernel VAS, whose kernel page tables are
+    /// shared with all other VASes.  The lowest page of the kernel stack must
+    /// be reserved for it.
+    pub unsafe fn place_kernel_stack_guard(&mut self) {
+        let guard_page = self.kernel_stack_guard_page();
+        KERNEL_VAS.lock().place_guard_page(guard_page);
+    }
+
+    /// Removes the guard page below the kernel stack.
+    pub unsafe fn remove_kernel_stack_guard(&mut self) {
+        let guard_page = self.kernel_stack_guard_page();
+        KERNEL_VAS.lock().remove_guard_page(guard_page);
     }
 
     pub unsafe fn set_tls(&mut self, value: usize) {
@@ -99,123 +204,603 @@ impl Task {
         );
     }
 
-    pub fn set_up_usermode_stack(
+    /// Replaces the program of the running task with the executable
+    /// `pathname`, which gets `argv` and `environ`.  The task keeps its ID,
+    /// kernel stack and opened files.
+    ///
+    /// Returns only if the executable cannot be opened, the task is intact
+    /// then.  If loading it fails after the old program has been discarded,
+    /// the task is terminated.
+    ///
+    /// # Safety
+    /// The task must be the running one.
+    pub unsafe fn exec(
+        &mut self,
+        pathname: &str,
+        argv: Vec<CString>,
+        environ: Vec<CString>,
+    ) -> LoadErr {
+        let (fd, elf) = match self.open_executable(pathname) {
+            Ok(opened) => opened,
+            Err(err) => return err,
+        };
+
+        // There is no way back to the old program from here on.  Nor to its
+        // memory, which `pathname` may point to.
+        self.discard_usermode_memory();
+        let loaded = self.load_executable(pathname, fd, &elf);
+        // The new program does not inherit the descriptor of its executable.
+        self.close_file(fd);
+        if let Err(err) = loaded {
+            println!(
+                "[TASK] Could not load the program of task ID {}: {:?}.",
+                self.id, err,
+            );
+            task_manager::task_exit(EXEC_FAILED_EXIT_STATUS);
+        }
+        if let Err(err) = self.set_up_usermode_stack(&argv, &environ) {
+            println!(
+                "[TASK] Could not set up the stack of task ID {}: {:?}.",
+                self.id, err,
+            );
+            task_manager::task_exit(EXEC_FAILED_EXIT_STATUS);
+        }
+        *self.fpu_state = FpuState::new();
+        self.fpu_state.restore();
+
+        // Nothing is returned to from here, so the arguments are freed now.
+        let entry = elf.entry_point as u32;
+        drop(elf);
+        drop(argv);
+        drop(environ);
+        self.enter_usermode(entry);
+    }
+
+    /// Frees the usermode memory of the running task by switching it to an
+    /// empty VAS.
+    ///
+    /// The other threads of the process keep the old memory, which is freed
+    /// when the last of them exits.
+    unsafe fn discard_usermode_memory(&mut self) {
+        let vas = VirtAddrSpace::kvas_copy_on_heap();
+        vas.load();
+        self.replace_process(vas);
+
+        self.usermode_stack = None;
+        // The new program may set up its own TLS block.
+        self.set_tls(0);
+    }
+
+    /// Jumps to `entry` in the usermode with the usermode stack set up by
+    /// [set_up_usermode_stack](Self::set_up_usermode_stack).
+    ///
+    /// # Safety
+    /// The task must be the running one.
+    pub unsafe fn enter_usermode(&self, entry: u32) -> ! {
+        let gp_regs = GpRegs {
+            edi: 0,
+            esi: 0,
+            ebp: 0,
+            esp: self.usermode_stack.as_ref().unwrap().top as u32,
+            ebx: 0,
+            edx: 0,
+            ecx: 0,
+            eax: 0,
+        };
+        println!("[TASK] Entering usermode at 0x{:08X}.", entry);
+
+        // The flags are pushed for the interrupt return, so that the program
+        // runs with the interrupts enabled.
+        asm!("sti");
+        jump_into_usermode(
+            gdt::USERMODE_CODE_SEG,
+            gdt::USERMODE_DATA_SEG,
+            gdt::TLS_SEG,
+            entry,
+            &gp_regs as *const GpRegs,
+        );
+    }
+
+    /// Maps the usermode stack and places `argv` and `environ` on it as the
+    /// program entry expects: argc, the argv and envp pointer arrays, both
+    /// NULL-terminated, and above them the strings they point to.
+    ///
+    /// # Safety
+    /// The task must be the running one.
+    pub unsafe fn set_up_usermode_stack(
         &mut self,
         argv: &[CString],
         environ: &[CString],
-    ) {
+    ) -> Result<(), StackGrowthErr> {
         // Allocate physical memory for the stack and map it.
+        let process = self.process_mut();
         unsafe {
             for four_mib_chunk in USERMODE_STACK_REGION
                 .align_boundaries_at(4 * 1024 * 1024)
                 .range()
                 .step_by(4 * 1024 * 1024)
             {
-                let pde_idx = four_mib_chunk >> 22;
-                let pgtbl_virt: *mut Table =
-                    alloc(Layout::from_size_align(4096, 4096).unwrap()).cast();
-                pgtbl_virt.write_bytes(0, 1);
-                self.vas.set_pde_virt(pde_idx, pgtbl_virt);
+                process.vas.ensure_pgtbl(four_mib_chunk as u32);
             }
 
-            for four_kib_chunk in USERMODE_STACK_REGION
-                .align_boundaries_at(4096)
-                .range()
-                .step_by(4096)
-            {
-                let phys = PMM_STACK.lock().pop_page();
-                self.vas.map_page(four_kib_chunk as u32, phys);
-                (four_kib_chunk as *mut u8).write_bytes(0, 4096);
-            }
+            // The stack pages are allocated on demand.
+            let stack_pages = USERMODE_STACK_REGION.align_boundaries_at(4096);
+            process.vas.reserve_pages(
+                stack_pages.start as u32,
+                stack_pages.end as u32,
+            );
+
+            // The stack grows when this guard page is hit.
+            let guard_page = stack_pages.start as u32 - 4096;
+            process.vas.ensure_pgtbl(guard_page);
+            process.vas.place_guard_page(guard_page);
         }
+        process.mem_mappings.push(MemMapping {
+            region: USERMODE_STACK_REGION,
+            _type: MemMappingType::Stack,
+            backing: None,
+        });
 
-        self.usermode_stack =
-            unsafe { Some(Stack::from_region(USERMODE_STACK_REGION)) };
+        // The strings are copied in ascending order, which the guard page
+        // cannot catch, so the stack is grown for all of it beforehand.
+        let strings_len: usize = argv
+            .iter()
+            .chain(environ)
+            .map(|string| string.as_cstr().to_bytes_with_nul().len())
+            .sum();
+        let strings_len = (strings_len + 3) & !3;
+        let ptrs_len = (argv.len() + environ.len() + 3) * size_of::<u32>();
+        let stack_region =
+            self.grow_usermode_stack_to(strings_len + ptrs_len)?;
+
+        self.usermode_stack = Some(Stack::from_region(stack_region));
         let usermode_stack = self.usermode_stack.as_mut().unwrap();
 
+        // The strings.
+        let mut string_at = stack_region.end - strings_len;
+        usermode_stack.top = string_at as *mut u32;
+        let mut copy_string = |string: &CString| {
+            let bytes = string.as_cstr().to_bytes_with_nul();
+            ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                string_at as *mut u8,
+                bytes.len(),
+            );
+            string_at += bytes.len();
+            (string_at - bytes.len()) as u32
+        };
+        let argv_at: Vec<u32> = argv.iter().map(&mut copy_string).collect();
+        let envp_at: Vec<u32> = environ.iter().map(&mut copy_string).collect();
+
         // envp[]
         usermode_stack.push(0).unwrap(); // environ[len(environ)] = NULL
-        for envp in environ.iter().rev() {
-            usermode_stack.push(envp.as_ptr() as u32).unwrap();
+        for &envp in envp_at.iter().rev() {
+            usermode_stack.push(envp).unwrap();
         }
 
         // argv[]
         usermode_stack.push(0).unwrap(); // argv[argc] = NULL
-        for arg in argv.iter().rev() {
-            usermode_stack.push(arg.as_ptr() as u32).unwrap();
+        for &arg in argv_at.iter().rev() {
+            usermode_stack.push(arg).unwrap();
         }
 
         // argc
         usermode_stack.push(argv.len() as u32).unwrap();
+        Ok(())
     }
 
-    // PROT_READ, PROT_WRITE, MAP_ANONYMOUS, MAP_PRIVATE
-    pub fn mem_map(&mut self, len: usize) -> &MemMapping {
-        assert_eq!(len % 4096, 0, "len must be page-aligned");
-        let mut candidate = Region {
-            start: USERMODE_REGION.start,
-            end: USERMODE_REGION.start,
-        };
-        while candidate.len() < len {
-            if candidate.conflicts_with(&USERMODE_STACK_REGION) {
-                candidate.start = USERMODE_STACK_REGION.end;
-                candidate.end = USERMODE_STACK_REGION.end;
+    /// Grows the usermode stack until it is at least `len` bytes long, returns
+    /// its region.
+    ///
+    /// # Safety
+    /// The task must be the running one.
+    unsafe fn grow_usermode_stack_to(
+        &mut self,
+        len: usize,
+    ) -> Result<Region<usize>, StackGrowthErr> {
+        loop {
+            let region = self
+                .process()
+                .mem_mappings
+                .iter()
+                .find(|mapping| mapping._type == MemMappingType::Stack)
+                .unwrap()
+                .region;
+            if region.len() >= len {
+                return Ok(region);
             }
-            for segment in &self.program_segments {
-                if candidate.conflicts_with(segment) {
-                    candidate.start = (segment.end + 0xFFF) & !0xFFF;
-                    candidate.end = (segment.end + 0xFFF) & !0xFFF;
+            self.grow_usermode_stack(region.start as u32 - 4096)?;
+        }
+    }
+
+    /// Privately maps `len` zeroed bytes at the page-aligned address `start`,
+    /// or at an address chosen by the kernel if it is `None`.  `len` is
+    /// rounded up to the page size.
+    ///
+    /// The pages are readable and writable and are allocated on the first
+    /// access.
+    pub fn mem_map(
+        &mut self,
+        start: Option<usize>,
+        len: usize,
+    ) -> Result<&MemMapping, MapErr> {
+        let len = match len.checked_add(0xFFF) {
+            Some(len) if len >= 4096 => len & !0xFFF,
+            _ => return Err(MapErr::InvalidArgs),
+        };
+        let process = self.process_mut();
+        let region = match start {
+            Some(start) => {
+                let end = start.checked_add(len).ok_or(MapErr::InvalidArgs)?;
+                let region = Region { start, end };
+                if start % 4096 != 0 || !region.is_in(&USERMODE_REGION) {
+                    return Err(MapErr::InvalidArgs);
                 }
-            }
-            for mapping in &self.mem_mappings {
-                if candidate.conflicts_with(&mapping.region) {
-                    candidate.start = (mapping.region.end + 0xFFF) & !0xFFF;
-                    candidate.end = (mapping.region.end + 0xFFF) & !0xFFF;
+                if !process.is_region_free(&region) {
+                    return Err(MapErr::Conflict);
                 }
+                region
             }
-            candidate.end += 4096;
+            None => process.find_free_region(len).ok_or(MapErr::NoSpace)?,
+        };
+        let mapping = MemMapping {
+            region,
+            _type: MemMappingType::Anonymous,
+            backing: None,
+        };
+        unsafe {
+            process.reserve_mapping_pages(&mapping);
         }
-        assert!(candidate.is_in(&USERMODE_REGION));
 
-        let mapping = MemMapping { region: candidate };
+        process.mem_mappings.push(mapping);
+        Ok(process.mem_mappings.last().unwrap())
+    }
+
+    /// Privately maps `len` bytes of the file `node` starting at `file_offset`
+    /// at an address chosen by the kernel.
+    ///
+    /// The pages are read from the file on the first access.  Changes to them
+    /// are never written back to the file.
+    pub fn mem_map_file(
+        &mut self,
+        node: fs::Node,
+        file_offset: usize,
+        len: usize,
+        writable: bool,
+    ) -> Result<&MemMapping, MapErr> {
+        let region = self
+            .process()
+            .find_free_region((len + 0xFFF) & !0xFFF)
+            .ok_or(MapErr::NoSpace)?;
         unsafe {
-            for four_mib_chunk in mapping
-                .region
-                .align_boundaries_at(4 * 1024 * 1024)
-                .range()
-                .step_by(4 * 1024 * 1024)
-            {
-                let pgtbl_virt = self.vas.pgtbl_virt_of(four_mib_chunk as u32);
-                if pgtbl_virt.is_null() {
-                    let pde_idx = four_mib_chunk >> 22;
-                    let new_pgtbl_virt =
-                        alloc(Layout::from_size_align(4096, 4096).unwrap())
-                            as *mut Table;
-                    new_pgtbl_virt.write_bytes(0, 1);
-                    self.vas.set_pde_virt(pde_idx, new_pgtbl_virt);
-                }
-            }
+            Ok(self.mem_map_file_at(
+                region.start,
+                len,
+                node,
+                file_offset,
+                len,
+                writable,
+            ))
+        }
+    }
 
-            for four_kib_chunk in mapping
-                .region
-                .align_boundaries_at(4096)
-                .range()
-                .step_by(4096)
-            {
-                assert!(
-                    !self.vas.is_mapped(four_kib_chunk as u32),
-                    "page 0x{:08X} is already mapped to {:#X?}",
-                    four_kib_chunk,
-                    self.vas.virt_to_phys(four_kib_chunk as u32).unwrap(),
+    /// Privately maps `len` bytes at the page-aligned address `start`, of which
+    /// the first `file_size` bytes are read from the file `node` starting at
+    /// `file_offset`, and the rest are zeroed.
+    ///
+    /// See [mem_map_file](Self::mem_map_file).
+    ///
+    /// # Safety
+    /// The region must lie within [USERMODE_REGION] and must not conflict with
+    /// other mappings of the task.
+    pub unsafe fn mem_map_file_at(
+        &mut self,
+        start: usize,
+        len: usize,
+        node: fs::Node,
+        file_offset: usize,
+        file_size: usize,
+        writable: bool,
+    ) -> &MemMapping {
+        assert_eq!(start % 4096, 0, "start must be page-aligned");
+        assert!(file_size <= len, "file_size must not exceed len");
+
+        let mapping = MemMapping {
+            region: Region::from_start_len(start, (len + 0xFFF) & !0xFFF),
+            _type: MemMappingType::File,
+            backing: Some(FileBacking {
+                node,
+                offset: file_offset,
+                size: file_size,
+            }),
+        };
+        assert!(mapping.region.is_in(&USERMODE_REGION));
+        let process = self.process_mut();
+        process.reserve_mapping_pages(&mapping);
+        if !writable {
+            process
+                .vas
+                .set_protection(
+                    mapping.region.start as u32,
+                    mapping.region.end as u32,
+                    false,
+                    true,
+                )
+                .unwrap();
+        }
+
+        process.mem_mappings.push(mapping);
+        process.mem_mappings.last().unwrap()
+    }
+
+    /// Returns the memory mapping containing `addr`.
+    pub fn mem_mapping_at(&self, addr: usize) -> Option<&MemMapping> {
+        self.process()
+            .mem_mappings
+            .iter()
+            .find(|mapping| mapping.region.contains(&addr))
+    }
+
+    /// Unmaps a page-aligned range of an anonymous or file memory mapping and
+    /// frees its pages.
+    ///
+    /// The range may be either the whole mapping or a part of it, in which case
+    /// the mapping is shrunk or split in two.  `len` is rounded up to the page
+    /// size.
+    pub fn mem_unmap(
+        &mut self,
+        start: usize,
+        len: usize,
+    ) -> Result<(), UnmapErr> {
+        if start % 4096 != 0 || len == 0 {
+            return Err(UnmapErr::InvalidArgs);
+        }
+        let len = len.checked_add(0xFFF).ok_or(UnmapErr::InvalidArgs)? & !0xFFF;
+        let end = start.checked_add(len).ok_or(UnmapErr::InvalidArgs)?;
+        let region = Region { start, end };
+
+        let process = self.process_mut();
+        let idx = process
+            .mem_mappings
+            .iter()
+            .position(|mapping| {
+                matches!(
+                    mapping._type,
+                    MemMappingType::Anonymous | MemMappingType::File
+                ) && region.is_in(&mapping.region)
+            })
+            .ok_or(UnmapErr::NotMapped)?;
+        let mapping = process.mem_mappings.remove(idx);
+
+        unsafe {
+            process.vas.free_pages_to_stack(start as u32, end as u32);
+        }
+
+        // Keep the parts of the mapping outside the unmapped range.
+        if mapping.region.start < region.start {
+            process.mem_mappings.push(MemMapping {
+                region: Region {
+                    start: mapping.region.start,
+                    end: region.start,
+                },
+                _type: mapping._type,
+                backing: mapping.backing.clone(),
+            });
+        }
+        if region.end < mapping.region.end {
+            let skipped = region.end - mapping.region.start;
+            process.mem_mappings.push(MemMapping {
+                region: Region {
+                    start: region.end,
+                    end: mapping.region.end,
+                },
+                _type: mapping._type,
+                backing: mapping.backing.map(|backing| FileBacking {
+                    node: backing.node,
+                    offset: backing.offset + skipped,
+                    size: backing.size.saturating_sub(skipped),
+                }),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Moves the program break to `new_end` and returns the new break.
+    ///
+    /// Growing reserves zeroed pages, shrinking unmaps and frees them.  If the
+    /// break cannot be moved, e.g. because the heap would run into another
+    /// mapping, the old break is returned.  Thus `set_program_break(0)` returns
+    /// the current break.
+    pub fn set_program_break(&mut self, new_end: usize) -> usize {
+        let process = self.process_mut();
+        if new_end < process.heap_start {
+            return process.heap_end;
+        }
+
+        let old_pages_end = (process.heap_end + 0xFFF) & !0xFFF;
+        let new_pages_end = match new_end.checked_add(0xFFF) {
+            Some(end) => end & !0xFFF,
+            None => return process.heap_end,
+        };
+
+        if new_pages_end > old_pages_end {
+            let grown = MemMapping {
+                region: Region {
+                    start: old_pages_end,
+                    end: new_pages_end,
+                },
+                _type: MemMappingType::Heap,
+                backing: None,
+            };
+            let conflicts = !grown.region.is_in(&USERMODE_REGION)
+                || grown.region.conflicts_with(&USERMODE_STACK_LIMIT_REGION)
+                || process
+                    .program_segments
+                    .iter()
+                    .any(|segment| grown.region.conflicts_with(segment))
+                || process.mem_mappings.iter().any(|mapping| {
+                    grown.region.conflicts_with(&mapping.region)
+                });
+            if conflicts {
+                return process.heap_end;
+            }
+            unsafe {
+                process.reserve_mapping_pages(&grown);
+            }
+        } else if new_pages_end < old_pages_end {
+            unsafe {
+                process.vas.free_pages_to_stack(
+                    new_pages_end as u32,
+                    old_pages_end as u32,
                 );
-                let phys = PMM_STACK.lock().pop_page();
-                self.vas.map_page(four_kib_chunk as u32, phys);
-                (four_kib_chunk as *mut u8).write_bytes(0, 4096);
             }
         }
 
-        self.mem_mappings.push(mapping);
-        self.mem_mappings.last().unwrap()
+        // Keep a single heap mapping covering the heap pages.
+        process
+            .mem_mappings
+            .retain(|mapping| mapping._type != MemMappingType::Heap);
+        if new_pages_end > process.heap_start {
+            process.mem_mappings.push(MemMapping {
+                region: Region {
+                    start: process.heap_start,
+                    end: new_pages_end,
+                },
+                _type: MemMappingType::Heap,
+                backing: None,
+            });
+        }
+
+        process.heap_end = new_end;
+        new_end
+    }
+
+    /// Maps a zeroed page at `addr` if it belongs to one of the task's lazily
+    /// allocated memory mappings and has not been accessed yet.
+    ///
+    /// Returns `false` if `addr` is not in such a mapping, e.g. if it is a wild
+    /// pointer.
+    ///
+    /// # Safety
+    /// The task must be the running one, so that its VAS is loaded.
+    pub unsafe fn commit_lazy_page(&mut self, addr: u32) -> bool {
+        let page = addr & !0xFFF;
+        let mapping = match self.mem_mapping_at(addr as usize) {
+            Some(mapping) if mapping._type.is_lazy() => mapping,
+            _ => return false,
+        };
+        if !self.process().vas.commit_reserved_page(page) {
+            return false;
+        }
+
+        // Read the file content, if any, into the zeroed page.
+        if let Some(backing) = &mapping.backing {
+            let in_mapping = page as usize - mapping.region.start;
+            if in_mapping < backing.size {
+                let len = cmp::min(4096, backing.size - in_mapping);
+                let buf = slice::from_raw_parts_mut(page as *mut u8, len);
+                let fs = backing.node.fs();
+                let id_in_fs = backing.node.0.borrow().id_in_fs.unwrap();
+                if let Err(err) =
+                    fs.read_file(id_in_fs, backing.offset + in_mapping, buf)
+                {
+                    println!(
+                        "[TASK] Could not read page 0x{:08X} of a file \
+                         mapping: {:?}.",
+                        page, err,
+                    );
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Checks if the `len` bytes at `addr` lie in the usermode region and are
+    /// mapped, so that the kernel can access them on behalf of the task.  If
+    /// `write` is `true`, the pages must also be writable by the task.  The
+    /// lazily allocated pages among them are committed with the protection of
+    /// their mapping, and the usermode stack is grown over them as on a page
+    /// fault.
+    ///
+    /// # Safety
+    /// The task must be the running one, so that its VAS is loaded.
+    pub unsafe fn check_user_buf(
+        &mut self,
+        addr: usize,
+        len: usize,
+        write: bool,
+    ) -> bool {
+        if len == 0 || addr.checked_add(len).is_none() {
+            return false;
+        }
+        if !Region::from_start_len(addr, len).is_in(&USERMODE_REGION) {
+            return false;
+        }
+        // The pages are walked from the top down, so that a buffer on a fresh
+        // part of the stack takes the guard page one page at a time.
+        let first_page = addr & !0xFFF;
+        (first_page..addr + len).step_by(4096).rev().all(|page| {
+            let page = page as u32;
+            (self.process().vas.is_mapped(page)
+                || self.commit_lazy_page(page)
+                || self.grow_usermode_stack(page).is_ok())
+                && self.process().vas.is_user_accessible(page, write)
+        })
+    }
+
+    /// Grows the usermode stack by one page if `addr` is within the guard page
+    /// right below it, then moves the guard page down.
+    ///
+    /// # Safety
+    /// The task must be the running one, so that its VAS is loaded.
+    pub unsafe fn grow_usermode_stack(
+        &mut self,
+        addr: u32,
+    ) -> Result<(), StackGrowthErr> {
+        let page = addr & !0xFFF;
+        let process = self.process_mut();
+        let stack = process
+            .mem_mappings
+            .iter_mut()
+            .find(|mapping| mapping._type == MemMappingType::Stack)
+            .ok_or(StackGrowthErr::NotGuardPage)?;
+
+        let guard_page = stack.region.start as u32 - 4096;
+        if page != guard_page || !process.vas.is_guard_page(guard_page) {
+            return Err(StackGrowthErr::NotGuardPage);
+        }
+        if stack.region.len() + 4096 > USERMODE_STACK_MAX_SIZE {
+            return Err(StackGrowthErr::LimitReached);
+        }
+
+        process.vas.remove_guard_page(guard_page);
+        process.vas.reserve_pages(guard_page, guard_page + 4096);
+        if !process.vas.commit_reserved_page(guard_page) {
+            return Err(StackGrowthErr::OutOfMemory);
+        }
+        stack.region.start = guard_page as usize;
+
+        if stack.region.len() < USERMODE_STACK_MAX_SIZE {
+            let new_guard_page = guard_page - 4096;
+            process.vas.ensure_pgtbl(new_guard_page);
+            process.vas.place_guard_page(new_guard_page);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the address the task continues from when it is switched to.
+    /// Meaningless for the running task.
+    ///
+    /// # Safety
+    /// The kernel stack must have been set up for a task switch.
+    pub unsafe fn saved_eip(&self) -> u32 {
+        // See switch_tasks in task_manager.s for the stack layout.
+        let saved_regs = self.kernel_stack.top as *const u32;
+        *saved_regs.add(7)
     }
 
     /// Updates the task's control block and returns a raw pointer to it.
@@ -225,7 +810,7 @@ impl Task {
     /// was moved into one of the task manager's vectors.
     pub fn raw_tcb(&mut self) -> *const TaskControlBlock {
         self.tcb = TaskControlBlock {
-            pgdir_phys: self.vas.pgdir_phys,
+            pgdir_phys: self.process().vas.pgdir_phys,
             esp0: &self.kernel_stack.bottom as *const _ as *const u32,
             esp: &self.kernel_stack.top as *const _ as *mut u32,
         };
@@ -233,6 +818,85 @@ impl Task {
     }
 }
 
+impl Process {
+    /// Finds a page-aligned region of `len` bytes within [USERMODE_REGION]
+    /// that does not conflict with the program segments, memory mappings and
+    /// the usermode stack, returns `None` if there is no such region.
+    fn find_free_region(&self, len: usize) -> Option<Region<usize>> {
+        let mut candidate = Region {
+            start: USERMODE_REGION.start,
+            end: USERMODE_REGION.start,
+        };
+        while candidate.len() < len {
+            if candidate.conflicts_with(&USERMODE_STACK_LIMIT_REGION) {
+                candidate.start = USERMODE_STACK_LIMIT_REGION.end;
+                candidate.end = USERMODE_STACK_LIMIT_REGION.end;
+            }
+            for segment in &self.program_segments {
+                if candidate.conflicts_with(segment) {
+                    candidate.start = (segment.end + 0xFFF) & !0xFFF;
+                    candidate.end = (segment.end + 0xFFF) & !0xFFF;
+                }
+            }
+            for mapping in &self.mem_mappings {
+                if candidate.conflicts_with(&mapping.region) {
+                    candidate.start = (mapping.region.end + 0xFFF) & !0xFFF;
+                    candidate.end = (mapping.region.end + 0xFFF) & !0xFFF;
+                }
+            }
+            if candidate.end >= USERMODE_REGION.end {
+                return None;
+            }
+            candidate.end += 4096;
+        }
+        Some(candidate)
+    }
+
+    /// Checks that `region` does not conflict with the program segments,
+    /// memory mappings and the usermode stack.
+    fn is_region_free(&self, region: &Region<usize>) -> bool {
+        !region.conflicts_with(&USERMODE_STACK_LIMIT_REGION)
+            && !self
+                .program_segments
+                .iter()
+                .any(|segment| region.conflicts_with(segment))
+            && !self
+                .mem_mappings
+                .iter()
+                .any(|mapping| region.conflicts_with(&mapping.region))
+    }
+
+    /// Creates the page tables for the mapping and reserves its pages so that
+    /// they are allocated on demand.
+    unsafe fn reserve_mapping_pages(&self, mapping: &MemMapping) {
+        for four_mib_chunk in mapping
+            .region
+            .align_boundaries_at(4 * 1024 * 1024)
+            .range()
+            .step_by(4 * 1024 * 1024)
+        {
+            self.vas.ensure_pgtbl(four_mib_chunk as u32);
+        }
+
+        for four_kib_chunk in mapping
+            .region
+            .align_boundaries_at(4096)
+            .range()
+            .step_by(4096)
+        {
+            assert!(
+                !self.vas.is_mapped(four_kib_chunk as u32),
+                "page 0x{:08X} is already mapped to {:#X?}",
+                four_kib_chunk,
+                self.vas.virt_to_phys(four_kib_chunk as u32).unwrap(),
+            );
+        }
+
+        let pages = mapping.region.align_boundaries_at(4096);
+        self.vas.reserve_pages(pages.start as u32, pages.end as u32);
+    }
+}
+
 /// Packed C representation of [Task] for task switching.
 ///
 /// This representation is used by assembly code responsible for task switching.
@@ -258,6 +922,87 @@ impl Default for TaskControlBlock {
 #[derive(Clone)]
 pub struct MemMapping {
     pub region: Region<usize>,
+    pub _type: MemMappingType,
+    /// `Some` only for [MemMappingType::File] mappings.
+    pub backing: Option<FileBacking>,
+}
+
+/// File content mapped at the start of a memory mapping.
+#[derive(Clone)]
+pub struct FileBacking {
+    pub node: fs::Node,
+    /// Offset in the file corresponding to the start of the mapping.
+    pub offset: usize,
+    /// Number of bytes read from the file, the rest of the mapping is zeroed.
+    pub size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemMappingType {
+    /// Anonymous memory given by [Task::mem_map].
+    Anonymous,
+    /// File content given by [Task::mem_map_file].
+    File,
+    /// The program break heap (see [Task::set_program_break]).
+    Heap,
+    /// The usermode stack.
+    Stack,
+}
+
+#[derive(Debug)]
+pub enum MapErr {
+    /// The start is not page-aligned, the length is zero or the range is not
+    /// within [USERMODE_REGION].
+    InvalidArgs,
+    /// The range conflicts with another mapping.
+    Conflict,
+    /// There is no free range large enough.
+    NoSpace,
+}
+
+#[derive(Debug)]
+pub enum UnmapErr {
+    /// The start is not page-aligned, the length is zero or the range
+    /// overflows.
+    InvalidArgs,
+    /// The range is not within a single anonymous memory mapping.
+    NotMapped,
+}
+
+/// Exit status of a task whose new program could not be loaded by
+/// [Task::exec] after the old one had been discarded.
+const EXEC_FAILED_EXIT_STATUS: i32 = -4;
+
+#[derive(Debug)]
+pub enum StackGrowthErr {
+    /// The address is not within the guard page of the usermode stack.
+    NotGuardPage,
+    /// The stack has reached [USERMODE_STACK_MAX_SIZE].
+    LimitReached,
+    /// There is no free physical page for the stack.
+    OutOfMemory,
+}
+
+impl MemMappingType {
+    /// Checks if the pages of such a mapping are allocated on the first
+    /// access.
+    pub fn is_lazy(&self) -> bool {
+        match self {
+            MemMappingType::Anonymous
+            | MemMappingType::File
+            | MemMappingType::Heap
+            | MemMappingType::Stack => true,
+        }
+    }
+}
+
+extern "C" fn kernel_thread_entry(f: fn(usize), arg: usize) -> ! {
+    // Like default_entry_point, this is reached with the interrupts disabled.
+    unsafe {
+        asm!("sti");
+    }
+    f(arg);
+    task_manager::task_exit(0);
 }
 
 pub extern "C" fn default_entry_point() -> ! {
@@ -275,31 +1020,22 @@ pub extern "C" fn default_entry_point() -> ! {
 
         let this_task = TASK_MANAGER.this_task();
 
-        let argv = vec![CString::new("/bin/test-fork").unwrap()];
+        let init = cmdline::get("init").unwrap_or("/bin/test-fork");
+        let argv = vec![CString::new(init).unwrap()];
         let environ = Vec::new();
 
-        let elf = this_task.load_from_file("/bin/test-fork");
-        this_task.set_up_usermode_stack(&argv, &environ);
+        let elf = this_task.load_from_file(init);
+        this_task.set_up_usermode_stack(&argv, &environ).unwrap();
+
+        // Ctrl+C terminates the init program until it selects another
+        // foreground task.
+        console::with_console(|console| console.set_owner_task(this_task.id));
 
         TASK_MANAGER.keep_scheduling();
 
-        let gp_regs = GpRegs {
-            edi: 0,
-            esi: 0,
-            ebp: 0,
-            esp: this_task.usermode_stack.as_ref().unwrap().top as u32,
-            ebx: 0,
-            edx: 0,
-            ecx: 0,
-            eax: 0,
-        };
-        println!("[TASK] Entering usermode at 0x{:08X}.", elf.entry_point);
-        jump_into_usermode(
-            gdt::USERMODE_CODE_SEG,
-            gdt::USERMODE_DATA_SEG,
-            gdt::TLS_SEG,
-            elf.entry_point as u32,
-            &gp_regs as *const GpRegs,
-        );
+        let entry = elf.entry_point as u32;
+        drop(elf);
+        drop(argv);
+        this_task.enter_usermode(entry);
     }
 }
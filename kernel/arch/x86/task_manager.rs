@@ -20,6 +20,7 @@ use crate::arch::vas::KERNEL_VAS;
 use crate::task_manager::{NO_SCHED_COUNTER, TASK_MANAGER, TEMP_SPAWNER_ON};
 
 use crate::arch::gdt;
+use crate::arch::interrupts::InterruptStackFrame;
 use crate::arch::task::TaskControlBlock;
 use crate::task::Task;
 use crate::task_manager::TaskManager;
@@ -65,6 +66,25 @@ impl TaskManager {
     }
 }
 
+/// Terminates the running task if it has been killed and the interrupt is
+/// about to return to the usermode.
+///
+/// Called by the timer and keyboard IRQ handlers (see `interrupts.s`), so that
+/// a task that does not make any syscalls can be killed too.
+#[no_mangle]
+pub extern "C" fn terminate_if_killed(stack_frame: &InterruptStackFrame) {
+    // Tasks interrupted in the kernel mode terminate on their way back from
+    // the syscall.
+    if stack_frame.cs & 3 == 3 {
+        unsafe {
+            // The interrupted usermode code holds no kernel locks, so the
+            // terminated tasks can be reaped here too.
+            TASK_MANAGER.reap_terminated_tasks();
+            TASK_MANAGER.terminate_this_task_if_killed();
+        }
+    }
+}
+
 pub fn init() {
     let mut tss = unsafe { &mut gdt::TSS };
     tss.ss0 = gdt::KERNEL_DATA_SEG;
@@ -75,8 +95,8 @@ pub fn init() {
         // The init task is created with an empty kernel stack because it will
         // not switched to, it will be switched from, so its context will be
         // pushed, not popped on the next task switch.
-        let init_task =
-            Task::with_empty_stack(init_task_id, KERNEL_VAS.lock().clone());
+        let kvas = KERNEL_VAS.lock().clone();
+        let init_task = Task::with_empty_stack(init_task_id, kvas);
 
         // Load the GDT with the new entries.
         gdt::GDT.lock().load();
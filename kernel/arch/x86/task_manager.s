@@ -16,7 +16,7 @@
 
 /*
  * Passes execution from the current task to the specified one.  Updates the
- * ESP0 field in the specified Task State Segment.
+ * ESP0 field in the specified Task State Segment and the SYSENTER_ESP MSR.
  * Arguments: 1) from: *const TaskControlBlock
  *            2) to: *const TaskControlBlock
  *            3) tss: *mut TaskStateSegment
@@ -58,8 +58,16 @@ switch_tasks:
     // Update the ESP0 field in the TSS.
     movl %ecx, 4(%eax)
 
+    // And the SYSENTER_ESP MSR, which serves the same purpose for sysenter.
+    cmpb $0, SYSENTER_ENABLED
+    je 2f
+    movl %ecx, %eax
+    xorl %edx, %edx
+    movl $0x175, %ecx           // IA32_SYSENTER_ESP
+    wrmsr
+
     // Change the virtual address space if needed.
-    movl %cr3, %eax
+2:  movl %cr3, %eax
     cmpl %ebx, %eax
     je 1f
     movl %ebx, %cr3
@@ -0,0 +1,192 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Time Stamp Counter.
+//!
+//! The TSC rate is calibrated against the system timer at boot.  Without the
+//! invariant TSC, the rate may change with the CPU frequency, so the times
+//! are [approximate](is_approximate).  Without a TSC at all, the times come
+//! from the system timer and have its resolution.
+//!
+//! [profile_scope] logs the time spent in a scope if the kernel is booted
+//! with the `profile` flag.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::arch::interrupts;
+use crate::cmdline;
+use crate::dev::timer;
+
+/// Number of timer ticks the TSC is calibrated over.
+const CALIBRATION_TICKS: u64 = 5;
+
+const CPUID_FEATURES: u32 = 0x0000_0001;
+const CPUID_MAX_EXTENDED: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+
+const FEATURE_EDX_TSC: u32 = 1 << 4;
+const POWER_MANAGEMENT_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+/// TSC ticks per millisecond, 0 if the TSC is not used.
+static TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// Returns EAX, EBX, ECX and EDX of the CPUID leaf `leaf`.
+pub fn cpuid(leaf: u32) -> [u32; 4] {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        // EBX may be reserved by the compiler, so it is swapped with another
+        // register.
+        asm!(
+            "movl %ebx, {0}",
+            "cpuid",
+            "xchgl %ebx, {0}",
+            out(reg) ebx,
+            inout("eax") leaf => eax,
+            out("ecx") ecx,
+            out("edx") edx,
+            options(att_syntax),
+        );
+    }
+    [eax, ebx, ecx, edx]
+}
+
+fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!(
+            "rdtsc",
+            out("eax") low,
+            out("edx") high,
+            options(att_syntax, nomem, nostack),
+        );
+    }
+    (high as u64) << 32 | low as u64
+}
+
+fn has_tsc() -> bool {
+    cpuid(CPUID_FEATURES)[3] & FEATURE_EDX_TSC != 0
+}
+
+fn has_invariant_tsc() -> bool {
+    cpuid(CPUID_MAX_EXTENDED)[0] >= CPUID_POWER_MANAGEMENT
+        && cpuid(CPUID_POWER_MANAGEMENT)[3] & POWER_MANAGEMENT_EDX_INVARIANT_TSC
+            != 0
+}
+
+/// Measures the TSC rate.  The system timer must be running.
+pub fn init() {
+    PROFILING.store(cmdline::has_flag("profile"), Ordering::SeqCst);
+    if !has_tsc() {
+        log_warn!("[TSC] There is no TSC, using the system timer.");
+        return;
+    }
+
+    // Start right after a tick.
+    let start_tick = timer::ticks() + 1;
+    while timer::ticks() < start_tick {
+        interrupts::wait_for_interrupt();
+    }
+    let start_tsc = rdtsc();
+    while timer::ticks() < start_tick + CALIBRATION_TICKS {
+        interrupts::wait_for_interrupt();
+    }
+    let elapsed_tsc = rdtsc() - start_tsc;
+    let elapsed_ms = timer::ms_per_tick() * CALIBRATION_TICKS;
+
+    let ticks_per_ms = (elapsed_tsc / elapsed_ms) as u32;
+    let invariant = has_invariant_tsc();
+    TICKS_PER_MS.store(ticks_per_ms, Ordering::SeqCst);
+    INVARIANT.store(invariant, Ordering::SeqCst);
+    log_info!(
+        "[TSC] {} ticks per ms, {}.",
+        ticks_per_ms,
+        if invariant {
+            "invariant"
+        } else {
+            "not invariant"
+        },
+    );
+}
+
+/// Returns the number of nanoseconds since some point in the past.
+pub fn now_ns() -> u64 {
+    let ticks_per_ms = TICKS_PER_MS.load(Ordering::SeqCst) as u64;
+    if ticks_per_ms == 0 {
+        return timer::uptime_ms() * 1_000_000;
+    }
+    let tsc = rdtsc();
+    tsc / ticks_per_ms * 1_000_000
+        + tsc % ticks_per_ms * 1_000_000 / ticks_per_ms
+}
+
+/// Checks if the times returned by [now_ns] may be off, because the TSC rate
+/// is not constant or there is no TSC.
+pub fn is_approximate() -> bool {
+    TICKS_PER_MS.load(Ordering::SeqCst) == 0
+        || !INVARIANT.load(Ordering::SeqCst)
+}
+
+/// Checks if the kernel has been booted with the `profile` flag.
+pub fn is_profiling() -> bool {
+    PROFILING.load(Ordering::SeqCst)
+}
+
+/// Logs the time between its creation and its drop, see [profile_scope].
+pub struct ProfileScope {
+    name: &'static str,
+    start_ns: u64,
+}
+
+impl ProfileScope {
+    pub fn new(name: &'static str) -> Self {
+        ProfileScope {
+            name,
+            start_ns: now_ns(),
+        }
+    }
+}
+
+impl Drop for ProfileScope {
+    fn drop(&mut self) {
+        log_info!(
+            "[PROFILE] {}: {} ns{}.",
+            self.name,
+            now_ns() - self.start_ns,
+            if is_approximate() {
+                " (approximate)"
+            } else {
+                ""
+            },
+        );
+    }
+}
+
+/// Logs the time spent from this point to the end of the scope, if profiling
+/// is enabled with the `profile` flag.
+#[macro_export]
+macro_rules! profile_scope {
+    ($name:expr) => {
+        let _profile_scope = if $crate::arch::tsc::is_profiling() {
+            Some($crate::arch::tsc::ProfileScope::new($name))
+        } else {
+            None
+        };
+    };
+}
+
+pub use crate::profile_scope;
@@ -14,14 +14,16 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use alloc::alloc::{alloc, Layout};
-use core::mem::align_of;
+use alloc::alloc::{alloc, dealloc, Layout};
 use core::ptr;
 
-use crate::arch::pmm_stack::PMM_STACK;
+use crate::arch::pmm_stack::{self, PMM_STACK};
+use crate::task_manager::{self, TASK_MANAGER};
 use crate::KERNEL_INFO;
 
-use crate::arch::interrupts::InterruptStackFrame;
+use crate::arch::interrupts::{self, InterruptStackFrame};
+use crate::arch::syscall::GpRegs;
+use crate::arch::task::StackGrowthErr;
 use crate::kernel_static::Mutex;
 use crate::memory_region::Region;
 
@@ -54,231 +56,301 @@ bitflags_new! {
         // OS-specific:
         const GUARD_PAGE = 1 << 9;
         const WAS_PRESENT = 1 << 10;
+        const RESERVED_ANON = 1 << 11;        // not present, allocated on demand
     }
 }
 
-// It is the user's obligation to ensure that the VAS is consistent, meaning
-// that the PDEs and PT pointers point to the same PTs.  Otherwise it is
-// undefined behavior.
+/// A virtual address space, i.e. a page directory and its page tables.
+///
+/// The last PDE of every page directory points to the directory itself, so the
+/// page tables of the loaded VAS are accessible at [PGTBLS_WINDOW].  The page
+/// tables of a VAS that is not loaded are accessed through the foreign slot of
+/// the loaded page directory (see [set_foreign_slot]).  Page tables are frames
+/// taken from the [PMM stack](static@super::pmm_stack::PMM_STACK), except for
+/// those that are a part of the kernel image.
 #[derive(Clone)]
 pub struct VirtAddrSpace {
     pgdir_virt: *mut Directory, // relative to the kernel VAS
     pub pgdir_phys: u32,
 
-    pgtbls_virt: *mut *mut Table, // same
-    pgtbls_phys: *mut u32,
-
     usermode: bool,
 }
 
 impl VirtAddrSpace {
-    pub unsafe fn new_identity_mapped(
+    /// Creates a VAS with the first `num_pdes` 4 MiB chunks of physical memory
+    /// mapped at [KERNEL_VIRT_BASE] with 4 MiB pages.
+    ///
+    /// `pgdir` must be a part of the kernel image.  CR4.PSE must be set before
+    /// the VAS is loaded.
+    pub unsafe fn new_higher_half(
         pgdir: &mut Directory,
-        pgtbls: &mut [Table],
-        pgtbls_ptrs: (*mut *mut Table, *mut u32),
+        num_pdes: usize,
     ) -> Self {
-        for i in 0..pgtbls.len() {
-            for j in 0..pgtbls[i].0.len() {
-                let entry = &mut pgtbls[i].0[j];
-                entry.set_addr((i << 22 | j << 12) as u32);
-                entry.insert(TableEntry::PRESENT);
-                entry.insert(TableEntry::READ_WRITE);
-                entry.insert(TableEntry::ANY_DPL);
-            }
-
-            pgdir.0[i].set_addr(&pgtbls[i] as *const _ as u32);
-            pgdir.0[i].insert(DirEntry::PRESENT);
-            pgdir.0[i].insert(DirEntry::READ_WRITE);
-            pgdir.0[i].insert(DirEntry::ANY_DPL);
-
-            *pgtbls_ptrs.0.add(i) = &mut pgtbls[i] as *mut Table;
-            *pgtbls_ptrs.1.add(i) = &pgtbls[i] as *const _ as u32;
-        }
-
-        VirtAddrSpace {
+        let vas = VirtAddrSpace {
             pgdir_virt: pgdir as *mut Directory,
-            pgdir_phys: pgdir as *const _ as u32,
-
-            pgtbls_virt: pgtbls_ptrs.0,
-            pgtbls_phys: pgtbls_ptrs.1,
+            pgdir_phys: (pgdir as *const _ as usize - KERNEL_VIRT_BASE) as u32,
 
             usermode: false,
+        };
+        vas.set_recursive_pde();
+        for i in 0..num_pdes {
+            let phys = (i << 22) as u32;
+            vas.map_large_page(KERNEL_VIRT_BASE as u32 + phys, phys);
         }
+        vas
     }
 
     pub unsafe fn kvas_copy_on_heap() -> Self {
+        // Allocate space on the heap.  This is done before locking the kernel
+        // VAS, since the heap may need to grow.
+        let heap_pgdir = alloc(Layout::from_size_align(4096, 4096).unwrap());
+        ptr::write_bytes(heap_pgdir, 0, 4096);
+
         // This should be used only in the kernel VAS because it uses the kernel
         // PD to translate virtual addresses (of heap allocations) to physical
         // ones.
         let kvas = KERNEL_VAS.lock();
 
-        // Allocate space on the heap.
-        let heap_pgdir = alloc(Layout::from_size_align(4096, 4096).unwrap());
-        let heap_pgtbls_virt = alloc(
-            Layout::from_size_align(4096, align_of::<*mut Table>()).unwrap(),
-        );
-        let heap_pgtbls_phys = alloc(
-            Layout::from_size_align(4096, align_of::<*mut Table>()).unwrap(),
-        );
-        ptr::write_bytes(heap_pgdir, 0, 4096);
-        ptr::write_bytes(heap_pgtbls_virt, 0, 4096);
-        ptr::write_bytes(heap_pgtbls_phys, 0, 4096);
-
         let vas = VirtAddrSpace {
             pgdir_virt: heap_pgdir as *mut Directory,
             pgdir_phys: (*kvas).virt_to_phys(heap_pgdir as u32).unwrap(),
 
-            pgtbls_virt: heap_pgtbls_virt as *mut *mut Table,
-            pgtbls_phys: heap_pgtbls_phys as *mut u32,
-
             usermode: true,
         };
-
-        // Copy the kernel VAS.
-        let pgdir = (*kvas).pgdir_virt.as_mut().unwrap();
-        for i in 0..1024 {
-            let pde = &pgdir.0[i];
-            if pde.contains(DirEntry::PRESENT) {
-                // Copy the corresponding page table.
-                let src = pde.addr() as *mut u8;
-                let dest = alloc(Layout::from_size_align(4096, 4096).unwrap());
-                ptr::copy_nonoverlapping(src, dest, 4096);
-                *vas.pgtbls_virt.add(i) = dest as *mut Table;
-                *vas.pgtbls_phys.add(i) =
-                    (*kvas).virt_to_phys(dest as u32).unwrap();
-
-                // Change the flags of all PTEs.
-                let pgtbl = (*vas.pgtbls_virt.add(i)).as_mut().unwrap();
-                for j in 0..1024 {
-                    if pgtbl.0[j].contains(TableEntry::PRESENT) {
-                        pgtbl.0[j] = TableEntry::with_addr(pgtbl.0[j].addr());
-                        pgtbl.0[j].insert(TableEntry::PRESENT);
-                        pgtbl.0[j].insert(TableEntry::READ_WRITE);
-                        pgtbl.0[j].insert(TableEntry::ANY_DPL);
-                    }
-                }
-
-                // Set the PDE.
-                let pgdir = vas.pgdir_virt.as_mut().unwrap();
-                pgdir.0[i].set_addr(*vas.pgtbls_phys.add(i));
-                pgdir.0[i].insert(DirEntry::PRESENT);
-                pgdir.0[i].insert(DirEntry::READ_WRITE);
-                pgdir.0[i].insert(DirEntry::ANY_DPL);
+        vas.set_recursive_pde();
+
+        // Share the kernel page tables and 4 MiB pages.  Nothing else is
+        // mapped in the kernel VAS.
+        let pgdir = (*kvas).pgdir_virt.as_ref().unwrap();
+        let new_pgdir = vas.pgdir_virt.as_mut().unwrap();
+        for i in 0..FOREIGN_PDE_IDX {
+            if is_kernel_pde(i) {
+                new_pgdir.0[i] = pgdir.0[i];
+            } else {
+                assert!(
+                    !pgdir.0[i].contains(DirEntry::PRESENT),
+                    "the kernel VAS has a mapping outside the kernel region",
+                );
             }
         }
 
         vas
     }
 
-    pub unsafe fn copy(&self) -> Self {
+    /// Copies the VAS, the memory which is not shared with the kernel VAS is
+    /// copied to new frames.  Returns `None` if there are not enough frames,
+    /// the frames taken so far are freed then.
+    ///
+    /// # Panics
+    /// This method panics if the VAS is not the loaded one.
+    pub unsafe fn copy(&self) -> Option<Self> {
+        assert!(self.is_loaded(), "only the loaded VAS can be copied");
+
         let new_pgdir_virt = alloc(Layout::from_size_align(4096, 4096).unwrap())
             as *mut Directory;
         let new_pgdir_phys = self.virt_to_phys(new_pgdir_virt as u32).unwrap();
+        new_pgdir_virt.write_bytes(0, 1);
 
         let new_vas = VirtAddrSpace {
             pgdir_virt: new_pgdir_virt,
             pgdir_phys: new_pgdir_phys,
 
-            pgtbls_virt: alloc(Layout::from_size_align(4096, 4096).unwrap())
-                .cast(),
-            pgtbls_phys: alloc(Layout::from_size_align(4096, 4096).unwrap())
-                .cast(),
-
             usermode: self.usermode,
         };
-
-        new_vas.pgdir_virt.write_bytes(0, 1);
-        new_vas.pgtbls_virt.write_bytes(0, 1024);
-        new_vas.pgtbls_phys.write_bytes(0, 1024);
+        new_vas.set_recursive_pde();
 
         let pgdir = self.pgdir_virt.as_ref().unwrap();
         let new_pgdir = new_pgdir_virt.as_mut().unwrap();
 
-        // Allocate a page on the heap and use it for copying physical pages
-        // from one VAS to another.  FIXME: this is dirty and slow.
-        let copying_virt =
-            alloc(Layout::from_size_align(4096, 4096).unwrap()) as u32;
-        let initial_mapping = self.pgtbl_entry(copying_virt).addr();
-
-        for (pde_idx, pde) in
-            self.pgdir_virt.as_ref().unwrap().0.iter().enumerate()
-        {
-            // println!("pde_idx = {}", pde_idx);
-            // println!("pde = 0x{:08X}", pde as *const _ as u32);
-
-            if pde.contains(DirEntry::PRESENT) {
-                // println!("- is PRESENT");
+        // Allocate a window on the heap to map the new frames into, so that
+        // the pages are copied from one VAS to another in batches.
+        let window_layout =
+            Layout::from_size_align(COPY_WINDOW_PAGES * 4096, 4096).unwrap();
+        let window_virt = alloc(window_layout) as u32;
+        let mut window_frames = [0; COPY_WINDOW_PAGES];
+        for (i, frame) in window_frames.iter_mut().enumerate() {
+            *frame = self.pgtbl_entry(window_virt + (i << 12) as u32).addr();
+        }
 
-                let pgtbl_virt = self.pgtbl_virt_of((pde_idx as u32) << 22);
-                let new_pgtbl_virt =
-                    alloc(Layout::from_size_align(4096, 4096).unwrap())
-                        as *mut Table;
-                new_pgtbl_virt.write_bytes(0, 1);
+        // Pairs of a virtual address to copy from and a frame to copy to.
+        let mut batch = [(0, 0); COPY_WINDOW_PAGES];
+        let mut batch_len = 0;
+        let mut out_of_memory = false;
 
-                let pgtbl = pgtbl_virt.as_ref().unwrap();
-                let new_pgtbl = new_pgtbl_virt.as_mut().unwrap();
+        'pdes: for (pde_idx, pde) in
+            pgdir.0.iter().enumerate().take(FOREIGN_PDE_IDX)
+        {
+            let virt = (pde_idx as u32) << 22;
 
+            if is_kernel_pde(pde_idx) {
+                // The kernel page tables are shared.
                 new_pgdir.0[pde_idx] = pgdir.0[pde_idx];
-                new_vas.set_pde_phys_virt(
-                    pde_idx,
-                    KERNEL_VAS
-                        .lock()
-                        .virt_to_phys(new_pgtbl_virt as u32)
-                        .unwrap(),
-                    new_pgtbl_virt,
-                );
+            } else if pde.contains(DirEntry::PAGE_SIZE_IS_4_MIB) {
+                unreachable!("4 MiB pages are used only in the kernel region");
+            } else if pde.contains(DirEntry::PRESENT) {
+                new_pgdir.0[pde_idx] = pgdir.0[pde_idx];
+                if !new_vas.try_new_pgtbl(pde_idx) {
+                    // Do not let the new VAS share this VAS's page table.
+                    new_pgdir.0[pde_idx] = DirEntry::empty();
+                    out_of_memory = true;
+                    break 'pdes;
+                }
 
-                for (pte_idx, pte) in pgtbl.0.iter().enumerate() {
-                    // println!(" - pte_idx = {}", pte_idx);
-                    // println!(" - pte = 0x{:08X}", pte as *const _ as u32);
-                    if pte.contains(TableEntry::PRESENT) {
-                        let copy_from =
-                            ((pde_idx << 22) | (pte_idx << 12)) as u32;
-
-                        let acpi_region = KERNEL_INFO
-                            .arch
-                            .hpet_region
-                            .unwrap_or(Region { start: 0, end: 0 });
-
-                        // If this page is within the kernel or ACPI region,
-                        // retain the mapping so that the kernel and ACPI memory
-                        // are mapped the same way across different VASes.
-                        if KERNEL_REGION.contains(&(copy_from as usize))
-                            || acpi_region.contains(&(copy_from as usize))
+                // This VAS is loaded, so the foreign slot is used only by the
+                // new one.  It must not be repointed while the page table is
+                // copied.
+                let copied = interrupts::with_disabled(|| {
+                    let pgtbl = self.pgtbl_virt_of(virt).as_ref().unwrap();
+                    let new_pgtbl =
+                        new_vas.pgtbl_virt_of(virt).as_mut().unwrap();
+
+                    for (pte_idx, pte) in pgtbl.0.iter().enumerate() {
+                        if pte.contains(TableEntry::RESERVED_ANON)
+                            || (pte.contains(TableEntry::GUARD_PAGE)
+                                && !pte.contains(TableEntry::PRESENT))
                         {
+                            // Either the page has not been accessed yet or it
+                            // is a guard page, so there is nothing to copy.
+                            new_pgtbl.0[pte_idx] = pgtbl.0[pte_idx];
+                        } else if pte.contains(TableEntry::PRESENT) {
+                            // Allocate a new physical page and copy the
+                            // original page contents into it when the batch
+                            // is full.
+                            let phys = match PMM_STACK.lock().pop_page() {
+                                Some(phys) => phys,
+                                None => return false,
+                            };
+
                             new_pgtbl.0[pte_idx] = pgtbl.0[pte_idx];
-                            continue;
+                            new_pgtbl.0[pte_idx].set_addr(phys);
+
+                            let copy_from = virt | (pte_idx << 12) as u32;
+                            batch[batch_len] = (copy_from, phys);
+                            batch_len += 1;
+                            if batch_len == COPY_WINDOW_PAGES {
+                                self.copy_via_window(window_virt, &batch);
+                                batch_len = 0;
+                            }
                         }
+                    }
+                    true
+                });
+                if !copied {
+                    out_of_memory = true;
+                    break 'pdes;
+                }
+            }
+        }
+        if !out_of_memory {
+            self.copy_via_window(window_virt, &batch[..batch_len]);
+        }
 
-                        // Otherwise, allocate a new physical page and copy the
-                        // original page contents into it via `copying_virt'.
+        // Restore the original mappings of the window.
+        for (i, &frame) in window_frames.iter().enumerate() {
+            self.pgtbl_entry(window_virt + (i << 12) as u32)
+                .set_addr(frame);
+        }
+        flush_tlb();
+        dealloc(window_virt as *mut u8, window_layout);
+
+        if out_of_memory {
+            println!("[VAS] Out of physical memory while copying a VAS.");
+            let mut new_vas = new_vas;
+            new_vas.destroy();
+            return None;
+        }
+        Some(new_vas)
+    }
 
-                        let phys = PMM_STACK.lock().pop_page();
+    /// Copies the pages at the virtual addresses in `batch` to the paired
+    /// frames by mapping the frames into the window at `window_virt`.
+    ///
+    /// The window must be at least as long as the batch.
+    unsafe fn copy_via_window(&self, window_virt: u32, batch: &[(u32, u32)]) {
+        for (i, &(_, phys)) in batch.iter().enumerate() {
+            self.pgtbl_entry(window_virt + (i << 12) as u32)
+                .set_addr(phys);
+        }
+        flush_tlb();
+
+        for (i, &(copy_from, _)) in batch.iter().enumerate() {
+            let copy_to = window_virt + (i << 12) as u32;
+            assert_ne!(copy_from, copy_to);
+            ptr::copy_nonoverlapping(
+                copy_from as *const u8,
+                copy_to as *mut u8,
+                4096,
+            );
+        }
+    }
 
-                        new_pgtbl.0[pte_idx] = pgtbl.0[pte_idx];
-                        new_pgtbl.0[pte_idx].set_addr(phys);
+    /// Frees all the memory used by a usermode VAS.
+    ///
+    /// The references to the frames mapped outside the regions shared with the
+    /// kernel VAS and to the page tables are [dropped](pmm_stack::unref_frame),
+    /// then the page directory is deallocated.
+    ///
+    /// # Safety
+    /// The VAS must not be loaded and must not be used after this call.  Its
+    /// page directory must have been allocated on the heap, which is the case
+    /// for the VASes made by [kvas_copy_on_heap](Self::kvas_copy_on_heap) and
+    /// [copy](Self::copy).
+    ///
+    /// # Panics
+    /// This method panics if called on a non-usermode VAS, e.g. the kernel one.
+    pub unsafe fn destroy(&mut self) {
+        assert!(self.usermode, "cannot destroy a non-usermode VAS");
+        assert_ne!(
+            self.pgdir_phys,
+            KERNEL_VAS.lock().pgdir_phys,
+            "cannot destroy the kernel VAS",
+        );
+        assert!(!self.is_loaded(), "cannot destroy the loaded VAS");
 
-                        self.pgtbl_entry(copying_virt).set_addr(phys);
-                        self.invalidate_cache(copying_virt);
+        let mut num_freed = 0;
 
-                        assert_ne!(copy_from, copying_virt);
+        interrupts::with_disabled(|| {
+            for pde_idx in 0..FOREIGN_PDE_IDX {
+                let pgtbl_virt = self.pgtbl_virt_of((pde_idx as u32) << 22);
+                if pgtbl_virt.is_null() || is_kernel_pde(pde_idx) {
+                    // The kernel page tables are shared.
+                    continue;
+                }
 
-                        // print!("Copying from 0x{:08X} to 0x{:08X}... ", copy_from, copying_virt);
-                        ptr::copy_nonoverlapping(
-                            copy_from as *const u8,
-                            copying_virt as *mut u8,
-                            4096,
-                        );
-                        // println!("done");
+                for (pte_idx, pte) in (*pgtbl_virt).0.iter().enumerate() {
+                    let virt = ((pde_idx << 22) | (pte_idx << 12)) as u32;
+                    if pte.contains(TableEntry::PRESENT)
+                        && !is_shared_page(virt)
+                        && !is_reserved_frame(pte.addr())
+                        && pmm_stack::unref_frame(pte.addr())
+                    {
+                        num_freed += 1;
                     }
                 }
+
+                let pgtbl_phys =
+                    self.pgdir_virt.as_ref().unwrap().0[pde_idx].addr();
+                pmm_stack::unref_frame(pgtbl_phys);
             }
-        }
 
-        // Restore the original mapping of the copying page.
-        self.pgtbl_entry(copying_virt).set_addr(initial_mapping);
+            // The foreign slot must not point to the freed page directory.
+            clear_foreign_slot();
+        });
+        dealloc(
+            self.pgdir_virt as *mut u8,
+            Layout::from_size_align(4096, 4096).unwrap(),
+        );
+
+        self.pgdir_virt = ptr::null_mut();
+        self.pgdir_phys = 0;
+
+        println!("[VAS] Destroyed a VAS, freed {} pages.", num_freed);
+    }
 
-        new_vas
+    /// Checks if the VAS can have usermode mappings, which is not the case for
+    /// the kernel VAS and its clones.
+    pub fn is_usermode(&self) -> bool {
+        self.usermode
     }
 
     pub unsafe fn load(&self) {
@@ -289,65 +361,407 @@ impl VirtAddrSpace {
         assert_eq!(virt & 0xFFF, 0, "virt must be page-aligned");
         assert_eq!(phys & 0xFFF, 0, "phys must be page-aligned");
 
-        let entry = self.pgtbl_entry(virt);
-        entry.set_addr(phys);
-        entry.insert(TableEntry::PRESENT);
-        entry.insert(TableEntry::READ_WRITE);
-        if self.usermode {
-            entry.insert(TableEntry::ANY_DPL);
+        interrupts::with_disabled(|| {
+            let entry = self.pgtbl_entry(virt);
+            entry.set_addr(phys);
+            entry.insert(TableEntry::PRESENT);
+            entry.insert(TableEntry::READ_WRITE);
+            if self.usermode {
+                entry.insert(TableEntry::ANY_DPL);
+            }
+
+            self.invalidate_cache(virt);
+        })
+    }
+
+    /// Maps the 4 MiB chunk containing `virt` to the 4 MiB physical frame at
+    /// `phys` with a single page directory entry.
+    ///
+    /// The page table previously used for the chunk, if any, is not freed.
+    pub unsafe fn map_large_page(&self, virt: u32, phys: u32) {
+        assert_eq!(virt & 0x3FFFFF, 0, "virt must be 4 MiB-aligned");
+        assert_eq!(phys & 0x3FFFFF, 0, "phys must be 4 MiB-aligned");
+
+        let pde_idx = (virt >> 22) as usize;
+        assert!(
+            !self.usermode || !is_kernel_pde(pde_idx),
+            "cannot change a kernel PDE of a usermode VAS",
+        );
+        let pde = &mut self.pgdir_virt.as_mut().unwrap().0[pde_idx];
+        *pde = DirEntry::with_addr(phys);
+        pde.insert(DirEntry::PRESENT);
+        pde.insert(DirEntry::READ_WRITE);
+        pde.insert(DirEntry::ANY_DPL);
+        pde.insert(DirEntry::PAGE_SIZE_IS_4_MIB);
+
+        invalidate_pgtbl_windows(pde_idx);
+        self.invalidate_large_page(virt);
+    }
+
+    /// Checks if the 4 MiB chunk containing `virt` is mapped with a 4 MiB page.
+    pub fn is_large_page(&self, virt: u32) -> bool {
+        let pde_idx = (virt >> 22) as usize;
+        let pde = unsafe { &self.pgdir_virt.as_ref().unwrap().0[pde_idx] };
+        pde.contains(DirEntry::PRESENT | DirEntry::PAGE_SIZE_IS_4_MIB)
+    }
+
+    /// Replaces the 4 MiB page containing `virt` with a new page table mapping
+    /// the same frames with 4 KiB pages.
+    ///
+    /// This is needed to change the flags of individual pages, e.g. to place a
+    /// guard page.
+    pub unsafe fn split_large_page(&self, virt: u32) {
+        assert!(self.is_large_page(virt), "not a 4 MiB page");
+
+        interrupts::with_disabled(|| {
+            let pde_idx = (virt >> 22) as usize;
+            let pde = self.pgdir_virt.as_ref().unwrap().0[pde_idx];
+            let frame = pde.addr() & !0x3FFFFF;
+
+            // Fill the page table before it replaces the 4 MiB page, the chunk
+            // may be in use, e.g. contain this code.
+            let pgtbl_phys =
+                PMM_STACK.lock().pop_page().expect("out of physical memory");
+            set_foreign_slot(pgtbl_phys);
+            let pgtbl = (SCRATCH_PAGE as *mut Table).as_mut().unwrap();
+            for (pte_idx, pte) in pgtbl.0.iter_mut().enumerate() {
+                *pte = TableEntry::with_addr(frame | (pte_idx << 12) as u32);
+                pte.insert(TableEntry::PRESENT);
+                if pde.contains(DirEntry::READ_WRITE) {
+                    pte.insert(TableEntry::READ_WRITE);
+                }
+                if pde.contains(DirEntry::ANY_DPL) {
+                    pte.insert(TableEntry::ANY_DPL);
+                }
+            }
+
+            self.set_pde_phys(pde_idx, pgtbl_phys);
+            self.invalidate_large_page(virt & !0x3FFFFF);
+        })
+    }
+
+    /// Checks if there is a guard page at `virt`.
+    pub fn is_guard_page(&self, virt: u32) -> bool {
+        interrupts::with_disabled(|| unsafe {
+            !self.pgtbl_virt_of(virt).is_null()
+                && self.pgtbl_entry(virt).contains(TableEntry::GUARD_PAGE)
+        })
+    }
+
+    /// Allocates an empty page table for the 4 MiB chunk containing `virt`
+    /// unless there is one already.
+    ///
+    /// A 4 MiB page is [split](Self::split_large_page) into a new page table.
+    pub unsafe fn ensure_pgtbl(&self, virt: u32) {
+        interrupts::with_disabled(|| {
+            if self.is_large_page(virt) {
+                self.split_large_page(virt);
+            } else if self.pgtbl_virt_of(virt).is_null() {
+                self.new_pgtbl((virt >> 22) as usize);
+            }
+        })
+    }
+
+    /// Allocates empty page tables for the kernel region where there are
+    /// neither page tables nor 4 MiB pages.
+    ///
+    /// This must be called on the kernel VAS before any usermode VAS is
+    /// created, so that all of them share the same kernel page tables.
+    pub unsafe fn ensure_kernel_pgtbls(&self) {
+        assert!(!self.usermode, "cannot be called on a usermode VAS");
+        let pgdir = self.pgdir_virt.as_ref().unwrap();
+        for pde_idx in 0..FOREIGN_PDE_IDX {
+            if is_kernel_pde(pde_idx)
+                && !pgdir.0[pde_idx].contains(DirEntry::PRESENT)
+            {
+                self.new_pgtbl(pde_idx);
+            }
         }
+    }
 
-        self.invalidate_cache(virt);
+    /// Sets up the page directory entry with the specified index with a zeroed
+    /// page table from the [PMM stack](static@super::pmm_stack::PMM_STACK).
+    unsafe fn new_pgtbl(&self, pde_idx: usize) {
+        if !self.try_new_pgtbl(pde_idx) {
+            panic!("out of physical memory");
+        }
+    }
+
+    /// Like [new_pgtbl](Self::new_pgtbl), but returns `false` if there is no
+    /// free frame for the page table.
+    unsafe fn try_new_pgtbl(&self, pde_idx: usize) -> bool {
+        let pgtbl_phys = match PMM_STACK.lock().pop_page() {
+            Some(phys) => phys,
+            None => return false,
+        };
+        interrupts::with_disabled(|| {
+            set_foreign_slot(pgtbl_phys);
+            (SCRATCH_PAGE as *mut u8).write_bytes(0, 4096);
+        });
+        self.set_pde_phys(pde_idx, pgtbl_phys);
+        true
     }
 
     pub fn is_mapped(&self, virt: u32) -> bool {
         unsafe { self.virt_to_phys(virt).is_some() }
     }
 
+    /// Checks if the page at `virt` is mapped and accessible by the usermode
+    /// code, and writable if `write` is `true`.
+    ///
+    /// CR0.WP is not set, so the kernel is not stopped by the read-only pages
+    /// and must check this before writing on behalf of the usermode.
+    pub unsafe fn is_user_accessible(&self, virt: u32, write: bool) -> bool {
+        interrupts::with_disabled(|| {
+            if self.is_large_page(virt) || self.pgtbl_virt_of(virt).is_null() {
+                return false;
+            }
+            let entry = self.pgtbl_entry(virt);
+            entry.contains(TableEntry::PRESENT | TableEntry::ANY_DPL)
+                && (!write || entry.contains(TableEntry::READ_WRITE))
+        })
+    }
+
     /// Maps the specified region to pages given by the [PMM
     /// stack](static@super::pmm_stack::PMM_STACK).
-    pub unsafe fn allocate_pages_from_stack(&self, start: u32, end: u32) {
+    ///
+    /// If the stack runs out of pages, the pages mapped so far are freed.
+    pub unsafe fn allocate_pages_from_stack(
+        &self,
+        start: u32,
+        end: u32,
+    ) -> Result<(), AllocPagesErr> {
         assert_eq!(start & 0xFFF, 0, "start must be page-aligned");
         assert_eq!(end & 0xFFF, 0, "end must be page-aligned");
         for virt in (start..end).step_by(4096) {
             let phys = PMM_STACK.lock().pop_page();
-            self.map_page(virt, phys);
+            match phys {
+                Some(phys) => self.map_page(virt, phys),
+                None => {
+                    self.free_pages_to_stack(start, virt);
+                    return Err(AllocPagesErr::OutOfMemory);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps the page at `virt` and returns the physical address it was mapped
+    /// to, or `None` if it was not mapped.
+    ///
+    /// A [reserved](Self::reserve_pages) page is unreserved.  The frame is not
+    /// returned to the PMM stack, see
+    /// [free_pages_to_stack](Self::free_pages_to_stack) for that.
+    pub unsafe fn unmap_page(&self, virt: u32) -> Option<u32> {
+        assert_eq!(virt & 0xFFF, 0, "virt must be page-aligned");
+
+        interrupts::with_disabled(|| {
+            if self.pgtbl_virt_of(virt).is_null() {
+                return None;
+            }
+
+            let entry = self.pgtbl_entry(virt);
+            if !entry.contains(TableEntry::PRESENT) {
+                if entry.contains(TableEntry::RESERVED_ANON) {
+                    *entry = TableEntry::empty();
+                }
+                return None;
+            }
+            let phys = entry.addr();
+            *entry = TableEntry::empty();
+
+            self.invalidate_cache(virt);
+            Some(phys)
+        })
+    }
+
+    /// Unmaps the specified region and [drops](pmm_stack::unref_frame) the
+    /// references to its frames, so that the unreferenced ones are returned to
+    /// the [PMM stack](static@super::pmm_stack::PMM_STACK).
+    ///
+    /// This is the inverse of
+    /// [allocate_pages_from_stack](Self::allocate_pages_from_stack).  The pages
+    /// that are not mapped are skipped.  The frames of the kernel image and
+    /// ACPI are never returned to the stack.
+    ///
+    /// # Panics
+    /// This method panics if this is a usermode VAS and the region touches the
+    /// pages shared with the kernel VAS.
+    pub unsafe fn free_pages_to_stack(&self, start: u32, end: u32) {
+        assert_eq!(start & 0xFFF, 0, "start must be page-aligned");
+        assert_eq!(end & 0xFFF, 0, "end must be page-aligned");
+        for virt in (start..end).step_by(4096) {
+            assert!(
+                !self.usermode || !is_shared_page(virt),
+                "cannot free a page shared with the kernel VAS",
+            );
+            if let Some(phys) = self.unmap_page(virt) {
+                if !is_reserved_frame(phys) {
+                    pmm_stack::unref_frame(phys);
+                }
+            }
         }
     }
 
+    /// Reserves the specified region for anonymous memory without mapping it.
+    ///
+    /// The pages are backed by zeroed frames from the [PMM
+    /// stack](static@super::pmm_stack::PMM_STACK) when first accessed, see
+    /// [commit_reserved_page](Self::commit_reserved_page).  The page tables for
+    /// the region must exist.
+    pub unsafe fn reserve_pages(&self, start: u32, end: u32) {
+        assert_eq!(start & 0xFFF, 0, "start must be page-aligned");
+        assert_eq!(end & 0xFFF, 0, "end must be page-aligned");
+
+        interrupts::with_disabled(|| {
+            for virt in (start..end).step_by(4096) {
+                let entry = self.pgtbl_entry(virt);
+                assert!(
+                    !entry.contains(TableEntry::PRESENT),
+                    "page 0x{:08X} is already mapped",
+                    virt,
+                );
+                *entry = TableEntry::RESERVED_ANON | TableEntry::READ_WRITE;
+                if self.usermode {
+                    entry.insert(TableEntry::ANY_DPL);
+                }
+            }
+        })
+    }
+
+    /// Maps a zeroed frame at `virt` if the page is
+    /// [reserved](Self::reserve_pages), returns `false` if it is not or if
+    /// there is no free frame.
+    ///
+    /// # Safety
+    /// The VAS must be the loaded one, since the page is zeroed through `virt`.
+    pub unsafe fn commit_reserved_page(&self, virt: u32) -> bool {
+        assert_eq!(virt & 0xFFF, 0, "virt must be page-aligned");
+
+        interrupts::with_disabled(|| {
+            if self.pgtbl_virt_of(virt).is_null() {
+                return false;
+            }
+
+            let entry = self.pgtbl_entry(virt);
+            if !entry.contains(TableEntry::RESERVED_ANON)
+                || entry.contains(TableEntry::PRESENT)
+            {
+                return false;
+            }
+            // Keep the protection flags, they may have been changed by
+            // set_protection().
+            let phys = match PMM_STACK.lock().pop_page() {
+                Some(phys) => phys,
+                None => {
+                    println!(
+                        "[VAS] Out of physical memory, cannot commit page \
+                         0x{:08X}.",
+                        virt,
+                    );
+                    return false;
+                }
+            };
+            entry.remove(TableEntry::RESERVED_ANON);
+            entry.set_addr(phys);
+            entry.insert(TableEntry::PRESENT);
+            self.invalidate_cache(virt);
+
+            // CR0.WP is not set, so the kernel can write to read-only pages.
+            ptr::write_bytes(virt as *mut u8, 0, 4096);
+            true
+        })
+    }
+
+    /// Changes the access rights of the pages in the specified region.
+    ///
+    /// Both mapped and [reserved](Self::reserve_pages) pages are accepted.  If
+    /// `user` is `false`, the pages are accessible only by the kernel.
+    ///
+    /// Nothing is changed if an error is returned.
+    pub unsafe fn set_protection(
+        &self,
+        start: u32,
+        end: u32,
+        writable: bool,
+        user: bool,
+    ) -> Result<(), SetProtectionErr> {
+        assert_eq!(start & 0xFFF, 0, "start must be page-aligned");
+        assert_eq!(end & 0xFFF, 0, "end must be page-aligned");
+
+        interrupts::with_disabled(|| {
+            for virt in (start..end).step_by(4096) {
+                if self.pgtbl_virt_of(virt).is_null() {
+                    return Err(SetProtectionErr::NotMapped(virt));
+                }
+                let entry = self.pgtbl_entry(virt);
+                if !entry.contains(TableEntry::PRESENT)
+                    && !entry.contains(TableEntry::RESERVED_ANON)
+                {
+                    return Err(SetProtectionErr::NotMapped(virt));
+                }
+            }
+
+            for virt in (start..end).step_by(4096) {
+                let entry = self.pgtbl_entry(virt);
+                if writable {
+                    entry.insert(TableEntry::READ_WRITE);
+                } else {
+                    entry.remove(TableEntry::READ_WRITE);
+                }
+                if user {
+                    entry.insert(TableEntry::ANY_DPL);
+                } else {
+                    entry.remove(TableEntry::ANY_DPL);
+                }
+                self.invalidate_cache(virt);
+            }
+
+            Ok(())
+        })
+    }
+
     pub unsafe fn place_guard_page(&mut self, at: u32) {
         assert_eq!(at & 0xFFF, 0, "at must be page-aligned");
-        let entry = self.pgtbl_entry(at);
 
-        if entry.contains(TableEntry::PRESENT) {
-            entry.insert(TableEntry::PRESENT);
-            entry.insert(TableEntry::WAS_PRESENT);
-        }
-        entry.insert(TableEntry::GUARD_PAGE);
+        interrupts::with_disabled(|| {
+            let entry = self.pgtbl_entry(at);
+
+            if entry.contains(TableEntry::PRESENT) {
+                entry.remove(TableEntry::PRESENT);
+                entry.insert(TableEntry::WAS_PRESENT);
+            }
+            entry.insert(TableEntry::GUARD_PAGE);
 
-        asm!("invlpg ({})", in(reg) at, options(att_syntax));
-        println!("[VAS] Placed a guard page at 0x{:08X}.", at);
+            asm!("invlpg ({})", in(reg) at, options(att_syntax));
+            println!("[VAS] Placed a guard page at 0x{:08X}.", at);
+        })
     }
 
     pub unsafe fn remove_guard_page(&mut self, from: u32) {
         assert_eq!(from & 0xFFF, 0, "from must be page-aligned");
-        let entry = self.pgtbl_entry(from);
 
-        if entry.contains(TableEntry::WAS_PRESENT) {
-            entry.insert(TableEntry::WAS_PRESENT);
-            entry.insert(TableEntry::PRESENT);
-        }
-        entry.insert(TableEntry::GUARD_PAGE);
+        interrupts::with_disabled(|| {
+            let entry = self.pgtbl_entry(from);
 
-        asm!("invlpg ({})", in(reg) from, options(att_syntax));
-        println!("[VAS] Removed a guard page from 0x{:08X}.", from);
+            if entry.contains(TableEntry::WAS_PRESENT) {
+                entry.remove(TableEntry::WAS_PRESENT);
+                entry.insert(TableEntry::PRESENT);
+            }
+            entry.remove(TableEntry::GUARD_PAGE);
+
+            asm!("invlpg ({})", in(reg) from, options(att_syntax));
+            println!("[VAS] Removed a guard page from 0x{:08X}.", from);
+        })
     }
 
     /// Sets up a page directory entry with the specified index with the
     /// physical mapping of the specified virtual address and the default flags.
     ///
+    /// This is meant for the page tables that are a part of the kernel image.
+    ///
     /// # Default flags
-    /// See [set_pde_phys_virt](Self::set_pde_phys_virt) for the default flags.
+    /// See [set_pde_phys](Self::set_pde_phys) for the default flags.
     ///
     /// # Panics
     /// This method panics if it cannot resolve the specified virtual address to
@@ -363,7 +777,7 @@ impl VirtAddrSpace {
         let pgtbl_phys = self
             .virt_to_phys(pgtbl_virt as u32)
             .expect("set_pde_addr: virt_to_phys failed");
-        self.set_pde_phys_virt(pde_idx, pgtbl_phys, pgtbl_virt);
+        self.set_pde_phys(pde_idx, pgtbl_phys);
     }
 
     /// Sets up a page directory entry with the specified index with the
@@ -372,49 +786,85 @@ impl VirtAddrSpace {
     /// Unlike [set_pde_virt](Self::set_pde_virt), this method does not try to
     /// resolve any mappings and thus does not panic.
     ///
-    /// The virtual address of the page table is saved internally.
-    ///
     /// # Default flags
     /// The default flags are:
     /// * [PRESENT](DirEntry::PRESENT),
     /// * [readable and writable](DirEntry::READ_WRITE),
     /// * [any DPL](DirEntry::ANY_DPL) (if [VirtAddrSpace::usermode] is `true`).
-    unsafe fn set_pde_phys_virt(
-        &self,
-        pde_idx: usize,
-        pgtbl_phys: u32,
-        pgtbl_virt: *mut Table,
-    ) {
-        assert!(pde_idx < 1024, "pde_idx must be less than 1024");
+    unsafe fn set_pde_phys(&self, pde_idx: usize, pgtbl_phys: u32) {
+        assert!(
+            pde_idx < FOREIGN_PDE_IDX,
+            "pde_idx must be less than {}",
+            FOREIGN_PDE_IDX,
+        );
         assert_eq!(pgtbl_phys % 4096, 0, "pgtbl_phys must be page-aligned");
-
-        *self.pgtbls_virt.add(pde_idx) = pgtbl_virt;
-        *self.pgtbls_phys.add(pde_idx) = pgtbl_phys;
+        assert!(
+            !self.usermode || !is_kernel_pde(pde_idx),
+            "cannot change a kernel PDE of a usermode VAS",
+        );
 
         let pgdir = self.pgdir_virt.as_mut().unwrap();
+        pgdir.0[pde_idx].remove(DirEntry::PAGE_SIZE_IS_4_MIB);
         pgdir.0[pde_idx].set_addr(pgtbl_phys);
         pgdir.0[pde_idx].insert(DirEntry::PRESENT);
         pgdir.0[pde_idx].insert(DirEntry::READ_WRITE);
         if self.usermode {
             pgdir.0[pde_idx].insert(DirEntry::ANY_DPL);
         }
+        invalidate_pgtbl_windows(pde_idx);
+    }
+
+    /// Points the last page directory entry to the page directory itself.
+    ///
+    /// The entry is not accessible from the usermode.
+    unsafe fn set_recursive_pde(&self) {
+        let pde = &mut self.pgdir_virt.as_mut().unwrap().0[RECURSIVE_PDE_IDX];
+        *pde = DirEntry::with_addr(self.pgdir_phys);
+        pde.insert(DirEntry::PRESENT);
+        pde.insert(DirEntry::READ_WRITE);
+    }
+
+    /// Checks if this VAS is the one loaded in CR3.
+    fn is_loaded(&self) -> bool {
+        let cr3: u32;
+        unsafe {
+            asm!("movl %cr3, {}", out(reg) cr3, options(att_syntax));
+        }
+        cr3 & !0xFFF == self.pgdir_phys
     }
 
     pub unsafe fn virt_to_phys(&self, virt: u32) -> Option<u32> {
-        let pgtbl_virt = self.pgtbl_virt_of(virt);
-        if !pgtbl_virt.is_null() {
-            let pte = self.pgtbl_entry(virt);
-            if pte.contains(TableEntry::PRESENT) {
-                Some(pte.addr())
+        interrupts::with_disabled(|| {
+            if self.is_large_page(virt) {
+                let pde =
+                    &self.pgdir_virt.as_ref().unwrap().0[(virt >> 22) as usize];
+                return Some(pde.addr() & !0x3FFFFF | virt & 0x3FFFFF);
+            }
+
+            let pgtbl_virt = self.pgtbl_virt_of(virt);
+            if !pgtbl_virt.is_null() {
+                let pte = self.pgtbl_entry(virt);
+                if pte.contains(TableEntry::PRESENT) {
+                    Some(pte.addr())
+                } else {
+                    None
+                }
             } else {
                 None
             }
-        } else {
-            None
-        }
+        })
     }
 
-    pub unsafe fn pgtbl_entry(&self, virt: u32) -> &mut TableEntry {
+    /// Returns the page table entry for `virt`.
+    ///
+    /// A 4 MiB page containing `virt` is [split](Self::split_large_page) into
+    /// a new page table.  See [pgtbl_virt_of](Self::pgtbl_virt_of) for how long
+    /// the reference is valid.
+    unsafe fn pgtbl_entry(&self, virt: u32) -> &mut TableEntry {
+        if self.is_large_page(virt) {
+            self.split_large_page(virt);
+        }
+
         let pgtbl_virt = self.pgtbl_virt_of(virt);
         assert!(!pgtbl_virt.is_null(), "page table does not exist");
 
@@ -422,9 +872,30 @@ impl VirtAddrSpace {
         &mut (*pgtbl_virt).0[pte_idx]
     }
 
-    pub unsafe fn pgtbl_virt_of(&self, virt: u32) -> *mut Table {
+    /// Returns the virtual address of the page table for `virt`, or a null
+    /// pointer if there is none.
+    ///
+    /// If this VAS is not loaded, the page table is accessed through the
+    /// foreign slot, so the interrupts must stay disabled while the pointer is
+    /// used (see [set_foreign_slot]).  It is valid only until the page tables
+    /// of another VAS that is not loaded are accessed.
+    unsafe fn pgtbl_virt_of(&self, virt: u32) -> *mut Table {
         let pde_idx = (virt >> 22) as usize;
-        *self.pgtbls_virt.add(pde_idx)
+        let pde = &self.pgdir_virt.as_ref().unwrap().0[pde_idx];
+        if pde_idx >= FOREIGN_PDE_IDX
+            || !pde.contains(DirEntry::PRESENT)
+            || pde.contains(DirEntry::PAGE_SIZE_IS_4_MIB)
+        {
+            return ptr::null_mut();
+        }
+
+        let window = if self.is_loaded() {
+            PGTBLS_WINDOW
+        } else {
+            set_foreign_slot(self.pgdir_phys);
+            FOREIGN_PGTBLS_WINDOW
+        };
+        (window + (pde_idx << 12) as u32) as *mut Table
     }
 
     fn invalidate_cache(&self, virt: u32) {
@@ -433,9 +904,186 @@ impl VirtAddrSpace {
             asm!("invlpg ({})", in(reg) virt, options(att_syntax));
         }
     }
+
+    fn invalidate_large_page(&self, virt: u32) {
+        // invlpg on any address within a 4 MiB page invalidates all of it.
+        self.invalidate_cache(virt & !0x3FFFFF);
+    }
+}
+
+/// Index of the page directory entry pointing to the page directory itself.
+const RECURSIVE_PDE_IDX: usize = 1023;
+
+/// Index of the page directory entry used to access a page directory that is
+/// not loaded, or a single frame (see [set_foreign_slot]).
+const FOREIGN_PDE_IDX: usize = 1022;
+
+/// Virtual address of the page tables of the loaded VAS.
+const PGTBLS_WINDOW: u32 = (RECURSIVE_PDE_IDX << 22) as u32;
+
+/// Virtual address of the page tables of the page directory in the foreign
+/// slot.
+const FOREIGN_PGTBLS_WINDOW: u32 = (FOREIGN_PDE_IDX << 22) as u32;
+
+/// Virtual address of the loaded page directory.
+const LOADED_PGDIR: u32 = PGTBLS_WINDOW | (RECURSIVE_PDE_IDX << 12) as u32;
+
+/// Virtual address of the frame in the foreign slot.
+const SCRATCH_PAGE: u32 = PGTBLS_WINDOW | (FOREIGN_PDE_IDX << 12) as u32;
+
+/// Points the foreign slot of the loaded page directory to the frame at `phys`.
+///
+/// The frame is then accessible at [SCRATCH_PAGE].  If it is a page directory,
+/// its page tables are accessible at [FOREIGN_PGTBLS_WINDOW].
+///
+/// The slot is shared by the tasks running in the loaded VAS and by the page
+/// fault handler, so the interrupts must stay disabled until the caller is
+/// done with the slot, otherwise it may be repointed in the meantime.
+///
+/// # Panics
+/// This function panics if the interrupts are enabled.
+unsafe fn set_foreign_slot(phys: u32) {
+    assert!(
+        !interrupts::are_enabled(),
+        "the foreign slot is used with the interrupts enabled",
+    );
+    let loaded_pgdir = (LOADED_PGDIR as *mut Directory).as_mut().unwrap();
+    let pde = loaded_pgdir.0[FOREIGN_PDE_IDX];
+    if pde.contains(DirEntry::PRESENT) && pde.addr() == phys {
+        return;
+    }
+
+    let cr3: u32;
+    asm!("movl %cr3, {}", out(reg) cr3, options(att_syntax));
+    assert_eq!(
+        loaded_pgdir.0[RECURSIVE_PDE_IDX].addr(),
+        cr3 & !0xFFF,
+        "the loaded page directory is not recursively mapped",
+    );
+
+    let pde = &mut loaded_pgdir.0[FOREIGN_PDE_IDX];
+    *pde = DirEntry::with_addr(phys);
+    pde.insert(DirEntry::PRESENT);
+    pde.insert(DirEntry::READ_WRITE);
+
+    // The whole window has changed, so flush the TLB.
+    flush_tlb();
+}
+
+/// Empties the foreign slot of the loaded page directory, see
+/// [set_foreign_slot].
+unsafe fn clear_foreign_slot() {
+    let loaded_pgdir = (LOADED_PGDIR as *mut Directory).as_mut().unwrap();
+    loaded_pgdir.0[FOREIGN_PDE_IDX] = DirEntry::empty();
+    flush_tlb();
+}
+
+/// Flushes all TLB entries by reloading CR3.
+fn flush_tlb() {
+    unsafe {
+        asm!("movl %cr3, %eax
+              movl %eax, %cr3",
+             out("eax") _,
+             options(att_syntax));
+    }
+}
+
+/// Maps the specified kernel region to pages given by the [PMM
+/// stack](static@super::pmm_stack::PMM_STACK) without locking [KERNEL_VAS].
+///
+/// The kernel page tables are shared by all VASes, so the pages are mapped
+/// through the page tables of the loaded VAS and are visible everywhere.  This
+/// lets the kernel heap grow while the allocating code holds the kernel VAS.
+/// The page tables for the region must exist (see
+/// [ensure_kernel_pgtbls](VirtAddrSpace::ensure_kernel_pgtbls)) and nothing
+/// else may map the region concurrently.
+///
+/// If the stack runs out of pages, the pages mapped so far are freed.
+pub unsafe fn allocate_kernel_pages_from_stack(
+    start: u32,
+    end: u32,
+) -> Result<(), AllocPagesErr> {
+    assert_eq!(start & 0xFFF, 0, "start must be page-aligned");
+    assert_eq!(end & 0xFFF, 0, "end must be page-aligned");
+    for virt in (start..end).step_by(4096) {
+        let entry = loaded_kernel_pgtbl_entry(virt);
+        let phys = PMM_STACK.lock().pop_page();
+        match phys {
+            Some(phys) => {
+                *entry = TableEntry::with_addr(phys);
+                entry.insert(TableEntry::PRESENT);
+                entry.insert(TableEntry::READ_WRITE);
+                asm!("invlpg ({})", in(reg) virt, options(att_syntax));
+            }
+            None => {
+                for virt in (start..virt).step_by(4096) {
+                    let entry = loaded_kernel_pgtbl_entry(virt);
+                    let phys = entry.addr();
+                    *entry = TableEntry::empty();
+                    asm!("invlpg ({})", in(reg) virt, options(att_syntax));
+                    pmm_stack::unref_frame(phys);
+                }
+                return Err(AllocPagesErr::OutOfMemory);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the entry for `virt` in the kernel page tables of the loaded VAS.
+///
+/// # Panics
+/// This function panics if `virt` is not in the kernel region or has no page
+/// table.
+unsafe fn loaded_kernel_pgtbl_entry(virt: u32) -> &'static mut TableEntry {
+    let pde_idx = (virt >> 22) as usize;
+    assert!(is_kernel_pde(pde_idx), "not a kernel page");
+    let loaded_pgdir = (LOADED_PGDIR as *const Directory).as_ref().unwrap();
+    let pde = loaded_pgdir.0[pde_idx];
+    assert!(
+        pde.contains(DirEntry::PRESENT)
+            && !pde.contains(DirEntry::PAGE_SIZE_IS_4_MIB),
+        "page table does not exist",
+    );
+    let pgtbl = (PGTBLS_WINDOW + (pde_idx << 12) as u32) as *mut Table;
+    let pte_idx = ((virt >> 12) & 0x3FF) as usize;
+    &mut (*pgtbl).0[pte_idx]
+}
+
+/// Number of pages copied at once by [VirtAddrSpace::copy].
+const COPY_WINDOW_PAGES: usize = 64;
+
+/// Invalidates the TLB entries for the page table with the index `pde_idx` in
+/// both page table windows.
+fn invalidate_pgtbl_windows(pde_idx: usize) {
+    let pgtbl_offset = (pde_idx << 12) as u32;
+    unsafe {
+        asm!("invlpg ({})", in(reg) PGTBLS_WINDOW + pgtbl_offset,
+             options(att_syntax));
+        asm!("invlpg ({})", in(reg) FOREIGN_PGTBLS_WINDOW + pgtbl_offset,
+             options(att_syntax));
+    }
+}
+
+#[derive(Debug)]
+pub enum AllocPagesErr {
+    /// The PMM stack has run out of pages.
+    OutOfMemory,
+}
+
+#[derive(Debug)]
+pub enum SetProtectionErr {
+    /// The page at this address is neither mapped nor reserved.
+    NotMapped(u32),
 }
 
 impl DirEntry {
+    fn with_addr(addr: u32) -> Self {
+        let mut entry = Self::empty();
+        entry.set_addr(addr);
+        entry
+    }
+
     fn addr(&self) -> u32 {
         self.bits() & !0xFFF
     }
@@ -484,31 +1132,80 @@ impl Table {
 
 kernel_static! {
     static ref KERNEL_PGDIR: Mutex<Directory> = Mutex::new(Directory::new());
-    static ref KERNEL_PGTBLS: Mutex<[Table; 2]> = Mutex::new([Table::new(); 2]);
-    static ref KERNEL_PGTBLS_VIRT: Mutex<[*mut Table; 1024]> = Mutex::new([ptr::null_mut(); 1024]);
-    static ref KERNEL_PGTBLS_PHYS: Mutex<[u32; 1024]> = Mutex::new([0; 1024]);
-
-    pub static ref ACPI_PGTBL: Mutex<Table> = Mutex::new(Table::new());
 
     pub static ref KERNEL_HEAP_PGTBL: Mutex<Table> = Mutex::new(Table::new());
 
     pub static ref KERNEL_VAS: Mutex<VirtAddrSpace> = Mutex::new(unsafe {
-        VirtAddrSpace::new_identity_mapped(
+        VirtAddrSpace::new_higher_half(
             &mut *KERNEL_PGDIR.lock(),
-            &mut *KERNEL_PGTBLS.lock(),
-            (KERNEL_PGTBLS_VIRT.lock().as_mut_ptr(), KERNEL_PGTBLS_PHYS.lock().as_mut_ptr()),
+            KERNEL_HIGHER_HALF_PDES,
         )
     });
 }
 
+/// Virtual address the kernel image and the low physical memory are mapped at.
+///
+/// Keep in sync with KERNEL_VIRT_BASE in linker.ld and boot.s.
+pub const KERNEL_VIRT_BASE: usize = 0xC0000000; // 3 GiB
+
+/// Number of 4 MiB pages mapped at [KERNEL_VIRT_BASE] in the kernel VAS.
+pub const KERNEL_HIGHER_HALF_PDES: usize = 2;
+
 const KERNEL_REGION: Region<usize> = Region {
-    start: 0x00000000,
-    end: 0x08000000, // 128 MiB
+    start: KERNEL_VIRT_BASE,
+    end: KERNEL_VIRT_BASE + 0x08000000, // 3 GiB + 128 MiB
 };
 
+/// Part of the kernel region where the framebuffer is mapped, if there is one.
+///
+/// It lies right after the maximum end of the kernel heap.
+pub const FRAMEBUFFER_REGION: Region<usize> = Region {
+    start: KERNEL_VIRT_BASE + 0x500_0000,
+    end: KERNEL_VIRT_BASE + 0x600_0000,
+};
+
+/// Part of the kernel region where the Multiboot modules are mapped.
+///
+/// It lies between [FRAMEBUFFER_REGION] and the PMM reference count table.
+pub const MODULES_REGION: Region<usize> = Region {
+    start: FRAMEBUFFER_REGION.end,
+    end: KERNEL_VIRT_BASE + 0x7C0_0000,
+};
+
+/// Checks if the page at `virt` is mapped the same way in all VASes, that is,
+/// belongs to the kernel or ACPI region.
+unsafe fn is_shared_page(virt: u32) -> bool {
+    let acpi_region = KERNEL_INFO
+        .arch
+        .hpet_region
+        .unwrap_or(Region { start: 0, end: 0 });
+    KERNEL_REGION.contains(&(virt as usize))
+        || acpi_region.contains(&(virt as usize))
+}
+
+/// Checks if the page directory entry with the index `pde_idx` belongs to the
+/// kernel region.
+///
+/// The page tables of these entries are shared by all VASes, so the changes to
+/// the kernel mappings are visible everywhere.  Therefore the entries
+/// themselves must not change after the first usermode VAS is created (see
+/// [ensure_kernel_pgtbls](VirtAddrSpace::ensure_kernel_pgtbls)).
+fn is_kernel_pde(pde_idx: usize) -> bool {
+    KERNEL_REGION.contains(&(pde_idx << 22))
+}
+
+/// Checks if the frame at `phys` must never be returned to the PMM stack, i.e.
+/// belongs to the kernel image.
+///
+/// The ACPI frames are not checked here, since they are mapped only in the
+/// ACPI region which is shared (see [is_shared_page]).
+unsafe fn is_reserved_frame(phys: u32) -> bool {
+    KERNEL_INFO.arch.kernel_region.contains(&(phys as usize))
+}
+
 pub const USERMODE_REGION: Region<usize> = Region {
-    start: 128 * 1024 * 1024,                      // 128 MiB
-    end: 3 * 1024 * 1024 * 1024 + 4 * 1024 * 1024, // 3 GiB + 4 MiB
+    start: 4 * 1024 * 1024, // 4 MiB
+    end: KERNEL_VIRT_BASE,  // 3 GiB
 };
 
 #[no_mangle]
@@ -516,8 +1213,51 @@ pub extern "C" fn page_fault_handler(
     int_num: u32,
     err_code: u32,
     stack_frame: &InterruptStackFrame,
+    gp_regs: &GpRegs,
 ) {
     assert_eq!(int_num, 14);
+
+    let cr2: u32;
+    unsafe {
+        asm!("movl %cr2, %eax", out("eax") cr2, options(att_syntax));
+    }
+
+    // A non-present page may belong to a lazily allocated region of the
+    // current task or be the guard page of its usermode stack.
+    if err_code & 1 == 0 {
+        if let Some(task) = unsafe { TASK_MANAGER.running_task() } {
+            if unsafe { task.commit_lazy_page(cr2) } {
+                return;
+            }
+            match unsafe { task.grow_usermode_stack(cr2) } {
+                Ok(()) => return,
+                Err(StackGrowthErr::NotGuardPage)
+                | Err(StackGrowthErr::OutOfMemory) => {}
+                Err(StackGrowthErr::LimitReached) => {
+                    let task_id = task.id;
+                    println!(
+                        "[VAS] Stack overflow in task ID {} at 0x{:08X}.",
+                        task_id, cr2,
+                    );
+                    if (err_code >> 2) & 1 == 1 {
+                        task_manager::task_exit(STACK_OVERFLOW_EXIT_STATUS);
+                    }
+                }
+            }
+        }
+    }
+
+    // A kernel stack overflow hits the guard page below the stack.
+    if let Some(task) = unsafe { TASK_MANAGER.running_task() } {
+        if cr2 & !0xFFF == task.kernel_stack_guard_page() {
+            let task_id = task.id;
+            println!(
+                "[VAS] Kernel stack overflow in task ID {} at 0x{:08X}.",
+                task_id, cr2,
+            );
+        }
+    }
+
     println!("A page fault has occurred.");
     println!(
         " error code: {:08b}_{:08b}_{:08b}_{:08b} (0x{:08X})",
@@ -530,11 +1270,6 @@ pub extern "C" fn page_fault_handler(
 
     let eip = stack_frame.eip;
     println!(" eip: 0x{:08X}", eip);
-
-    let cr2: u32;
-    unsafe {
-        asm!("movl %cr2, %eax", out("eax") cr2, options(att_syntax));
-    }
     println!(" cr2: 0x{:08X}", cr2);
 
     print!("Details: ");
@@ -558,18 +1293,33 @@ pub extern "C" fn page_fault_handler(
 
     if let Some(kvas) = KERNEL_VAS.try_lock() {
         let page = cr2 & !0xFFF;
-        let pgtbl_virt = unsafe { kvas.pgtbl_virt_of(page) };
-        if pgtbl_virt.is_null() {
+        let has_pgtbl = interrupts::with_disabled(|| unsafe {
+            !kvas.pgtbl_virt_of(page).is_null()
+        });
+        if !has_pgtbl {
             println!("No page table for 0x{:08X}.", cr2);
-        } else {
-            let entry = unsafe { kvas.pgtbl_entry(page) };
-            if entry.contains(TableEntry::GUARD_PAGE) {
-                println!("There is a guard page at 0x{:08X}.", page);
-            }
+        } else if kvas.is_guard_page(page) {
+            println!("There is a guard page at 0x{:08X}.", page);
         }
     } else {
         println!("Unable to lock the kernel VAS.");
     }
 
+    // A wild pointer in the usermode kills the task, not the kernel.
+    if (err_code >> 2) & 1 == 1 {
+        let task = unsafe { TASK_MANAGER.this_task() };
+        task.print_user_symbol(eip as usize);
+        println!("[VAS] Killing task ID {} due to the page fault.", task.id);
+        task_manager::task_exit(PAGE_FAULT_EXIT_STATUS);
+    }
+
+    interrupts::save_panic_regs(stack_frame, gp_regs);
     panic!("Unhandled page fault.");
 }
+
+/// Exit status of a task killed by an unhandled page fault.
+const PAGE_FAULT_EXIT_STATUS: i32 = -1;
+
+/// Exit status of a task killed because its usermode stack exceeded
+/// [USERMODE_STACK_MAX_SIZE](crate::task::USERMODE_STACK_MAX_SIZE).
+const STACK_OVERFLOW_EXIT_STATUS: i32 = -2;
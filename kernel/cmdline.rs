@@ -0,0 +1,145 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Kernel command line.
+//!
+//! The command line is a list of whitespace-separated options, each of which
+//! is either a `key=value` pair or a flag.  Recognized options:
+//! * `root=disk<N>` - the disk to initialize the VFS root on (disk 0 if it is
+//!   missing or malformed),
+//! * `console=serial` - mirror the kernel output to the serial port,
+//! * `init=<path>` - the program to run in the first task,
+//! * `kbd=<layout>` - the keyboard layout, `us` (default) or `de`,
+//! * `profile` - log the time spent in the disk and the ext2 operations,
+//! * `timer_ms=<N>` - change the timer period from 10 ms to `N` ms, 1 to 50,
+//! * `selftest=<name>[,<name>...]` or `selftest=all` - run the kernel
+//!   [self-tests](crate::selftest).
+
+use core::str;
+
+use crate::KERNEL_INFO;
+
+/// Maximum length of the command line, the rest is cut off.
+pub const CMDLINE_MAX_LEN: usize = 256;
+
+const KNOWN_OPTIONS: [&str; 7] = [
+    "root", "console", "init", "kbd", "profile", "timer_ms", "selftest",
+];
+
+/// Command line copied out of the Multiboot information structure.
+#[derive(Clone, Copy)]
+pub struct CmdLine {
+    buf: [u8; CMDLINE_MAX_LEN],
+    len: usize,
+}
+
+impl CmdLine {
+    pub const fn new() -> Self {
+        CmdLine {
+            buf: [0; CMDLINE_MAX_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn set(&mut self, cmdline: &str) {
+        let mut len = cmdline.len();
+        if len > CMDLINE_MAX_LEN {
+            log_warn!(
+                "[CMDLINE] Command line is longer than {} bytes, cutting it off.",
+                CMDLINE_MAX_LEN,
+            );
+            len = CMDLINE_MAX_LEN;
+            while !cmdline.is_char_boundary(len) {
+                len -= 1;
+            }
+        }
+        self.buf[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+        self.len = len;
+    }
+
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..self.len]).unwrap()
+    }
+
+    fn options(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.as_str().split_whitespace().map(|token| {
+            let mut parts = token.splitn(2, '=');
+            (parts.next().unwrap(), parts.next())
+        })
+    }
+
+    /// Returns the value of the last `key=value` option with the key `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options()
+            .filter(|&(k, _)| k == key)
+            .filter_map(|(_, value)| value)
+            .last()
+    }
+
+    /// Checks if the flag `flag` is present.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.options().any(|option| option == (flag, None))
+    }
+}
+
+/// Returns the value of the option `key`.  See [CmdLine::get].
+pub fn get(key: &str) -> Option<&'static str> {
+    unsafe { KERNEL_INFO.cmdline.get(key) }
+}
+
+/// Checks if the flag `flag` is present.  See [CmdLine::has_flag].
+pub fn has_flag(flag: &str) -> bool {
+    unsafe { KERNEL_INFO.cmdline.has_flag(flag) }
+}
+
+/// Logs the options that the kernel does not recognize.
+pub fn report_unknown_options() {
+    let cmdline = unsafe { &KERNEL_INFO.cmdline };
+    for (key, value) in cmdline.options() {
+        if !KNOWN_OPTIONS.contains(&key) {
+            match value {
+                Some(value) => log_warn!(
+                    "[CMDLINE] Ignoring unknown option {}={}.",
+                    key,
+                    value,
+                ),
+                None => log_warn!("[CMDLINE] Ignoring unknown flag {}.", key),
+            }
+        }
+    }
+}
+
+/// Returns the ID of the disk selected with `root=disk<N>`, `None` if there is
+/// no such option or it is malformed.
+pub fn root_disk_id() -> Option<usize> {
+    let root = get("root")?;
+    let id = root.strip_prefix("disk").and_then(|id| id.parse().ok());
+    if id.is_none() {
+        log_warn!("[CMDLINE] Ignoring invalid option root={}.", root);
+    }
+    id
+}
+
+/// Returns the timer period selected with `timer_ms=<N>`, `None` if there is
+/// no such option or it is not a number of milliseconds from 1 to 50.
+pub fn timer_period_ms() -> Option<usize> {
+    let value = get("timer_ms")?;
+    let period_ms = value.parse().ok().filter(|ms| (1..=50).contains(ms));
+    if period_ms.is_none() {
+        log_warn!("[CMDLINE] Ignoring invalid option timer_ms={}.", value);
+    }
+    period_ms
+}
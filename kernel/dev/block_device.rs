@@ -70,6 +70,7 @@ pub enum WriteErr {
     NoSuchBlock,
     TooMuchBlocks,
     EmptyDataPassed,
+    NotWritable,
 }
 
 impl From<disk::WriteErr> for WriteErr {
@@ -78,6 +79,7 @@ impl From<disk::WriteErr> for WriteErr {
             disk::WriteErr::NoSuchBlock => WriteErr::NoSuchBlock,
             disk::WriteErr::TooMuchBlocks => WriteErr::TooMuchBlocks,
             disk::WriteErr::EmptyDataPassed => WriteErr::EmptyDataPassed,
+            disk::WriteErr::NotWritable => WriteErr::NotWritable,
         }
     }
 }
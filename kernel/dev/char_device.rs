@@ -18,8 +18,40 @@ use alloc::rc::Rc;
 use alloc::vec::Vec;
 use core::cell::RefCell;
 
+use crate::errno::Errno;
 use crate::fs::{ReadFileErr, WriteFileErr};
 use crate::kernel_static::Mutex;
+use crate::task_manager::WaitQueue;
+
+/// Gets the terminal attributes, `arg` is a `*mut Termios`.
+pub const TCGETS: u32 = 0x5401;
+/// Sets the terminal attributes, `arg` is a `*const Termios`.
+pub const TCSETS: u32 = 0x5402;
+/// Gets the terminal size, `arg` is a `*mut WinSize`.
+pub const TIOCGWINSZ: u32 = 0x5413;
+
+/// Canonical mode, see [Termios].
+pub const ICANON: u32 = 0o2;
+/// Echoing of the typed characters, see [Termios].
+pub const ECHO: u32 = 0o10;
+
+/// Terminal attributes for [TCGETS] and [TCSETS].  Only the local modes are
+/// supported, with the same bits as in Linux.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Termios {
+    pub local_modes: u32,
+}
+
+/// Terminal size for [TIOCGWINSZ].
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct WinSize {
+    pub rows: u16,
+    pub cols: u16,
+    pub x_pixels: u16,
+    pub y_pixels: u16,
+}
 
 pub trait CharDevice {
     fn read(&mut self) -> Result<u8, ReadErr>;
@@ -27,13 +59,26 @@ pub trait CharDevice {
 
     fn write(&mut self, byte: u8) -> Result<(), WriteErr>;
     fn write_many(&mut self, bytes: &[u8]) -> Result<(), WriteErr>;
+
+    /// Name of the device in devfs, `chr<N>` if there is none.
+    fn name(&self) -> Option<&str> {
+        None
+    }
+
+    /// Performs a device-specific request.  `arg` is either a value or a
+    /// pointer to the usermode memory of the running task, depending on the
+    /// request.
+    fn ioctl(&mut self, _request: u32, _arg: usize) -> Result<u32, Errno> {
+        Err(Errno::ENOTTY)
+    }
 }
 
 #[derive(Debug)]
 pub enum ReadErr {
     NotReadable,
     InvalidLen,
-    Block,
+    /// Nothing to read yet, the caller should wait on the queue and retry.
+    Block(&'static WaitQueue),
 }
 
 impl From<ReadErr> for ReadFileErr {
@@ -41,7 +86,7 @@ impl From<ReadErr> for ReadFileErr {
         match err {
             ReadErr::NotReadable => ReadFileErr::NotReadable,
             ReadErr::InvalidLen => ReadFileErr::InvalidOffsetOrLen,
-            ReadErr::Block => ReadFileErr::Block,
+            ReadErr::Block(queue) => ReadFileErr::Block(queue),
         }
     }
 }
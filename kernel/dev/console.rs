@@ -16,248 +16,335 @@
 
 use alloc::collections::vec_deque::VecDeque;
 use alloc::rc::Rc;
+use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::cmp;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::task_manager::TASK_MANAGER;
+use crate::task_manager::{WaitQueue, TASK_MANAGER};
+use crate::task_manager::{STATUS_INTERRUPTED, STATUS_KILLED};
 
 use crate::arch::dev::keyboard::{Event, EventListener, Key, KEYBOARD};
-use crate::dev::char_device::{CharDevice, ReadErr, WriteErr};
+use crate::arch::interrupts;
+use crate::dev::char_device::{
+    CharDevice, ReadErr, Termios, WinSize, WriteErr,
+};
+use crate::dev::char_device::{ECHO, ICANON, TCGETS, TCSETS, TIOCGWINSZ};
+use crate::dev::keymap::{KeyInput, KeyRepeat, Keymap};
+use crate::dev::timer;
 use crate::dev::vga;
+use crate::errno::Errno;
 use crate::kernel_static::Mutex;
-
-const MAX_KBD_EVENTS: usize = 64;
+use crate::usercopy;
+
+/// Maximum length of a line in the canonical mode or of the unread input in
+/// the raw mode, the rest is ignored.
+const MAX_LINE_LEN: usize = 256;
+
+const BACKSPACE: char = '\x08';
+const CTRL_C: char = '\x03';
+const CTRL_D: char = '\x04';
+const CTRL_U: char = '\x15';
+const CTRL_BACKSLASH: char = '\x1C';
+
+/// Number of rows kept in the scrollback buffer.
+const SCROLLBACK_ROWS: usize = 500;
+
+/// How the console delivers the typed characters to the readers.
+#[derive(Clone, Copy, PartialEq)]
+pub enum InputMode {
+    /// The characters are collected into a line, which can be edited until
+    /// Enter is pressed.  A read returns at most one line.
+    Canonical,
+    /// The characters are delivered as soon as they are typed, without being
+    /// echoed.
+    Raw,
+}
 
 pub struct Console {
     writer: vga::Writer,
-    kbd_events: VecDeque<Event>,
-
-    shift: bool,
-    caps_lock: bool,
-    num_lock: bool,
-
-    task_blocked_by_read: Option<usize>,
-    current_buf_idx: usize,
+    keymap: Keymap,
+    key_repeat: KeyRepeat,
+
+    mode: InputMode,
+    /// Whether the typed characters are printed in the canonical mode.
+    echo: bool,
+    /// Line being edited in the canonical mode.
+    line: Vec<u8>,
+    /// Completed lines in the canonical mode.  An empty line is an end of
+    /// file (Ctrl+D at the line start).
+    lines: VecDeque<Vec<u8>>,
+    /// Typed characters in the raw mode.
+    raw_input: VecDeque<u8>,
+
+    /// Task that Ctrl+C and Ctrl+\\ terminate in the canonical mode.
+    foreground: Option<usize>,
+    /// Task that the console belongs to, i.e. the init program, which may
+    /// always select the foreground task.
+    owner: Option<usize>,
 }
 
 impl Console {
     pub fn new() -> Self {
         Console {
-            writer: vga::Writer {
-                pos: vga::CursorPos::new(24, 0),
-                color_code: vga::ColorCode::new(
-                    vga::Color::White,
-                    vga::Color::Black,
-                ),
-                buffer: 0xB8000 as *mut vga::Buffer,
-            },
-            kbd_events: VecDeque::new(),
-
-            shift: false,
-            caps_lock: false,
-            num_lock: false,
-
-            task_blocked_by_read: None,
-            current_buf_idx: 0,
+            writer: vga::Writer::on_last_row(vga::ColorCode::new(
+                vga::Color::White,
+                vga::Color::Black,
+            )),
+            keymap: Keymap::new(),
+            key_repeat: KeyRepeat::new(),
+
+            mode: InputMode::Canonical,
+            echo: true,
+            line: Vec::new(),
+            lines: VecDeque::new(),
+            raw_input: VecDeque::new(),
+
+            foreground: None,
+            owner: None,
         }
     }
 
-    fn try_resolve_into_ascii(&mut self) -> Option<u8> {
-        loop {
-            if self.kbd_events.is_empty() {
-                // println!("[CONSOLE] Empty keyboard events buffer.");
-                return None;
-            }
-            let res = self.resolve_event();
-            if let ResolveEvent::Ascii(ascii) = res {
-                return Some(ascii);
+    /// Switches the input mode, discarding the input that has not been read.
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        self.mode = mode;
+        self.line.clear();
+        self.lines.clear();
+        self.raw_input.clear();
+    }
+
+    /// Turns echoing of the typed characters in the canonical mode on or off.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Sets the task that Ctrl+C and Ctrl+\\ terminate.
+    pub fn set_foreground_task(&mut self, task_id: Option<usize>) {
+        self.foreground = task_id;
+    }
+
+    /// Makes the task with the ID `task_id` the owner of the console and its
+    /// foreground task.
+    pub fn set_owner_task(&mut self, task_id: usize) {
+        self.owner = Some(task_id);
+        self.foreground = Some(task_id);
+    }
+
+    /// Checks if the task with the ID `task_id` may select the foreground
+    /// task, i.e. it is the owner or the current foreground task.
+    pub fn may_set_foreground(&self, task_id: usize) -> bool {
+        self.owner == Some(task_id) || self.foreground == Some(task_id)
+    }
+
+    /// Hands the foreground over to the parent of an exited process, like a
+    /// shell gets it back when the command it has run exits.
+    pub fn process_exited(
+        &mut self,
+        process_id: usize,
+        parent_id: Option<usize>,
+    ) {
+        if self.foreground == Some(process_id) {
+            self.foreground = parent_id;
+        }
+        if self.owner == Some(process_id) {
+            self.owner = None;
+        }
+    }
+
+    /// Feeds a typed character to the line editor or the raw input.
+    /// The input is encoded in UTF-8.
+    fn process_input(&mut self, ch: char) {
+        match self.mode {
+            InputMode::Canonical => self.edit_line(ch),
+            InputMode::Raw => {
+                let mut buf = [0; 4];
+                let bytes = ch.encode_utf8(&mut buf).as_bytes();
+                if self.raw_input.len() + bytes.len() <= MAX_LINE_LEN {
+                    self.raw_input.extend(bytes);
+                }
             }
         }
     }
 
-    fn resolve_event(&mut self) -> ResolveEvent {
-        let event = self.kbd_events.pop_front().unwrap();
-        let symbol = |s1: &str, s2: &str| {
-            if event.pressed {
-                let ch = if !self.shift {
-                    s1.as_bytes()[0]
-                } else {
-                    s2.as_bytes()[0]
-                };
-                ResolveEvent::Ascii(ch)
-            } else {
-                ResolveEvent::None
+    fn edit_line(&mut self, ch: char) {
+        match ch {
+            '\n' => {
+                self.line.push(b'\n');
+                self.echo_char(b'\n');
+                self.lines.push_back(mem::take(&mut self.line));
             }
-        };
-        let letter = |s: &str| {
-            if event.pressed {
-                let mut ch = s.as_bytes()[0];
-                if self.is_uppercase() {
-                    ch -= 32;
+            BACKSPACE => {
+                if self.pop_char() {
+                    self.echo_char(BACKSPACE as u8);
                 }
-                ResolveEvent::Ascii(ch)
-            } else {
-                ResolveEvent::None
             }
-        };
-        let no_numlock_symbol = |s: &str| {
-            if event.pressed && !self.num_lock {
-                let ch = s.as_bytes()[0];
-                return ResolveEvent::Ascii(ch);
+            CTRL_U => {
+                while self.pop_char() {
+                    self.echo_char(BACKSPACE as u8);
+                }
+            }
+            CTRL_C => self.interrupt(CTRL_C, STATUS_INTERRUPTED),
+            CTRL_BACKSLASH => self.interrupt(CTRL_BACKSLASH, STATUS_KILLED),
+            CTRL_D => {
+                // At the line start this is an end of file, otherwise the
+                // line is delivered without a newline.
+                self.lines.push_back(mem::take(&mut self.line));
             }
-            ResolveEvent::None
-        };
-        match event.key {
-            Key::CapsLock => {
-                if !event.pressed {
-                    self.caps_lock = !self.caps_lock;
+            ch if ch.is_ascii_control() => {}
+            ch => {
+                let mut buf = [0; 4];
+                let bytes = ch.encode_utf8(&mut buf).as_bytes();
+                if self.line.len() + bytes.len() <= MAX_LINE_LEN {
+                    self.line.extend_from_slice(bytes);
+                    // The screen has glyphs only for ASCII.
+                    self.echo_char(if ch.is_ascii() { ch as u8 } else { b'?' });
                 }
-                ResolveEvent::FlagUpdate
             }
-            Key::LeftShift | Key::RightShift => {
-                self.shift = event.pressed;
-                ResolveEvent::FlagUpdate
+        }
+    }
+
+    /// Removes the last character of the line being edited, returns whether
+    /// there has been one.
+    fn pop_char(&mut self) -> bool {
+        while let Some(byte) = self.line.pop() {
+            // Skip the UTF-8 continuation bytes.
+            if byte & 0xC0 != 0x80 {
+                return true;
             }
-            Key::NumLock => {
-                if !event.pressed {
-                    self.num_lock = !self.num_lock;
+        }
+        false
+    }
+
+    /// Discards the line being edited and kills the foreground task with
+    /// `status`.  The interrupt character `ch` itself is only echoed.
+    fn interrupt(&mut self, ch: char, status: i32) {
+        self.line.clear();
+        self.echo_char(b'^');
+        self.echo_char(ch as u8 + b'@');
+        self.echo_char(b'\n');
+
+        // The task may have exited already, then there is nothing to kill.
+        // It stays in the foreground until it exits and hands it over to its
+        // parent.
+        if let Some(task_id) = self.foreground {
+            unsafe {
+                TASK_MANAGER.kill_task(task_id, status);
+            }
+        }
+    }
+
+    fn echo_char(&mut self, ch: u8) {
+        if self.echo {
+            self.writer.write_char(ch);
+        }
+    }
+
+    /// Checks if a read would not block.
+    fn has_input(&self) -> bool {
+        match self.mode {
+            InputMode::Canonical => !self.lines.is_empty(),
+            InputMode::Raw => !self.raw_input.is_empty(),
+        }
+    }
+
+    /// Moves the available input to `buf`, returns `None` if there is none.
+    fn take_input(&mut self, buf: &mut [u8]) -> Option<usize> {
+        match self.mode {
+            InputMode::Canonical => {
+                let mut line = self.lines.pop_front()?;
+                if line.len() > buf.len() {
+                    // Leave the rest of the line for the next read.
+                    let rest = line.split_off(buf.len());
+                    self.lines.push_front(rest);
                 }
-                ResolveEvent::FlagUpdate
+                buf[..line.len()].copy_from_slice(&line);
+                Some(line.len())
             }
+            InputMode::Raw => {
+                if self.raw_input.is_empty() {
+                    return None;
+                }
+                let len = cmp::min(buf.len(), self.raw_input.len());
+                for (dst, src) in
+                    buf.iter_mut().zip(self.raw_input.drain(..len))
+                {
+                    *dst = src;
+                }
+                Some(len)
+            }
+        }
+    }
 
-            Key::Backtick => symbol("`", "~"),
-            Key::Space => symbol(" ", " "),
-
-            Key::One => symbol("1", "!"),
-            Key::Two => symbol("2", "@"),
-            Key::Three => symbol("3", "#"),
-            Key::Four => symbol("4", "$"),
-            Key::Five => symbol("5", "%"),
-            Key::Six => symbol("6", "^"),
-            Key::Seven => symbol("7", "&"),
-            Key::Eight => symbol("8", "*"),
-            Key::Nine => symbol("9", "("),
-            Key::Zero => symbol("0", ")"),
-
-            Key::Minus => symbol("-", "_"),
-            Key::Equals => symbol("=", "+"),
-
-            Key::A => letter("a"),
-            Key::B => letter("b"),
-            Key::C => letter("c"),
-            Key::D => letter("d"),
-            Key::E => letter("e"),
-            Key::F => letter("f"),
-            Key::G => letter("g"),
-            Key::H => letter("h"),
-            Key::I => letter("i"),
-            Key::J => letter("j"),
-            Key::K => letter("k"),
-            Key::L => letter("l"),
-            Key::M => letter("m"),
-            Key::N => letter("n"),
-            Key::O => letter("o"),
-            Key::P => letter("p"),
-            Key::Q => letter("q"),
-            Key::R => letter("r"),
-            Key::S => letter("s"),
-            Key::T => letter("t"),
-            Key::U => letter("u"),
-            Key::V => letter("v"),
-            Key::W => letter("w"),
-            Key::X => letter("x"),
-            Key::Y => letter("y"),
-            Key::Z => letter("z"),
-
-            Key::LeftSquareBracket => symbol("[", "{"),
-            Key::RightSquareBracket => symbol("]", "}"),
-            Key::Backslash => symbol("\\", "|"),
-            Key::Semicolon => symbol(";", ":"),
-            Key::Apostrophe => symbol("'", "\""),
-            Key::Enter => symbol("\n", "\n"),
-
-            Key::Comma => symbol(",", "<"),
-            Key::Period => symbol(".", ">"),
-            Key::Slash => symbol("/", "?"),
-
-            Key::NumpadSlash => symbol("/", "/"),
-            Key::NumpadAsterisk => symbol("*", "*"),
-            Key::NumpadMinus => symbol("-", "-"),
-            Key::NumpadPlus => symbol("+", "+"),
-            Key::NumpadEnter => symbol("\n", "\n"),
-            Key::NumpadPeriod => no_numlock_symbol("."),
-
-            Key::NumpadOne => no_numlock_symbol("1"),
-            Key::NumpadTwo => no_numlock_symbol("2"),
-            Key::NumpadThree => no_numlock_symbol("3"),
-            Key::NumpadFour => no_numlock_symbol("4"),
-            Key::NumpadFive => no_numlock_symbol("5"),
-            Key::NumpadSix => no_numlock_symbol("6"),
-            Key::NumpadSeven => no_numlock_symbol("7"),
-            Key::NumpadEight => no_numlock_symbol("8"),
-            Key::NumpadNine => no_numlock_symbol("9"),
-            Key::NumpadZero => no_numlock_symbol("0"),
-
-            _ => ResolveEvent::None,
+    /// Repeats the held key if it is time to.
+    fn repeat_key(&mut self) {
+        if let Some(key) = self.key_repeat.due(timer::uptime_ms()) {
+            let event = Event { key, pressed: true };
+            if let Some(input) = self.keymap.translate(&event) {
+                self.handle_input(input);
+            }
         }
     }
 
-    fn is_uppercase(&self) -> bool {
-        self.shift || self.caps_lock
+    fn handle_input(&mut self, input: KeyInput) {
+        match input {
+            KeyInput::Key(Key::PageUp) | KeyInput::Key(Key::PageDown)
+                if self.keymap.shift() =>
+            {
+                scroll_back(input == KeyInput::Key(Key::PageUp));
+                return;
+            }
+            _ => leave_scrollback(),
+        }
+
+        if let KeyInput::Char(ch) = input {
+            self.process_input(ch);
+        }
+        if self.has_input() {
+            READERS.wake_all();
+        }
     }
 }
 
 impl EventListener for Console {
     fn receive_event(&mut self, event: Event) {
-        if self.kbd_events.len() < MAX_KBD_EVENTS {
-            self.kbd_events.push_back(event);
-            if let Some(task_id) = self.task_blocked_by_read {
-                self.task_blocked_by_read = None;
-                unsafe {
-                    TASK_MANAGER.unblock_task(task_id);
-                }
-            }
-        } else {
-            println!("[CONSOLE] Keyboard event buffer is full.");
+        if event.pressed && self.key_repeat.is_held(event.key) {
+            // Repeated by the keyboard, see KeyRepeat.
+            return;
+        } else if !event.pressed {
+            self.key_repeat.release(event.key);
+        }
+
+        // Only the keys that mean something are repeated, e.g. not the
+        // modifiers.
+        if let Some(input) = self.keymap.translate(&event) {
+            self.key_repeat.press(event.key, timer::uptime_ms());
+            self.handle_input(input);
         }
     }
 }
 
 impl CharDevice for Console {
+    /// Reads one character, an end of file cannot be read this way.
     fn read(&mut self) -> Result<u8, ReadErr> {
-        let task_id = unsafe { TASK_MANAGER.this_task().id };
-
-        let maybe_ascii = self.try_resolve_into_ascii();
-        if self.task_blocked_by_read.is_none() {
-            if let Some(ascii) = maybe_ascii {
-                self.write(ascii).unwrap();
-                Ok(ascii)
-            } else {
-                self.task_blocked_by_read = Some(task_id);
-                Err(ReadErr::Block)
-            }
-        } else {
-            Err(ReadErr::NotReadable)
+        let mut buf = [0];
+        match self.read_many(&mut buf)? {
+            0 => Err(ReadErr::NotReadable),
+            _ => Ok(buf[0]),
         }
     }
 
+    /// Reads a line in the canonical mode or the typed characters in the raw
+    /// mode, blocks if there is nothing to read.  Returns 0 at an end of
+    /// file.
     fn read_many(&mut self, buf: &mut [u8]) -> Result<usize, ReadErr> {
-        let task_id = unsafe { TASK_MANAGER.this_task().id };
-
-        let ch = self.read()?;
-        if self.current_buf_idx < buf.len() {
-            buf[self.current_buf_idx] = ch;
-            self.current_buf_idx += 1;
-        } else {
-            // Discard characters which would overflow the buffer.
+        if buf.is_empty() {
+            return Err(ReadErr::InvalidLen);
         }
-        if ch == 0x0A {
-            // Newline.
-            let bytes_read = self.current_buf_idx;
-            self.current_buf_idx = 0;
-            Ok(bytes_read)
-        } else {
-            self.task_blocked_by_read = Some(task_id);
-            Err(ReadErr::Block)
+        match self.take_input(buf) {
+            Some(len) => Ok(len),
+            // The caller waits until receive_event() wakes it.
+            None => Err(ReadErr::Block(&READERS)),
         }
     }
 
@@ -272,12 +359,172 @@ impl CharDevice for Console {
         }
         Ok(())
     }
+
+    /// Supports [TCGETS] and [TCSETS] with the [ICANON] and [ECHO] local modes,
+    /// and [TIOCGWINSZ].  The input mode is switched only if it changes, since
+    /// that discards the unread input.
+    fn ioctl(&mut self, request: u32, arg: usize) -> Result<u32, Errno> {
+        match request {
+            TCGETS => {
+                let mut local_modes = 0;
+                if self.mode == InputMode::Canonical {
+                    local_modes |= ICANON;
+                }
+                if self.echo {
+                    local_modes |= ECHO;
+                }
+                usercopy::write_to_user(arg, Termios { local_modes })?;
+                Ok(0)
+            }
+            TCSETS => {
+                let termios = usercopy::read_from_user::<Termios>(arg)?;
+                let mode = if termios.local_modes & ICANON != 0 {
+                    InputMode::Canonical
+                } else {
+                    InputMode::Raw
+                };
+                if mode != self.mode {
+                    self.set_input_mode(mode);
+                }
+                self.set_echo(termios.local_modes & ECHO != 0);
+                Ok(0)
+            }
+            TIOCGWINSZ => {
+                let screen = vga::Screen::current();
+                let win_size = WinSize {
+                    rows: screen.rows() as u16,
+                    cols: screen.cols() as u16,
+                    x_pixels: 0,
+                    y_pixels: 0,
+                };
+                usercopy::write_to_user(arg, win_size)?;
+                Ok(0)
+            }
+            _ => Err(Errno::ENOTTY),
+        }
+    }
 }
 
-enum ResolveEvent {
-    None,
-    Ascii(u8),
-    FlagUpdate,
+/// Rows that have scrolled off the top of the VGA text buffer.
+///
+/// Only the text mode has one, the framebuffer has no cells that the live
+/// screen could be saved from.
+struct Scrollback {
+    /// Ring of the scrolled off rows, the oldest one is at `start`.
+    rows: Vec<vga::Row>,
+    start: usize,
+    /// Number of rows the view is scrolled back by, 0 in the live view.
+    offset: usize,
+    /// Live screen saved while the view is scrolled back.
+    live: Vec<vga::Row>,
+    /// Cursor position saved while the view is scrolled back.
+    live_cursor: Option<(usize, usize)>,
+}
+
+impl Scrollback {
+    fn new() -> Self {
+        Scrollback {
+            rows: Vec::with_capacity(SCROLLBACK_ROWS),
+            start: 0,
+            offset: 0,
+            live: Vec::with_capacity(vga::BUFFER_HEIGHT),
+            live_cursor: None,
+        }
+    }
+
+    fn push(&mut self, row: &vga::Row) {
+        if self.rows.len() < SCROLLBACK_ROWS {
+            self.rows.push(*row);
+        } else {
+            self.rows[self.start] = *row;
+            self.start = (self.start + 1) % SCROLLBACK_ROWS;
+        }
+    }
+
+    /// Returns a row of the saved rows followed by the live screen.
+    fn row(&self, idx: usize) -> &vga::Row {
+        if idx < self.rows.len() {
+            &self.rows[(self.start + idx) % SCROLLBACK_ROWS]
+        } else {
+            &self.live[idx - self.rows.len()]
+        }
+    }
+
+    /// Shows the screen scrolled back by `offset` rows.
+    fn show(&mut self, offset: usize) {
+        let offset = cmp::min(offset, self.rows.len());
+        if offset == self.offset {
+            return;
+        }
+        if self.offset == 0 {
+            self.live.clear();
+            for row in 0..vga::BUFFER_HEIGHT {
+                self.live.push(vga::read_row(row));
+            }
+            self.live_cursor = vga::get_cursor();
+            vga::disable_cursor();
+        }
+        self.offset = offset;
+        let first = self.rows.len() - offset;
+        for row in 0..vga::BUFFER_HEIGHT {
+            vga::write_row(row, self.row(first + row));
+        }
+        if offset == 0 {
+            if let Some((row, col)) = self.live_cursor {
+                vga::set_cursor(row, col);
+            }
+            vga::enable_cursor();
+        }
+        SCROLLED_BACK.store(offset != 0, Ordering::SeqCst);
+    }
+}
+
+kernel_static! {
+    static ref SCROLLBACK: Mutex<Option<Scrollback>> = Mutex::new(None);
+}
+
+/// Whether the screen shows the scrollback and not the live view.
+static SCROLLED_BACK: AtomicBool = AtomicBool::new(false);
+
+/// Saves a row that is about to scroll off the top of the VGA text buffer.
+pub fn save_scrolled_row(row: &vga::Row) {
+    interrupts::with_disabled(|| {
+        if let Some(scrollback) = SCROLLBACK.lock().as_mut() {
+            scrollback.push(row);
+        }
+    });
+}
+
+/// Scrolls the view one page up or down.
+fn scroll_back(up: bool) {
+    interrupts::with_disabled(|| {
+        if let Some(scrollback) = SCROLLBACK.lock().as_mut() {
+            // Keep one row of the previous page for context.
+            let page = vga::BUFFER_HEIGHT - 1;
+            let offset = if up {
+                scrollback.offset + page
+            } else {
+                scrollback.offset.saturating_sub(page)
+            };
+            scrollback.show(offset);
+        }
+    });
+}
+
+/// Returns to the live view if the screen shows the scrollback.
+pub fn leave_scrollback() {
+    if SCROLLED_BACK.load(Ordering::SeqCst) {
+        interrupts::with_disabled(|| {
+            if let Some(scrollback) = SCROLLBACK.lock().as_mut() {
+                scrollback.show(0);
+            }
+        });
+    }
+}
+
+// Tasks waiting for the console input.
+kernel_static! {
+    static ref READERS: WaitQueue = WaitQueue::new();
 }
 
 kernel_static! {
@@ -285,9 +532,36 @@ kernel_static! {
         = Mutex::new(Some(Rc::new(RefCell::new(Console::new()))));
 }
 
+/// Runs `f` on the console with the interrupts disabled, so that the keyboard
+/// IRQ handler does not find it borrowed.
+pub fn with_console<R>(f: impl FnOnce(&mut Console) -> R) -> R {
+    interrupts::with_disabled(|| {
+        let console = CONSOLE.lock();
+        let mut console = console.as_ref().unwrap().borrow_mut();
+        f(&mut console)
+    })
+}
+
+/// Repeats the held key, called on every timer tick.
+fn repeat_held_key(_: usize) {
+    // The tick may interrupt the code that uses the console.
+    if let Some(console) = CONSOLE.try_lock() {
+        if let Ok(mut console) = console.as_ref().unwrap().try_borrow_mut() {
+            console.repeat_key();
+        }
+    }
+}
+
 pub fn init() {
-    unsafe {
-        let rc_console = Rc::clone(&CONSOLE.lock().as_ref().unwrap());
-        KEYBOARD.as_mut().unwrap().set_listener(rc_console);
+    timer::every_tick(repeat_held_key, 0);
+    if vga::is_text_mode() {
+        *SCROLLBACK.lock() = Some(Scrollback::new());
     }
+    let rc_console = Rc::clone(&CONSOLE.lock().as_ref().unwrap());
+    rc_console.borrow().keymap.update_leds();
+    interrupts::with_disabled(|| {
+        if let Some(keyboard) = KEYBOARD.lock().as_mut() {
+            keyboard.add_listener(rc_console);
+        }
+    });
 }
@@ -16,14 +16,16 @@
 
 use alloc::rc::Rc;
 use alloc::vec::Vec;
-use core::cell::RefCell;
 use core::mem::align_of;
 use core::slice;
 
 use crate::arch::dev::pic::PIC;
+use crate::arch::dev::pit::Pit;
 use crate::arch::interrupts::{InterruptStackFrame, IDT, STAGE2_IRQ15_HANDLER};
+use crate::arch::tsc::profile_scope;
 use crate::dev::disk::{ReadErr, ReadWriteInterface, WriteErr};
 use crate::port::{Port, PortBuilder};
+use crate::sync::Mutex;
 
 extern "C" {
     // See interrupts.s
@@ -92,8 +94,9 @@ impl Bus {
                 val &= !(1 << 4); // DRV
                 val |= (matches!(drive, DriveId::Slave) as u8) << 4;
                 self.registers.drive.write(val);
-                // FIXME: 400ns delay?
             }
+            // The drive takes 400 ns to switch.
+            Pit::one_shot_poll_us(1);
             self.selected_drive = drive;
         }
     }
@@ -133,9 +136,7 @@ impl Bus {
             self.wait_until_ready();
 
             let mut buf = [0u16; 256];
-            for i in 0..256 {
-                buf[i] = self.registers.data.read();
-            }
+            self.registers.data.read_slice_u16(&mut buf);
 
             Some(buf)
         }
@@ -223,13 +224,23 @@ impl Bus {
             self.registers.command.write(0x20u8);
         }
 
-        for i in 0..num_sectors {
+        for sector in buf.chunks_exact_mut(512) {
             self.wait_until_ready();
-            for j in 0..256 {
-                let word: u16 = unsafe { self.registers.data.read() };
-                let idx = (i as usize) * 512 + j * 2;
-                buf[idx] = word as u8;
-                buf[idx + 1] = (word >> 8) as u8;
+            // The words are little-endian, so they are read right into the
+            // buffer unless it is misaligned.
+            match unsafe { sector.align_to_mut::<u16>() } {
+                ([], words, []) => unsafe {
+                    self.registers.data.read_slice_u16(words);
+                },
+                _ => {
+                    let mut words = [0u16; 256];
+                    unsafe {
+                        self.registers.data.read_slice_u16(&mut words);
+                    }
+                    for (dst, word) in sector.chunks_exact_mut(2).zip(&words) {
+                        dst.copy_from_slice(&word.to_le_bytes());
+                    }
+                }
             }
         }
 
@@ -244,13 +255,10 @@ impl Bus {
             self.set_lba(lba);
             self.registers.command.write(0x30u8);
         }
-        self.wait_until_ready();
-        for (i, &word) in data.iter().enumerate() {
-            if i % 256 == 0 {
-                self.wait_until_ready();
-            }
+        for sector in data.chunks_exact(256) {
+            self.wait_until_ready();
             unsafe {
-                self.registers.data.write(word);
+                self.registers.data.write_slice_u16(sector);
             }
         }
     }
@@ -281,13 +289,12 @@ pub struct Drive {
     // 2) Second, an Rc is used because an ATA bus has a master and a slave
     //    drives which are separate Disks for the kernel; both point to the same
     //    Bus, so a shared pointer is necessary.
-    // 3) Third, a RefCell is used for interior mutability with runtime checks:
-    //    interior mutability allows the Drive methods to mutate its Bus state
-    //    without the Drive itself being mutable, otherwise the
-    //    ReadWriteInterface methods would need to be mutable as well, and that
-    //    would make things even harder; using compile-time checks seemed
-    //    impossible to me for a similar reason.
-    bus: Option<Rc<RefCell<Bus>>>,
+    // 3) Third, a sleeping Mutex is used for interior mutability: it allows the
+    //    Drive methods to mutate its Bus state without the Drive itself being
+    //    mutable, otherwise the ReadWriteInterface methods would need to be
+    //    mutable as well.  The bus is held for a whole PIO transfer, so the
+    //    tasks using the other drive on the same bus sleep instead of spinning.
+    bus: Option<Rc<Mutex<Bus>>>,
     id: DriveId,
     supports_lba48: bool,
     num_sectors_lba28: u32,
@@ -327,7 +334,7 @@ impl ReadWriteInterface for Drive {
         block_idx: usize,
         buf: &mut [u8],
     ) -> Result<usize, ReadErr> {
-        let mut bus = self.bus.as_ref().unwrap().borrow_mut();
+        let mut bus = self.bus.as_ref().unwrap().lock();
         bus.select_drive(self.id);
         if self.has_block(block_idx) {
             Ok(bus.read(block_idx as u32, buf))
@@ -341,6 +348,7 @@ impl ReadWriteInterface for Drive {
         first_block_idx: usize,
         buf: &mut [u8],
     ) -> Result<usize, ReadErr> {
+        profile_scope!("ata read_blocks");
         assert_eq!(buf.len() % self.block_size(), 0); // FIXME: Err(...)
 
         let num_blocks = buf.len() / self.block_size();
@@ -352,7 +360,7 @@ impl ReadWriteInterface for Drive {
             return Err(ReadErr::TooMuchBlocks);
         }
 
-        let mut bus = self.bus.as_ref().unwrap().borrow_mut();
+        let mut bus = self.bus.as_ref().unwrap().lock();
         bus.select_drive(self.id);
 
         if self.has_block(first_block_idx) {
@@ -367,7 +375,7 @@ impl ReadWriteInterface for Drive {
         block_idx: usize,
         data: [u8; 512],
     ) -> Result<(), WriteErr> {
-        let mut bus = self.bus.as_ref().unwrap().borrow_mut();
+        let mut bus = self.bus.as_ref().unwrap().lock();
         bus.select_drive(self.id);
         if !self.has_block(block_idx) {
             Err(WriteErr::NoSuchBlock)
@@ -383,13 +391,14 @@ impl ReadWriteInterface for Drive {
         first_block_idx: usize,
         data: &[u8],
     ) -> Result<(), WriteErr> {
+        profile_scope!("ata write_blocks");
         if data.len() == 0 {
             return Err(WriteErr::EmptyDataPassed);
         }
         assert_eq!(data.len() % self.block_size(), 0, "invalid data size");
         let num_blocks = data.len() / self.block_size();
 
-        let mut bus = self.bus.as_ref().unwrap().borrow_mut();
+        let mut bus = self.bus.as_ref().unwrap().lock();
         bus.select_drive(self.id);
 
         let last_block_idx = first_block_idx + num_blocks - 1;
@@ -489,23 +498,21 @@ pub unsafe fn init() -> Vec<Drive> {
     // 2. Prepare shared pointers to the buses.
     let primary = Bus::new(ATA0_PORT_IO_BASE, ATA0_PORT_CONTROL_BASE);
     let secondary = Bus::new(ATA1_PORT_IO_BASE, ATA1_PORT_CONTROL_BASE);
-    let rc_buses = [
-        Rc::new(RefCell::new(primary)),
-        Rc::new(RefCell::new(secondary)),
-    ];
+    let rc_buses =
+        [Rc::new(Mutex::new(primary)), Rc::new(Mutex::new(secondary))];
 
     // 3. Check for the drives.
     let mut all_drives = Vec::new();
     for (i, rc_bus) in rc_buses.iter().enumerate() {
         println!("[ATA] Initializing bus {}.", i);
-        if rc_bus.borrow().registers.status.read::<u8>() == 0xFF {
+        if rc_bus.lock().registers.status.read::<u8>() == 0xFF {
             println!("[ATA] Ignoring a floating bus.");
             continue;
         }
 
         // 4. Connect each Drive to its Bus.  This is not done in Bus::init_etc.
         //    because I've found that somewhat difficult.
-        let mut drives = rc_bus.borrow_mut().init_and_get_drives();
+        let mut drives = rc_bus.lock().init_and_get_drives();
         if let Some(master) = &mut drives[0] {
             master.bus = Some(Rc::clone(&rc_bus));
             all_drives.push(master.clone())
@@ -520,14 +527,14 @@ pub unsafe fn init() -> Vec<Drive> {
 
 #[no_mangle]
 pub extern "C" fn ata_irq14_handler(_: &InterruptStackFrame) {
-    println!("[ATA] IRQ 14");
+    try_println!("[ATA] IRQ 14");
     unsafe {
         PIC.send_eoi(14);
     }
 }
 
 pub fn ata_irq15_handler(_: &InterruptStackFrame) {
-    println!("[ATA] IRQ 15");
+    try_println!("[ATA] IRQ 15");
     unsafe {
         PIC.send_eoi(15);
     }
@@ -0,0 +1,89 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::{ReadErr, ReadWriteInterface, WriteErr};
+
+/// Read-only disk backed by memory, e.g. a boot module with a disk image.
+pub struct MemBlockDevice {
+    data: &'static [u8],
+}
+
+impl MemBlockDevice {
+    pub fn new(data: &'static [u8]) -> Self {
+        MemBlockDevice { data }
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.data.len() / self.block_size()
+    }
+}
+
+impl ReadWriteInterface for MemBlockDevice {
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    fn has_block(&self, block_idx: usize) -> bool {
+        block_idx < self.num_blocks()
+    }
+
+    fn read_block(
+        &self,
+        block_idx: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, ReadErr> {
+        self.read_blocks(block_idx, &mut buf[..self.block_size()])
+    }
+
+    fn read_blocks(
+        &self,
+        first_block_idx: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, ReadErr> {
+        assert_eq!(buf.len() % self.block_size(), 0); // FIXME: Err(...)
+
+        let num_blocks = buf.len() / self.block_size();
+        if num_blocks == 0 {
+            return Err(ReadErr::InvalidNumBlocks);
+        }
+        if !self.has_block(first_block_idx) {
+            return Err(ReadErr::NoSuchBlock);
+        }
+        if first_block_idx + num_blocks > self.num_blocks() {
+            return Err(ReadErr::TooMuchBlocks);
+        }
+
+        let start = first_block_idx * self.block_size();
+        buf.copy_from_slice(&self.data[start..start + buf.len()]);
+        Ok(buf.len())
+    }
+
+    fn write_block(
+        &self,
+        _block_idx: usize,
+        _data: [u8; 512],
+    ) -> Result<(), WriteErr> {
+        Err(WriteErr::NotWritable)
+    }
+
+    fn write_blocks(
+        &self,
+        _first_block_idx: usize,
+        _data: &[u8],
+    ) -> Result<(), WriteErr> {
+        Err(WriteErr::NotWritable)
+    }
+}
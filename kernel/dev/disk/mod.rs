@@ -15,6 +15,7 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 pub mod ata;
+pub mod mem;
 
 use alloc::rc::Rc;
 use alloc::vec;
@@ -253,6 +254,7 @@ pub enum WriteErr {
     NoSuchBlock,
     TooMuchBlocks,
     EmptyDataPassed,
+    NotWritable,
 }
 
 kernel_static! {
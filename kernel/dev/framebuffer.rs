@@ -0,0 +1,152 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Linear RGB framebuffer set up by the bootloader.
+
+use core::ptr;
+
+use crate::dev::font::{self, FONT_HEIGHT, FONT_WIDTH};
+
+/// RGB values of the VGA text mode colors (see [Color](super::vga::Color)).
+const VGA_PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0x00, 0x00, 0xAA),
+    (0x00, 0xAA, 0x00),
+    (0x00, 0xAA, 0xAA),
+    (0xAA, 0x00, 0x00),
+    (0xAA, 0x00, 0xAA),
+    (0xAA, 0x55, 0x00),
+    (0xAA, 0xAA, 0xAA),
+    (0x55, 0x55, 0x55),
+    (0x55, 0x55, 0xFF),
+    (0x55, 0xFF, 0x55),
+    (0x55, 0xFF, 0xFF),
+    (0xFF, 0x55, 0x55),
+    (0xFF, 0x55, 0xFF),
+    (0xFF, 0xFF, 0x55),
+    (0xFF, 0xFF, 0xFF),
+];
+
+/// Framebuffer with 32 bits per pixel and 8 bits per color component.
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    /// Physical address of the framebuffer.
+    pub phys: usize,
+    /// Address the framebuffer is accessed at.
+    ///
+    /// It is the same as `phys` until the framebuffer is mapped into the
+    /// kernel VAS, since the boot page directory maps the physical memory
+    /// one-to-one.
+    pub virt: usize,
+    /// Number of bytes in a row of pixels.
+    pub pitch: usize,
+    pub width: usize,
+    pub height: usize,
+    pub red_pos: u8,
+    pub green_pos: u8,
+    pub blue_pos: u8,
+}
+
+impl Framebuffer {
+    /// Returns the size of the framebuffer in bytes.
+    pub fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+
+    /// Returns the number of text columns that fit in the framebuffer.
+    pub fn text_cols(&self) -> usize {
+        self.width / FONT_WIDTH
+    }
+
+    /// Returns the number of text rows that fit in the framebuffer.
+    pub fn text_rows(&self) -> usize {
+        self.height / FONT_HEIGHT
+    }
+
+    /// Converts a VGA color index into a pixel value.
+    fn pixel(&self, vga_color: u8) -> u32 {
+        let (r, g, b) = VGA_PALETTE[vga_color as usize & 0xF];
+        (r as u32) << self.red_pos
+            | (g as u32) << self.green_pos
+            | (b as u32) << self.blue_pos
+    }
+
+    fn row_ptr(&self, y: usize) -> *mut u32 {
+        (self.virt + y * self.pitch) as *mut u32
+    }
+
+    /// Draws `ch` in the text cell at `row` and `col` with the VGA attribute
+    /// `attr`, i.e. the background color in the high nibble and the foreground
+    /// color in the low one.
+    pub fn draw_char(&self, row: usize, col: usize, ch: u8, attr: u8) {
+        let fg = self.pixel(attr & 0xF);
+        let bg = self.pixel(attr >> 4);
+        let glyph = font::glyph(ch);
+        for (dy, bits) in glyph.iter().enumerate() {
+            let line = self.row_ptr(row * FONT_HEIGHT + dy);
+            for dx in 0..FONT_WIDTH {
+                let pixel = if bits & (0x80 >> dx) != 0 { fg } else { bg };
+                unsafe {
+                    line.add(col * FONT_WIDTH + dx).write_volatile(pixel);
+                }
+            }
+        }
+    }
+
+    /// Draws the cursor, an underline in the foreground color of `attr`, in
+    /// the text cell at `row` and `col`.
+    pub fn draw_cursor(&self, row: usize, col: usize, attr: u8) {
+        let fg = self.pixel(attr & 0xF);
+        for dy in FONT_HEIGHT - 2..FONT_HEIGHT {
+            let line = self.row_ptr(row * FONT_HEIGHT + dy);
+            for dx in 0..FONT_WIDTH {
+                unsafe {
+                    line.add(col * FONT_WIDTH + dx).write_volatile(fg);
+                }
+            }
+        }
+    }
+
+    /// Moves the text rows up by `num_rows`, the last rows are left as they
+    /// were.
+    pub fn scroll_up(&self, num_rows: usize) {
+        let rows = self.text_rows();
+        if num_rows >= rows {
+            return;
+        }
+        let row_bytes = FONT_HEIGHT * self.pitch;
+        unsafe {
+            ptr::copy(
+                (self.virt + num_rows * row_bytes) as *const u8,
+                self.virt as *mut u8,
+                (rows - num_rows) * row_bytes,
+            );
+        }
+    }
+
+    /// Fills the text row `row` with the background color of `attr`.
+    pub fn clear_row(&self, row: usize, attr: u8) {
+        let bg = self.pixel(attr >> 4);
+        for y in row * FONT_HEIGHT..(row + 1) * FONT_HEIGHT {
+            let line = self.row_ptr(y);
+            for x in 0..self.width {
+                unsafe {
+                    line.add(x).write_volatile(bg);
+                }
+            }
+        }
+    }
+}
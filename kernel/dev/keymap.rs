@@ -0,0 +1,557 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Translation of the keyboard events into the typed characters.
+//!
+//! What a key types is determined by the current [layout](set_layout), which
+//! can be selected with the `kbd=<name>` command line option.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::dev::keyboard;
+use crate::arch::dev::keyboard::{Event, Key, NUM_KEYS};
+use crate::cmdline;
+
+/// What a key press means to the reader of the keyboard.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum KeyInput {
+    /// Typed character.  Enter, Tab, Backspace and Escape type the control
+    /// characters `\n`, `\t`, `\x08` and `\x1B`, Ctrl+<key> types the control
+    /// character of the key, e.g. `\x03` for Ctrl+C.
+    Char(char),
+    /// Press of a key that does not type a character, e.g. an arrow.
+    Key(Key),
+}
+
+/// State of the modifier keys, which determines what the other keys type.
+pub struct Keymap {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+}
+
+impl Keymap {
+    pub const fn new() -> Self {
+        Keymap {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            // The BIOS turns Num Lock on.
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+
+    /// Makes the keyboard LEDs show the state of the locks.
+    pub fn update_leds(&self) {
+        keyboard::set_leds(self.scroll_lock, self.num_lock, self.caps_lock);
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    /// The left Alt key, the right one is AltGr.
+    #[allow(dead_code)]
+    pub fn alt(&self) -> bool {
+        self.alt
+    }
+
+    /// Updates the modifiers and translates a key press with the current
+    /// layout.  Returns `None` for the releases, the modifier keys and the
+    /// keys that mean nothing in the current state.
+    pub fn translate(&mut self, event: &Event) -> Option<KeyInput> {
+        let input = self.translate_in(current_layout(), event);
+        if !event.pressed && is_lock(event.key) {
+            self.update_leds();
+        }
+        input
+    }
+
+    /// Same as [translate](Self::translate), but with the layout `id` and
+    /// without touching the LEDs.
+    pub fn translate_with(
+        &mut self,
+        id: LayoutId,
+        event: &Event,
+    ) -> Option<KeyInput> {
+        self.translate_in(LAYOUTS[id as usize], event)
+    }
+
+    fn translate_in(
+        &mut self,
+        layout: &Layout,
+        event: &Event,
+    ) -> Option<KeyInput> {
+        if self.update_modifiers(event) || !event.pressed {
+            return None;
+        }
+        let key = match self.numpad_nav_key(event.key) {
+            Some(nav_key) => return nav_key.map(KeyInput::Key),
+            None => event.key,
+        };
+        match self.char_of(layout, key) {
+            Some(ch) if self.ctrl() => {
+                Some(KeyInput::Char(control_char(ch).unwrap_or(ch)))
+            }
+            Some(ch) => Some(KeyInput::Char(ch)),
+            None => Some(KeyInput::Key(key)),
+        }
+    }
+
+    /// Returns whether the event is about a modifier key.
+    fn update_modifiers(&mut self, event: &Event) -> bool {
+        let pressed = event.pressed;
+        match event.key {
+            Key::LeftShift => self.left_shift = pressed,
+            Key::RightShift => self.right_shift = pressed,
+            Key::LeftCtrl => self.left_ctrl = pressed,
+            Key::RightCtrl => self.right_ctrl = pressed,
+            Key::LeftAlt => self.alt = pressed,
+            Key::RightAlt => self.alt_gr = pressed,
+            // The locks toggle on the release so that the typematic repeat of
+            // a held key does not toggle them again and again.
+            Key::CapsLock => {
+                if !pressed {
+                    self.caps_lock = !self.caps_lock;
+                }
+            }
+            Key::NumLock => {
+                if !pressed {
+                    self.num_lock = !self.num_lock;
+                }
+            }
+            Key::ScrollLock => {
+                if !pressed {
+                    self.scroll_lock = !self.scroll_lock;
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Returns the navigation key that a numpad key acts as with Num Lock
+    /// off, or `None` if `key` types its character from the layout.
+    fn numpad_nav_key(&self, key: Key) -> Option<Option<Key>> {
+        if self.num_lock {
+            return None;
+        }
+        Some(match key {
+            Key::NumpadOne => Some(Key::End),
+            Key::NumpadTwo => Some(Key::DownArrow),
+            Key::NumpadThree => Some(Key::PageDown),
+            Key::NumpadFour => Some(Key::LeftArrow),
+            Key::NumpadFive => None,
+            Key::NumpadSix => Some(Key::RightArrow),
+            Key::NumpadSeven => Some(Key::Home),
+            Key::NumpadEight => Some(Key::UpArrow),
+            Key::NumpadNine => Some(Key::PageUp),
+            Key::NumpadZero => Some(Key::Insert),
+            Key::NumpadPeriod => Some(Key::Delete),
+            _ => return None,
+        })
+    }
+
+    /// Returns the character that `key` types with the current modifiers.
+    fn char_of(&self, layout: &Layout, key: Key) -> Option<char> {
+        let [normal, shifted, alt_gr] = layout.keys[key as usize];
+        if self.alt_gr {
+            return alt_gr;
+        }
+        // Caps Lock affects only the letters and is reversed by Shift.
+        let is_letter = match (normal, shifted) {
+            (Some(normal), Some(shifted)) => {
+                normal.is_lowercase() && shifted.is_uppercase()
+            }
+            _ => false,
+        };
+        if self.shift() != (is_letter && self.caps_lock) {
+            shifted
+        } else {
+            normal
+        }
+    }
+}
+
+fn is_lock(key: Key) -> bool {
+    matches!(key, Key::CapsLock | Key::NumLock | Key::ScrollLock)
+}
+
+/// Returns the control character that Ctrl+`ch` types, e.g. `\x03` for
+/// Ctrl+C, or `None` if `ch` has none.
+fn control_char(ch: char) -> Option<char> {
+    match ch {
+        'a'..='z' | 'A'..='Z' | '@' | '[' | '\\' | ']' | '^' | '_' => {
+            Some((ch as u8 & 0x1F) as char)
+        }
+        _ => None,
+    }
+}
+
+/// Delay before a held key starts repeating.
+const REPEAT_DELAY_MS: u64 = 500;
+/// Interval between the repeats of a held key.
+const REPEAT_INTERVAL_MS: u64 = 50;
+
+/// Repeat of the key that is held down.
+///
+/// The keyboard repeats the held keys on its own, but that repeat is ignored
+/// and the key is repeated with the timer instead, so that the delay and the
+/// interval are under control.
+pub struct KeyRepeat {
+    /// Time between the press and the first repeat.
+    pub delay_ms: u64,
+    /// Time between the repeats.
+    pub interval_ms: u64,
+    held: Option<Key>,
+    /// Uptime of the next repeat.
+    next_repeat_ms: u64,
+}
+
+impl KeyRepeat {
+    pub const fn new() -> Self {
+        KeyRepeat {
+            delay_ms: REPEAT_DELAY_MS,
+            interval_ms: REPEAT_INTERVAL_MS,
+            held: None,
+            next_repeat_ms: 0,
+        }
+    }
+
+    /// Checks if `key` is the held key, i.e. its press is a repeat by the
+    /// keyboard.
+    pub fn is_held(&self, key: Key) -> bool {
+        self.held == Some(key)
+    }
+
+    /// Starts repeating `key`, which is pressed at `now_ms`.
+    pub fn press(&mut self, key: Key, now_ms: u64) {
+        self.held = Some(key);
+        self.next_repeat_ms = now_ms + self.delay_ms;
+    }
+
+    /// Stops repeating `key` if it is the held key.
+    pub fn release(&mut self, key: Key) {
+        if self.is_held(key) {
+            self.held = None;
+        }
+    }
+
+    /// Returns the held key if it is time to repeat it at `now_ms`.
+    pub fn due(&mut self, now_ms: u64) -> Option<Key> {
+        let key = self.held?;
+        if now_ms < self.next_repeat_ms {
+            return None;
+        }
+        self.next_repeat_ms = now_ms + self.interval_ms;
+        Some(key)
+    }
+}
+
+/// Characters that a key types without modifiers, with Shift and with AltGr.
+type KeyChars = [Option<char>; 3];
+
+/// Keyboard layout: the characters of each [Key], indexed by the key.
+pub struct Layout {
+    name: &'static str,
+    keys: [KeyChars; NUM_KEYS],
+}
+
+/// Builds the key table of a layout out of the entries shared by all the
+/// layouts and the layout's own ones.
+///
+/// Every key must have exactly one entry, which is checked at compile time
+/// since the layouts are statics.
+const fn key_table(
+    common: &[(Key, KeyChars)],
+    own: &[(Key, KeyChars)],
+) -> [KeyChars; NUM_KEYS] {
+    let mut table = [[None; 3]; NUM_KEYS];
+    let mut has_entry = [false; NUM_KEYS];
+    let mut i = 0;
+    while i < common.len() + own.len() {
+        let (key, chars) = if i < common.len() {
+            common[i]
+        } else {
+            own[i - common.len()]
+        };
+        assert!(!has_entry[key as usize], "key has two entries");
+        table[key as usize] = chars;
+        has_entry[key as usize] = true;
+        i += 1;
+    }
+    let mut key = 0;
+    while key < NUM_KEYS {
+        assert!(has_entry[key], "key has no entry");
+        key += 1;
+    }
+    table
+}
+
+/// Key that types nothing.
+const fn none(key: Key) -> (Key, KeyChars) {
+    (key, [None, None, None])
+}
+
+/// Key that types `normal` and `shifted` with Shift.
+const fn chars(key: Key, normal: char, shifted: char) -> (Key, KeyChars) {
+    (key, [Some(normal), Some(shifted), None])
+}
+
+/// Key that also types `alt_gr` with AltGr.
+const fn chars3(
+    key: Key,
+    normal: char,
+    shifted: char,
+    alt_gr: char,
+) -> (Key, KeyChars) {
+    (key, [Some(normal), Some(shifted), Some(alt_gr)])
+}
+
+/// Keys that are the same in all the layouts.
+const COMMON_KEYS: &[(Key, KeyChars)] = &[
+    chars(Key::Escape, '\x1B', '\x1B'),
+    chars(Key::Tab, '\t', '\t'),
+    chars(Key::Backspace, '\x08', '\x08'),
+    chars(Key::Enter, '\n', '\n'),
+    chars(Key::Space, ' ', ' '),
+    none(Key::CapsLock),
+    none(Key::LeftShift),
+    none(Key::RightShift),
+    none(Key::LeftCtrl),
+    none(Key::RightCtrl),
+    none(Key::LeftAlt),
+    none(Key::RightAlt),
+    none(Key::Menu),
+    none(Key::Logo),
+    none(Key::F1),
+    none(Key::F2),
+    none(Key::F3),
+    none(Key::F4),
+    none(Key::F5),
+    none(Key::F6),
+    none(Key::F7),
+    none(Key::F8),
+    none(Key::F9),
+    none(Key::F10),
+    none(Key::F11),
+    none(Key::F12),
+    none(Key::PrintScreenSysRq),
+    none(Key::PauseBreak),
+    none(Key::NumLock),
+    none(Key::ScrollLock),
+    none(Key::Insert),
+    none(Key::Delete),
+    none(Key::Home),
+    none(Key::End),
+    none(Key::PageUp),
+    none(Key::PageDown),
+    none(Key::LeftArrow),
+    none(Key::UpArrow),
+    none(Key::DownArrow),
+    none(Key::RightArrow),
+    chars(Key::NumpadSlash, '/', '/'),
+    chars(Key::NumpadAsterisk, '*', '*'),
+    chars(Key::NumpadMinus, '-', '-'),
+    chars(Key::NumpadPlus, '+', '+'),
+    chars(Key::NumpadEnter, '\n', '\n'),
+    chars(Key::NumpadOne, '1', '1'),
+    chars(Key::NumpadTwo, '2', '2'),
+    chars(Key::NumpadThree, '3', '3'),
+    chars(Key::NumpadFour, '4', '4'),
+    chars(Key::NumpadFive, '5', '5'),
+    chars(Key::NumpadSix, '6', '6'),
+    chars(Key::NumpadSeven, '7', '7'),
+    chars(Key::NumpadEight, '8', '8'),
+    chars(Key::NumpadNine, '9', '9'),
+    chars(Key::NumpadZero, '0', '0'),
+];
+
+/// US QWERTY.
+static US: Layout = Layout {
+    name: "us",
+    keys: key_table(
+        COMMON_KEYS,
+        &[
+            chars(Key::Backtick, '`', '~'),
+            chars(Key::One, '1', '!'),
+            chars(Key::Two, '2', '@'),
+            chars(Key::Three, '3', '#'),
+            chars(Key::Four, '4', '$'),
+            chars(Key::Five, '5', '%'),
+            chars(Key::Six, '6', '^'),
+            chars(Key::Seven, '7', '&'),
+            chars(Key::Eight, '8', '*'),
+            chars(Key::Nine, '9', '('),
+            chars(Key::Zero, '0', ')'),
+            chars(Key::Minus, '-', '_'),
+            chars(Key::Equals, '=', '+'),
+            chars(Key::Q, 'q', 'Q'),
+            chars(Key::W, 'w', 'W'),
+            chars(Key::E, 'e', 'E'),
+            chars(Key::R, 'r', 'R'),
+            chars(Key::T, 't', 'T'),
+            chars(Key::Y, 'y', 'Y'),
+            chars(Key::U, 'u', 'U'),
+            chars(Key::I, 'i', 'I'),
+            chars(Key::O, 'o', 'O'),
+            chars(Key::P, 'p', 'P'),
+            chars(Key::LeftSquareBracket, '[', '{'),
+            chars(Key::RightSquareBracket, ']', '}'),
+            chars(Key::Backslash, '\\', '|'),
+            chars(Key::A, 'a', 'A'),
+            chars(Key::S, 's', 'S'),
+            chars(Key::D, 'd', 'D'),
+            chars(Key::F, 'f', 'F'),
+            chars(Key::G, 'g', 'G'),
+            chars(Key::H, 'h', 'H'),
+            chars(Key::J, 'j', 'J'),
+            chars(Key::K, 'k', 'K'),
+            chars(Key::L, 'l', 'L'),
+            chars(Key::Semicolon, ';', ':'),
+            chars(Key::Apostrophe, '\'', '"'),
+            chars(Key::NonUsBackslash, '\\', '|'),
+            chars(Key::Z, 'z', 'Z'),
+            chars(Key::X, 'x', 'X'),
+            chars(Key::C, 'c', 'C'),
+            chars(Key::V, 'v', 'V'),
+            chars(Key::B, 'b', 'B'),
+            chars(Key::N, 'n', 'N'),
+            chars(Key::M, 'm', 'M'),
+            chars(Key::Comma, ',', '<'),
+            chars(Key::Period, '.', '>'),
+            chars(Key::Slash, '/', '?'),
+            chars(Key::NumpadPeriod, '.', '.'),
+        ],
+    ),
+};
+
+/// German QWERTZ.  The dead keys type their characters right away.
+static DE: Layout = Layout {
+    name: "de",
+    keys: key_table(
+        COMMON_KEYS,
+        &[
+            chars(Key::Backtick, '^', '°'),
+            chars(Key::One, '1', '!'),
+            chars3(Key::Two, '2', '"', '²'),
+            chars3(Key::Three, '3', '§', '³'),
+            chars(Key::Four, '4', '$'),
+            chars(Key::Five, '5', '%'),
+            chars(Key::Six, '6', '&'),
+            chars3(Key::Seven, '7', '/', '{'),
+            chars3(Key::Eight, '8', '(', '['),
+            chars3(Key::Nine, '9', ')', ']'),
+            chars3(Key::Zero, '0', '=', '}'),
+            chars3(Key::Minus, 'ß', '?', '\\'),
+            chars(Key::Equals, '´', '`'),
+            chars3(Key::Q, 'q', 'Q', '@'),
+            chars(Key::W, 'w', 'W'),
+            chars3(Key::E, 'e', 'E', '€'),
+            chars(Key::R, 'r', 'R'),
+            chars(Key::T, 't', 'T'),
+            chars(Key::Y, 'z', 'Z'),
+            chars(Key::U, 'u', 'U'),
+            chars(Key::I, 'i', 'I'),
+            chars(Key::O, 'o', 'O'),
+            chars(Key::P, 'p', 'P'),
+            chars(Key::LeftSquareBracket, 'ü', 'Ü'),
+            chars3(Key::RightSquareBracket, '+', '*', '~'),
+            chars(Key::Backslash, '#', '\''),
+            chars(Key::A, 'a', 'A'),
+            chars(Key::S, 's', 'S'),
+            chars(Key::D, 'd', 'D'),
+            chars(Key::F, 'f', 'F'),
+            chars(Key::G, 'g', 'G'),
+            chars(Key::H, 'h', 'H'),
+            chars(Key::J, 'j', 'J'),
+            chars(Key::K, 'k', 'K'),
+            chars(Key::L, 'l', 'L'),
+            chars(Key::Semicolon, 'ö', 'Ö'),
+            chars(Key::Apostrophe, 'ä', 'Ä'),
+            chars3(Key::NonUsBackslash, '<', '>', '|'),
+            chars(Key::Z, 'y', 'Y'),
+            chars(Key::X, 'x', 'X'),
+            chars(Key::C, 'c', 'C'),
+            chars(Key::V, 'v', 'V'),
+            chars(Key::B, 'b', 'B'),
+            chars(Key::N, 'n', 'N'),
+            chars3(Key::M, 'm', 'M', 'µ'),
+            chars(Key::Comma, ',', ';'),
+            chars(Key::Period, '.', ':'),
+            chars(Key::Slash, '-', '_'),
+            chars(Key::NumpadPeriod, ',', ','),
+        ],
+    ),
+};
+
+/// Available layouts.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayoutId {
+    Us,
+    De,
+}
+
+static LAYOUTS: [&Layout; 2] = [&US, &DE];
+
+static CURRENT_LAYOUT: AtomicUsize = AtomicUsize::new(LayoutId::Us as usize);
+
+impl LayoutId {
+    /// Returns the layout named `name`, e.g. `us`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [LayoutId::Us, LayoutId::De]
+            .iter()
+            .copied()
+            .find(|&id| LAYOUTS[id as usize].name == name)
+    }
+}
+
+fn current_layout() -> &'static Layout {
+    LAYOUTS[CURRENT_LAYOUT.load(Ordering::SeqCst)]
+}
+
+/// Makes the keyboard type according to the layout `id`.
+pub fn set_layout(id: LayoutId) {
+    CURRENT_LAYOUT.store(id as usize, Ordering::SeqCst);
+}
+
+/// Selects the layout given with the `kbd=<name>` command line option.
+pub fn init() {
+    if let Some(name) = cmdline::get("kbd") {
+        match LayoutId::from_name(name) {
+            Some(id) => {
+                set_layout(id);
+                println!("[KEYMAP] Using the {} keyboard layout.", name);
+            }
+            None => log_warn!("[KEYMAP] Unknown keyboard layout {}.", name),
+        }
+    }
+}
@@ -16,6 +16,8 @@
 
 #[macro_use]
 pub mod vga;
+pub mod font;
+pub mod framebuffer;
 
 pub mod timer;
 
@@ -24,3 +26,5 @@ pub mod disk;
 
 pub mod char_device;
 pub mod console;
+pub mod keymap;
+pub mod sysrq;
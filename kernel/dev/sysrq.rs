@@ -0,0 +1,146 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Magic SysRq keys.
+//!
+//! Holding Alt+SysRq and pressing one of the keys below runs a debug action
+//! right from the keyboard IRQ handler, so that the state of a hung system
+//! can still be seen:
+//! * `t` - list the tasks with their saved registers and stack usage, and
+//!   trace the kernel stacks of the ones that are not running,
+//! * `m` - print the heap and the physical memory usage,
+//! * `i` - print the IRQ counters,
+//! * `b` - reboot.
+//!
+//! The actions print with [try_println] and skip whatever is locked, since
+//! the interrupted code may hold any lock.
+
+use crate::arch::dev::i8042;
+use crate::arch::dev::keyboard::{Event, Key};
+use crate::arch::dev::pic;
+use crate::arch::pmm_stack;
+use crate::arch::stack_trace;
+use crate::heap;
+use crate::task_manager::TASK_MANAGER;
+
+/// Key chord description for the boot messages.
+pub const HELP: &str = "Alt+SysRq+t (tasks), m (memory), i (IRQs), b (reboot)";
+
+/// Tracks the keys of the SysRq chord.
+pub struct SysRq {
+    left_alt: bool,
+    right_alt: bool,
+    sysrq: bool,
+}
+
+impl SysRq {
+    pub const fn new() -> Self {
+        SysRq {
+            left_alt: false,
+            right_alt: false,
+            sysrq: false,
+        }
+    }
+
+    /// Runs the action of the key pressed with Alt+SysRq.  Returns `true` if
+    /// the event is a part of the chord and must not be passed further.
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        match event.key {
+            Key::LeftAlt => self.left_alt = event.pressed,
+            Key::RightAlt => self.right_alt = event.pressed,
+            Key::PrintScreenSysRq => {
+                let was_held = self.sysrq;
+                self.sysrq = event.pressed && (self.left_alt || self.right_alt);
+                return self.sysrq || was_held;
+            }
+            key if self.sysrq => {
+                if event.pressed {
+                    run_action(key);
+                }
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+fn run_action(key: Key) {
+    match key {
+        Key::T => dump_tasks(),
+        Key::M => dump_memory(),
+        Key::I => dump_irq_counts(),
+        Key::B => {
+            try_println!("[SYSRQ] Rebooting.");
+            i8042::reset_cpu();
+            try_println!("[SYSRQ] Reboot failed.");
+        }
+        _ => try_println!("[SYSRQ] Keys: {}.", HELP),
+    }
+}
+
+fn dump_tasks() {
+    try_println!("[SYSRQ] Tasks:");
+    // The lists may be in the middle of a change, but it is a debug dump
+    // anyway.
+    unsafe {
+        TASK_MANAGER.dump();
+        for task in TASK_MANAGER.tasks() {
+            if let Some(trace) = stack_trace::of_task(task) {
+                try_println!(
+                    "[SYSRQ] Task ID {} ({}), {}:",
+                    task.id,
+                    task.name(),
+                    task.state().name(),
+                );
+                try_print!("{}", trace);
+            }
+        }
+    }
+}
+
+fn dump_memory() {
+    match heap::try_usage() {
+        Some(usage) => try_println!(
+            "[SYSRQ] Heap: {} bytes, used: {}, free: {}, peak: {}, \
+             allocations: {}.",
+            usage.total,
+            usage.used,
+            usage.free,
+            usage.peak,
+            usage.allocations,
+        ),
+        None => try_println!("[SYSRQ] Heap is locked."),
+    }
+    match pmm_stack::try_stats() {
+        Some(stats) => try_println!(
+            "[SYSRQ] Pages: {}, free: {}, max used: {}.",
+            stats.total_pages,
+            stats.free_pages,
+            stats.max_used_pages,
+        ),
+        None => try_println!("[SYSRQ] PMM stack is locked."),
+    }
+}
+
+fn dump_irq_counts() {
+    for irq in 0..16 {
+        let count = pic::irq_count(irq);
+        if count != 0 {
+            try_println!("[SYSRQ] IRQ {}: {}.", irq, count);
+        }
+    }
+}
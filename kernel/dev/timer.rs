@@ -15,17 +15,235 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::interrupts;
+use crate::arch::port_io;
+use crate::kernel_static::Mutex;
+use crate::task_manager;
 
 pub trait Timer {
     fn init_with_period_ms(period_ms: usize) -> Self
     where
         Self: Sized;
     fn period_ms(&self) -> usize;
-
-    fn set_callback(&mut self, callback: TimerCallback);
-    fn callback(&self) -> Option<TimerCallback>;
+    /// Changes the period, the next tick comes in `period_ms`.
+    fn set_period_ms(&mut self, period_ms: usize);
 }
 
-pub type TimerCallback = fn();
+/// Function called by a [timer event](after_ms) with the argument it has been
+/// registered with.
+pub type TimerCallback = fn(usize);
 
 pub static mut TIMER: Option<Box<dyn Timer>> = None;
+
+/// Number of timer ticks since the timer was initialized.
+///
+/// There are no 64-bit atomics on i386, so it is accessed with the interrupts
+/// disabled.
+static mut TICKS: u64 = 0;
+
+/// Milliseconds since the timer was initialized.  Each tick adds the period
+/// it has been made with, so that the uptime stays right when the period is
+/// changed.  Accessed like [TICKS].
+static mut UPTIME_MS: u64 = 0;
+
+/// Callback to be called at a tick, see [after_ms] and [every_ms].
+struct TimerEvent {
+    id: usize,
+    wake_ms: u64,
+    /// Interval of a periodic event, `None` for a one-shot one.
+    period_ms: Option<u64>,
+    callback: TimerCallback,
+    arg: usize,
+}
+
+// Pending timer events, the earliest first.  Locked with the interrupts
+// disabled.
+kernel_static! {
+    static ref EVENTS: Mutex<Vec<TimerEvent>> = Mutex::new(Vec::new());
+}
+
+static NEXT_EVENT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Timer event registered with [after_ms] or [every_ms].  Dropping the handle
+/// does not cancel the event.
+pub struct TimerHandle {
+    id: usize,
+}
+
+impl TimerHandle {
+    /// Removes the event, returns `false` if it is a one-shot one that has
+    /// already fired.  The callback is not called after this returns.
+    pub fn cancel(self) -> bool {
+        interrupts::with_disabled(|| {
+            let mut events = EVENTS.lock();
+            match events.iter().position(|event| event.id == self.id) {
+                Some(idx) => {
+                    events.remove(idx);
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+}
+
+/// Makes `callback(arg)` be called once after at least `delay_ms`
+/// milliseconds.
+///
+/// The callback is called from the timer IRQ handler with the interrupts
+/// disabled, so it must be short.  The events due at the same tick are fired in
+/// the order of their wake-up time, then of their registration.  A callback may
+/// switch tasks, as the scheduler does, which leaves the rest of the due events
+/// to the next tick.
+pub fn after_ms(
+    delay_ms: u64,
+    callback: TimerCallback,
+    arg: usize,
+) -> TimerHandle {
+    add_event(delay_ms, None, callback, arg)
+}
+
+/// Makes `callback(arg)` be called every `period_ms` milliseconds, starting
+/// `period_ms` from now, until the event is cancelled.  See [after_ms].
+///
+/// If the timer period is longer, the callback is called once per tick.
+///
+/// # Panics
+/// This function panics if `period_ms` is 0.
+pub fn every_ms(
+    period_ms: u64,
+    callback: TimerCallback,
+    arg: usize,
+) -> TimerHandle {
+    assert_ne!(period_ms, 0, "zero timer event period");
+    add_event(period_ms, Some(period_ms), callback, arg)
+}
+
+/// Makes `callback(arg)` be called on every timer tick, starting with the next
+/// one.  See [every_ms].
+pub fn every_tick(callback: TimerCallback, arg: usize) -> TimerHandle {
+    add_event(0, Some(1), callback, arg)
+}
+
+fn add_event(
+    delay_ms: u64,
+    period_ms: Option<u64>,
+    callback: TimerCallback,
+    arg: usize,
+) -> TimerHandle {
+    let id = NEXT_EVENT_ID.fetch_add(1, Ordering::SeqCst);
+    interrupts::with_disabled(|| {
+        insert_event(
+            &mut EVENTS.lock(),
+            TimerEvent {
+                id,
+                wake_ms: uptime_ms() + delay_ms,
+                period_ms,
+                callback,
+                arg,
+            },
+        );
+    });
+    TimerHandle { id }
+}
+
+/// Inserts `event` after the ones that are due at the same time or earlier.
+fn insert_event(events: &mut Vec<TimerEvent>, event: TimerEvent) {
+    let idx = events
+        .iter()
+        .position(|other| other.wake_ms > event.wake_ms)
+        .unwrap_or(events.len());
+    events.insert(idx, event);
+}
+
+/// Calls the callbacks of the events that are due.
+fn run_due_events(now_ms: u64) {
+    loop {
+        let mut events = EVENTS.lock();
+        let (callback, arg) = match events.first() {
+            Some(event) if event.wake_ms <= now_ms => {
+                let mut event = events.remove(0);
+                let callback = (event.callback, event.arg);
+                // A periodic event is rescheduled before the call, which may
+                // not return until the task is switched back to.  The periods
+                // that have passed in the meantime are skipped.
+                if let Some(period_ms) = event.period_ms {
+                    while event.wake_ms <= now_ms {
+                        event.wake_ms += period_ms;
+                    }
+                    insert_event(&mut events, event);
+                }
+                callback
+            }
+            _ => break,
+        };
+        drop(events);
+        callback(arg);
+    }
+}
+
+/// Counts the tick and calls the callbacks of the due [timer events](after_ms),
+/// one of which is the scheduler.  Called by the IRQ handler of the active
+/// timer, once per tick.
+pub fn tick() {
+    unsafe {
+        TICKS += 1;
+        UPTIME_MS += ms_per_tick();
+        run_due_events(UPTIME_MS);
+    }
+}
+
+/// Returns the number of timer ticks since the timer was initialized.
+pub fn ticks() -> u64 {
+    interrupts::with_disabled(|| unsafe { TICKS })
+}
+
+/// Returns the timer period in milliseconds, 0 if there is no timer.
+pub fn ms_per_tick() -> u64 {
+    match unsafe { TIMER.as_ref() } {
+        Some(timer) => timer.period_ms() as u64,
+        None => 0,
+    }
+}
+
+/// Changes the period of the active timer and makes the scheduler recompute
+/// its quantum in ticks.
+///
+/// # Panics
+/// This function panics if there is no timer.
+pub fn set_period_ms(period_ms: usize) {
+    interrupts::with_disabled(|| unsafe {
+        TIMER
+            .as_mut()
+            .expect("there is no timer")
+            .set_period_ms(period_ms);
+    });
+    task_manager::set_timer_period_ms(period_ms as u64);
+}
+
+/// Waits for `ms` milliseconds without letting the other tasks run.
+///
+/// The CPU is halted between the ticks.  If there is no timer yet, it spins
+/// on writes to the POST port instead, which take about a microsecond each.
+pub fn busy_wait_ms(ms: u64) {
+    if unsafe { TIMER.is_none() } {
+        for _ in 0..ms * 1000 {
+            unsafe {
+                port_io::outb(0x80, 0);
+            }
+        }
+        return;
+    }
+    let end_ms = uptime_ms() + ms;
+    while uptime_ms() < end_ms {
+        interrupts::wait_for_interrupt();
+    }
+}
+
+/// Returns the number of milliseconds since the timer was initialized.
+pub fn uptime_ms() -> u64 {
+    interrupts::with_disabled(|| unsafe { UPTIME_MS })
+}
@@ -14,18 +14,46 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use core::cmp;
 use core::fmt;
 use core::fmt::Write;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use crate::arch::port_io;
+use crate::arch::dev::serial;
+use crate::arch::interrupts;
+use crate::arch::vas::KERNEL_VIRT_BASE;
+use crate::dev::console;
+use crate::dev::framebuffer::Framebuffer;
 use crate::kernel_static::Mutex;
+use crate::klog;
+use crate::port::{Port, PortBuilder};
+use crate::KERNEL_INFO;
 
-extern "C" {
-    fn get_eflags() -> u32;
-}
+pub const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+
+/// Distance between the tab stops.
+const TAB_WIDTH: usize = 8;
+
+/// Character put in the last column of a row that has been truncated because
+/// the line wrapping is disabled.
+const TRUNCATION_MARKER: u8 = b'>';
+
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
 
-const BUFFER_WIDTH: usize = 80;
-const BUFFER_HEIGHT: usize = 25;
+// CRT controller registers.
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+/// Bit of the cursor start register that hides the cursor.
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+/// Scan lines of the character cell that the cursor occupies.
+const CURSOR_SCAN_LINES: (u8, u8) = (14, 15);
 
 pub struct CursorPos {
     row: usize,
@@ -61,6 +89,8 @@ pub enum Color {
     White,
 }
 
+/// VGA attribute: the background color in the high nibble and the foreground
+/// color in the low one.
 #[derive(Clone, Copy)]
 #[repr(transparent)]
 pub struct ColorCode(u8);
@@ -73,43 +103,173 @@ impl ColorCode {
 
 #[derive(Clone, Copy)]
 #[repr(C)]
-struct ScreenChar {
+pub struct ScreenChar {
     ascii_char: u8,
     color_code: ColorCode,
 }
 
+/// Row of the VGA text buffer.
+pub type Row = [ScreenChar; BUFFER_WIDTH];
+
 #[repr(transparent)]
 pub struct Buffer {
-    chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    chars: [Row; BUFFER_HEIGHT],
+}
+
+/// Where a [Writer] puts the characters.
+#[derive(Clone, Copy)]
+pub enum Screen {
+    /// VGA text buffer.
+    Text(*mut Buffer),
+    /// Linear framebuffer with the text drawn using a bitmap font.
+    Framebuffer(Framebuffer),
+}
+
+impl Screen {
+    /// Returns the screen selected at boot: the framebuffer if the bootloader
+    /// has set up a supported one, or the VGA text buffer otherwise.
+    pub fn current() -> Self {
+        match unsafe { KERNEL_INFO.framebuffer } {
+            Some(fb) => Screen::Framebuffer(fb),
+            None => Screen::Text(BUFFER_ADDR as *mut Buffer),
+        }
+    }
+
+    pub fn cols(&self) -> usize {
+        match self {
+            Screen::Text(_) => BUFFER_WIDTH,
+            Screen::Framebuffer(fb) => fb.text_cols(),
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        match self {
+            Screen::Text(_) => BUFFER_HEIGHT,
+            Screen::Framebuffer(fb) => fb.text_rows(),
+        }
+    }
 }
 
 pub struct Writer {
     pub pos: CursorPos,
     pub color_code: ColorCode,
-    pub buffer: *mut Buffer,
+    pub screen: Screen,
+    /// Whether the characters that do not fit in the row are moved to the next
+    /// one or dropped.
+    pub wrap: bool,
 }
 
 impl Writer {
-    pub fn write_char(&mut self, ch: u8) {
-        // Duplicate to COM1.
-        unsafe {
-            port_io::outb(0x3F8, ch);
+    /// Creates a writer for the [current](Screen::current) screen with the
+    /// cursor at the start of the last row.
+    pub fn on_last_row(color_code: ColorCode) -> Self {
+        let screen = Screen::current();
+        Writer {
+            pos: CursorPos {
+                row: screen.rows() - 1,
+                col: 0,
+            },
+            color_code,
+            screen,
+            wrap: true,
         }
+    }
 
+    pub fn write_char(&mut self, ch: u8) {
+        serial::mirror(ch);
+        console::leave_scrollback();
+
+        self.hide_cursor();
         match ch {
             b'\n' => self.new_line(),
-            ch => {
-                if self.pos.col >= BUFFER_WIDTH {
-                    self.new_line();
+            b'\r' => self.pos.col = 0,
+            // Backspace erases the previous character of the row.
+            0x08 => {
+                if self.pos.col > 0 {
+                    // The column is past the last one if the row is truncated.
+                    self.pos.col =
+                        cmp::min(self.pos.col, self.screen.cols()) - 1;
+                    self.put_char(self.pos.row, self.pos.col, b' ');
+                }
+            }
+            b'\t' => loop {
+                // Tab moves at least by one column.
+                self.put_printable(b' ');
+                if self.pos.col % TAB_WIDTH == 0
+                    || self.pos.col > self.screen.cols()
+                {
+                    break;
                 }
-                unsafe {
-                    (*self.buffer).chars[self.pos.row][self.pos.col] =
-                        ScreenChar {
-                            ascii_char: ch,
-                            color_code: self.color_code,
-                        };
+            },
+            ch => self.put_printable(ch),
+        }
+        self.show_cursor();
+    }
+
+    /// Puts a character at the cursor and advances it, wrapping or truncating
+    /// the row at its end.
+    fn put_printable(&mut self, ch: u8) {
+        let cols = self.screen.cols();
+        if self.pos.col >= cols {
+            if self.wrap {
+                self.new_line();
+            } else {
+                // The column past the last one means that the row has been
+                // marked already.
+                if self.pos.col == cols {
+                    self.put_char(self.pos.row, cols - 1, TRUNCATION_MARKER);
+                    self.pos.col += 1;
                 }
-                self.pos.col += 1;
+                return;
+            }
+        }
+        self.put_char(self.pos.row, self.pos.col, ch);
+        self.pos.col += 1;
+    }
+
+    fn put_char(&mut self, row: usize, col: usize, ch: u8) {
+        match self.screen {
+            Screen::Text(buffer) => unsafe {
+                (*buffer).chars[row][col] = ScreenChar {
+                    ascii_char: ch,
+                    color_code: self.color_code,
+                };
+            },
+            Screen::Framebuffer(fb) => {
+                fb.draw_char(row, col, ch, self.color_code.0)
+            }
+        }
+    }
+
+    /// Draws the cursor on the framebuffer or moves the hardware cursor in
+    /// the text mode.
+    fn show_cursor(&mut self) {
+        match self.screen {
+            Screen::Text(_) => set_cursor(
+                self.pos.row,
+                cmp::min(self.pos.col, BUFFER_WIDTH - 1),
+            ),
+            Screen::Framebuffer(fb) => {
+                if self.pos.col < fb.text_cols() {
+                    fb.draw_cursor(
+                        self.pos.row,
+                        self.pos.col,
+                        self.color_code.0,
+                    );
+                }
+            }
+        }
+    }
+
+    fn hide_cursor(&mut self) {
+        if let Screen::Framebuffer(fb) = self.screen {
+            if self.pos.col < fb.text_cols() {
+                fb.draw_char(
+                    self.pos.row,
+                    self.pos.col,
+                    b' ',
+                    self.color_code.0,
+                );
             }
         }
     }
@@ -123,36 +283,46 @@ impl Writer {
     fn new_line(&mut self) {
         self.pos.col = 0;
         self.pos.row += 1;
-        if self.pos.row >= BUFFER_HEIGHT {
+        if self.pos.row >= self.screen.rows() {
             self.scroll_screen(1);
-            self.pos.row = BUFFER_HEIGHT - 1;
+            self.pos.row = self.screen.rows() - 1;
             self.clear_row(self.pos.row);
         }
     }
 
     fn scroll_screen(&mut self, num_rows: usize) {
-        unsafe {
-            for row in num_rows..BUFFER_HEIGHT {
-                (*self.buffer).chars[row - num_rows] =
-                    (*self.buffer).chars[row];
-            }
+        match self.screen {
+            Screen::Text(buffer) => unsafe {
+                for row in 0..num_rows {
+                    console::save_scrolled_row(&(*buffer).chars[row]);
+                }
+                for row in num_rows..BUFFER_HEIGHT {
+                    (*buffer).chars[row - num_rows] = (*buffer).chars[row];
+                }
+            },
+            Screen::Framebuffer(fb) => fb.scroll_up(num_rows),
         }
     }
 
     fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
-            ascii_char: b' ',
-            color_code: self.color_code,
-        };
-        for col in 0..BUFFER_WIDTH {
-            unsafe {
-                (*self.buffer).chars[row][col] = blank;
+        match self.screen {
+            Screen::Text(buffer) => {
+                let blank = ScreenChar {
+                    ascii_char: b' ',
+                    color_code: self.color_code,
+                };
+                for col in 0..BUFFER_WIDTH {
+                    unsafe {
+                        (*buffer).chars[row][col] = blank;
+                    }
+                }
             }
+            Screen::Framebuffer(fb) => fb.clear_row(row, self.color_code.0),
         }
     }
 
     fn clear_screen(&mut self) {
-        for row in 0..BUFFER_HEIGHT {
+        for row in 0..self.screen.rows() {
             self.clear_row(row);
         }
     }
@@ -178,40 +348,239 @@ macro_rules! println {
     })
 }
 
+/// Like `print!()`, but does not wait for the screen lock, see [_try_print].
+#[macro_export]
+macro_rules! try_print {
+    ($($arg:tt)*) => ({
+        $crate::dev::vga::_try_print(format_args!($($arg)*));
+    });
+}
+
+#[macro_export]
+macro_rules! try_println {
+    () => ($crate::try_print!("\n"));
+    ($($arg:tt)*) => ({
+        $crate::try_print!("{}\n", format_args!($($arg)*));
+    })
+}
+
+/// Virtual address of the VGA text buffer (physical address 0xB8000).
+pub const BUFFER_ADDR: usize = KERNEL_VIRT_BASE + 0xB8000;
+
 kernel_static! {
     static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
             pos: CursorPos { row: 0, col: 0 },
             color_code: ColorCode::new(Color::White, Color::Black),
-            buffer: 0xB8000 as *mut Buffer,
+            screen: Screen::Text(BUFFER_ADDR as *mut Buffer),
+            wrap: true,
     });
 }
 
+/// Selects the [screen](Screen::current) to print to and clears it.
+///
+/// Must be called after the Multiboot information has been parsed.
 pub fn init() {
-    WRITER.lock().clear_screen();
+    let mut writer = WRITER.lock();
+    writer.screen = Screen::current();
+    writer.pos = CursorPos { row: 0, col: 0 };
+    writer.clear_screen();
 }
 
-pub fn _print(args: fmt::Arguments) {
-    // The interrupts should be disabled when printing to the screen to prevent
-    // a context switch from happening while WRITER is locked.  But using
-    // SCHEDULER.stop_scheduling() here is a bit difficult, so we do a slightly
-    // different thing.
-    let do_sti = unsafe {
-        // Check IF and disable it temporarily if it has not been already.
-        match get_eflags() & (1 << 9) {
-            0 => false,
-            _ => {
-                asm!("cli");
-                true
-            }
-        }
-    };
-    {
-        WRITER.lock().write_fmt(args).unwrap();
+/// Runs `f` with the line wrapping of `print!()` disabled, i.e. the lines that
+/// do not fit in a row are truncated and marked with `>`.  Intended for the
+/// table-like output.
+pub fn without_wrap<R, F: FnOnce() -> R>(f: F) -> R {
+    let wrap = interrupts::with_disabled(|| {
+        mem::replace(&mut WRITER.lock().wrap, false)
+    });
+    let res = f();
+    interrupts::with_disabled(|| WRITER.lock().wrap = wrap);
+    res
+}
+
+/// Checks if the screen is the VGA text buffer and not a framebuffer.
+pub fn is_text_mode() -> bool {
+    matches!(Screen::current(), Screen::Text(_))
+}
+
+/// Returns a row of the VGA text buffer.
+///
+/// # Panics
+/// This function panics if the screen is [not](is_text_mode) the VGA text
+/// buffer.
+pub fn read_row(row: usize) -> Row {
+    match Screen::current() {
+        Screen::Text(buffer) => unsafe { (*buffer).chars[row] },
+        Screen::Framebuffer(_) => panic!("the screen is not the text buffer"),
+    }
+}
+
+/// Overwrites a row of the VGA text buffer.
+///
+/// # Panics
+/// This function panics if the screen is [not](is_text_mode) the VGA text
+/// buffer.
+pub fn write_row(row: usize, cells: &Row) {
+    match Screen::current() {
+        Screen::Text(buffer) => unsafe { (*buffer).chars[row] = *cells },
+        Screen::Framebuffer(_) => panic!("the screen is not the text buffer"),
+    }
+}
+
+/// CRT controller of the VGA, which draws the text mode cursor.
+struct Crtc {
+    index: Port,
+    data: Port,
+}
+
+impl Crtc {
+    unsafe fn read(&self, reg: u8) -> u8 {
+        self.index.write(reg);
+        self.data.read()
+    }
+
+    unsafe fn write(&self, reg: u8, value: u8) {
+        self.index.write(reg);
+        self.data.write(value);
+    }
+}
+
+static mut CRTC: Option<Crtc> = None;
+
+/// Sets up the hardware cursor, which is not managed before this call.
+///
+/// Must be called after the kernel heap is initialized.
+pub fn init_cursor() {
+    if !is_text_mode() {
+        return;
     }
     unsafe {
-        // SCHEDULER.keep_scheduling();
-        if do_sti {
-            asm!("sti");
+        CRTC = Some(Crtc {
+            index: PortBuilder::port(CRTC_INDEX).size(8).done(),
+            data: PortBuilder::port(CRTC_DATA).size(8).done(),
+        });
+    }
+    enable_cursor();
+    let writer = WRITER.lock();
+    set_cursor(writer.pos.row, cmp::min(writer.pos.col, BUFFER_WIDTH - 1));
+}
+
+/// Runs `f` on the CRT controller if the hardware cursor is managed.
+fn with_crtc<R, F: FnOnce(&Crtc) -> R>(f: F) -> Option<R> {
+    // The index register must not be changed between the accesses.
+    interrupts::with_disabled(|| unsafe { CRTC.as_ref().map(f) })
+}
+
+/// Moves the hardware cursor.
+pub fn set_cursor(row: usize, col: usize) {
+    let pos = row * BUFFER_WIDTH + col;
+    with_crtc(|crtc| unsafe {
+        crtc.write(CRTC_CURSOR_LOCATION_LOW, pos as u8);
+        crtc.write(CRTC_CURSOR_LOCATION_HIGH, (pos >> 8) as u8);
+    });
+}
+
+/// Returns the row and the column of the hardware cursor, or `None` if it is
+/// not managed.
+pub fn get_cursor() -> Option<(usize, usize)> {
+    with_crtc(|crtc| unsafe {
+        let low = crtc.read(CRTC_CURSOR_LOCATION_LOW) as usize;
+        let high = crtc.read(CRTC_CURSOR_LOCATION_HIGH) as usize;
+        let pos = high << 8 | low;
+        (pos / BUFFER_WIDTH, pos % BUFFER_WIDTH)
+    })
+}
+
+pub fn enable_cursor() {
+    with_crtc(|crtc| unsafe {
+        let start = crtc.read(CRTC_CURSOR_START) & 0xC0;
+        crtc.write(CRTC_CURSOR_START, start | CURSOR_SCAN_LINES.0);
+        let end = crtc.read(CRTC_CURSOR_END) & 0xE0;
+        crtc.write(CRTC_CURSOR_END, end | CURSOR_SCAN_LINES.1);
+    });
+}
+
+pub fn disable_cursor() {
+    with_crtc(|crtc| unsafe {
+        crtc.write(CRTC_CURSOR_START, CURSOR_DISABLE);
+    });
+}
+
+/// Makes the writers access the framebuffer at `virt`, where it is mapped in
+/// the kernel VAS.
+///
+/// The framebuffer becomes inaccessible at its old address once the kernel VAS
+/// is loaded, so nothing must be printed between loading it and calling this
+/// function.
+pub fn move_framebuffer(virt: usize) {
+    let fb = unsafe { KERNEL_INFO.framebuffer.as_mut() }
+        .expect("there is no framebuffer");
+    fb.virt = virt;
+    WRITER.lock().screen = Screen::Framebuffer(*fb);
+}
+
+/// Offset in the kernel log up to which it has been printed on the screen.
+static LOG_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Prints the kernel log bytes that have not been printed yet.  Must be called
+/// with the writer locked.
+fn drain_log(writer: &mut Writer) {
+    let mut num_dropped = 0;
+    let mut buf = [0; 64];
+    loop {
+        let offset = LOG_OFFSET.load(Ordering::SeqCst);
+        match klog::read_from(offset, &mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                for &byte in &buf[..len] {
+                    writer.write_char(byte);
+                }
+                LOG_OFFSET.store(offset + len, Ordering::SeqCst);
+            }
+            Err(klog::ReadErr::Overwritten { start }) => {
+                num_dropped += start - offset;
+                LOG_OFFSET.store(start, Ordering::SeqCst);
+            }
+            Err(klog::ReadErr::InvalidOffset) => unreachable!(),
         }
     }
+    if num_dropped != 0 {
+        writer
+            .write_fmt(format_args!("[VGA] Dropped {} bytes.\n", num_dropped))
+            .unwrap();
+    }
+}
+
+/// Prints the kernel log bytes that have not been printed yet.
+pub fn print_log() {
+    // The interrupts are disabled so that neither a context switch nor an
+    // interrupt handler that prints can happen while WRITER is locked.
+    interrupts::with_disabled(|| drain_log(&mut WRITER.lock()));
+}
+
+pub fn _print(args: fmt::Arguments) {
+    klog::_log(klog::Level::Info, args);
+}
+
+/// Logs without waiting for the writer lock.
+///
+/// Intended for the interrupt and exception handlers that may interrupt a
+/// `print!()` in progress and thus must not wait for the lock.  If the lock is
+/// held, the output is printed by the next `print!()`.
+pub fn _try_print(args: fmt::Arguments) {
+    interrupts::with_disabled(|| {
+        klog::append(klog::Level::Info, args);
+        if let Some(mut writer) = WRITER.try_lock() {
+            drain_log(&mut writer);
+        }
+    });
+}
+
+/// Releases the writer lock, which may be held by the code that has
+/// panicked, so that the panic message can be printed.
+///
+/// # Safety
+/// Must be called only on a panic.
+pub unsafe fn break_lock() {
+    WRITER.force_unlock();
 }
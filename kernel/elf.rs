@@ -14,9 +14,13 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::cmp;
 use core::mem::size_of;
+use core::ptr;
+use core::str;
 
 use crate::feeder::Feeder;
 
@@ -77,7 +81,9 @@ impl ElfHeader {
             ));
         }
 
-        if { header._type } != Type::ExecutableFile {
+        if { header._type } != Type::ExecutableFile && { header._type }
+            != Type::SharedObjectFile
+        {
             return Err(ElfHeaderErr::InvalidType(header._type as u16));
         }
         if { header.machine } != Machine::X86 {
@@ -136,6 +142,8 @@ enum Type {
     None = 0,
     RelocatableFile = 1,
     ExecutableFile = 2,
+    /// Also a position-independent executable.
+    SharedObjectFile = 3,
 }
 
 #[repr(u16)]
@@ -191,7 +199,7 @@ bitflags_new! {
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug)]
 struct ProgHeader {
-    _type: ProgHeaderType,
+    _type: u32,
     offset: u32,
     vaddr: u32,
     _skip: u32,
@@ -222,13 +230,68 @@ enum ProgHeaderType {
     Tls = 7,
 }
 
+const PF_W: u32 = 0b010;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct DynamicEntry {
+    tag: i32,
+    value: u32,
+}
+
+const DT_NULL: i32 = 0;
+const DT_NEEDED: i32 = 1;
+const DT_PLTRELSZ: i32 = 2;
+const DT_STRTAB: i32 = 5;
+const DT_SYMTAB: i32 = 6;
+const DT_RELA: i32 = 7;
+const DT_REL: i32 = 17;
+const DT_RELSZ: i32 = 18;
+const DT_JMPREL: i32 = 23;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct Rel {
+    offset: u32,
+    info: u32,
+}
+
+const R_386_NONE: u8 = 0;
+const R_386_32: u8 = 1;
+const R_386_GLOB_DAT: u8 = 6;
+const R_386_JMP_SLOT: u8 = 7;
+const R_386_RELATIVE: u8 = 8;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+struct Symbol {
+    name: u32,
+    value: u32,
+    size: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+}
+
+const SYMBOL_TYPE_FUNC: u8 = 2;
+const SYMBOL_BIND_WEAK: u8 = 2;
+const SYMBOL_SECTION_UNDEF: u16 = 0;
+const SYMBOL_SECTION_ABS: u16 = 0xFFF1;
+
 // Above are standard ELF structures.  Below are structures used by the kernel.
 
 #[derive(Clone, Debug)]
 pub struct ElfObj {
-    pub sections: Vec<SectionInfo>,
+    /// Only the program headers are read up front.  The section headers are
+    /// not needed for loading, see [find_section](Self::find_section).
+    header: ElfHeader,
     pub program_segments: Vec<ProgSegment>,
     pub entry_point: usize,
+    /// Set for a position-independent executable (ET_DYN), which is linked at
+    /// 0 and must be [relocated](Self::relocate).
+    pub position_independent: bool,
+    /// Added to the link-time addresses, see [relocate](Self::relocate).
+    pub load_bias: usize,
 }
 
 #[derive(Debug)]
@@ -236,6 +299,33 @@ pub enum ElfObjErr {
     ElfHeaderErr(ElfHeaderErr),
 }
 
+#[derive(Debug)]
+pub enum DynamicErr {
+    /// The load bias moves a segment past the end of the address space.
+    AddressOverflow,
+    /// The executable requests a dynamic linker (PT_INTERP).
+    NeedsInterpreter,
+    /// The executable depends on shared libraries (DT_NEEDED).
+    NeedsSharedLibrary,
+    /// A relocation refers to a symbol that is not defined in the executable.
+    UndefinedSymbol(String),
+    UnsupportedRelocation(u8),
+    /// The relocations have explicit addends (DT_RELA), which is not the case
+    /// on i386.
+    RelaTable,
+    /// A relocation table or its target is not within a loadable segment.
+    InvalidAddress(usize),
+}
+
+/// A word in a loaded executable to be fixed up.
+#[derive(Clone, Copy, Debug)]
+pub enum Relocation {
+    /// Adds `value` to the word at `at`.
+    Add { at: usize, value: usize },
+    /// Stores `value` in the word at `at`.
+    Set { at: usize, value: usize },
+}
+
 impl From<ElfHeaderErr> for ElfObjErr {
     fn from(e: ElfHeaderErr) -> Self {
         ElfObjErr::ElfHeaderErr(e)
@@ -247,39 +337,8 @@ impl ElfObj {
         let elf_header =
             ElfHeader::from_bytes(&feeder.get_len(0, size_of::<ElfHeader>()))?;
 
-        let names_section = SectionHeader::from_bytes(&feeder.get_len(
-            elf_header.section_header_idx(elf_header.shstrndx as usize),
-            size_of::<SectionHeader>(),
-        ));
-        let names_section_start = names_section.offset as usize;
-
         Ok(ElfObj {
-            sections: {
-                let mut vec = Vec::new();
-                for i in 0..elf_header.shnum as usize {
-                    let sh = SectionHeader::from_bytes(&feeder.get_len(
-                        elf_header.section_header_idx(i),
-                        size_of::<SectionHeader>(),
-                    ));
-
-                    vec.push(SectionInfo {
-                        name: if elf_header.shstrndx != 0 && sh.name != 0 {
-                            let name_start =
-                                names_section_start + sh.name as usize;
-                            let name_bytes =
-                                feeder.get_until(name_start, |&x| x == 0);
-                            Some(
-                                String::from_utf8(name_bytes.to_vec()).unwrap(),
-                            )
-                        } else {
-                            None
-                        },
-                        offset: sh.offset as usize,
-                        size: sh.size as usize,
-                    });
-                }
-                vec
-            },
+            header: elf_header,
             program_segments: {
                 let mut vec = Vec::new();
                 for i in 0..elf_header.phnum as usize {
@@ -287,18 +346,256 @@ impl ElfObj {
                         elf_header.program_header_idx(i),
                         size_of::<ProgHeader>(),
                     ));
-                    vec.push(ProgSegment::from_prog_header(&ph));
+                    if let Some(segment) = ProgSegment::from_prog_header(&ph) {
+                        vec.push(segment);
+                    }
                 }
                 vec
             },
             entry_point: elf_header.entry as usize,
+            position_independent: { elf_header._type }
+                == Type::SharedObjectFile,
+            load_bias: 0,
         })
     }
+
+    /// Moves a [position-independent](Self::position_independent) executable
+    /// by `bias`, which is added to the addresses of its segments and to its
+    /// entry point.  The contents are fixed up by the relocations, see
+    /// [for_each_relocation](Self::for_each_relocation).
+    pub fn relocate(&mut self, bias: usize) -> Result<(), DynamicErr> {
+        assert!(self.position_independent, "not a position-independent ELF");
+        for segment in &mut self.program_segments {
+            segment.in_mem_at = segment
+                .in_mem_at
+                .checked_add(bias)
+                .ok_or(DynamicErr::AddressOverflow)?;
+        }
+        self.entry_point = self
+            .entry_point
+            .checked_add(bias)
+            .ok_or(DynamicErr::AddressOverflow)?;
+        self.load_bias = bias;
+        Ok(())
+    }
+
+    /// Reads the relocations from the dynamic section, which only a
+    /// [position-independent](Self::position_independent) executable has, and
+    /// calls `f` on each of them.
+    ///
+    /// Only the relocations that the executable can satisfy by itself are
+    /// supported, there is no dynamic linking.  The tables are read in chunks
+    /// of [TABLE_CHUNK_SIZE] bytes.
+    pub fn for_each_relocation(
+        &self,
+        feeder: &mut dyn Feeder,
+        mut f: impl FnMut(Relocation),
+    ) -> Result<(), DynamicErr> {
+        let mut dynamic = None;
+        for segment in &self.program_segments {
+            match segment._type {
+                ProgSegmentType::Interp => {
+                    return Err(DynamicErr::NeedsInterpreter);
+                }
+                ProgSegmentType::Dynamic => dynamic = Some(segment),
+                _ => {}
+            }
+        }
+        let dynamic = match dynamic {
+            Some(dynamic) => dynamic,
+            None => return Ok(()),
+        };
+
+        let mut tables = [(0, 0); 2];
+        let mut symtab = 0;
+        let mut strtab = 0;
+        'dynamic: for chunk_at in
+            (0..dynamic.in_file_size).step_by(TABLE_CHUNK_SIZE)
+        {
+            let len =
+                cmp::min(TABLE_CHUNK_SIZE, dynamic.in_file_size - chunk_at);
+            let entries = feeder.get_len(dynamic.in_file_at + chunk_at, len);
+            for bytes in entries.chunks_exact(size_of::<DynamicEntry>()) {
+                let entry = unsafe {
+                    ptr::read_unaligned(bytes.as_ptr() as *const DynamicEntry)
+                };
+                let value = entry.value as usize;
+                match entry.tag {
+                    DT_NULL => break 'dynamic,
+                    DT_NEEDED => return Err(DynamicErr::NeedsSharedLibrary),
+                    DT_RELA => return Err(DynamicErr::RelaTable),
+                    DT_REL => tables[0].0 = value,
+                    DT_RELSZ => tables[0].1 = value,
+                    DT_JMPREL => tables[1].0 = value,
+                    DT_PLTRELSZ => tables[1].1 = value,
+                    DT_SYMTAB => symtab = value,
+                    DT_STRTAB => strtab = value,
+                    _ => {}
+                }
+            }
+        }
+
+        for &(table, size) in &tables {
+            for chunk_at in (0..size).step_by(TABLE_CHUNK_SIZE) {
+                let len = cmp::min(TABLE_CHUNK_SIZE, size - chunk_at);
+                let chunk = table
+                    .checked_add(chunk_at)
+                    .ok_or(DynamicErr::InvalidAddress(table))?;
+                let rels = self.read_linked(feeder, chunk, len)?;
+                for bytes in rels.chunks_exact(size_of::<Rel>()) {
+                    self.process_rel(feeder, bytes, symtab, strtab, &mut f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn process_rel(
+        &self,
+        feeder: &mut dyn Feeder,
+        bytes: &[u8],
+        symtab: usize,
+        strtab: usize,
+        f: &mut impl FnMut(Relocation),
+    ) -> Result<(), DynamicErr> {
+        let rel = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Rel) };
+        let at = self.load_bias + rel.offset as usize;
+        if !self.is_loaded(at, size_of::<u32>()) {
+            return Err(DynamicErr::InvalidAddress(at));
+        }
+        let sym_idx = rel.info as usize >> 8;
+        f(match rel.info as u8 {
+            R_386_NONE => return Ok(()),
+            R_386_RELATIVE => Relocation::Add {
+                at,
+                value: self.load_bias,
+            },
+            R_386_32 => Relocation::Add {
+                at,
+                value: self.symbol_value(feeder, symtab, strtab, sym_idx)?,
+            },
+            R_386_GLOB_DAT | R_386_JMP_SLOT => Relocation::Set {
+                at,
+                value: self.symbol_value(feeder, symtab, strtab, sym_idx)?,
+            },
+            other => return Err(DynamicErr::UnsupportedRelocation(other)),
+        });
+        Ok(())
+    }
+
+    /// Returns the run-time address of the dynamic symbol `idx`.
+    fn symbol_value(
+        &self,
+        feeder: &mut dyn Feeder,
+        symtab: usize,
+        strtab: usize,
+        idx: usize,
+    ) -> Result<usize, DynamicErr> {
+        let sym_at = symtab + idx * size_of::<Symbol>();
+        let bytes = self.read_linked(feeder, sym_at, size_of::<Symbol>())?;
+        let sym =
+            unsafe { ptr::read_unaligned(bytes.as_ptr() as *const Symbol) };
+        match sym.shndx {
+            SYMBOL_SECTION_UNDEF if sym.info >> 4 == SYMBOL_BIND_WEAK => Ok(0),
+            SYMBOL_SECTION_UNDEF => {
+                let name_at = strtab + sym.name as usize;
+                let name = match self.file_offset_of(name_at, 1) {
+                    Some(offset) => feeder.get_until(offset, |&ch| ch == 0),
+                    None => return Err(DynamicErr::InvalidAddress(name_at)),
+                };
+                Err(DynamicErr::UndefinedSymbol(
+                    String::from_utf8_lossy(&name).into_owned(),
+                ))
+            }
+            SYMBOL_SECTION_ABS => Ok(sym.value as usize),
+            _ => Ok(self.load_bias + sym.value as usize),
+        }
+    }
+
+    /// Reads `len` bytes at the link-time address `addr` from the file.
+    fn read_linked(
+        &self,
+        feeder: &mut dyn Feeder,
+        addr: usize,
+        len: usize,
+    ) -> Result<Box<[u8]>, DynamicErr> {
+        let offset = self
+            .file_offset_of(addr, len)
+            .ok_or(DynamicErr::InvalidAddress(addr))?;
+        Ok(feeder.get_len(offset, len))
+    }
+
+    /// Finds the file offset of the `len` bytes at the link-time address
+    /// `addr`, if they are within the file part of a loadable segment.
+    fn file_offset_of(&self, addr: usize, len: usize) -> Option<usize> {
+        let end = addr.checked_add(len)?;
+        self.program_segments
+            .iter()
+            .filter(|segment| segment._type == ProgSegmentType::Load)
+            .find_map(|segment| {
+                let start = segment.in_mem_at - self.load_bias;
+                if start <= addr && end <= start + segment.in_file_size {
+                    Some(segment.in_file_at + (addr - start))
+                } else {
+                    None
+                }
+            })
+    }
+
+    /// Checks if the `len` bytes at the run-time address `addr` are within a
+    /// loadable segment.
+    fn is_loaded(&self, addr: usize, len: usize) -> bool {
+        let end = match addr.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        self.program_segments.iter().any(|segment| {
+            segment._type == ProgSegmentType::Load
+                && segment.in_mem_at <= addr
+                && end <= segment.in_mem_at + segment.in_mem_size
+        })
+    }
+
+    /// Reads the section headers until the one named `name`.
+    fn find_section(
+        &self,
+        feeder: &mut dyn Feeder,
+        name: &str,
+    ) -> Option<SectionInfo> {
+        let header = &self.header;
+        if header.shstrndx == 0 {
+            return None;
+        }
+        let names_section = unsafe {
+            SectionHeader::from_bytes(&feeder.get_len(
+                header.section_header_idx(header.shstrndx as usize),
+                size_of::<SectionHeader>(),
+            ))
+        };
+        for i in 0..header.shnum as usize {
+            let sh = unsafe {
+                SectionHeader::from_bytes(&feeder.get_len(
+                    header.section_header_idx(i),
+                    size_of::<SectionHeader>(),
+                ))
+            };
+            if sh.name == 0 {
+                continue;
+            }
+            let name_start = names_section.offset as usize + sh.name as usize;
+            if *feeder.get_until(name_start, |&x| x == 0) == *name.as_bytes() {
+                return Some(SectionInfo {
+                    offset: sh.offset as usize,
+                    size: sh.size as usize,
+                });
+            }
+        }
+        None
+    }
 }
 
 #[derive(Clone, Debug)]
-pub struct SectionInfo {
-    name: Option<String>,
+struct SectionInfo {
     offset: usize,
     size: usize,
 }
@@ -312,16 +609,27 @@ pub struct ProgSegment {
 
     pub in_mem_at: usize,
     pub in_mem_size: usize,
+
+    pub align: usize,
+    /// PF_W, the segment may be written to.
+    pub writable: bool,
 }
 
 impl ProgSegment {
-    unsafe fn from_prog_header(ph: &ProgHeader) -> Self {
-        let _type = { ph._type };
-        ProgSegment {
-            _type: match _type {
-                ProgHeaderType::Load => ProgSegmentType::Load,
-                ProgHeaderType::Tls => ProgSegmentType::Tls,
-                _ => unimplemented!("ProgHeaderType::{:?}", _type as u32),
+    /// Returns `None` for the segments that the kernel does not use, e.g.
+    /// PT_NOTE.
+    fn from_prog_header(ph: &ProgHeader) -> Option<Self> {
+        Some(ProgSegment {
+            _type: match { ph._type } {
+                x if x == ProgHeaderType::Load as u32 => ProgSegmentType::Load,
+                x if x == ProgHeaderType::Dynamic as u32 => {
+                    ProgSegmentType::Dynamic
+                }
+                x if x == ProgHeaderType::Interp as u32 => {
+                    ProgSegmentType::Interp
+                }
+                x if x == ProgHeaderType::Tls as u32 => ProgSegmentType::Tls,
+                _ => return None,
             },
 
             in_file_at: ph.offset as usize,
@@ -329,12 +637,108 @@ impl ProgSegment {
 
             in_mem_at: ph.vaddr as usize,
             in_mem_size: ph.memsz as usize,
-        }
+
+            align: ph.align as usize,
+            writable: ph.flags & PF_W != 0,
+        })
     }
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum ProgSegmentType {
     Load,
+    Dynamic,
+    Interp,
     Tls,
 }
+
+/// Relocation and dynamic tables are read in chunks of this size, which bounds
+/// the heap taken by them whatever their size in the file.  The peak heap use
+/// of loading an executable as a whole has not been measured.
+const TABLE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Symbol and string tables larger than this are not read by
+/// [SymbolTable::read].
+const MAX_SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+
+#[derive(Debug)]
+struct FuncSymbol {
+    start: usize,
+    size: usize,
+    name: String,
+}
+
+/// Function symbols of an executable, used to tell which function a faulting
+/// usermode address belongs to.
+#[derive(Debug)]
+pub struct SymbolTable {
+    /// Path of the executable.
+    pub path: String,
+    /// Sorted by their addresses.
+    funcs: Vec<FuncSymbol>,
+}
+
+impl SymbolTable {
+    /// Reads the function symbols from the `.symtab` and `.strtab` sections of
+    /// `elf`, which is read from `path`.
+    ///
+    /// Returns `None` if the executable is stripped or its tables are larger
+    /// than 256 KiB.
+    pub fn read(
+        elf: &ElfObj,
+        feeder: &mut dyn Feeder,
+        path: &str,
+    ) -> Option<Self> {
+        let symtab = elf.find_section(feeder, ".symtab")?;
+        let strtab = elf.find_section(feeder, ".strtab")?;
+        if symtab.size > MAX_SYMBOL_TABLE_SIZE
+            || strtab.size > MAX_SYMBOL_TABLE_SIZE
+        {
+            return None;
+        }
+        let symtab = feeder.get_len(symtab.offset, symtab.size);
+        let strtab = feeder.get_len(strtab.offset, strtab.size);
+
+        let mut funcs: Vec<FuncSymbol> = symtab
+            .chunks_exact(size_of::<Symbol>())
+            .map(|bytes| unsafe {
+                ptr::read_unaligned(bytes.as_ptr() as *const Symbol)
+            })
+            .filter(|sym| sym.info & 0xF == SYMBOL_TYPE_FUNC && sym.value != 0)
+            .filter_map(|sym| {
+                let name = strtab.get(sym.name as usize..)?;
+                let len = name.iter().position(|&ch| ch == 0)?;
+                Some(FuncSymbol {
+                    start: elf.load_bias + sym.value as usize,
+                    size: sym.size as usize,
+                    name: String::from(str::from_utf8(&name[..len]).ok()?),
+                })
+            })
+            .collect();
+        if funcs.is_empty() {
+            return None;
+        }
+        funcs.sort_unstable_by_key(|func| func.start);
+        Some(SymbolTable {
+            path: String::from(path),
+            funcs,
+        })
+    }
+
+    /// Finds the function containing `addr` and returns its name and the
+    /// offset of `addr` in it.
+    pub fn symbolize(&self, addr: usize) -> Option<(&str, usize)> {
+        let idx = match self.funcs.binary_search_by_key(&addr, |f| f.start) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let func = &self.funcs[idx];
+        let offset = addr - func.start;
+        if offset < func.size || offset == 0 {
+            Some((&func.name, offset))
+        } else {
+            None
+        }
+    }
+}
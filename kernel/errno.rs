@@ -0,0 +1,277 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Error numbers returned by the syscalls.
+//!
+//! A syscall returns `-errno` in EAX on failure.  The values are those of
+//! newlib, which the usermode programs are linked with.  The error types of
+//! the syscalls and the file systems are converted to them here, so that the
+//! same error is reported the same way by all the syscalls.
+
+use crate::arch::task::UnmapErr;
+use crate::fs;
+use crate::syscall;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    E2BIG = 7,
+    EIO = 5,
+    ENOEXEC = 8,
+    EBADF = 9,
+    ECHILD = 10,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EEXIST = 17,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOTTY = 25,
+    ESPIPE = 29,
+    EROFS = 30,
+    EPIPE = 32,
+    ENOSYS = 88,
+    ENAMETOOLONG = 91,
+    ELOOP = 92,
+}
+
+impl Errno {
+    /// Returns the value of EAX for the error, i.e. `-errno`.
+    pub fn to_return_value(self) -> u32 {
+        (-(self as i32)) as u32
+    }
+}
+
+impl From<fs::LookupErr> for Errno {
+    fn from(err: fs::LookupErr) -> Self {
+        match err {
+            fs::LookupErr::NotFound => Errno::ENOENT,
+            fs::LookupErr::NotDir => Errno::ENOTDIR,
+            fs::LookupErr::NameTooLong => Errno::ENAMETOOLONG,
+            fs::LookupErr::TooManyLinks => Errno::ELOOP,
+        }
+    }
+}
+
+impl From<fs::ReadFileErr> for Errno {
+    fn from(err: fs::ReadFileErr) -> Self {
+        match err {
+            fs::ReadFileErr::NotReadable => Errno::EBADF,
+            fs::ReadFileErr::InvalidOffsetOrLen => Errno::EINVAL,
+            fs::ReadFileErr::NoRwInterface
+            | fs::ReadFileErr::DiskErr(_)
+            | fs::ReadFileErr::InvalidBlockNum
+            | fs::ReadFileErr::Block(_) => Errno::EIO,
+        }
+    }
+}
+
+impl From<fs::WriteFileErr> for Errno {
+    fn from(err: fs::WriteFileErr) -> Self {
+        match err {
+            fs::WriteFileErr::NotWritable => Errno::EBADF,
+            fs::WriteFileErr::BrokenPipe => Errno::EPIPE,
+            fs::WriteFileErr::Block(_) => Errno::EIO,
+        }
+    }
+}
+
+impl From<syscall::OpenErr> for Errno {
+    fn from(err: syscall::OpenErr) -> Self {
+        match err {
+            syscall::OpenErr::NotFound => Errno::ENOENT,
+            syscall::OpenErr::NotDir => Errno::ENOTDIR,
+            syscall::OpenErr::NameTooLong => Errno::ENAMETOOLONG,
+            syscall::OpenErr::MaxOpenedFiles => Errno::EMFILE,
+            syscall::OpenErr::IsDir => Errno::EISDIR,
+            syscall::OpenErr::Exists => Errno::EEXIST,
+            syscall::OpenErr::ReadOnlyFs => Errno::EROFS,
+            syscall::OpenErr::InvalidFlags => Errno::EINVAL,
+            syscall::OpenErr::TooManyLinks => Errno::ELOOP,
+        }
+    }
+}
+
+impl From<syscall::CloseErr> for Errno {
+    fn from(err: syscall::CloseErr) -> Self {
+        match err {
+            syscall::CloseErr::BadFd => Errno::EBADF,
+        }
+    }
+}
+
+impl From<syscall::ReadErr> for Errno {
+    fn from(err: syscall::ReadErr) -> Self {
+        match err {
+            syscall::ReadErr::BadFd => Errno::EBADF,
+            syscall::ReadErr::NotReadable => Errno::EBADF,
+            syscall::ReadErr::IsDir => Errno::EISDIR,
+            syscall::ReadErr::IoError => Errno::EIO,
+        }
+    }
+}
+
+impl From<syscall::WriteErr> for Errno {
+    fn from(err: syscall::WriteErr) -> Self {
+        match err {
+            syscall::WriteErr::BadFd => Errno::EBADF,
+            syscall::WriteErr::NotWritable => Errno::EBADF,
+            syscall::WriteErr::BrokenPipe => Errno::EPIPE,
+        }
+    }
+}
+
+impl From<syscall::PipeErr> for Errno {
+    fn from(err: syscall::PipeErr) -> Self {
+        match err {
+            syscall::PipeErr::MaxOpenedFiles => Errno::EMFILE,
+        }
+    }
+}
+
+impl From<syscall::SeekErr> for Errno {
+    fn from(err: syscall::SeekErr) -> Self {
+        match err {
+            syscall::SeekErr::BadFd => Errno::EBADF,
+            syscall::SeekErr::NotSeekable => Errno::ESPIPE,
+            syscall::SeekErr::InvalidOffset => Errno::EINVAL,
+        }
+    }
+}
+
+impl From<syscall::StatErr> for Errno {
+    fn from(err: syscall::StatErr) -> Self {
+        match err {
+            syscall::StatErr::BadFd => Errno::EBADF,
+            syscall::StatErr::NotFound => Errno::ENOENT,
+            syscall::StatErr::NotDir => Errno::ENOTDIR,
+            syscall::StatErr::NameTooLong => Errno::ENAMETOOLONG,
+            syscall::StatErr::TooManyLinks => Errno::ELOOP,
+            syscall::StatErr::IoError => Errno::EIO,
+        }
+    }
+}
+
+impl From<syscall::MemMapErr> for Errno {
+    fn from(err: syscall::MemMapErr) -> Self {
+        match err {
+            syscall::MemMapErr::InvalidArgs => Errno::EINVAL,
+            syscall::MemMapErr::NotSupported => Errno::ENODEV,
+            syscall::MemMapErr::NoMemory => Errno::ENOMEM,
+        }
+    }
+}
+
+impl From<syscall::MemProtectErr> for Errno {
+    fn from(err: syscall::MemProtectErr) -> Self {
+        match err {
+            syscall::MemProtectErr::InvalidArgs => Errno::EINVAL,
+            syscall::MemProtectErr::NotMapped => Errno::ENOMEM,
+        }
+    }
+}
+
+impl From<UnmapErr> for Errno {
+    fn from(err: UnmapErr) -> Self {
+        match err {
+            UnmapErr::InvalidArgs | UnmapErr::NotMapped => Errno::EINVAL,
+        }
+    }
+}
+
+impl From<syscall::SetTlsErr> for Errno {
+    fn from(err: syscall::SetTlsErr) -> Self {
+        match err {
+            syscall::SetTlsErr::InvalidPointer => Errno::EFAULT,
+        }
+    }
+}
+
+impl From<syscall::IsTtyErr> for Errno {
+    fn from(err: syscall::IsTtyErr) -> Self {
+        match err {
+            syscall::IsTtyErr::BadFd => Errno::EBADF,
+        }
+    }
+}
+
+impl From<syscall::SetForegroundErr> for Errno {
+    fn from(err: syscall::SetForegroundErr) -> Self {
+        match err {
+            syscall::SetForegroundErr::NoSuchTask => Errno::ESRCH,
+            syscall::SetForegroundErr::NotPermitted => Errno::EPERM,
+        }
+    }
+}
+
+impl From<syscall::NanosleepErr> for Errno {
+    fn from(err: syscall::NanosleepErr) -> Self {
+        match err {
+            syscall::NanosleepErr::InvalidTime => Errno::EINVAL,
+            syscall::NanosleepErr::Interrupted(_) => Errno::EINTR,
+        }
+    }
+}
+
+impl From<syscall::SetPriorityErr> for Errno {
+    fn from(err: syscall::SetPriorityErr) -> Self {
+        match err {
+            syscall::SetPriorityErr::NoSuchTask => Errno::ESRCH,
+            syscall::SetPriorityErr::InvalidPriority => Errno::EINVAL,
+        }
+    }
+}
+
+impl From<syscall::WaitErr> for Errno {
+    fn from(err: syscall::WaitErr) -> Self {
+        match err {
+            syscall::WaitErr::NoChildren => Errno::ECHILD,
+        }
+    }
+}
+
+impl From<syscall::ExecveErr> for Errno {
+    fn from(err: syscall::ExecveErr) -> Self {
+        match err {
+            syscall::ExecveErr::NotFound => Errno::ENOENT,
+            syscall::ExecveErr::MaxOpenedFiles => Errno::EMFILE,
+            syscall::ExecveErr::NotExecutable => Errno::ENOEXEC,
+        }
+    }
+}
+
+impl From<syscall::KillErr> for Errno {
+    fn from(err: syscall::KillErr) -> Self {
+        match err {
+            syscall::KillErr::NoSuchTask => Errno::ESRCH,
+        }
+    }
+}
+
+impl From<syscall::ThreadJoinErr> for Errno {
+    fn from(err: syscall::ThreadJoinErr) -> Self {
+        match err {
+            syscall::ThreadJoinErr::NoSuchThread => Errno::ESRCH,
+            syscall::ThreadJoinErr::JoinSelf => Errno::EINVAL,
+        }
+    }
+}
@@ -23,10 +23,11 @@ use core::cell::RefCell;
 
 use crate::dev::block_device;
 use crate::dev::char_device;
+use crate::errno::Errno;
 
 use super::{
-    FileSystem, Node, NodeInternals, NodeType, ReadDirErr, ReadFileErr,
-    WriteFileErr,
+    CreateFileErr, FileSystem, Metadata, Node, NodeInternals, NodeType,
+    ReadDirErr, ReadFileErr, WriteFileErr,
 };
 
 const ROOT_ID: usize = 200;
@@ -105,8 +106,8 @@ impl DevFs {
     ) -> usize {
         let id_in_fs = self.allocate_id(false);
         println!(
-            "[DEVFS] Registering a char device chr{}.",
-            id_in_fs - MAX_BLOCK_DEVICES,
+            "[DEVFS] Registering a char device {}.",
+            char_device_name(chrdev, id_in_fs - MAX_BLOCK_DEVICES),
         );
         self.char_devices.push(Rc::clone(chrdev));
         id_in_fs
@@ -146,11 +147,11 @@ impl FileSystem for DevFs {
             )));
         }
 
-        for (i, _) in self.char_devices.iter().enumerate() {
+        for (i, chrdev) in self.char_devices.iter().enumerate() {
             node_mut.maybe_children.as_mut().unwrap().push(Node(Rc::new(
                 RefCell::new(NodeInternals {
                     _type: NodeType::CharDevice,
-                    name: format!("chr{}", i),
+                    name: char_device_name(chrdev, i),
                     id_in_fs: Some(i + MAX_BLOCK_DEVICES),
 
                     parent: Some(Weak::clone(&node_weak)),
@@ -203,20 +204,76 @@ impl FileSystem for DevFs {
         id: usize,
         _offset: usize,
         buf: &[u8],
-    ) -> Result<(), WriteFileErr> {
+    ) -> Result<usize, WriteFileErr> {
         match self.resolve_id(id) {
-            ResolveId::BlockDevice(_) => unimplemented!(),
+            // FIXME: writing to block devices is not supported yet.
+            ResolveId::BlockDevice(_) => return Err(WriteFileErr::NotWritable),
             ResolveId::CharDevice(rc_refcell_chrdev) => {
                 let mut chrdev = rc_refcell_chrdev.borrow_mut();
                 chrdev.write_many(buf)?;
             }
         }
-        Ok(())
+        Ok(buf.len())
+    }
+
+    fn create_file(
+        &self,
+        _dir_id: usize,
+        _name: &str,
+    ) -> Result<Node, CreateFileErr> {
+        Err(CreateFileErr::ReadOnly)
+    }
+
+    fn truncate_file(&self, _id: usize) -> Result<(), WriteFileErr> {
+        Err(WriteFileErr::NotWritable)
+    }
+
+    /// The devices are owned by root and can be read and written by anyone.
+    /// There is no clock to tell the times.
+    fn metadata(&self, id: usize) -> Result<Metadata, ReadFileErr> {
+        let (permissions, hard_links) = if id == ROOT_ID {
+            (0o755, 2)
+        } else {
+            (0o666, 1)
+        };
+        Ok(Metadata {
+            size: 0,
+            permissions,
+            user_id: 0,
+            group_id: 0,
+            hard_links,
+            access_time: 0,
+            modification_time: 0,
+            change_time: 0,
+        })
+    }
+
+    fn read_link(&self, _id: usize) -> Result<String, ReadFileErr> {
+        Err(ReadFileErr::NotReadable)
     }
 
     fn file_size_bytes(&self, _id: usize) -> Result<usize, ReadFileErr> {
         Ok(0)
     }
+
+    fn ioctl(&self, id: usize, request: u32, arg: usize) -> Result<u32, Errno> {
+        match self.resolve_id(id) {
+            ResolveId::BlockDevice(_) => Err(Errno::ENOTTY),
+            ResolveId::CharDevice(rc_refcell_chrdev) => {
+                rc_refcell_chrdev.borrow_mut().ioctl(request, arg)
+            }
+        }
+    }
+}
+
+fn char_device_name(
+    chrdev: &Rc<RefCell<dyn char_device::CharDevice>>,
+    idx: usize,
+) -> String {
+    match chrdev.borrow().name() {
+        Some(name) => String::from(name),
+        None => format!("chr{}", idx),
+    }
 }
 
 enum ResolveId {
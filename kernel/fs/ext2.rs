@@ -26,9 +26,10 @@ use core::ops::Range;
 use core::slice;
 
 use super::{
-    FileSystem, Node, NodeInternals, NodeType, ReadDirErr, ReadFileErr,
-    WriteFileErr,
+    CreateFileErr, FileSystem, Metadata, Node, NodeInternals, NodeType,
+    ReadDirErr, ReadFileErr, WriteFileErr,
 };
+use crate::arch::tsc::profile_scope;
 use crate::dev::disk;
 
 #[allow(dead_code)]
@@ -198,6 +199,21 @@ impl Inode {
         InodeType::try_from(raw).unwrap()
     }
 
+    /// Returns the target of a fast symbolic link, which is stored in place
+    /// of the block pointers if it is shorter than 60 bytes.
+    fn fast_symlink_target(&self) -> [u8; 60] {
+        let mut ptrs = [0u32; 15];
+        ptrs[..12].copy_from_slice(&self.direct_block_ptrs());
+        ptrs[12] = self.singly_indirect_block_ptr;
+        ptrs[13] = self.doubly_indirect_block_ptr;
+        ptrs[14] = self.triply_indirect_block_ptr;
+        let mut target = [0u8; 60];
+        for (i, ptr) in ptrs.iter().enumerate() {
+            target[i * 4..i * 4 + 4].copy_from_slice(&ptr.to_le_bytes());
+        }
+        target
+    }
+
     fn direct_block_ptrs(&self) -> [u32; 12] {
         [
             self.direct_block_ptr_0,
@@ -872,6 +888,7 @@ impl FileSystem for Ext2 {
         offset: usize,
         buf: &mut [u8],
     ) -> Result<usize, ReadFileErr> {
+        profile_scope!("ext2 read_file");
         assert_ne!(id as u32, 0, "invalid id");
         let inode = self.read_inode(id as u32)?;
         print!(
@@ -916,8 +933,50 @@ impl FileSystem for Ext2 {
         _id: usize,
         _offset: usize,
         _buf: &[u8],
-    ) -> Result<(), WriteFileErr> {
-        unimplemented!();
+    ) -> Result<usize, WriteFileErr> {
+        // FIXME: writing is not supported yet.
+        Err(WriteFileErr::NotWritable)
+    }
+
+    fn metadata(&self, id: usize) -> Result<Metadata, ReadFileErr> {
+        assert_ne!(id as u32, 0, "invalid id");
+        let inode = self.read_inode(id as u32)?;
+        Ok(Metadata {
+            size: self.inode_size(&inode),
+            permissions: inode.type_and_permissions & 0o7777,
+            user_id: inode.user_id,
+            group_id: inode.group_id,
+            hard_links: inode.count_hard_links,
+            access_time: inode.last_access_time,
+            modification_time: inode.last_modification_time,
+            change_time: inode.creation_time,
+        })
+    }
+
+    fn read_link(&self, id: usize) -> Result<String, ReadFileErr> {
+        assert_ne!(id as u32, 0, "invalid id");
+        let inode = self.read_inode(id as u32)?;
+        let size = self.inode_size(&inode);
+        let target = if size < 60 && inode.count_disk_sectors == 0 {
+            inode.fast_symlink_target()[..size].to_vec()
+        } else {
+            let mut target = vec![0u8; size];
+            self.read_file(id, 0, &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| ReadFileErr::InvalidOffsetOrLen)
+    }
+
+    fn create_file(
+        &self,
+        _dir_id: usize,
+        _name: &str,
+    ) -> Result<Node, CreateFileErr> {
+        Err(CreateFileErr::ReadOnly)
+    }
+
+    fn truncate_file(&self, _id: usize) -> Result<(), WriteFileErr> {
+        Err(WriteFileErr::NotWritable)
     }
 
     fn file_size_bytes(&self, id: usize) -> Result<usize, ReadFileErr> {
@@ -943,6 +1002,7 @@ impl From<InodeType> for NodeType {
         match inode_type {
             InodeType::RegularFile => NodeType::RegularFile,
             InodeType::Dir => NodeType::Dir,
+            InodeType::SymbolicLink => NodeType::Symlink,
             _ => unimplemented!(),
         }
     }
@@ -954,6 +1014,7 @@ impl TryFrom<DirEntryType> for NodeType {
         match entry_type {
             DirEntryType::RegularFile => Ok(NodeType::RegularFile),
             DirEntryType::Dir => Ok(NodeType::Dir),
+            DirEntryType::SymbolicLink => Ok(NodeType::Symlink),
             _ => Err("unknown dir entry type"),
         }
     }
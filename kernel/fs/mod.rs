@@ -16,6 +16,7 @@
 
 pub mod devfs;
 pub mod ext2;
+pub mod pipefs;
 
 use alloc::rc::{Rc, Weak};
 use alloc::string::{FromUtf8Error, String};
@@ -24,8 +25,11 @@ use core::cell::RefCell;
 use core::cmp;
 use core::fmt;
 
-use crate::dev::disk;
+use crate::dev::{block_device, disk};
+use crate::errno::Errno;
 use crate::kernel_static::Mutex;
+use crate::multiboot;
+use crate::task_manager::WaitQueue;
 
 #[derive(Clone, Debug)]
 pub struct Node(pub Rc<RefCell<NodeInternals>>);
@@ -209,7 +213,7 @@ impl Node {
         mount_node.0.borrow_mut()._type =
             NodeType::MountPoint(Rc::clone(&mountable));
         mount_node.0.borrow_mut().name = String::from(child_name);
-        mount_node.0.borrow_mut().parent = Some(Rc::downgrade(&child.0));
+        mount_node.0.borrow_mut().parent = child.0.borrow().parent.clone();
         child.0.replace(mount_node.0.borrow().clone());
         let child_weak = Rc::downgrade(&child.0);
 
@@ -220,24 +224,153 @@ impl Node {
     }
 
     pub fn path(&mut self, path: &str) -> Option<Node> {
-        let mut current = self.clone();
-        let last_is_dir = path.ends_with("/");
-        for elem in path.split("/") {
-            if !elem.is_empty() {
-                if let Some(child) = current.child_named(elem) {
-                    current = child;
-                } else {
-                    return None;
+        self.lookup(path).ok()
+    }
+
+    /// Resolves `path` relative to the node, which is also the root for the
+    /// absolute symbolic links.  Unlike [`Node::path()`], this tells why the
+    /// path cannot be resolved and does not panic if one of the path
+    /// components is not a directory.
+    ///
+    /// The symbolic links are followed, including the last component.
+    pub fn lookup(&mut self, path: &str) -> Result<Node, LookupErr> {
+        self.resolve(self, path, true, 0)
+    }
+
+    /// Same as [`Node::lookup()`], but if the last component of the path is a
+    /// symbolic link, returns the link itself.
+    pub fn lookup_no_follow(&mut self, path: &str) -> Result<Node, LookupErr> {
+        self.resolve(self, path, false, 0)
+    }
+
+    /// Resolves `path` relative to the node, or to `root` if it is absolute.
+    /// `depth` is the number of symbolic links followed so far.
+    fn resolve(
+        &self,
+        root: &Node,
+        path: &str,
+        follow_last: bool,
+        depth: usize,
+    ) -> Result<Node, LookupErr> {
+        let mut current = if path.starts_with('/') {
+            root.clone()
+        } else {
+            self.clone()
+        };
+        let last_is_dir = path.ends_with('/');
+        let mut elems = path.split('/').filter(|elem| !elem.is_empty());
+        let mut maybe_elem = elems.next();
+        while let Some(elem) = maybe_elem {
+            maybe_elem = elems.next();
+            if elem.len() > NAME_MAX {
+                return Err(LookupErr::NameTooLong);
+            }
+            if !current.is_dir() {
+                return Err(LookupErr::NotDir);
+            }
+            if elem == "." {
+                continue;
+            } else if elem == ".." {
+                let parent = current.0.borrow().parent.clone();
+                if let Some(parent) = parent {
+                    current = Node(parent.upgrade().unwrap());
                 }
+                continue;
             }
+
+            let parent = current.clone();
+            current = current.child_named(elem).ok_or(LookupErr::NotFound)?;
+            let is_last = maybe_elem.is_none();
+            if current.0.borrow()._type == NodeType::Symlink
+                && (!is_last || follow_last || last_is_dir)
+            {
+                if depth == MAX_SYMLINKS {
+                    return Err(LookupErr::TooManyLinks);
+                }
+                let id_in_fs = current.0.borrow().id_in_fs.unwrap();
+                let target = current
+                    .fs()
+                    .read_link(id_in_fs)
+                    .map_err(|_| LookupErr::NotFound)?;
+                current = parent.resolve(root, &target, true, depth + 1)?;
+            }
+        }
+        if last_is_dir && !current.is_dir() {
+            return Err(LookupErr::NotDir);
+        }
+        Ok(current)
+    }
+
+    /// Returns the metadata of the node.  For a mount point, this is the
+    /// metadata of the root directory of the mounted file system.
+    pub fn metadata(&self) -> Result<Metadata, ReadFileErr> {
+        let id_in_fs = self.0.borrow().id_in_fs.unwrap();
+        self.fs().metadata(id_in_fs)
+    }
+
+    /// Returns an ID of the file system the node resides on, which is unique
+    /// among the mounted file systems.
+    pub fn fs_id(&self) -> usize {
+        Rc::as_ptr(&self.fs()) as *const u8 as usize
+    }
+
+    /// Performs a device-specific request if the node is a char device.
+    pub fn ioctl(&self, request: u32, arg: usize) -> Result<u32, Errno> {
+        let internals = self.0.borrow();
+        if internals._type != NodeType::CharDevice {
+            return Err(Errno::ENOTTY);
+        }
+        let id_in_fs = internals.id_in_fs.unwrap();
+        drop(internals);
+        self.fs().ioctl(id_in_fs, request, arg)
+    }
+
+    /// Checks if the node is a directory or a mount point.
+    pub fn is_dir(&self) -> bool {
+        let internals = self.0.borrow();
+        internals._type == NodeType::Dir || internals.is_mount_point()
+    }
+
+    /// Creates an empty regular file named `name` in the directory node.
+    ///
+    /// # Panics
+    /// See [`Node::children()`].
+    pub fn create_child(&mut self, name: &str) -> Result<Node, CreateFileErr> {
+        if name.len() > NAME_MAX {
+            return Err(CreateFileErr::NameTooLong);
         }
-        if last_is_dir && current.0.borrow()._type != NodeType::Dir {
-            return None;
+        let mut children = self.children();
+        if children.iter().any(|child| child.0.borrow().name == name) {
+            return Err(CreateFileErr::Exists);
         }
-        Some(current)
+
+        let id_in_fs = self.0.borrow().id_in_fs.unwrap();
+        let child = self.fs().create_file(id_in_fs, name)?;
+        child.0.borrow_mut().parent = Some(Rc::downgrade(&self.0));
+        children.push(child.clone());
+        self.0.borrow_mut().maybe_children = Some(children);
+        Ok(child)
     }
 }
 
+/// Maximum length of a file name in bytes.
+pub const NAME_MAX: usize = 255;
+
+/// Maximum number of symbolic links followed in a path lookup.
+pub const MAX_SYMLINKS: usize = 8;
+
+#[derive(Debug)]
+pub enum LookupErr {
+    NotFound,
+    /// A path component other than the last one is not a directory, or the
+    /// path ends with a slash and the last one is not a directory.
+    NotDir,
+    NameTooLong,
+    /// The lookup has followed [MAX_SYMLINKS] symbolic links, which may be a
+    /// loop.
+    TooManyLinks,
+}
+
 #[derive(Clone)]
 pub enum NodeType {
     MountPoint(Rc<RefCell<dyn Mountable>>),
@@ -245,6 +378,8 @@ pub enum NodeType {
     RegularFile,
     BlockDevice,
     CharDevice,
+    Symlink,
+    Fifo,
 }
 
 impl NodeType {
@@ -265,6 +400,8 @@ impl cmp::PartialEq for NodeType {
             NodeType::RegularFile => matches!(other, NodeType::RegularFile),
             NodeType::BlockDevice => matches!(other, NodeType::BlockDevice),
             NodeType::CharDevice => matches!(other, NodeType::CharDevice),
+            NodeType::Symlink => matches!(other, NodeType::Symlink),
+            NodeType::Fifo => matches!(other, NodeType::Fifo),
         }
     }
 }
@@ -277,6 +414,8 @@ impl fmt::Debug for NodeType {
             NodeType::RegularFile => fmt.write_str("RegularFile"),
             NodeType::BlockDevice => fmt.write_str("BlockDevice"),
             NodeType::CharDevice => fmt.write_str("CharDevice"),
+            NodeType::Symlink => fmt.write_str("Symlink"),
+            NodeType::Fifo => fmt.write_str("Fifo"),
         }
     }
 }
@@ -296,14 +435,52 @@ pub trait FileSystem {
         buf: &mut [u8],
     ) -> Result<usize, ReadFileErr>;
 
+    /// Writes `buf` to the file `id` at `offset`, returns the number of bytes
+    /// written, which may be less than `buf.len()` only for a pipe.
     fn write_file(
         &self,
         id: usize,
         offset: usize,
         buf: &[u8],
-    ) -> Result<(), WriteFileErr>;
+    ) -> Result<usize, WriteFileErr>;
+
+    /// Creates an empty regular file named `name` in the directory `dir_id`,
+    /// returns its node.  The node is not added to the directory's children,
+    /// see [`Node::create_child()`].
+    fn create_file(
+        &self,
+        dir_id: usize,
+        name: &str,
+    ) -> Result<Node, CreateFileErr>;
+
+    /// Truncates the regular file `id` to zero length.
+    fn truncate_file(&self, id: usize) -> Result<(), WriteFileErr>;
+
+    /// Returns the metadata of the node `id`.
+    fn metadata(&self, id: usize) -> Result<Metadata, ReadFileErr>;
+
+    /// Returns the target path of the symbolic link `id`.
+    fn read_link(&self, id: usize) -> Result<String, ReadFileErr>;
 
     fn file_size_bytes(&self, id: usize) -> Result<usize, ReadFileErr>;
+
+    /// Called when the node `id` is opened by a task, including the copies of
+    /// an opened file made by a fork.
+    fn file_opened(&self, _id: usize) {}
+
+    /// Called when an opened file of the node `id` is closed.
+    fn file_closed(&self, _id: usize) {}
+
+    /// Performs a device-specific request on the device node `id`, see
+    /// [CharDevice::ioctl](crate::dev::char_device::CharDevice::ioctl).
+    fn ioctl(
+        &self,
+        _id: usize,
+        _request: u32,
+        _arg: usize,
+    ) -> Result<u32, Errno> {
+        Err(Errno::ENOTTY)
+    }
 }
 
 #[derive(Debug)]
@@ -327,12 +504,38 @@ pub enum ReadFileErr {
     InvalidBlockNum, // FIXME: is this ext2-specific?
     InvalidOffsetOrLen,
     NotReadable,
-    Block,
+    Block(&'static WaitQueue),
 }
 
 #[derive(Debug)]
 pub enum WriteFileErr {
     NotWritable,
+    /// There are no readers left to read what is written to a pipe.
+    BrokenPipe,
+    Block(&'static WaitQueue),
+}
+
+/// Metadata of a node as stored by its file system, see [`Node::metadata()`].
+#[derive(Clone, Copy, Debug)]
+pub struct Metadata {
+    pub size: usize,
+    /// Permission bits, e.g. `0o755`.
+    pub permissions: u16,
+    pub user_id: u16,
+    pub group_id: u16,
+    pub hard_links: u16,
+    /// Last access, modification and status change times in seconds since
+    /// the Unix epoch, 0 if unknown.
+    pub access_time: u32,
+    pub modification_time: u32,
+    pub change_time: u32,
+}
+
+#[derive(Debug)]
+pub enum CreateFileErr {
+    ReadOnly,
+    Exists,
+    NameTooLong,
 }
 
 pub struct FsWrapper(Rc<dyn FileSystem>);
@@ -382,3 +585,45 @@ pub fn init_vfs_root_on_disk(disk_id: usize) {
 
     *VFS_ROOT.lock() = Some(root_node);
 }
+
+/// Mounts the first mapped boot module on `/initrd` if it contains a known
+/// file system.
+///
+/// # Locks
+/// This function accesses the mutexes:
+/// * [`static@disk::DISKS`],
+/// * [`static@block_device::BLOCK_DEVICES`] and
+/// * [`static@VFS_ROOT`].
+pub fn mount_initrd() {
+    let module = match multiboot::modules()
+        .iter()
+        .find(|module| module.virt.is_some())
+    {
+        Some(module) => module,
+        None => return,
+    };
+    println!(
+        "[VFS] Mounting the boot module {:?} on /initrd.",
+        module.cmdline(),
+    );
+
+    let rc_disk = Rc::new(RefCell::new(disk::Disk {
+        id: disk::DISKS.lock().len(),
+        rw_interface: Rc::new(disk::mem::MemBlockDevice::new(module.data())),
+        file_system: None,
+    }));
+    if let Err(err) = rc_disk.borrow_mut().try_init_fs() {
+        println!("[VFS] Could not mount the boot module: {:?}", err);
+        return;
+    }
+    disk::DISKS.lock().push(Rc::clone(&rc_disk));
+    block_device::BLOCK_DEVICES.lock().push(rc_disk.clone());
+
+    let mut vfs_root = VFS_ROOT.lock();
+    let root_node = vfs_root.as_mut().unwrap();
+    if root_node.child_named("initrd").is_none() {
+        println!("[VFS] There is no /initrd directory to mount on.");
+        return;
+    }
+    root_node.mount_on_child("initrd", rc_disk);
+}
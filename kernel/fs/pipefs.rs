@@ -0,0 +1,294 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Pipes.
+//!
+//! A pipe is a pair of nodes, the read end and the write end, of a file system
+//! that is not mounted anywhere.  Its opened files are read and written the
+//! same way as the others, the file system keeps track of how many of them
+//! each end has to tell the end of file and a broken pipe.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::cmp;
+
+use crate::kernel_static::Mutex;
+use crate::task_manager::WaitQueue;
+
+use super::{
+    CreateFileErr, FileSystem, FsWrapper, Metadata, Node, NodeInternals,
+    NodeType, ReadDirErr, ReadFileErr, WriteFileErr,
+};
+
+/// Number of bytes a pipe can hold before the writes block.
+pub const PIPE_BUF_SIZE: usize = 4096;
+
+const ROOT_ID: usize = 0;
+/// The read end of the pipe at index `idx` has the ID `FIRST_PIPE_ID + 2 *
+/// idx`, the write end has the next one.
+const FIRST_PIPE_ID: usize = 2;
+
+struct Pipe {
+    buf: VecDeque<u8>,
+    /// Number of opened files of the read end.
+    readers: usize,
+    /// Number of opened files of the write end.
+    writers: usize,
+}
+
+/// A pipe and the wait queues of its ends.  The slot is reused for a new pipe
+/// once both ends are closed, the queues are kept so that they can be
+/// `'static`.
+struct PipeSlot {
+    pipe: Option<Pipe>,
+    /// Tasks waiting for data or for the last writer to close.
+    readers_queue: &'static WaitQueue,
+    /// Tasks waiting for space or for the last reader to close.
+    writers_queue: &'static WaitQueue,
+}
+
+pub struct PipeFs {
+    slots: RefCell<Vec<PipeSlot>>,
+}
+
+kernel_static! {
+    // The root node is the mount point of the pipe file system, so that it is
+    // the file system of the pipe ends.
+    static ref PIPE_FS: Mutex<Option<(Rc<PipeFs>, Node)>> = Mutex::new(None);
+}
+
+/// Creates a pipe, returns its read end and write end.
+///
+/// The ends are not counted as opened until they are opened by a task, so
+/// the pipe is freed only after both of them have been opened and closed.
+pub fn new_pipe() -> (Node, Node) {
+    let mut pipe_fs = PIPE_FS.lock();
+    if pipe_fs.is_none() {
+        let fs = Rc::new(PipeFs {
+            slots: RefCell::new(Vec::new()),
+        });
+        let root = fs.root_dir().unwrap();
+        let wrapper = FsWrapper(Rc::clone(&fs) as Rc<dyn FileSystem>);
+        root.0.borrow_mut()._type =
+            NodeType::MountPoint(Rc::new(RefCell::new(wrapper)));
+        *pipe_fs = Some((fs, root));
+    }
+    let (fs, root) = pipe_fs.as_ref().unwrap();
+
+    let idx = fs.allocate_pipe();
+    let read_end = pipe_end(root, FIRST_PIPE_ID + 2 * idx, "r");
+    let write_end = pipe_end(root, FIRST_PIPE_ID + 2 * idx + 1, "w");
+    (read_end, write_end)
+}
+
+fn pipe_end(root: &Node, id: usize, suffix: &str) -> Node {
+    Node(Rc::new(RefCell::new(NodeInternals {
+        _type: NodeType::Fifo,
+        name: format!("pipe{}{}", (id - FIRST_PIPE_ID) / 2, suffix),
+        id_in_fs: Some(id),
+
+        parent: Some(Rc::downgrade(&root.0)),
+        maybe_children: None,
+    })))
+}
+
+impl PipeFs {
+    /// Creates an empty pipe with no opened ends, returns its index.
+    fn allocate_pipe(&self) -> usize {
+        let pipe = Pipe {
+            buf: VecDeque::with_capacity(PIPE_BUF_SIZE),
+            readers: 0,
+            writers: 0,
+        };
+        let mut slots = self.slots.borrow_mut();
+        match slots.iter().position(|slot| slot.pipe.is_none()) {
+            Some(idx) => {
+                slots[idx].pipe = Some(pipe);
+                idx
+            }
+            None => {
+                slots.push(PipeSlot {
+                    pipe: Some(pipe),
+                    readers_queue: Box::leak(Box::new(WaitQueue::new())),
+                    writers_queue: Box::leak(Box::new(WaitQueue::new())),
+                });
+                slots.len() - 1
+            }
+        }
+    }
+
+    /// Returns the index of the pipe of the end `id` and whether it is the
+    /// write end.
+    fn resolve_id(id: usize) -> (usize, bool) {
+        assert!(id >= FIRST_PIPE_ID, "invalid id");
+        ((id - FIRST_PIPE_ID) / 2, (id - FIRST_PIPE_ID) % 2 == 1)
+    }
+}
+
+impl FileSystem for PipeFs {
+    fn root_dir(&self) -> Result<Node, ReadDirErr> {
+        self.read_dir(ROOT_ID)
+    }
+
+    fn read_dir(&self, id: usize) -> Result<Node, ReadDirErr> {
+        // The pipes are not listed in the root directory.
+        assert_eq!(id, ROOT_ID, "invalid id");
+        Ok(Node(Rc::new(RefCell::new(NodeInternals {
+            _type: NodeType::Dir,
+            name: String::from("/"),
+            id_in_fs: Some(ROOT_ID),
+
+            parent: None,
+            maybe_children: Some(Vec::new()),
+        }))))
+    }
+
+    /// Reads what is in the pipe, up to `buf.len()` bytes.  Blocks while the
+    /// pipe is empty, unless there are no writers left, then returns 0.
+    fn read_file(
+        &self,
+        id: usize,
+        _offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, ReadFileErr> {
+        let (idx, is_write_end) = Self::resolve_id(id);
+        if is_write_end {
+            return Err(ReadFileErr::NotReadable);
+        }
+        let mut slots = self.slots.borrow_mut();
+        let slot = &mut slots[idx];
+        let pipe = slot.pipe.as_mut().unwrap();
+        if pipe.buf.is_empty() {
+            return if pipe.writers == 0 {
+                Ok(0)
+            } else {
+                Err(ReadFileErr::Block(slot.readers_queue))
+            };
+        }
+        let n = cmp::min(buf.len(), pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        slot.writers_queue.wake_all();
+        Ok(n)
+    }
+
+    /// Writes as much of `buf` as fits in the pipe.  Blocks while the pipe is
+    /// full, fails if there are no readers left.
+    fn write_file(
+        &self,
+        id: usize,
+        _offset: usize,
+        buf: &[u8],
+    ) -> Result<usize, WriteFileErr> {
+        let (idx, is_write_end) = Self::resolve_id(id);
+        if !is_write_end {
+            return Err(WriteFileErr::NotWritable);
+        }
+        let mut slots = self.slots.borrow_mut();
+        let slot = &mut slots[idx];
+        let pipe = slot.pipe.as_mut().unwrap();
+        if pipe.readers == 0 {
+            return Err(WriteFileErr::BrokenPipe);
+        }
+        let n = cmp::min(buf.len(), PIPE_BUF_SIZE - pipe.buf.len());
+        if n == 0 {
+            return Err(WriteFileErr::Block(slot.writers_queue));
+        }
+        pipe.buf.extend(&buf[..n]);
+        slot.readers_queue.wake_all();
+        Ok(n)
+    }
+
+    fn create_file(
+        &self,
+        _dir_id: usize,
+        _name: &str,
+    ) -> Result<Node, CreateFileErr> {
+        Err(CreateFileErr::ReadOnly)
+    }
+
+    fn truncate_file(&self, _id: usize) -> Result<(), WriteFileErr> {
+        Err(WriteFileErr::NotWritable)
+    }
+
+    /// The size of a pipe end is the number of bytes in the pipe.
+    fn metadata(&self, id: usize) -> Result<Metadata, ReadFileErr> {
+        let size = if id == ROOT_ID {
+            0
+        } else {
+            self.file_size_bytes(id)?
+        };
+        Ok(Metadata {
+            size,
+            permissions: 0o600,
+            user_id: 0,
+            group_id: 0,
+            hard_links: 1,
+            access_time: 0,
+            modification_time: 0,
+            change_time: 0,
+        })
+    }
+
+    fn read_link(&self, _id: usize) -> Result<String, ReadFileErr> {
+        Err(ReadFileErr::NotReadable)
+    }
+
+    fn file_size_bytes(&self, id: usize) -> Result<usize, ReadFileErr> {
+        let (idx, _) = Self::resolve_id(id);
+        Ok(self.slots.borrow()[idx].pipe.as_ref().unwrap().buf.len())
+    }
+
+    fn file_opened(&self, id: usize) {
+        let (idx, is_write_end) = Self::resolve_id(id);
+        let mut slots = self.slots.borrow_mut();
+        let pipe = slots[idx].pipe.as_mut().unwrap();
+        if is_write_end {
+            pipe.writers += 1;
+        } else {
+            pipe.readers += 1;
+        }
+    }
+
+    /// Wakes up the other end when the last opened file of an end is closed,
+    /// frees the pipe when both ends are closed.
+    fn file_closed(&self, id: usize) {
+        let (idx, is_write_end) = Self::resolve_id(id);
+        let mut slots = self.slots.borrow_mut();
+        let slot = &mut slots[idx];
+        let pipe = slot.pipe.as_mut().unwrap();
+        if is_write_end {
+            pipe.writers -= 1;
+            if pipe.writers == 0 {
+                slot.readers_queue.wake_all();
+            }
+        } else {
+            pipe.readers -= 1;
+            if pipe.readers == 0 {
+                slot.writers_queue.wake_all();
+            }
+        }
+        if pipe.readers == 0 && pipe.writers == 0 {
+            slot.pipe = None;
+        }
+    }
+}
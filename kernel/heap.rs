@@ -14,129 +14,359 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use crate::arch::interrupts;
+use crate::arch::vas;
 use crate::kernel_static::Mutex;
 use crate::memory_region::Region;
+use crate::slab;
 use crate::KERNEL_INFO;
 
 use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
 use core::mem::{align_of, size_of};
+use core::ops::Range;
+use core::ptr::{self, NonNull};
 
 struct Allocator;
 
+/// Small allocations are served by the [slab allocator](crate::slab), the rest
+/// by the tag allocator (see [alloc_tagged]).  A pointer is freed by the
+/// allocator whose memory it is in.
+// The allocator is used in interrupt handlers too, so the heap and slab locks
+// must not be held when an interrupt arrives.
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // println!(
-        //     "alloc: layout: size: {}, align: {}",
-        //     layout.size(),
-        //     layout.align(),
-        // );
-
-        let heap = match *KERNEL_HEAP.lock() {
-            Some(kernel_heap) => kernel_heap,
-            None => panic!("Kernel heap is not initiailized."),
-        };
-
-        // Find a suitable free chunk.
-        let mut needed_size = 0;
-        let mut chosen_tag: *mut Tag = core::ptr::null_mut();
-        let mut chunk_start: *mut u8 = core::ptr::null_mut();
-        for possible_tag in heap.iter_free_tags() {
-            let chunk_size = possible_tag.chunk_size();
-            chunk_start = (possible_tag as *mut Tag).offset(1) as *mut u8;
-            needed_size = ((chunk_start as usize + layout.align() - 1)
-                & !(layout.align() - 1))
-                - chunk_start as usize
-                + layout.size();
-            if chunk_size >= needed_size {
-                chosen_tag = possible_tag as *mut Tag;
-                break;
+        interrupts::with_disabled(|| {
+            if slab::serves(layout) {
+                slab::alloc(layout)
+            } else {
+                alloc_tagged(layout)
             }
-        }
-        if chosen_tag.is_null() {
-            panic!(
-                "alloc: insufficient free heap: {} bytes, need: {} bytes",
-                heap.total_free(),
-                needed_size,
-            );
-            //return core::ptr::null_mut();
-        }
+        })
+    }
 
-        // Add +1 byte just in case an alignment for the tag is needed.
-        if (*chosen_tag).chunk_size() - needed_size
-            < size_of::<Tag>() + heap.min_chunk_size + 1
-        {
-            (*chosen_tag).set_used(true);
-        } else {
-            // Divide the chunk.
-            let second_part = (((chosen_tag.add(1) as usize + needed_size) + 1)
-                & !1) as *mut Tag;
-            *second_part = Tag::new(false, 1, (*chosen_tag).next_tag());
-            *chosen_tag = Tag::new(true, layout.align(), second_part);
-        }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::with_disabled(|| {
+            if slab::slot_size_at(ptr).is_some() {
+                slab::dealloc(ptr);
+            } else {
+                dealloc_tagged(ptr, layout);
+            }
+        })
+    }
 
-        let aligned = chunk_start.add(chunk_start.align_offset(layout.align()));
-        assert_eq!(
-            aligned as usize,
-            (chunk_start as usize + layout.align() - 1) & !(layout.align() - 1),
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let new_layout =
+            Layout::from_size_align_unchecked(new_size, layout.align());
+        interrupts::with_disabled(|| {
+            match (slab::slot_size_at(ptr), slab::slot_size_of(new_layout)) {
+                (Some(slot_size), Some(new_slot_size))
+                    if slot_size == new_slot_size =>
+                {
+                    ptr
+                }
+                (None, None) => realloc_tagged(ptr, layout, new_size),
+                _ => realloc_by_moving(ptr, layout, new_layout),
+            }
+        })
+    }
+}
+
+/// Moves the allocation at `ptr` to a new one with `new_layout`.
+unsafe fn realloc_by_moving(
+    ptr: *mut u8,
+    layout: Layout,
+    new_layout: Layout,
+) -> *mut u8 {
+    let new_ptr = GLOBAL_ALLOCATOR.alloc(new_layout);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(
+            ptr,
+            new_ptr,
+            cmp::min(layout.size(), new_layout.size()),
         );
+        GLOBAL_ALLOCATOR.dealloc(ptr, layout);
+    }
+    new_ptr
+}
 
-        // Place 0xFF's right before the aligned start so that it will be easy
-        // to find the tag (Tag::align is never 0xFF).
-        let n = aligned as usize - chunk_start as usize;
-        (chunk_start as *mut u8).write_bytes(0xFF, n);
+/// Allocates memory in the first free chunk that fits `layout`, returns a null
+/// pointer if the heap is exhausted and cannot grow.
+pub unsafe fn alloc_tagged(layout: Layout) -> *mut u8 {
+    // println!(
+    //     "alloc: layout: size: {}, align: {}",
+    //     layout.size(),
+    //     layout.align(),
+    // );
+
+    let mut kernel_heap = KERNEL_HEAP.lock();
+    let heap = match kernel_heap.as_mut() {
+        Some(heap) => heap,
+        None => panic!("Kernel heap is not initiailized."),
+    };
+
+    // Find a suitable free chunk.  Only the chunks following the freed ones
+    // are merged on dealloc(), so merge all of them before giving up.
+    let mut found = heap.find_free_chunk(layout);
+    if found.is_none() {
+        heap.join_adjacent_free_chunks();
+        found = heap.find_free_chunk(layout);
+    }
+    if found.is_none() {
+        // Grow the heap so that there is a chunk large enough even if it
+        // needs to be aligned, then retry.
+        let grow_by = size_of::<Tag>()
+            + size_of::<*mut Tag>()
+            + layout.align()
+            + layout.size();
+        if heap.grow(grow_by) {
+            found = heap.find_free_chunk(layout);
+        }
+    }
+    let (mut chosen_tag, mut needed_size) = match found {
+        Some(found) => found,
+        // Infallible allocations end up in alloc_error_handler().
+        None => return core::ptr::null_mut(),
+    };
+    let mut chunk_start = chosen_tag.add(1) as *mut u8;
+    check_poison(chosen_tag, chunk_start as usize + needed_size);
+
+    // With a large alignment the padding before the aligned start may be big
+    // enough to leave it as a free chunk of its own.  Its tag is placed right
+    // before the tag address that precedes the aligned start.
+    let padding = needed_size - layout.size() - size_of::<*mut Tag>();
+    if padding >= size_of::<Tag>() + heap.min_chunk_size + 1 {
+        let aligned = chunk_start as usize + padding + size_of::<*mut Tag>();
+        let padding_tag = ((aligned - size_of::<*mut Tag>() - size_of::<Tag>())
+            & !1) as *mut Tag;
+        (*(*chosen_tag).next_tag()).check_magic();
+        *padding_tag = Tag::new(false, 1, (*chosen_tag).next_tag());
+        *chosen_tag = Tag::new(false, 1, padding_tag);
+
+        chosen_tag = padding_tag;
+        chunk_start = chosen_tag.add(1) as *mut u8;
+        needed_size = aligned - chunk_start as usize + layout.size();
+    }
 
-        assert_eq!(aligned.align_offset(layout.align()), 0);
-        assert_ne!(aligned as usize, chosen_tag as usize);
-        aligned
+    // Add +1 byte just in case an alignment for the tag is needed.
+    if (*chosen_tag).chunk_size() - needed_size
+        < size_of::<Tag>() + heap.min_chunk_size + 1
+    {
+        (*chosen_tag).set_used(true);
+    } else {
+        // Divide the chunk.
+        (*(*chosen_tag).next_tag()).check_magic();
+        let second_part =
+            (((chosen_tag.add(1) as usize + needed_size) + 1) & !1) as *mut Tag;
+        *second_part = Tag::new(false, 1, (*chosen_tag).next_tag());
+        *chosen_tag = Tag::new(true, layout.align(), second_part);
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // println!(
-        //     "dealloc: ptr: 0x{:08X}, layout: size: {}, align: {}",
-        //     ptr as u32,
-        //     layout.size(),
-        //     layout.align(),
-        // );
+    let aligned =
+        align_up(chunk_start as usize + size_of::<*mut Tag>(), layout.align())
+            as *mut u8;
 
-        assert_eq!(
-            ptr.align_offset(layout.align()),
-            0,
-            "dealloc: ptr is not properly aligned",
-        );
+    // Store the tag address right before the aligned start so that
+    // dealloc() can find it.
+    (aligned as *mut *mut Tag)
+        .sub(1)
+        .write_unaligned(chosen_tag);
 
-        let heap = match *KERNEL_HEAP.lock() {
-            Some(kernel_heap) => kernel_heap,
-            None => panic!("dealloc on uninitialized kernel heap"),
-        };
+    assert_eq!(aligned.align_offset(layout.align()), 0);
+    assert_ne!(aligned as usize, chosen_tag as usize);
 
-        let mut tag_ptr: *const u8 = ptr.sub(1);
-        while *tag_ptr == 0xFF {
-            tag_ptr = tag_ptr.sub(1);
+    heap.count_alloc(layout.size());
+    aligned
+}
+
+/// Frees memory allocated by [alloc_tagged] with the same `layout`.
+pub unsafe fn dealloc_tagged(ptr: *mut u8, layout: Layout) {
+    // println!(
+    //     "dealloc: ptr: 0x{:08X}, layout: size: {}, align: {}",
+    //     ptr as u32,
+    //     layout.size(),
+    //     layout.align(),
+    // );
+
+    assert_eq!(
+        ptr.align_offset(layout.align()),
+        0,
+        "dealloc: ptr is not properly aligned",
+    );
+
+    let mut kernel_heap = KERNEL_HEAP.lock();
+    let heap = match kernel_heap.as_mut() {
+        Some(heap) => heap,
+        None => panic!("dealloc on uninitialized kernel heap"),
+    };
+
+    let tag = (ptr as *const *mut Tag).sub(1).read_unaligned();
+    (*tag).check_magic();
+    assert!(
+        (*tag).is_used(),
+        "dealloc: double free of 0x{:08X}",
+        ptr as usize
+    );
+    // println!(
+    //     "- tag at 0x{:08X} -> 0x{:08X}, used: {}, align: {}, size: {}",
+    //     tag as u32,
+    //     (*tag).next_tag_addr(),
+    //     (*tag).is_used() as usize,
+    //     (*tag).align(),
+    //     (*tag).chunk_size(),
+    // );
+
+    (*tag).set_used(false);
+    (*tag).align = 1;
+    poison(tag.add(1) as usize, (*tag).next_tag_addr());
+
+    Heap::join_following_free_chunks(tag);
+    heap.count_dealloc(layout.size());
+}
+
+/// Resizes the allocation at `ptr` made by [alloc_tagged] in place if
+/// possible, otherwise moves it.
+///
+/// The chunk grows into the next one if it is free and large enough.  The
+/// excess of a chunk is split off as a new free chunk.
+unsafe fn realloc_tagged(
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    let mut kernel_heap = KERNEL_HEAP.lock();
+    let heap = match kernel_heap.as_mut() {
+        Some(heap) => heap,
+        None => panic!("realloc on uninitialized kernel heap"),
+    };
+
+    let tag = (ptr as *const *mut Tag).sub(1).read_unaligned();
+    (*tag).check_magic();
+    assert!(
+        (*tag).is_used(),
+        "realloc: 0x{:08X} is not allocated",
+        ptr as usize,
+    );
+
+    // The next tag must be aligned at 2 bytes (see alloc_tagged()).
+    let new_end = (ptr as usize + new_size + 1) & !1;
+
+    let next = (*tag).next_tag();
+    if new_end > next as usize {
+        if (*next).is_used()
+            || (*next).is_end_tag()
+            || new_end > (*next).next_tag_addr()
+        {
+            // The heap is locked again to allocate.
+            drop(kernel_heap);
+            let new_layout =
+                Layout::from_size_align_unchecked(new_size, layout.align());
+            return realloc_by_moving(ptr, layout, new_layout);
         }
+        // Absorb the next chunk.
+        check_poison(next, new_end);
+        (*(*next).next_tag()).check_magic();
+        *tag = Tag::new(true, (*tag).align(), (*next).next_tag());
+    }
 
-        let tag = (tag_ptr.add(1) as *mut Tag).sub(1);
-        // println!(
-        //     "- tag at 0x{:08X} -> 0x{:08X}, used: {}, align: {}, size: {}",
-        //     tag as u32,
-        //     (*tag).next_tag_addr(),
-        //     (*tag).is_used() as usize,
-        //     (*tag).align(),
-        //     (*tag).chunk_size(),
-        // );
+    if (*tag).next_tag_addr() - new_end
+        >= size_of::<Tag>() + heap.min_chunk_size + 1
+    {
+        let tail = new_end as *mut Tag;
+        *tail = Tag::new(false, 1, (*tag).next_tag());
+        *tag = Tag::new(true, (*tag).align(), tail);
+        poison(tail.add(1) as usize, (*tail).next_tag_addr());
+        Heap::join_following_free_chunks(tail);
+    }
 
-        (*tag).set_used(false);
-        (*tag).align = 1;
+    heap.count_dealloc(layout.size());
+    heap.count_alloc(new_size);
+    ptr
+}
 
-        heap.join_adjacent_free_chunks();
+/// Whether the free chunks are [poisoned](POISON) to catch use-after-free.  The
+/// tag magic is checked regardless.
+const DEBUG_HEAP: bool = cfg!(debug_assertions);
+
+/// Byte the payload of free chunks is filled with if [DEBUG_HEAP] is `true`.
+const POISON: u8 = 0xDE;
+
+/// Fills the specified range of free memory with [POISON].
+unsafe fn poison(start: usize, end: usize) {
+    if DEBUG_HEAP {
+        (start as *mut u8).write_bytes(POISON, end - start);
+    }
+}
+
+/// Checks that the payload of the free chunk at `tag` is [poisoned](POISON) up
+/// to `end`, panics with the first corrupted offset if it is not.
+unsafe fn check_poison(tag: *mut Tag, end: usize) {
+    if !DEBUG_HEAP {
+        return;
+    }
+    let start = tag.add(1) as usize;
+    for addr in start..end {
+        if *(addr as *const u8) != POISON {
+            panic!(
+                "heap: free chunk at 0x{:08X} was modified at offset {} \
+                 (0x{:08X}), use after free?",
+                tag as usize,
+                addr - start,
+                addr,
+            );
+        }
     }
 }
 
+/// Rounds `addr` up to a multiple of `align`, which must be a power of two.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
 #[global_allocator]
 static GLOBAL_ALLOCATOR: Allocator = Allocator;
 
 #[alloc_error_handler]
-fn alloc_error_handler(_: Layout) -> ! {
-    panic!("alloc_error_handler called");
+fn alloc_error_handler(layout: Layout) -> ! {
+    println!(
+        "[HEAP] Out of memory: need {} bytes (align {}).",
+        layout.size(),
+        layout.align(),
+    );
+    match KERNEL_HEAP.try_lock() {
+        Some(kernel_heap) => match kernel_heap.as_ref() {
+            Some(heap) => {
+                println!("[HEAP] {:?}.", heap.usage());
+                heap.stats();
+            }
+            None => println!("[HEAP] The heap is not initialized."),
+        },
+        None => println!("[HEAP] Unable to lock the heap."),
+    }
+    slab::stats();
+    panic!("alloc: insufficient free heap");
+}
+
+/// Allocates memory on the kernel heap, returns `None` if there is not enough.
+///
+/// Unlike the infallible allocations (e.g. [`Box::new()`]), this does not panic
+/// when the heap is exhausted, so the caller may shed load instead, e.g. drop a
+/// cache or fail a syscall with ENOMEM.  The memory must be deallocated with
+/// [`alloc::alloc::dealloc()`] with the same layout.  For collections,
+/// `Vec::try_reserve()` serves the same purpose once it is available.
+///
+/// # Panics
+/// This function panics if `layout` has zero size.
+///
+/// [`Box::new()`]: alloc::boxed::Box::new
+pub fn try_alloc(layout: Layout) -> Option<NonNull<u8>> {
+    assert_ne!(layout.size(), 0, "layout must have non-zero size");
+    NonNull::new(unsafe { GLOBAL_ALLOCATOR.alloc(layout) })
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -230,13 +460,118 @@ impl Tag {
 pub struct Heap {
     region: Region<usize>,
     min_chunk_size: usize,
+
+    bytes_allocated: usize,
+    allocation_count: usize,
+    peak_bytes: usize,
+}
+
+/// Kernel heap usage, in bytes.
+///
+/// The allocations are counted by the tag allocator, so a slab counts as one
+/// allocation of a whole page no matter how many of its slots are used.  The
+/// memory used by the allocator itself, e.g. the tags and the alignment
+/// padding, counts neither as used nor as free.
+#[derive(Clone, Copy, Debug)]
+pub struct HeapUsage {
+    /// Current size of the heap.
+    pub total: usize,
+    /// Bytes requested from the tag allocator by the live allocations,
+    /// including the slabs.
+    pub used: usize,
+    /// Bytes in the free chunks.
+    pub free: usize,
+    /// Size of the largest free chunk.
+    pub largest_free_chunk: usize,
+    /// Number of free chunks.
+    pub free_chunks: usize,
+    /// Highest value of [used](Self::used) since the heap was initialized.
+    pub peak: usize,
+    /// Number of live allocations of the tag allocator, including the slabs.
+    pub allocations: usize,
 }
 
 impl Heap {
+    fn count_alloc(&mut self, size: usize) {
+        self.bytes_allocated += size;
+        self.allocation_count += 1;
+        self.peak_bytes = cmp::max(self.peak_bytes, self.bytes_allocated);
+    }
+
+    fn count_dealloc(&mut self, size: usize) {
+        self.bytes_allocated -= size;
+        self.allocation_count -= 1;
+    }
+
+    pub fn usage(&self) -> HeapUsage {
+        let mut free = 0;
+        let mut largest_free_chunk = 0;
+        let mut free_chunks = 0;
+        for tag in self.iter_free_tags() {
+            let chunk_size = tag.chunk_size();
+            free += chunk_size;
+            free_chunks += 1;
+            largest_free_chunk = cmp::max(largest_free_chunk, chunk_size);
+        }
+        HeapUsage {
+            total: self.region.len(),
+            used: self.bytes_allocated,
+            free,
+            largest_free_chunk,
+            free_chunks,
+            peak: self.peak_bytes,
+            allocations: self.allocation_count,
+        }
+    }
+
     fn first_tag(&self) -> *mut Tag {
         self.region.start as *mut Tag
     }
 
+    /// Extends the heap by at least `size` bytes by mapping new pages after its
+    /// end, returns `false` if it cannot grow.
+    ///
+    /// The heap never grows beyond [KERNEL_HEAP_MAX_SIZE].  The new pages are
+    /// visible in every VAS since the kernel page tables are shared.
+    unsafe fn grow(&mut self, size: usize) -> bool {
+        let grow_by = cmp::max((size + 0xFFF) & !0xFFF, KERNEL_HEAP_MIN_GROWTH);
+        let new_end = self.region.end + grow_by;
+        if new_end - self.region.start > KERNEL_HEAP_MAX_SIZE {
+            return false;
+        }
+
+        // The kernel VAS may be locked by the code that is allocating, so the
+        // pages are mapped without locking it.  The heap page tables are
+        // touched only here, under the heap lock.
+        let res = vas::allocate_kernel_pages_from_stack(
+            self.region.end as u32,
+            new_end as u32,
+        );
+        if res.is_err() {
+            return false;
+        }
+
+        // The old end tag becomes the tag of a free chunk that spans the new
+        // pages.
+        let old_end_tag = (self.region.end - size_of::<Tag>()) as *mut Tag;
+        let new_end_tag = (new_end - size_of::<Tag>()) as *mut Tag;
+        *new_end_tag = Tag::new(false, 1, core::ptr::null());
+        *old_end_tag = Tag::new(false, 1, new_end_tag);
+        poison(old_end_tag.add(1) as usize, new_end_tag as usize);
+
+        self.region.end = new_end;
+        KERNEL_INFO.arch.heap_region.end = new_end;
+        self.join_adjacent_free_chunks();
+
+        println!(
+            "[HEAP] Grew by {} KiB to {} KiB, total free: {} bytes.",
+            grow_by / 1024,
+            self.region.len() / 1024,
+            self.total_free(),
+        );
+        true
+    }
+
     fn total_free(&self) -> usize {
         let mut total_free: usize = 0;
         for tag in self.iter_free_tags() {
@@ -247,28 +582,51 @@ impl Heap {
         total_free
     }
 
+    /// Finds the first free chunk that fits `layout`, returns its tag and the
+    /// number of bytes needed from the start of the chunk.
+    fn find_free_chunk(&self, layout: Layout) -> Option<(*mut Tag, usize)> {
+        for tag in self.iter_free_tags() {
+            let chunk_start = (tag as *mut Tag as usize) + size_of::<Tag>();
+            let needed_size =
+                align_up(chunk_start + size_of::<*mut Tag>(), layout.align())
+                    - chunk_start
+                    + layout.size();
+            if tag.chunk_size() >= needed_size {
+                return Some((tag, needed_size));
+            }
+        }
+        None
+    }
+
+    /// Merges every run of adjacent free chunks into a single chunk.
     pub fn join_adjacent_free_chunks(&self) {
-        let mut from: *mut Tag = core::ptr::null_mut();
-        let mut to: *const Tag = core::ptr::null();
-        for tag in self.iter_tags() {
-            if !tag.is_used() && !tag.is_end_tag() {
-                if from.is_null() {
-                    from = tag;
-                } else {
-                    to = tag;
-                }
-            } else if !to.is_null() {
-                unsafe {
-                    *from = Tag::new(false, 1, (*to).next_tag());
+        let mut tag = self.first_tag();
+        unsafe {
+            while !(*tag).is_end_tag() {
+                if !(*tag).is_used() {
+                    Self::join_following_free_chunks(tag);
                 }
-                from = core::ptr::null_mut();
-                to = core::ptr::null();
-            } else {
-                from = core::ptr::null_mut();
+                tag = (*tag).next_tag();
             }
         }
     }
 
+    /// Extends the free chunk of `tag` over all the free chunks right after it.
+    unsafe fn join_following_free_chunks(tag: *mut Tag) {
+        let mut next = (*tag).next_tag();
+        (*next).check_magic();
+        while !(*next).is_used() && !(*next).is_end_tag() {
+            // The tag becomes a part of the free chunk.
+            let absorbed = next;
+            next = (*next).next_tag();
+            (*next).check_magic();
+            poison(absorbed as usize, absorbed.add(1) as usize);
+        }
+        if next != (*tag).next_tag() {
+            *tag = Tag::new(false, 1, next);
+        }
+    }
+
     fn iter_tags(&self) -> HeapIter {
         HeapIter {
             heap: self,
@@ -325,6 +683,22 @@ impl Heap {
         free_sizes.reverse();
         println!("[HEAP] Used sizes: {:?}.", used_sizes);
         println!("[HEAP] Free sizes: {:?}.", free_sizes);
+
+        // The allocation in a chunk follows the tag address.
+        let mut used_by_class = [0; slab::SLOT_SIZES.len() + 1];
+        for tag in self.iter_tags().filter(|tag| tag.is_used()) {
+            let size = tag.chunk_size() - size_of::<*mut Tag>();
+            let idx = slab::SLOT_SIZES
+                .iter()
+                .position(|&slot_size| size <= slot_size)
+                .unwrap_or(slab::SLOT_SIZES.len());
+            used_by_class[idx] += 1;
+        }
+        println!(
+            "[HEAP] Used chunks by slot size {:?} and larger: {:?}.",
+            slab::SLOT_SIZES,
+            used_by_class,
+        );
     }
 }
 
@@ -367,8 +741,15 @@ impl<'a> Iterator for HeapIter<'a> {
     }
 }
 
+/// Initial size of the kernel heap.
 pub const KERNEL_HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
 
+/// Size the kernel heap may grow to on demand.
+pub const KERNEL_HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+
+/// The kernel heap grows by at least this many bytes at a time.
+const KERNEL_HEAP_MIN_GROWTH: usize = 64 * 1024; // 64 KiB
+
 kernel_static! {
     pub static ref KERNEL_HEAP: Mutex<Option<Heap>> = Mutex::new(None);
 }
@@ -404,10 +785,18 @@ pub fn init() {
     unsafe {
         *heap_start_tag_ptr = start_tag;
         *heap_end_tag_ptr = end_tag;
+        poison(
+            heap_start_tag_ptr.add(1) as usize,
+            heap_end_tag_ptr as usize,
+        );
 
         *KERNEL_HEAP.lock() = Some(Heap {
             region: heap_region,
             min_chunk_size: 1,
+
+            bytes_allocated: 0,
+            allocation_count: 0,
+            peak_bytes: 0,
         });
     }
 
@@ -418,3 +807,90 @@ pub fn init() {
         KERNEL_HEAP.lock().unwrap().total_free(),
     );
 }
+
+/// Walks the whole kernel heap, checking the magic of every tag and, if
+/// [DEBUG_HEAP] is `true`, that the free chunks are still poisoned.
+///
+/// # Panics
+/// This function panics if the heap is corrupted or not initialized.
+pub fn check() {
+    interrupts::with_disabled(|| {
+        let kernel_heap = KERNEL_HEAP.lock();
+        let heap = kernel_heap.as_ref().expect("the heap is not initialized");
+        for tag in heap.iter_tags() {
+            if !tag.is_used() && !tag.is_end_tag() {
+                unsafe {
+                    check_poison(tag, tag.next_tag_addr());
+                }
+            }
+        }
+    });
+}
+
+/// Returns the range from the tag of the chunk of the allocation at `ptr` made
+/// by [alloc_tagged] to the next tag.
+///
+/// # Safety
+/// `ptr` must be a live allocation of the tag allocator.
+pub unsafe fn chunk_of(ptr: *const u8) -> Range<usize> {
+    let tag = (ptr as *const *mut Tag).sub(1).read_unaligned();
+    (*tag).check_magic();
+    tag as usize..(*tag).next_tag_addr()
+}
+
+/// Returns the range from `tag_addr` to the next tag if there is the tag of a
+/// free chunk at `tag_addr`, `None` if the chunk is used.
+///
+/// # Safety
+/// There must be a tag at `tag_addr`, e.g. one returned by [chunk_of] that has
+/// not been merged into another chunk.
+pub unsafe fn free_chunk_at(tag_addr: usize) -> Option<Range<usize>> {
+    let tag = tag_addr as *const Tag;
+    (*tag).check_magic();
+    if (*tag).is_used() {
+        None
+    } else {
+        Some(tag_addr..(*tag).next_tag_addr())
+    }
+}
+
+/// Merges every run of adjacent free chunks of the kernel heap, see
+/// [Heap::join_adjacent_free_chunks].
+pub fn join_free_chunks() {
+    interrupts::with_disabled(|| {
+        KERNEL_HEAP
+            .lock()
+            .as_ref()
+            .expect("the heap is not initialized")
+            .join_adjacent_free_chunks();
+    });
+}
+
+/// Prints the chunk size histograms of the kernel heap, see [Heap::stats].
+pub fn stats() {
+    interrupts::with_disabled(|| {
+        KERNEL_HEAP
+            .lock()
+            .as_ref()
+            .expect("the heap is not initialized")
+            .stats();
+    });
+}
+
+/// Same as [usage], but returns `None` if the heap is locked or not
+/// initialized.
+pub fn try_usage() -> Option<HeapUsage> {
+    KERNEL_HEAP.try_lock()?.as_ref().map(|heap| heap.usage())
+}
+
+/// Returns the current kernel heap usage.
+///
+/// # Panics
+/// This function panics if the heap is not initialized.
+pub fn usage() -> HeapUsage {
+    KERNEL_HEAP
+        .lock()
+        .as_ref()
+        .expect("the heap is not initialized")
+        .usage()
+}
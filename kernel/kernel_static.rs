@@ -77,8 +77,8 @@ impl<T> Mutex<T> {
             let mut color: u8 = 0;
             while self.locked.load(Ordering::Relaxed) {
                 unsafe {
-                    let ch_ptr = 0xB8000 as *mut u8;
-                    let color_ptr = 0xB8001 as *mut u8;
+                    let ch_ptr = crate::dev::vga::BUFFER_ADDR as *mut u8;
+                    let color_ptr = ch_ptr.add(1);
                     *ch_ptr = 0x25; // %
                     *color_ptr = color;
                 }
@@ -95,6 +95,20 @@ impl<T> Mutex<T> {
         }
     }
 
+    /// Releases the lock regardless of who holds it.
+    ///
+    /// # Safety
+    /// The holder must never access the data again, e.g. because the kernel
+    /// has panicked.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    /// Checks if the lock is held by anyone.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
     pub fn try_lock(&self) -> Option<MutexWrapper<T>> {
         if self
             .locked
@@ -0,0 +1,232 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Kernel log.
+//!
+//! Everything printed with `print!()` and the `log_*!()` macros is appended to
+//! a ring that is allocated statically, so that it works before the heap and
+//! the screen are initialized.  Each line starts with the uptime and, for the
+//! warnings and the errors, the severity, e.g. `[    1.250] warning: ...`.
+//!
+//! The log is read at absolute byte offsets, which keep growing while the
+//! oldest bytes are overwritten.  The screen is one of the readers (see
+//! [crate::dev::vga]), the others use [read_from].
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::arch::interrupts;
+use crate::dev::timer;
+use crate::dev::vga;
+
+/// Size of the log ring in bytes.
+pub const KLOG_SIZE: usize = 65536;
+
+/// Severity of a log message.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Level {
+    Info,
+    Warn,
+    Err,
+}
+
+impl Level {
+    fn prefix(&self) -> &'static str {
+        match self {
+            Level::Info => "",
+            Level::Warn => "warning: ",
+            Level::Err => "error: ",
+        }
+    }
+}
+
+/// Ring of the logged bytes.
+///
+/// The bytes are appended with the interrupts disabled, so the only
+/// concurrent writer can be an exception handler.  Each byte reserves its
+/// position with `end` before being stored, so the nested messages may be
+/// interleaved with the interrupted one, but do not overwrite it.
+struct Ring {
+    buf: UnsafeCell<[u8; KLOG_SIZE]>,
+    /// Offset right after the last byte.
+    end: AtomicUsize,
+    /// Whether the next byte starts a line and must be preceded by a prefix.
+    at_line_start: AtomicBool,
+}
+
+unsafe impl Sync for Ring {}
+
+static KLOG: Ring = Ring {
+    buf: UnsafeCell::new([0; KLOG_SIZE]),
+    end: AtomicUsize::new(0),
+    at_line_start: AtomicBool::new(true),
+};
+
+impl Ring {
+    fn push(&self, byte: u8) {
+        let pos = self.end.fetch_add(1, Ordering::SeqCst);
+        unsafe {
+            (*self.buf.get())[pos % KLOG_SIZE] = byte;
+        }
+    }
+}
+
+/// Appends the formatted message to the ring, prefixing the lines.
+struct LogWriter {
+    level: Level,
+}
+
+/// Appends the formatted bytes to the ring as they are.
+struct RawWriter;
+
+impl fmt::Write for RawWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            KLOG.push(byte);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Write for LogWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if KLOG.at_line_start.swap(false, Ordering::SeqCst) {
+                let uptime_ms = timer::uptime_ms();
+                write!(
+                    RawWriter,
+                    "[{:5}.{:03}] {}",
+                    uptime_ms / 1000,
+                    uptime_ms % 1000,
+                    self.level.prefix(),
+                )?;
+            }
+            KLOG.push(byte);
+            if byte == b'\n' {
+                KLOG.at_line_start.store(true, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends a message to the log without printing it.
+///
+/// The level only affects the lines that the message starts, a message that
+/// continues a line gets no prefix.
+pub fn append(level: Level, args: fmt::Arguments) {
+    interrupts::with_disabled(|| {
+        LogWriter { level }.write_fmt(args).unwrap();
+    });
+}
+
+/// Appends a message to the log and prints it.
+pub fn _log(level: Level, args: fmt::Arguments) {
+    append(level, args);
+    vga::print_log();
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => ({
+        $crate::klog::_log(
+            $crate::klog::Level::Info,
+            format_args!("{}\n", format_args!($($arg)*)),
+        );
+    })
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => ({
+        $crate::klog::_log(
+            $crate::klog::Level::Warn,
+            format_args!("{}\n", format_args!($($arg)*)),
+        );
+    })
+}
+
+#[macro_export]
+macro_rules! log_err {
+    ($($arg:tt)*) => ({
+        $crate::klog::_log(
+            $crate::klog::Level::Err,
+            format_args!("{}\n", format_args!($($arg)*)),
+        );
+    })
+}
+
+/// Returns the offset of the oldest byte that has not been overwritten.
+pub fn start() -> usize {
+    end().saturating_sub(KLOG_SIZE)
+}
+
+/// Returns the offset right after the last logged byte.
+pub fn end() -> usize {
+    KLOG.end.load(Ordering::SeqCst)
+}
+
+/// Returns the offset of the first of the last `num_lines` lines, or the
+/// [start] if there are fewer lines in the log.
+pub fn tail_start(num_lines: usize) -> usize {
+    interrupts::with_disabled(|| {
+        let (start, end) = (start(), end());
+        let mut offset = end;
+        let mut num_found = 0;
+        while offset > start {
+            let byte = unsafe { (*KLOG.buf.get())[(offset - 1) % KLOG_SIZE] };
+            // The newline of the last line does not start another one.
+            if byte == b'\n' && offset != end {
+                num_found += 1;
+                if num_found == num_lines {
+                    break;
+                }
+            }
+            offset -= 1;
+        }
+        offset
+    })
+}
+
+/// Copies the logged bytes starting at `offset` to `buf`, returns the number
+/// of bytes copied, which is 0 at the end of the log.
+pub fn read_from(offset: usize, buf: &mut [u8]) -> Result<usize, ReadErr> {
+    interrupts::with_disabled(|| {
+        let (start, end) = (start(), end());
+        if offset < start {
+            return Err(ReadErr::Overwritten { start });
+        }
+        if offset > end {
+            return Err(ReadErr::InvalidOffset);
+        }
+        let len = buf.len().min(end - offset);
+        for (i, dst) in buf[..len].iter_mut().enumerate() {
+            *dst = unsafe { (*KLOG.buf.get())[(offset + i) % KLOG_SIZE] };
+        }
+        Ok(len)
+    })
+}
+
+#[derive(Debug)]
+pub enum ReadErr {
+    /// The bytes at the offset have been overwritten, the oldest available
+    /// ones start at `start`.
+    Overwritten { start: usize },
+    /// The offset is past the end of the log.
+    InvalidOffset,
+}
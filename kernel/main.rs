@@ -26,8 +26,12 @@ pub mod bitflags;
 #[macro_use]
 pub mod kernel_static;
 
+pub mod mmio;
 pub mod port;
 
+#[macro_use]
+pub mod klog;
+
 #[macro_use]
 pub mod dev;
 
@@ -35,15 +39,20 @@ pub mod dev;
 pub mod arch;
 
 pub mod heap;
+pub mod slab;
 pub mod multiboot;
+pub mod cmdline;
 pub mod memory_region;
 
+pub mod errno;
 pub mod syscall;
+pub mod usercopy;
 
 pub mod stack;
 
 pub mod task;
 pub mod task_manager;
+pub mod sync;
 
 pub mod fs;
 
@@ -52,7 +61,11 @@ pub mod ffi;
 pub mod feeder;
 pub mod elf;
 
+pub mod selftest;
+
 use alloc::rc::Rc;
+use core::cell::RefCell;
+use core::ops::Range;
 use core::panic::PanicInfo;
 
 use memory_region::Region;
@@ -60,6 +73,18 @@ use memory_region::Region;
 pub struct KernelInfo {
     arch: arch::ArchInitInfo,
     available_memory_regions: [Region<usize>; 32], // 32 is enough maybe
+    /// Regions given by the bootloader that must not be used as free memory,
+    /// i.e. the Multiboot information structure and the modules.
+    reserved_memory_regions: [Region<usize>; 32],
+    /// Amount of available memory above 4 GiB, which cannot be used.
+    total_memory_above_4g: u64,
+    cmdline: cmdline::CmdLine,
+    boot_modules: [multiboot::BootModule; multiboot::MAX_BOOT_MODULES],
+    num_boot_modules: usize,
+    elf_sections: [multiboot::ElfSectionHeader; multiboot::MAX_ELF_SECTIONS],
+    num_elf_sections: usize,
+    /// Framebuffer to use instead of the VGA text buffer.
+    framebuffer: Option<dev::framebuffer::Framebuffer>,
 }
 
 impl KernelInfo {
@@ -67,24 +92,96 @@ impl KernelInfo {
         KernelInfo {
             arch: arch::ArchInitInfo::new(),
             available_memory_regions: [Region { start: 0, end: 0 }; 32],
+            reserved_memory_regions: [Region { start: 0, end: 0 }; 32],
+            total_memory_above_4g: 0,
+            cmdline: cmdline::CmdLine::new(),
+            boot_modules: [multiboot::BootModule::new();
+                multiboot::MAX_BOOT_MODULES],
+            num_boot_modules: 0,
+            elf_sections: [multiboot::ElfSectionHeader::new();
+                multiboot::MAX_ELF_SECTIONS],
+            num_elf_sections: 0,
+            framebuffer: None,
         }
     }
 }
 
 pub static mut KERNEL_INFO: KernelInfo = KernelInfo::new();
 
+/// Saves the information passed by the bootloader to [KERNEL_INFO].
+unsafe fn apply_boot_info(boot_info: &multiboot::ParsedBootInfo) {
+    for (i, region) in boot_info.reserved_regions().enumerate() {
+        assert!(
+            i < KERNEL_INFO.reserved_memory_regions.len(),
+            "too many reserved memory regions",
+        );
+        KERNEL_INFO.reserved_memory_regions[i] = region;
+    }
+    let mut num_ignored_regions = 0;
+    for (i, region) in boot_info.available_regions().enumerate() {
+        match KERNEL_INFO.available_memory_regions.get_mut(i) {
+            Some(slot) => *slot = region,
+            None => num_ignored_regions += 1,
+        }
+    }
+    if num_ignored_regions != 0 {
+        println!(
+            "Too many available memory regions, ignored {}.",
+            num_ignored_regions,
+        );
+    }
+    KERNEL_INFO.total_memory_above_4g = boot_info.memory_above_4g();
+
+    if let Some(cmdline) = boot_info.cmdline {
+        // The MBI may be reclaimed, so copy the string.
+        KERNEL_INFO.cmdline.set(cmdline);
+    }
+
+    let modules = boot_info.modules();
+    KERNEL_INFO.boot_modules[..modules.len()].copy_from_slice(modules);
+    KERNEL_INFO.num_boot_modules = modules.len();
+
+    let elf_sections = boot_info.elf_sections();
+    KERNEL_INFO.elf_sections[..elf_sections.len()]
+        .copy_from_slice(elf_sections);
+    KERNEL_INFO.num_elf_sections = elf_sections.len();
+
+    KERNEL_INFO.framebuffer =
+        boot_info.framebuffer.and_then(|fb| fb.to_framebuffer());
+
+    KERNEL_INFO.arch.rsdt_phys = boot_info.rsdt_phys;
+    KERNEL_INFO.arch.xsdt_phys = match boot_info.xsdt_phys {
+        Some(xsdt_phys) if xsdt_phys >> 32 == 0 => Some(xsdt_phys as u32),
+        Some(_) => {
+            println!("XSDT is above 4 GiB, ignoring.");
+            None
+        }
+        None => None,
+    };
+}
+
 #[no_mangle]
 pub extern "C" fn main(magic_num: u32, boot_info: *const multiboot::BootInfo) {
-    dev::vga::init();
-
-    if magic_num == 0x36D76289 {
+    let boot_info = if magic_num == 0x36D76289 {
         println!("Booted by a Multiboot2-compliant bootloader.");
-        unsafe {
-            multiboot::parse(boot_info);
+        match unsafe { multiboot::parse(boot_info) } {
+            Ok(boot_info) => boot_info,
+            Err(err) => panic!("Invalid Multiboot information: {:?}", err),
         }
     } else {
         panic!("Booted by an unknown bootloader.");
+    };
+    unsafe {
+        apply_boot_info(&boot_info);
+    }
+
+    arch::dev::serial::init();
+    if cmdline::get("console") == Some("serial") {
+        arch::dev::serial::set_mirror(true);
     }
+    dev::vga::init();
+    multiboot::report(&boot_info);
+    cmdline::report_unknown_options();
 
     arch::init();
 
@@ -98,14 +195,33 @@ pub extern "C" fn main(magic_num: u32, boot_info: *const multiboot::BootInfo) {
 
     // FIXME
     arch::pci::init();
-    arch::dev::keyboard::init();
+    if arch::dev::i8042::init().is_ok() {
+        arch::dev::keyboard::init();
+        arch::dev::mouse::init();
+    }
+    arch::dev::serial::init_irq();
 
+    dev::vga::init_cursor();
+    dev::keymap::init();
     dev::console::init();
 
     let rc_console = Rc::clone(dev::console::CONSOLE.lock().as_ref().unwrap());
     dev::char_device::CHAR_DEVICES.lock().push(rc_console);
+    if arch::dev::serial::is_present() {
+        dev::char_device::CHAR_DEVICES
+            .lock()
+            .push(Rc::new(RefCell::new(arch::dev::serial::SerialPort)));
+    }
+    if arch::dev::mouse::is_present() {
+        dev::char_device::CHAR_DEVICES
+            .lock()
+            .push(Rc::new(RefCell::new(arch::dev::mouse::MouseDevice)));
+    }
 
-    if dev::disk::DISKS.lock().len() > 0 {
+    if let Some(disk_id) = cmdline::root_disk_id() {
+        println!("Initializing the VFS root on disk {}.", disk_id);
+        fs::init_vfs_root_on_disk(disk_id);
+    } else if dev::disk::DISKS.lock().len() > 0 {
         println!("Initializing the VFS root on disk 0.");
         fs::init_vfs_root_on_disk(0);
     }
@@ -113,16 +229,61 @@ pub extern "C" fn main(magic_num: u32, boot_info: *const multiboot::BootInfo) {
         fs::VFS_ROOT.lock().is_some(),
         "VFS has not been initialized",
     );
+    fs::mount_initrd();
 
+    if let Some(period_ms) = cmdline::timer_period_ms() {
+        println!("Changing the timer period to {} ms.", period_ms);
+        dev::timer::set_period_ms(period_ms);
+    }
+
+    println!("Magic SysRq: {}.", dev::sysrq::HELP);
     task_manager::init();
     // loop {}
 
     // println!("Reached the end of main.");
 }
 
+/// Number of the last log lines printed in the panic report.
+const PANIC_LOG_LINES: usize = 20;
+
+/// Prints the log bytes in `range`, replacing the non-ASCII ones with `?`.
+fn print_log(range: Range<usize>) {
+    let mut offset = range.start;
+    let mut buf = [0; 128];
+    while offset < range.end {
+        let len = (range.end - offset).min(buf.len());
+        let len = match klog::read_from(offset, &mut buf[..len]) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        for byte in buf[..len].iter_mut() {
+            if !byte.is_ascii() {
+                *byte = b'?';
+            }
+        }
+        print!("{}", core::str::from_utf8(&buf[..len]).unwrap());
+        offset += len;
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    // The report is logged too, so the tail must be taken before it.
+    let log_tail = klog::tail_start(PANIC_LOG_LINES)..klog::end();
+    // The panicking code may hold the screen lock.
+    unsafe {
+        dev::vga::break_lock();
+    }
+    // Send the output that is not on the serial port yet, so that the panic
+    // message can be read even if it scrolls off the screen.
+    arch::dev::serial::set_mirror(true);
+    log_err!("{}", info);
+    if let Some(task) = unsafe { task_manager::TASK_MANAGER.running_task() } {
+        log_err!("panicked while running task {} '{}'", task.id, task.name(),);
+    }
     arch::panic();
+    println!(" last {} log lines:", PANIC_LOG_LINES);
+    print_log(log_tail);
+    arch::dev::serial::flush();
     loop {}
 }
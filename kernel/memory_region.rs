@@ -51,7 +51,7 @@ impl<T: RegionType> Region<T> {
             if self.end <= region.end {
                 return OverlappingWith::IsIn;
             }
-        } else if self.end > region.start && self.end < region.end {
+        } else if self.end > region.start && self.end <= region.end {
             return OverlappingWith::EndsIn;
         }
         return OverlappingWith::NoOverlap;
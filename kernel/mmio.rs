@@ -0,0 +1,133 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Memory-mapped I/O.
+//!
+//! [MmioRegion] is a mapped block of device registers accessed at offsets
+//! that are checked against its length.  [VolatileCell] is a register in a
+//! `#[repr(C)]` struct laid over such a block, see [MmioRegion::block].
+//!
+//! All the accesses are volatile, so the compiler neither elides nor reorders
+//! them.
+
+use core::cell::UnsafeCell;
+use core::mem::{align_of, size_of};
+
+/// A block of memory-mapped device registers.
+pub struct MmioRegion {
+    base: usize,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// Creates a region of `len` bytes of registers at the virtual address
+    /// `base`.
+    ///
+    /// # Safety
+    /// The region must be mapped and not be used as normal memory.
+    pub unsafe fn new(base: usize, len: usize) -> Self {
+        assert!(base.checked_add(len).is_some(), "region wraps around");
+        MmioRegion { base, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns a pointer to a `T` at `offset`.
+    ///
+    /// # Panics
+    /// This method panics if the `T` does not lie within the region or is
+    /// misaligned.
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset
+                .checked_add(size_of::<T>())
+                .map_or(false, |end| end <= self.len),
+            "offset 0x{:X} of a {}-byte register is outside the {}-byte MMIO \
+             region",
+            offset,
+            size_of::<T>(),
+            self.len,
+        );
+        let addr = self.base + offset;
+        assert_eq!(addr % align_of::<T>(), 0, "misaligned MMIO register");
+        addr as *mut T
+    }
+
+    /// Reads the register of type `T` at `offset`.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Writes `value` to the register of type `T` at `offset`.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.read(offset)
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        self.write(offset, value)
+    }
+
+    /// Reads a 64-bit register.  On x86 this takes two 32-bit reads, the lower
+    /// half first.
+    pub fn read64(&self, offset: usize) -> u64 {
+        self.read(offset)
+    }
+
+    /// Writes a 64-bit register.  On x86 this takes two 32-bit writes, the
+    /// lower half first.
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.write(offset, value)
+    }
+
+    /// Returns the register block `T` at `offset`, whose registers must be
+    /// [VolatileCell]s.
+    ///
+    /// # Safety
+    /// Every byte of `T` must be a register that may be read and written as a
+    /// part of the field it belongs to.
+    pub unsafe fn block<T>(&self, offset: usize) -> &T {
+        &*self.ptr::<T>(offset)
+    }
+}
+
+/// A memory-mapped register of type `T` in a register block.
+#[repr(transparent)]
+pub struct VolatileCell<T: Copy> {
+    value: UnsafeCell<T>,
+}
+
+impl<T: Copy> VolatileCell<T> {
+    pub fn get(&self) -> T {
+        unsafe { self.value.get().read_volatile() }
+    }
+
+    pub fn set(&self, value: T) {
+        unsafe { self.value.get().write_volatile(value) }
+    }
+
+    /// Reads the register, changes the value with `f` and writes it back.
+    pub fn update<F: FnOnce(&mut T)>(&self, f: F) {
+        let mut value = self.get();
+        f(&mut value);
+        self.set(value);
+    }
+}
@@ -16,16 +16,132 @@
 
 #![allow(dead_code)]
 
+use core::cmp;
 use core::fmt;
+use core::iter;
 use core::mem;
 use core::slice;
 use core::str;
 
 use crate::arch::acpi::sdt;
-use crate::arch::dev::acpi::hpet;
-use crate::memory_region;
+use crate::cmdline::CmdLine;
+use crate::dev::framebuffer::Framebuffer;
+use crate::memory_region::Region;
 use crate::KERNEL_INFO;
 
+/// Maximum number of boot modules that are kept, the rest are ignored.
+pub const MAX_BOOT_MODULES: usize = 8;
+
+/// Module loaded by the bootloader, e.g. an initial ramdisk.
+///
+/// The module pages are reserved in
+/// [`KernelInfo::reserved_memory_regions`](crate::KernelInfo::reserved_memory_regions)
+/// and mapped read-only into the kernel VAS during `arch::init()`, unless
+/// they do not fit in the region for the modules.
+#[derive(Clone, Copy)]
+pub struct BootModule {
+    /// Physical memory occupied by the module.
+    pub phys: Region<usize>,
+    /// Where the module is mapped in the kernel VAS, `None` if it is not.
+    pub virt: Option<Region<usize>>,
+    cmdline: CmdLine,
+}
+
+impl BootModule {
+    pub const fn new() -> Self {
+        BootModule {
+            phys: Region { start: 0, end: 0 },
+            virt: None,
+            cmdline: CmdLine::new(),
+        }
+    }
+
+    /// Returns the string that the bootloader passed along with the module.
+    pub fn cmdline(&self) -> &str {
+        self.cmdline.as_str()
+    }
+
+    /// Returns the contents of the module.
+    ///
+    /// # Panics
+    /// This function panics if the module is not mapped yet.
+    pub fn data(&self) -> &'static [u8] {
+        let virt = self.virt.expect("boot module is not mapped");
+        unsafe { slice::from_raw_parts(virt.start as *const u8, virt.len()) }
+    }
+}
+
+/// Maximum number of the kernel ELF section headers that are kept.
+pub const MAX_ELF_SECTIONS: usize = 64;
+
+/// Section header of the kernel ELF image.
+///
+/// The non-allocated sections, e.g. the symbol table, are loaded by the
+/// bootloader too, and their `addr` is a physical address.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ElfSectionHeader {
+    pub name: u32,
+    pub sh_type: u32,
+    pub flags: u32,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub link: u32,
+    pub info: u32,
+    pub addr_align: u32,
+    pub entry_size: u32,
+}
+
+impl ElfSectionHeader {
+    pub const SHT_SYMTAB: u32 = 2;
+    pub const SHF_ALLOC: u32 = 0x2;
+
+    pub const fn new() -> Self {
+        ElfSectionHeader {
+            name: 0,
+            sh_type: 0,
+            flags: 0,
+            addr: 0,
+            offset: 0,
+            size: 0,
+            link: 0,
+            info: 0,
+            addr_align: 0,
+            entry_size: 0,
+        }
+    }
+
+    /// Returns the physical memory the section was loaded at by the
+    /// bootloader, or `None` if it is an allocated section or was not loaded.
+    pub fn phys_region(&self) -> Option<Region<usize>> {
+        if self.flags & Self::SHF_ALLOC != 0 || self.addr == 0 {
+            None
+        } else {
+            Some(Region::from_start_len(
+                self.addr as usize,
+                self.size as usize,
+            ))
+        }
+    }
+}
+
+/// Returns the section headers of the kernel image, or an empty slice if the
+/// bootloader did not pass them.
+pub fn elf_sections() -> &'static [ElfSectionHeader] {
+    unsafe { &KERNEL_INFO.elf_sections[..KERNEL_INFO.num_elf_sections] }
+}
+
+/// Returns the modules loaded by the bootloader.
+pub fn modules() -> &'static [BootModule] {
+    unsafe { &KERNEL_INFO.boot_modules[..KERNEL_INFO.num_boot_modules] }
+}
+
+/// Returns the modules loaded by the bootloader for updating their mappings.
+pub unsafe fn modules_mut() -> &'static mut [BootModule] {
+    &mut KERNEL_INFO.boot_modules[..KERNEL_INFO.num_boot_modules]
+}
+
 macro_rules! type_enum {
     (#[repr($REPR:ident)] enum $N:ident { Reserved = $R:literal, $($V:ident = $D:literal,)* }) => {
         #[repr($REPR)]
@@ -101,14 +217,14 @@ struct Module {
     string: [u8; 0],
 }
 
+// GRUB uses 32-bit fields here, unlike the standard.
 #[repr(C, packed)]
 struct ElfSymbols {
     tag_type: u32, // 9
     tag_size: u32,
-    num: u16,
-    entsize: u16,
-    shndx: u16,
-    reserved: u16,
+    num: u32,
+    entsize: u32,
+    shndx: u32,
     section_headers: VariedSizeField,
 }
 
@@ -183,7 +299,7 @@ struct FramebufferInfo {
     height: u32,
     bpp: u8,
     _type: u8,
-    reserved: u8,
+    reserved: u16,
     color_info: VariedSizeField,
 }
 
@@ -211,13 +327,14 @@ struct FramebufferPaletteColorDescriptor {
 }
 
 #[repr(C, packed)]
-struct FramebufferRgbColorInfo {
-    red_field_pos: u8,
-    red_mask_size: u8,
-    green_field_pos: u8,
-    green_mask_size: u8,
-    blue_field_pos: u8,
-    blue_mask_size: u8,
+#[derive(Clone, Copy)]
+pub struct FramebufferRgbColorInfo {
+    pub red_field_pos: u8,
+    pub red_mask_size: u8,
+    pub green_field_pos: u8,
+    pub green_mask_size: u8,
+    pub blue_field_pos: u8,
+    pub blue_mask_size: u8,
 }
 
 #[repr(C, packed)]
@@ -301,383 +418,596 @@ struct ImageLoadBasePhysicalAddress {
     load_base_addr: u32,
 }
 
-fn str_from_ascii(ptr: &[u8], size: u32) -> &str {
-    let slice = unsafe {
-        slice::from_raw_parts(ptr as *const _ as *const u8, size as usize - 1)
-    };
-    for (pos, ch) in slice.iter().enumerate() {
-        if ch & (1 << 7) != 0 {
-            panic!("str_from_ascii: non-ASCII character at {}", pos);
+#[repr(C, packed)]
+struct TagHeader {
+    tag_type: u32,
+    tag_size: u32,
+}
+
+/// Maximum number of tags in the MBI, more mean that it is corrupted.
+const MAX_TAGS: usize = 64;
+
+/// Maximum number of memory map entries that are kept, the rest are ignored.
+pub const MAX_MEMORY_MAP_ENTRIES: usize = 64;
+
+/// Start of the memory that the kernel cannot use.  A region cannot end at
+/// 4 GiB, so the last page below it is lost.
+const LOW_MEMORY_END: u64 = 0x1_0000_0000 - 4096;
+
+#[derive(Debug)]
+pub enum ParseErr {
+    /// The MBI is not aligned at 8 bytes.
+    MisalignedMbi,
+    /// The MBI is shorter than its header or its declared size.
+    TruncatedMbi,
+    /// The tag list ends without an end tag.
+    NoEndTag,
+    TooManyTags,
+    /// The tag of this type is shorter than its fields.
+    TagTooSmall(u32),
+    /// The tag of this type does not fit into the MBI.
+    TruncatedTag(u32),
+    /// The tag of this type contains invalid data.
+    InvalidTag(u32),
+    InvalidRsdp,
+    NonAsciiString(u32),
+    /// The end tag is not where the declared MBI size says.
+    SizeMismatch {
+        declared: usize,
+        actual: usize,
+    },
+}
+
+/// Memory map entry as given by the bootloader.
+#[derive(Clone, Copy)]
+pub struct MemoryMapRegion {
+    pub start: u64,
+    pub length: u64,
+    pub region_type: u32,
+}
+
+impl MemoryMapRegion {
+    const fn new() -> Self {
+        MemoryMapRegion {
+            start: 0,
+            length: 0,
+            region_type: 0,
         }
     }
-    str::from_utf8(slice).unwrap()
-}
 
-pub unsafe fn parse(boot_info: *const BootInfo) {
-    let mut ptr = boot_info as *const u8;
+    pub fn end(&self) -> u64 {
+        self.start.saturating_add(self.length)
+    }
 
-    let bi = &*(ptr as *const BootInfo);
-    println!(
-        "Multiboot information is at 0x{:08X}, total size: {} bytes",
-        ptr as u32,
-        { bi.total_size },
-    );
-    ptr = ptr.offset(8);
+    pub fn is_available(&self) -> bool {
+        self.region_type == MemoryMapRegionType::Available as u32
+    }
 
-    let mut num_tags = 0;
-    loop {
-        assert!(num_tags < 32, "too many tags");
-        assert_eq!(ptr as u32 % 8, 0, "tag address is not aligned at 8 bytes");
+    /// Returns the part of the region below [LOW_MEMORY_END], which is the
+    /// only part usable by the kernel.
+    pub fn low_part(&self) -> Option<Region<usize>> {
+        let end = cmp::min(self.end(), LOW_MEMORY_END);
+        if self.start >= end {
+            None
+        } else {
+            Some(Region {
+                start: self.start as usize,
+                end: end as usize,
+            })
+        }
+    }
 
-        let tag_type: u32 = *ptr.cast();
-        let tag_size: u32 = *ptr.cast::<u32>().offset(1);
-        assert!(tag_size >= 8, "tag_size is less than 8 bytes");
+    /// Returns the length of the part of the region above 4 GiB.
+    pub fn high_len(&self) -> u64 {
+        let end = self.end();
+        if end > 0x1_0000_0000 {
+            end - cmp::max(self.start, 0x1_0000_0000)
+        } else {
+            0
+        }
+    }
+}
 
-        // Break here so the type and size don't get printed.
-        if tag_type == 0 && tag_size == 8 {
-            break;
+/// Framebuffer as given by the bootloader.
+#[derive(Clone, Copy)]
+pub struct FramebufferParams {
+    pub addr: u64,
+    pub pitch: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bpp: u8,
+    fb_type: u8,
+    /// Color field positions and sizes, only for an RGB framebuffer.
+    pub rgb: Option<FramebufferRgbColorInfo>,
+}
+
+impl FramebufferParams {
+    pub fn is_ega_text(&self) -> bool {
+        matches!(
+            FramebufferType::from(self.fb_type),
+            FramebufferType::EgaText
+        )
+    }
+
+    /// Returns the framebuffer if it is supported by [`Framebuffer`], i.e. has
+    /// 32-bit RGB pixels and lies below 4 GiB.
+    pub fn to_framebuffer(&self) -> Option<Framebuffer> {
+        let rgb = self.rgb?;
+        let len = self.pitch as u64 * self.height as u64;
+        if self.bpp != 32
+            || self
+                .addr
+                .checked_add(len)
+                .map_or(true, |end| end > 0x1_0000_0000)
+            || rgb.red_mask_size != 8
+            || rgb.green_mask_size != 8
+            || rgb.blue_mask_size != 8
+        {
+            return None;
         }
+        Some(Framebuffer {
+            phys: self.addr as usize,
+            virt: self.addr as usize,
+            pitch: self.pitch as usize,
+            width: self.width as usize,
+            height: self.height as usize,
+            red_pos: rgb.red_field_pos,
+            green_pos: rgb.green_field_pos,
+            blue_pos: rgb.blue_field_pos,
+        })
+    }
+}
 
-        print!("<{:02}:", tag_type);
-        match tag_size {
-            size if size < 1000 => {
-                print!("{:03}> ", size);
-            }
-            size if 1000 <= size && size < 2 * 1024 => {
-                print!(" 1K> ");
-            }
-            size if 2 * 1024 <= size => {
-                print!("{:2}K> ", size / 1024);
-            }
-            _ => unreachable!(),
+/// Information collected from the MBI by [parse].
+///
+/// The strings are borrowed from the MBI, the rest is copied out of it.
+pub struct ParsedBootInfo<'a> {
+    /// Memory occupied by the MBI itself.
+    pub mbi_region: Region<usize>,
+    pub cmdline: Option<&'a str>,
+    pub bootloader_name: Option<&'a str>,
+    /// Amounts of lower and upper memory in KiB.
+    pub basic_memory_info: Option<(u32, u32)>,
+    /// BIOS drive number, partition and subpartition.
+    pub bios_boot_device: Option<(u32, u32, u32)>,
+    memory_map: [MemoryMapRegion; MAX_MEMORY_MAP_ENTRIES],
+    num_memory_map_entries: usize,
+    pub num_ignored_memory_map_entries: usize,
+    modules: [BootModule; MAX_BOOT_MODULES],
+    num_modules: usize,
+    pub num_ignored_modules: usize,
+    pub framebuffer: Option<FramebufferParams>,
+    elf_sections: [ElfSectionHeader; MAX_ELF_SECTIONS],
+    num_elf_sections: usize,
+    pub num_ignored_elf_sections: usize,
+    pub rsdt_phys: Option<u32>,
+    pub xsdt_phys: Option<u64>,
+    /// Types of the tags that are not interpreted.
+    other_tags: [u32; MAX_TAGS],
+    num_other_tags: usize,
+}
+
+impl<'a> ParsedBootInfo<'a> {
+    fn new(mbi_region: Region<usize>) -> Self {
+        ParsedBootInfo {
+            mbi_region,
+            cmdline: None,
+            bootloader_name: None,
+            basic_memory_info: None,
+            bios_boot_device: None,
+            memory_map: [MemoryMapRegion::new(); MAX_MEMORY_MAP_ENTRIES],
+            num_memory_map_entries: 0,
+            num_ignored_memory_map_entries: 0,
+            modules: [BootModule::new(); MAX_BOOT_MODULES],
+            num_modules: 0,
+            num_ignored_modules: 0,
+            framebuffer: None,
+            elf_sections: [ElfSectionHeader::new(); MAX_ELF_SECTIONS],
+            num_elf_sections: 0,
+            num_ignored_elf_sections: 0,
+            rsdt_phys: None,
+            xsdt_phys: None,
+            other_tags: [0; MAX_TAGS],
+            num_other_tags: 0,
         }
+    }
+
+    pub fn memory_map(&self) -> &[MemoryMapRegion] {
+        &self.memory_map[..self.num_memory_map_entries]
+    }
+
+    pub fn modules(&self) -> &[BootModule] {
+        &self.modules[..self.num_modules]
+    }
 
+    pub fn elf_sections(&self) -> &[ElfSectionHeader] {
+        &self.elf_sections[..self.num_elf_sections]
+    }
+
+    /// Returns the available memory regions clamped at [LOW_MEMORY_END].
+    pub fn available_regions(
+        &self,
+    ) -> impl Iterator<Item = Region<usize>> + '_ {
+        self.memory_map()
+            .iter()
+            .filter(|region| region.is_available())
+            .filter_map(|region| region.low_part())
+    }
+
+    /// Returns the amount of available memory above 4 GiB.
+    pub fn memory_above_4g(&self) -> u64 {
+        self.memory_map()
+            .iter()
+            .filter(|region| region.is_available())
+            .map(|region| region.high_len())
+            .sum()
+    }
+
+    /// Returns the symbol table section and its string table section.
+    fn symbol_table_sections(
+        &self,
+    ) -> impl Iterator<Item = &ElfSectionHeader> + '_ {
+        let sections = self.elf_sections();
+        let symtab = sections
+            .iter()
+            .find(|sh| sh.sh_type == ElfSectionHeader::SHT_SYMTAB);
+        let strtab = symtab.and_then(|sh| sections.get(sh.link as usize));
+        symtab.into_iter().chain(strtab)
+    }
+
+    /// Returns the memory that must not be used as free memory: the MBI, the
+    /// modules, the symbol table and its string table.
+    pub fn reserved_regions(&self) -> impl Iterator<Item = Region<usize>> + '_ {
+        iter::once(self.mbi_region)
+            .chain(self.modules().iter().map(|module| module.phys))
+            .chain(
+                self.symbol_table_sections()
+                    .filter_map(|sh| sh.phys_region()),
+            )
+    }
+
+    fn parse_tag(
+        &mut self,
+        tag_type: u32,
+        tag: &'a [u8],
+    ) -> Result<(), ParseErr> {
         match tag_type {
-            1 => {
-                let tag = &*(ptr as *const BootCommandLine);
-                println!(
-                    "Boot command line: {:?}",
-                    str_from_ascii(&tag.string, tag.tag_size - 8)
-                );
-            }
-            2 => {
-                let tag = &*(ptr as *const BootloaderName);
-                println!(
-                    "Bootloader name: {}",
-                    str_from_ascii(&tag.string, tag.tag_size - 8)
-                );
-            }
+            1 => self.cmdline = Some(tag_str(tag, 8, tag_type)?),
+            2 => self.bootloader_name = Some(tag_str(tag, 8, tag_type)?),
             3 => {
-                let tag = &*(ptr as *const Module);
-                println!(
-                    "Module: {}: start: 0x{:08X}, end: 0x{:08X}",
-                    str_from_ascii(&tag.string, tag.tag_size - 16),
-                    { tag.mod_start },
-                    { tag.mod_end },
-                );
+                let module: Module = read_tag(tag, tag_type)?;
+                if module.mod_end < module.mod_start {
+                    return Err(ParseErr::InvalidTag(tag_type));
+                }
+                let string = tag_str(tag, mem::size_of::<Module>(), tag_type)?;
+                if self.num_modules < MAX_BOOT_MODULES {
+                    let slot = &mut self.modules[self.num_modules];
+                    slot.phys = Region {
+                        start: module.mod_start as usize,
+                        end: module.mod_end as usize,
+                    };
+                    slot.cmdline.set(string);
+                    self.num_modules += 1;
+                } else {
+                    self.num_ignored_modules += 1;
+                }
             }
             4 => {
-                let tag = &*(ptr as *const BasicMemoryInfo);
-                println!(
-                    "Basic memory info: lower: {} KiB, upper: {} KiB",
-                    { tag.mem_lower },
-                    { tag.mem_upper },
-                );
+                let basic: BasicMemoryInfo = read_tag(tag, tag_type)?;
+                self.basic_memory_info =
+                    Some((basic.mem_lower, basic.mem_upper));
             }
             5 => {
-                let tag = &*(ptr as *const BiosBootDevice);
-                println!(
-                    "BIOS boot device: drive num {}, partition: {}, \
-                     subpartition: {}",
-                    { tag.bios_dev },
-                    tag.partition as i32,
-                    tag.subpartition as i32,
-                );
+                let dev: BiosBootDevice = read_tag(tag, tag_type)?;
+                self.bios_boot_device =
+                    Some((dev.bios_dev, dev.partition, dev.subpartition));
             }
             6 => {
-                let tag = &*(ptr as *const MemoryMap);
-                let num_entries = (tag.tag_size - 16) / tag.entry_size;
-                println!(
-                    "Memory map: entry size: {}, entry version: {}, \
-                     entries: {}",
-                    { tag.entry_size },
-                    { tag.entry_version },
-                    num_entries,
-                );
-                let mut i = 0;
-                let mut added_to_info = 0;
-                while i < num_entries {
-                    let entry = &*((&tag.entries as *const _ as *const u8)
-                        .add((i * tag.entry_size) as usize)
-                        as *const MemoryMapEntry);
-                    let start = entry.base_addr;
-                    let length = entry.length;
-                    let _type = MemoryMapRegionType::from(entry.region_type);
-                    print!(
-                        "         0x{:08X}_{:08X}..0x{:08X}_{:08X}: {}",
-                        (start >> 32) & 0xFFFFFFFF,
-                        (start >> 00) & 0xFFFFFFFF,
-                        ((start + length) >> 32) & 0xFFFFFFFF,
-                        ((start + length) >> 00) & 0xFFFFFFFF,
-                        _type,
-                    );
-                    if start >> 32 != 0 || (start + length) >> 32 != 0 {
-                        println!(", ignored");
-                        i += 1;
-                        continue;
-                    }
-                    match _type {
-                        MemoryMapRegionType::Available
-                            if added_to_info
-                                < KERNEL_INFO
-                                    .available_memory_regions
-                                    .len() =>
-                        {
-                            KERNEL_INFO.available_memory_regions
-                                [added_to_info] = memory_region::Region {
-                                start: start as usize,
-                                end: start as usize + length as usize,
+                let map: MemoryMap = read_tag(tag, tag_type)?;
+                let entry_size = map.entry_size as usize;
+                if entry_size < mem::size_of::<MemoryMapEntry>() {
+                    return Err(ParseErr::InvalidTag(tag_type));
+                }
+                let entries = &tag[mem::size_of::<MemoryMap>()..];
+                if entries.len() % entry_size != 0 {
+                    return Err(ParseErr::TagTooSmall(tag_type));
+                }
+                for chunk in entries.chunks_exact(entry_size) {
+                    let entry: MemoryMapEntry = unsafe { read_at(chunk, 0) }
+                        .ok_or(ParseErr::TagTooSmall(tag_type))?;
+                    if self.num_memory_map_entries < MAX_MEMORY_MAP_ENTRIES {
+                        self.memory_map[self.num_memory_map_entries] =
+                            MemoryMapRegion {
+                                start: entry.base_addr,
+                                length: entry.length,
+                                region_type: entry.region_type,
                             };
-                            added_to_info += 1;
-                        }
-                        _ => {}
+                        self.num_memory_map_entries += 1;
+                    } else {
+                        self.num_ignored_memory_map_entries += 1;
                     }
-                    println!();
-                    i += 1;
                 }
             }
-            7 => {
-                let tag = &*(ptr as *const VbeInfo);
-                println!(
-                    "VBE info: mode: {}, interface seg: {}, \
-                     interface off: {}, interface len: {}",
-                    { tag.mode },
-                    { tag.interface_seg },
-                    { tag.interface_off },
-                    { tag.interface_len },
-                );
-            }
             8 => {
-                let tag = &*(ptr as *const FramebufferInfo);
-                println!(
-                    "Framebuffer info: at phys: 0x{:08X}, pitch: {}, \
-                     {}x{}, bpp: {}, type: {}",
-                    tag.addr as u32,
-                    { tag.pitch },
-                    { tag.width },
-                    { tag.height },
-                    { tag.bpp },
-                    FramebufferType::from(tag._type),
-                );
-            }
-            9 => {
-                let tag = &*(ptr as *const ElfSymbols);
-                println!(
-                    "ELF symbols at 0x{:08X}: num: {}, entsize: {}, shndx: {}",
-                    tag as *const _ as u32,
-                    { tag.num },
-                    { tag.entsize },
-                    { tag.shndx },
-                );
-            }
-            10 => {
-                let tag = &*(ptr as *const ApmTable);
-                println!(
-                    "APM table: v{}, cseg: 0x{:04X}, offset: 0x{:08X}, \
-                     flags: {}, len: {}",
-                    { tag.version },
-                    { tag.cseg },
-                    { tag.offset },
-                    { tag.flags },
-                    { tag.cseg_len },
-                );
-            }
-            11 => {
-                let tag = &*(ptr as *const Efi32BitSystemTablePointer);
-                println!("EFI 32-bit system table pointer: 0x{:08X}", {
-                    tag.pointer
+                let fb: FramebufferInfo = read_tag(tag, tag_type)?;
+                let rgb = match FramebufferType::from(fb._type) {
+                    FramebufferType::RgbColor => Some(
+                        unsafe {
+                            read_at(tag, mem::size_of::<FramebufferInfo>())
+                        }
+                        .ok_or(ParseErr::TagTooSmall(tag_type))?,
+                    ),
+                    _ => None,
+                };
+                self.framebuffer = Some(FramebufferParams {
+                    addr: fb.addr,
+                    pitch: fb.pitch,
+                    width: fb.width,
+                    height: fb.height,
+                    bpp: fb.bpp,
+                    fb_type: fb._type,
+                    rgb,
                 });
             }
-            12 => {
-                let tag = &*(ptr as *const Efi64BitSystemTablePointer);
-                println!(
-                    "EFI 64-bit system table pointer: 0x{:08X}_{:08X}",
-                    (tag.pointer >> 32) & 0xFFFFFFFF,
-                    (tag.pointer >> 00) & 0xFFFFFFFF,
-                );
-            }
-            13 => {
-                let tag = &*(ptr as *const SmbiosTables);
-                println!(
-                    "SMBIOS tables: v{}.{}",
-                    tag.major_version, tag.minor_version,
-                );
+            9 => {
+                let symbols: ElfSymbols = read_tag(tag, tag_type)?;
+                if symbols.entsize as usize
+                    != mem::size_of::<ElfSectionHeader>()
+                {
+                    return Err(ParseErr::InvalidTag(tag_type));
+                }
+                let num = symbols.num as usize;
+                let kept = cmp::min(num, MAX_ELF_SECTIONS);
+                for i in 0..kept {
+                    let offset = mem::size_of::<ElfSymbols>()
+                        + i * mem::size_of::<ElfSectionHeader>();
+                    self.elf_sections[i] = unsafe { read_at(tag, offset) }
+                        .ok_or(ParseErr::TagTooSmall(tag_type))?;
+                }
+                self.num_elf_sections = kept;
+                self.num_ignored_elf_sections = num - kept;
             }
             14 => {
-                let tag = &*(ptr as *const AcpiOldRsdp);
-                println!("ACPI old RSDP");
-                assert_eq!(
-                    (tag.tag_size - 8) as usize,
-                    mem::size_of::<sdt::OldRsdp>(),
-                );
-                let rsdp = (&tag.rsdpv1 as *const _ as *const sdt::OldRsdp)
-                    .read_unaligned();
-
-                // println!("{:#X?}", rsdp);
-                assert!(rsdp.is_valid(), "invalid RSDP");
-
-                let rsdt =
-                    (rsdp.rsdt_phys_addr as *const sdt::Sdt).read_unaligned();
-                // println!("{:#X?}", rsdt);
-
-                let num_sdts =
-                    (rsdt.length as usize - mem::size_of::<sdt::Sdt>()) / 4;
-                let sdt_ptrs = core::slice::from_raw_parts(
-                    (rsdp.rsdt_phys_addr as usize + mem::size_of::<sdt::Sdt>())
-                        as *const *const sdt::Sdt,
-                    num_sdts,
-                );
-
-                let rsdt_sum = rsdt.sum_fields()
-                    + sdt_ptrs.iter().fold(0, |acc, x| {
-                        acc + ((*x as u32 >> 0) & 0xFF) as usize
-                            + ((*x as u32 >> 8) & 0xFF) as usize
-                            + ((*x as u32 >> 16) & 0xFF) as usize
-                            + ((*x as u32 >> 24) & 0xFF) as usize
-                    });
-                assert_eq!(rsdt_sum as u8, 0, "invalid RSDT");
-
-                for sdt_ptr in sdt_ptrs {
-                    let sdt = sdt_ptr.read_unaligned();
-                    let name = core::str::from_utf8(&sdt.signature).unwrap();
-                    println!(
-                        "{} at 0x{:08X}, length: {} bytes",
-                        name,
-                        *sdt_ptr as usize,
-                        { sdt.length },
-                    );
-
-                    if name == "HPET" {
-                        if KERNEL_INFO.arch.hpet_dt.is_none() {
-                            let hpet_dt = sdt_ptr
-                                .add(1)
-                                .cast::<hpet::HpetDt>()
-                                .read_unaligned();
-                            KERNEL_INFO.arch.hpet_dt = Some(hpet_dt);
-                        } else {
-                            println!("Another HPET timer, ignoring.");
-                        }
-                    }
+                let rsdp: sdt::OldRsdp = unsafe { read_at(tag, 8) }
+                    .ok_or(ParseErr::TagTooSmall(tag_type))?;
+                if !rsdp.is_valid() {
+                    return Err(ParseErr::InvalidRsdp);
                 }
-
-                // KERNEL_INFO.arch.old_rsdp = Some();
+                self.rsdt_phys = Some(rsdp.rsdt_phys_addr);
             }
             15 => {
-                let tag = &*(ptr as *const AcpiNewRsdp);
-                println!("ACPI new RSDP");
-                let rsdp = (&tag.rsdpv2 as *const _ as *const sdt::NewRsdp)
-                    .read_unaligned();
-                assert!(rsdp.is_valid());
-                assert_eq!(tag.tag_size - 8, { rsdp.length });
-
-                assert_eq!(rsdp.xsdt_phys_addr >> 32, 0);
-                let xsdt = (rsdp.xsdt_phys_addr as u32 as *const sdt::Sdt)
-                    .read_unaligned();
-
-                let num_sdts =
-                    (xsdt.length as usize - mem::size_of::<sdt::Sdt>()) / 8;
-                let sdt_ptrs = core::slice::from_raw_parts(
-                    (rsdp.xsdt_phys_addr as usize + mem::size_of::<sdt::Sdt>())
-                        as *const u64,
-                    num_sdts,
-                );
-
-                let xsdt_sum = xsdt.sum_fields()
-                    + sdt_ptrs.iter().fold(0, |acc, x| {
-                        acc + ((*x >> 0) & 0xFF) as usize
-                            + ((*x >> 8) & 0xFF) as usize
-                            + ((*x >> 16) & 0xFF) as usize
-                            + ((*x >> 24) & 0xFF) as usize
-                            + ((*x >> 32) & 0xFF) as usize
-                            + ((*x >> 40) & 0xFF) as usize
-                            + ((*x >> 48) & 0xFF) as usize
-                            + ((*x >> 56) & 0xFF) as usize
-                    });
-                assert_eq!(xsdt_sum as u8, 0, "invalid XSDT");
-
-                for sdt_ptr in sdt_ptrs {
-                    assert_eq!(*sdt_ptr >> 32, 0);
-                    let sdt_ptr = *sdt_ptr as u32 as *const sdt::Sdt;
-                    let sdt = sdt_ptr.read_unaligned();
-                    let name = core::str::from_utf8(&sdt.signature).unwrap();
-                    println!(
-                        "{} at 0x{:08X}, length: {} bytes",
-                        name,
-                        sdt_ptr as usize,
-                        { sdt.length },
-                    );
-
-                    if name == "HPET" {
-                        let hpet_dt = sdt_ptr
-                            .add(1)
-                            .cast::<hpet::HpetDt>()
-                            .read_unaligned();
-                        KERNEL_INFO.arch.hpet_dt = Some(hpet_dt);
-                    }
+                let rsdp: sdt::NewRsdp = unsafe { read_at(tag, 8) }
+                    .ok_or(ParseErr::TagTooSmall(tag_type))?;
+                if !rsdp.is_valid() || rsdp.length as usize != tag.len() - 8 {
+                    return Err(ParseErr::InvalidRsdp);
                 }
+                self.xsdt_phys = Some(rsdp.xsdt_phys_addr);
             }
-            16 => {
-                //let tag = &*(ptr as *const NetworkingInformation);
-                println!("Networking information");
-            }
-            17 => {
-                let tag = &*(ptr as *const EfiMemoryMap);
-                println!(
-                    "EFI memory map: descriptor size: {}, \
-                     descriptor version: {}",
-                    { tag.descriptor_size },
-                    { tag.descriptor_version },
-                );
-            }
-            18 => {
-                //let tag = &*(ptr as *const EfiBootServicesNotTerminated);
-                println!("EFI boot services not terminated");
-            }
-            19 => {
-                let tag = &*(ptr as *const Efi32BitImageHandlePointer);
-                println!("EFI 32-bit image handle pointer: 0x{:08X}", {
-                    tag.pointer
-                });
-            }
-            20 => {
-                let tag = &*(ptr as *const Efi64BitImageHandlePointer);
-                println!(
-                    "EFI 64-bit image handle pointer: 0x{:08X}_{:08X}",
-                    (tag.pointer >> 32) & 0xFFFFFFFF,
-                    (tag.pointer >> 00) & 0xFFFFFFFF,
-                );
+            _ => {
+                // The tag count is limited by MAX_TAGS, so this fits.
+                self.other_tags[self.num_other_tags] = tag_type;
+                self.num_other_tags += 1;
             }
-            21 => {
-                let tag = &*(ptr as *const ImageLoadBasePhysicalAddress);
-                println!("Image load base physical address: 0x{:08X}", {
-                    tag.load_base_addr
+        }
+        Ok(())
+    }
+}
+
+/// Reads a `T` at `offset` in `bytes`, or returns `None` if it does not fit.
+///
+/// # Safety
+/// Any bit pattern must be a valid `T`, e.g. `T` is a struct of integers.
+unsafe fn read_at<T>(bytes: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(mem::size_of::<T>())?;
+    if end > bytes.len() {
+        return None;
+    }
+    Some((bytes.as_ptr().add(offset) as *const T).read_unaligned())
+}
+
+/// Reads the fixed part of a tag.
+fn read_tag<T>(tag: &[u8], tag_type: u32) -> Result<T, ParseErr> {
+    unsafe { read_at(tag, 0) }.ok_or(ParseErr::TagTooSmall(tag_type))
+}
+
+/// Returns the NUL-terminated ASCII string at `offset` in a tag.
+fn tag_str(tag: &[u8], offset: usize, tag_type: u32) -> Result<&str, ParseErr> {
+    let bytes = tag.get(offset..).ok_or(ParseErr::TagTooSmall(tag_type))?;
+    let len = bytes.iter().position(|&ch| ch == 0).unwrap_or(bytes.len());
+    let bytes = &bytes[..len];
+    if !bytes.is_ascii() {
+        return Err(ParseErr::NonAsciiString(tag_type));
+    }
+    Ok(str::from_utf8(bytes).unwrap())
+}
+
+fn tag_name(tag_type: u32) -> &'static str {
+    match tag_type {
+        7 => "VBE info",
+        10 => "APM table",
+        11 => "EFI 32-bit system table pointer",
+        12 => "EFI 64-bit system table pointer",
+        13 => "SMBIOS tables",
+        16 => "Networking information",
+        17 => "EFI memory map",
+        18 => "EFI boot services not terminated",
+        19 => "EFI 32-bit image handle pointer",
+        20 => "EFI 64-bit image handle pointer",
+        21 => "Image load base physical address",
+        _ => "Unknown",
+    }
+}
+
+/// Parses the Multiboot information structure at `boot_info`.
+///
+/// # Safety
+/// `boot_info` must point to an MBI that stays mapped and unchanged for the
+/// lifetime of the result.
+pub unsafe fn parse(
+    boot_info: *const BootInfo,
+) -> Result<ParsedBootInfo<'static>, ParseErr> {
+    if boot_info as usize % 8 != 0 {
+        return Err(ParseErr::MisalignedMbi);
+    }
+    let total_size = (*boot_info).total_size as usize;
+    parse_bytes(slice::from_raw_parts(boot_info as *const u8, total_size))
+}
+
+/// Parses an MBI contained in `mbi`.
+pub fn parse_bytes(mbi: &[u8]) -> Result<ParsedBootInfo<'_>, ParseErr> {
+    let header: BootInfo =
+        unsafe { read_at(mbi, 0) }.ok_or(ParseErr::TruncatedMbi)?;
+    let total_size = header.total_size as usize;
+    if total_size < mem::size_of::<BootInfo>() || total_size > mbi.len() {
+        return Err(ParseErr::TruncatedMbi);
+    }
+    let mbi = &mbi[..total_size];
+
+    let mut info = ParsedBootInfo::new(Region::from_start_len(
+        mbi.as_ptr() as usize,
+        total_size,
+    ));
+    let mut offset = mem::size_of::<BootInfo>();
+    let mut num_tags = 0;
+    loop {
+        let header: TagHeader =
+            unsafe { read_at(mbi, offset) }.ok_or(ParseErr::NoEndTag)?;
+        let tag_type = header.tag_type;
+        let tag_size = header.tag_size as usize;
+        if tag_size < mem::size_of::<TagHeader>() {
+            return Err(ParseErr::TagTooSmall(tag_type));
+        }
+        let tag = offset
+            .checked_add(tag_size)
+            .and_then(|end| mbi.get(offset..end))
+            .ok_or(ParseErr::TruncatedTag(tag_type))?;
+
+        if tag_type == 0 {
+            let actual = offset + tag_size;
+            if actual != total_size {
+                return Err(ParseErr::SizeMismatch {
+                    declared: total_size,
+                    actual,
                 });
             }
-            _ => {
-                println!("Ignoring unknown tag");
-            }
+            break;
+        }
+        if num_tags == MAX_TAGS {
+            return Err(ParseErr::TooManyTags);
         }
+        info.parse_tag(tag_type, tag)?;
 
-        ptr = ptr.add(tag_size as usize);
-        ptr = ptr.add(ptr.align_offset(8)); // 8 bytes
+        // The tags are aligned at 8 bytes.
+        offset = (offset + tag_size + 7) & !7;
         num_tags += 1;
     }
 
-    let actual_size = ptr as u32 + 8 - boot_info as u32; // 8 is for the end tag
-    println!("Actual MBI size: {} bytes", actual_size);
-    assert_eq!(
-        { bi.total_size },
-        actual_size,
-        "declared and actual MBI sizes are different"
+    Ok(info)
+}
+
+/// Prints the information passed by the bootloader.
+pub fn report(info: &ParsedBootInfo) {
+    println!(
+        "Multiboot information is at 0x{:08X}, total size: {} bytes",
+        info.mbi_region.start,
+        info.mbi_region.len(),
     );
+    if let Some(name) = info.bootloader_name {
+        println!("Bootloader name: {}", name);
+    }
+    if let Some(cmdline) = info.cmdline {
+        println!("Boot command line: {:?}", cmdline);
+    }
+    if let Some((lower, upper)) = info.basic_memory_info {
+        println!(
+            "Basic memory info: lower: {} KiB, upper: {} KiB",
+            lower, upper
+        );
+    }
+    if let Some((bios_dev, partition, subpartition)) = info.bios_boot_device {
+        println!(
+            "BIOS boot device: drive num {}, partition: {}, subpartition: {}",
+            bios_dev, partition as i32, subpartition as i32,
+        );
+    }
+
+    for module in info.modules() {
+        println!(
+            "Module: {}: start: 0x{:08X}, end: 0x{:08X}",
+            module.cmdline(),
+            module.phys.start,
+            module.phys.end,
+        );
+    }
+    if info.num_ignored_modules != 0 {
+        println!("Too many modules, ignored {}.", info.num_ignored_modules);
+    }
+
+    println!("Memory map: {} entries", info.memory_map().len());
+    for region in info.memory_map() {
+        let (start, end) = (region.start, region.end());
+        print!(
+            "    0x{:08X}_{:08X}..0x{:08X}_{:08X}: {}",
+            (start >> 32) & 0xFFFFFFFF,
+            (start >> 00) & 0xFFFFFFFF,
+            (end >> 32) & 0xFFFFFFFF,
+            (end >> 00) & 0xFFFFFFFF,
+            MemoryMapRegionType::from(region.region_type),
+        );
+        match region.low_part() {
+            None => println!(", ignored"),
+            Some(low) if low.end as u64 != end => {
+                println!(", truncated to 0x{:08X}", low.end)
+            }
+            Some(_) => println!(),
+        }
+    }
+    if info.num_ignored_memory_map_entries != 0 {
+        println!(
+            "Too many memory map entries, ignored {}.",
+            info.num_ignored_memory_map_entries,
+        );
+    }
+
+    if let Some(fb) = info.framebuffer {
+        println!(
+            "Framebuffer info: at phys: 0x{:08X}_{:08X}, pitch: {}, {}x{}, \
+             bpp: {}, type: {}",
+            (fb.addr >> 32) & 0xFFFFFFFF,
+            (fb.addr >> 00) & 0xFFFFFFFF,
+            fb.pitch,
+            fb.width,
+            fb.height,
+            fb.bpp,
+            FramebufferType::from(fb.fb_type),
+        );
+        if !fb.is_ega_text() && fb.to_framebuffer().is_none() {
+            println!("Unsupported framebuffer, keeping the VGA text mode.");
+        }
+    }
+
+    if !info.elf_sections().is_empty() {
+        println!("ELF section headers: {}", info.elf_sections().len());
+    }
+    if info.num_ignored_elf_sections != 0 {
+        println!(
+            "Too many ELF sections, ignored {}.",
+            info.num_ignored_elf_sections,
+        );
+    }
+
+    if let Some(rsdt_phys) = info.rsdt_phys {
+        println!("ACPI RSDT at 0x{:08X}", rsdt_phys);
+    }
+    if let Some(xsdt_phys) = info.xsdt_phys {
+        println!(
+            "ACPI XSDT at 0x{:08X}_{:08X}",
+            (xsdt_phys >> 32) & 0xFFFFFFFF,
+            (xsdt_phys >> 00) & 0xFFFFFFFF,
+        );
+    }
+
+    for &tag_type in &info.other_tags[..info.num_other_tags] {
+        println!("Ignoring tag {}: {}", tag_type, tag_name(tag_type));
+    }
 }
@@ -28,19 +28,47 @@ pub struct Port {
 
 impl Port {
     pub unsafe fn read<T: ReadableFromPort>(&self) -> T {
-        let size = 8 * size_of::<T>();
-        if self.can_read_size(size) {
-            T::read_from_port(self.port)
-        } else {
+        self.assert_can_read_size(8 * size_of::<T>());
+        T::read_from_port(self.port)
+    }
+
+    pub unsafe fn write<T: WritableToPort>(&self, value: T) {
+        self.assert_can_write_size(8 * size_of::<T>());
+        value.write_to_port(self.port)
+    }
+
+    /// Fills `buf` with the words read from the port with a single `rep insw`.
+    pub unsafe fn read_slice_u16(&self, buf: &mut [u16]) {
+        self.assert_can_read_size(16);
+        port_io::insw(self.port, buf);
+    }
+
+    /// Writes the words of `data` to the port with a single `rep outsw`.
+    pub unsafe fn write_slice_u16(&self, data: &[u16]) {
+        self.assert_can_write_size(16);
+        port_io::outsw(self.port, data);
+    }
+
+    /// Fills `buf` with the dwords read from the port with a single `rep insl`.
+    pub unsafe fn read_slice_u32(&self, buf: &mut [u32]) {
+        self.assert_can_read_size(32);
+        port_io::insl(self.port, buf);
+    }
+
+    /// Writes the dwords of `data` to the port with a single `rep outsl`.
+    pub unsafe fn write_slice_u32(&self, data: &[u32]) {
+        self.assert_can_write_size(32);
+        port_io::outsl(self.port, data);
+    }
+
+    fn assert_can_read_size(&self, size: usize) {
+        if !self.can_read_size(size) {
             panic!("Cannot read size {} from port 0x{:02X}", size, self.port);
         }
     }
 
-    pub unsafe fn write<T: WritableToPort>(&self, value: T) {
-        let size = 8 * size_of::<T>();
-        if self.can_write_size(size) {
-            value.write_to_port(self.port)
-        } else {
+    fn assert_can_write_size(&self, size: usize) {
+        if !self.can_write_size(size) {
             panic!("Cannot write size {} to port 0x{:02X}", size, self.port);
         }
     }
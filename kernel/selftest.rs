@@ -0,0 +1,1253 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Kernel self-tests.
+//!
+//! The tests named in the `selftest=<name>[,<name>...]` option, or all of them
+//! with `selftest=all`, are run in a kernel thread once the first usermode
+//! program has been spawned.  A test panics if it fails.
+//!
+//! The tests that check the panic output panic on purpose, so they are only
+//! run when named and after all the others.
+
+use alloc::alloc::{alloc, dealloc, realloc};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::cmp;
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::arch::dev::keyboard::{Event, Key};
+use crate::arch::interrupts;
+use crate::arch::stack_trace::{self, StackTrace};
+use crate::arch::tsc;
+use crate::arch::vas::KERNEL_VIRT_BASE;
+use crate::cmdline;
+use crate::dev::disk::DISKS;
+use crate::dev::keymap::{KeyInput, Keymap, LayoutId};
+use crate::dev::timer;
+use crate::fs::{FileSystem, VFS_ROOT};
+use crate::heap;
+use crate::kernel_static::Mutex;
+use crate::memory_region::Region;
+use crate::multiboot::{self, ParseErr};
+use crate::slab;
+use crate::sync::Semaphore;
+use crate::task_manager;
+
+/// Self-tests by name.
+const TESTS: &[(&str, fn())] = &[
+    ("slab_poison", slab_poison),
+    ("slab_fragmentation", slab_fragmentation),
+    ("timer_events", timer_events),
+    ("disk_contention", disk_contention),
+    ("kernel_thread_ping_pong", kernel_thread_ping_pong),
+    ("disk_read_throughput", disk_read_throughput),
+    ("heap_mixed_align", heap_mixed_align),
+    ("heap_coalescing", heap_coalescing),
+    ("heap_realloc", heap_realloc),
+    ("heap_large_align", heap_large_align),
+    ("heap_irq_alloc", heap_irq_alloc),
+    ("multiboot_parse", multiboot_parse),
+    ("keymap_translate", keymap_translate),
+    ("timer_period", timer_period),
+    ("stack_trace_walk", stack_trace_walk),
+];
+
+/// Self-tests that panic on purpose, by name.  `selftest=all` skips them.
+const PANICKING_TESTS: &[(&str, fn())] =
+    &[("stack_scribble_panic", stack_scribble_panic)];
+
+/// Spawns the thread that runs the tests selected on the command line, if
+/// there are any.
+pub fn init() {
+    let names = match cmdline::get("selftest") {
+        Some(names) => names,
+        None => return,
+    };
+    for name in names.split(',') {
+        if name != "all"
+            && !TESTS
+                .iter()
+                .chain(PANICKING_TESTS)
+                .any(|&(test, _)| test == name)
+        {
+            log_warn!("[SELFTEST] Ignoring unknown test {}.", name);
+        }
+    }
+    task_manager::spawn_kernel_thread("selftest", run_selected, 0);
+}
+
+fn run_selected(_: usize) {
+    let names = cmdline::get("selftest").unwrap();
+    let is_selected = |name| {
+        names
+            .split(',')
+            .any(|selected| selected == "all" || selected == name)
+    };
+    let mut num_passed = 0;
+    for &(name, test) in TESTS.iter().filter(|&&(name, _)| is_selected(name)) {
+        println!("[SELFTEST] Running {}.", name);
+        test();
+        println!("[SELFTEST] {}: OK", name);
+        num_passed += 1;
+    }
+    println!("[SELFTEST] Passed {} tests.", num_passed);
+
+    for &(name, test) in PANICKING_TESTS {
+        if names.split(',').any(|selected| selected == name) {
+            println!("[SELFTEST] Running {}, which panics.", name);
+            test();
+            panic!("{} has not panicked", name);
+        }
+    }
+}
+
+/// Writes to a freed slab slot and checks that the write is detected.
+fn slab_poison() {
+    let layout = Layout::from_size_align(48, 8).unwrap();
+    assert!(slab::serves(layout));
+
+    // Nothing else may allocate from the size class in the meantime.
+    interrupts::with_disabled(|| unsafe {
+        // The second slot keeps the slab of the first one from being returned
+        // to the heap: either the slab had other used slots, or it is a new
+        // one that the second slot is allocated from too.
+        let freed = alloc(layout);
+        let kept = alloc(layout);
+        assert!(!freed.is_null() && !kept.is_null());
+        dealloc(freed, layout);
+        assert!(
+            slab::is_poisoned(freed, layout),
+            "freed slot is not poisoned"
+        );
+
+        let dangling = freed.add(layout.size() - 1);
+        dangling.write_volatile(0);
+        assert!(
+            !slab::is_poisoned(freed, layout),
+            "write after free is not detected",
+        );
+        // Otherwise the next allocation of the slot panics.
+        dangling.write_volatile(slab::POISON);
+        assert!(slab::is_poisoned(freed, layout));
+
+        dealloc(kept, layout);
+    });
+}
+
+/// Number of root directory listings made by [list_root_dir].
+const NUM_DIR_LISTINGS: usize = 64;
+
+/// Lists the root directory of `fs` [NUM_DIR_LISTINGS] times, keeping every
+/// other listing alive, and returns the number of free heap chunks this adds.
+fn list_root_dir(fs: &dyn FileSystem, slabs: bool) -> usize {
+    slab::set_enabled(slabs);
+    let mut kept = Vec::with_capacity(NUM_DIR_LISTINGS / 2);
+    heap::join_free_chunks();
+    let free_chunks_before = heap::usage().free_chunks;
+
+    for i in 0..NUM_DIR_LISTINGS {
+        let mut listing = fs.root_dir().expect("could not list the root");
+        let names: Vec<String> = listing
+            .children()
+            .iter()
+            .map(|child| child.0.borrow().name.clone())
+            .collect();
+        if i % 2 == 0 {
+            kept.push((listing, names));
+        }
+    }
+
+    heap::join_free_chunks();
+    let free_chunks_added =
+        heap::usage().free_chunks.saturating_sub(free_chunks_before);
+    heap::stats();
+    drop(kept);
+    slab::set_enabled(true);
+    free_chunks_added
+}
+
+/// Checks that the slabs leave fewer holes in the heap than the tag allocator
+/// on a directory listing workload.
+fn slab_fragmentation() {
+    let fs = match VFS_ROOT.lock().as_ref() {
+        Some(root) => root.fs(),
+        None => {
+            println!("[SELFTEST] There is no root directory to list.");
+            return;
+        }
+    };
+    let without_slabs = list_root_dir(&*fs, false);
+    let with_slabs = list_root_dir(&*fs, true);
+    println!(
+        "[SELFTEST] Free chunks added by listing the root: {} without the \
+         slabs, {} with them.",
+        without_slabs, with_slabs,
+    );
+    assert!(
+        with_slabs <= without_slabs,
+        "the slabs fragment the heap more than the tag allocator",
+    );
+}
+
+/// Number of the one-shot events registered by [timer_events].
+const NUM_TIMER_EVENTS: usize = 300;
+
+// Arguments of the fired one-shot events of [timer_events] and the uptime they
+// have fired at.  Locked with the interrupts disabled.
+kernel_static! {
+    static ref FIRED_EVENTS: Mutex<Vec<(usize, u64)>> = Mutex::new(Vec::new());
+}
+
+/// Number of calls of the periodic event of [timer_events].
+static NUM_PERIODIC_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn record_fired_event(idx: usize) {
+    FIRED_EVENTS.lock().push((idx, timer::uptime_ms()));
+}
+
+fn count_periodic_call(_: usize) {
+    NUM_PERIODIC_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Delay of the one-shot event `idx` of [timer_events].  Many events share a
+/// delay, and the delays are not in the registration order.
+fn timer_event_delay(idx: usize) -> u64 {
+    20 + (idx * 37 % 100) as u64
+}
+
+/// Registers lots of timer events and cancels a third of them, then checks
+/// that the rest fire once each, in order and not early, and that the
+/// cancelled ones never fire.  Then checks that a periodic event stops firing
+/// once it is cancelled.
+fn timer_events() {
+    interrupts::with_disabled(|| {
+        let mut fired = FIRED_EVENTS.lock();
+        fired.clear();
+        // The callbacks must not grow the vector in the IRQ handler.
+        fired.reserve(NUM_TIMER_EVENTS);
+    });
+
+    // The events are registered at the same uptime, so they are due in the
+    // order of their delays, then of their registration.
+    let (start_ms, handles) = interrupts::with_disabled(|| {
+        let handles: Vec<_> = (0..NUM_TIMER_EVENTS)
+            .map(|idx| {
+                timer::after_ms(timer_event_delay(idx), record_fired_event, idx)
+            })
+            .collect();
+        (timer::uptime_ms(), handles)
+    });
+    // An event may fire before it is cancelled if this thread is preempted.
+    let cancelled: Vec<bool> = handles
+        .into_iter()
+        .enumerate()
+        .map(|(idx, handle)| idx % 3 == 0 && handle.cancel())
+        .collect();
+    let num_cancelled = cancelled.iter().filter(|&&c| c).count();
+    assert_ne!(num_cancelled, 0, "no event has been cancelled in time");
+
+    let max_delay_ms = (0..NUM_TIMER_EVENTS).map(timer_event_delay).max();
+    task_manager::sleep_ms(max_delay_ms.unwrap() + 2 * timer::ms_per_tick());
+
+    let fired = interrupts::with_disabled(|| FIRED_EVENTS.lock().clone());
+    assert_eq!(fired.len(), NUM_TIMER_EVENTS - num_cancelled);
+    let mut has_fired = [false; NUM_TIMER_EVENTS];
+    for &(idx, fired_ms) in fired.iter() {
+        assert!(!cancelled[idx], "cancelled event {} has fired", idx);
+        assert!(!has_fired[idx], "event {} has fired twice", idx);
+        has_fired[idx] = true;
+        assert!(
+            fired_ms >= start_ms + timer_event_delay(idx),
+            "event {} has fired early",
+            idx,
+        );
+    }
+    for pair in fired.windows(2) {
+        let (prev, next) = (pair[0].0, pair[1].0);
+        assert!(
+            (timer_event_delay(prev), prev) < (timer_event_delay(next), next),
+            "event {} has fired before event {}",
+            prev,
+            next,
+        );
+    }
+
+    NUM_PERIODIC_CALLS.store(0, Ordering::SeqCst);
+    let handle = timer::every_ms(5, count_periodic_call, 0);
+    task_manager::sleep_ms(100);
+    assert!(handle.cancel(), "periodic event is gone");
+    let num_calls = NUM_PERIODIC_CALLS.load(Ordering::SeqCst);
+    assert_ne!(num_calls, 0, "periodic event has not fired");
+    task_manager::sleep_ms(50);
+    assert_eq!(
+        NUM_PERIODIC_CALLS.load(Ordering::SeqCst),
+        num_calls,
+        "cancelled periodic event has fired",
+    );
+}
+
+/// Number of the threads of [disk_contention].
+const NUM_DISK_READERS: usize = 2;
+
+/// Number of the reads by each thread of [disk_contention].
+const NUM_CONTENDED_READS: usize = 500;
+
+/// Number of the blocks that the threads of [disk_contention] read in turn.
+const NUM_CONTENDED_BLOCKS: usize = 8;
+
+/// Number of the reads done by each thread of [disk_contention].
+static NUM_DONE_READS: [AtomicUsize; NUM_DISK_READERS] =
+    [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+// Posted by each thread of [disk_contention] when it is done.
+kernel_static! {
+    static ref DISK_READERS_DONE: Semaphore = Semaphore::new(0);
+}
+
+/// Reads the first blocks of disk 0 over and over again, checking that the
+/// data stays the same.
+fn read_disk_in_turn(reader_idx: usize) {
+    let disk = DISKS.lock()[0].borrow().rw_interface.clone();
+    let block_size = disk.block_size();
+    let mut expected = vec![0; NUM_CONTENDED_BLOCKS * block_size];
+    disk.read_blocks(0, &mut expected).unwrap();
+
+    let mut buf = vec![0; block_size];
+    for read_idx in 0..NUM_CONTENDED_READS {
+        let block_idx = read_idx % NUM_CONTENDED_BLOCKS;
+        assert_eq!(disk.read_block(block_idx, &mut buf).unwrap(), block_size);
+        assert!(
+            buf[..] == expected[block_idx * block_size..][..block_size],
+            "reader {} has read a wrong block {}",
+            reader_idx,
+            block_idx,
+        );
+        NUM_DONE_READS[reader_idx].fetch_add(1, Ordering::SeqCst);
+    }
+    DISK_READERS_DONE.post();
+}
+
+/// Panics with the progress of the threads of [disk_contention], which should
+/// have finished by the time this is called.
+fn report_stuck_disk_readers(_: usize) {
+    panic!(
+        "disk readers are stuck after {} and {} reads out of {}",
+        NUM_DONE_READS[0].load(Ordering::SeqCst),
+        NUM_DONE_READS[1].load(Ordering::SeqCst),
+        NUM_CONTENDED_READS,
+    );
+}
+
+/// Makes two threads read disk 0 at the same time, so that they contend for
+/// the sleeping mutex of its bus, and waits for them on a semaphore.  A lost
+/// wakeup leaves a thread blocked forever, which trips a watchdog.
+fn disk_contention() {
+    if DISKS.lock().is_empty() {
+        println!("[SELFTEST] There are no disks, skipping the test.");
+        return;
+    }
+    for num_done in NUM_DONE_READS.iter() {
+        num_done.store(0, Ordering::SeqCst);
+    }
+
+    let watchdog = timer::after_ms(60_000, report_stuck_disk_readers, 0);
+    for reader_idx in 0..NUM_DISK_READERS {
+        task_manager::spawn_kernel_thread(
+            "disk reader",
+            read_disk_in_turn,
+            reader_idx,
+        );
+    }
+    for _ in 0..NUM_DISK_READERS {
+        DISK_READERS_DONE.wait();
+    }
+    assert!(watchdog.cancel());
+
+    for num_done in NUM_DONE_READS.iter() {
+        assert_eq!(num_done.load(Ordering::SeqCst), NUM_CONTENDED_READS);
+    }
+}
+
+/// Number of the blocks read at once by [disk_read_throughput], an ATA read
+/// command takes less than 256.
+const THROUGHPUT_READ_BLOCKS: usize = 128;
+
+/// Number of the reads made by [disk_read_throughput].
+const NUM_THROUGHPUT_READS: usize = 16;
+
+/// Reads the start of disk 0 sequentially and prints the read rate, so that it
+/// can be compared between kernel builds.  Nothing is checked.
+fn disk_read_throughput() {
+    if DISKS.lock().is_empty() {
+        println!("[SELFTEST] There are no disks, skipping the test.");
+        return;
+    }
+    let disk = DISKS.lock()[0].borrow().rw_interface.clone();
+    let num_blocks = NUM_THROUGHPUT_READS * THROUGHPUT_READ_BLOCKS;
+    if !disk.has_block(num_blocks - 1) {
+        println!("[SELFTEST] Disk 0 is too small, skipping the test.");
+        return;
+    }
+
+    let mut buf = vec![0; THROUGHPUT_READ_BLOCKS * disk.block_size()];
+    let start_ns = tsc::now_ns();
+    for read_idx in 0..NUM_THROUGHPUT_READS {
+        disk.read_blocks(read_idx * THROUGHPUT_READ_BLOCKS, &mut buf)
+            .unwrap();
+    }
+    let elapsed_us = cmp::max((tsc::now_ns() - start_ns) / 1000, 1);
+
+    let num_kib = (num_blocks * disk.block_size() / 1024) as u64;
+    println!(
+        "[SELFTEST] Read {} KiB from disk 0 in {} us, {} KiB/s{}.",
+        num_kib,
+        elapsed_us,
+        num_kib * 1_000_000 / elapsed_us,
+        if tsc::is_approximate() {
+            " (approximate)"
+        } else {
+            ""
+        },
+    );
+}
+
+/// Number of the turns taken by each thread of [kernel_thread_ping_pong].
+const NUM_PING_PONGS: usize = 1000;
+
+/// Number of the turns taken by both threads of [kernel_thread_ping_pong].
+static NUM_PING_PONG_TURNS: AtomicUsize = AtomicUsize::new(0);
+
+// The turns of the two threads of [kernel_thread_ping_pong], the first thread
+// goes first.  Each thread posts [PING_PONG_DONE] when it is done.
+kernel_static! {
+    static ref PING_PONG_TURNS: [Semaphore; 2] =
+        [Semaphore::new(1), Semaphore::new(0)];
+    static ref PING_PONG_DONE: Semaphore = Semaphore::new(0);
+}
+
+/// Takes [NUM_PING_PONGS] turns with the other thread of
+/// [kernel_thread_ping_pong], checking that the turns alternate.
+fn ping_pong(thread_idx: usize) {
+    for round in 0..NUM_PING_PONGS {
+        PING_PONG_TURNS[thread_idx].wait();
+        let turn = NUM_PING_PONG_TURNS.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(
+            turn,
+            2 * round + thread_idx,
+            "thread {} has taken a turn out of order",
+            thread_idx,
+        );
+        PING_PONG_TURNS[1 - thread_idx].post();
+    }
+    PING_PONG_DONE.post();
+}
+
+/// Panics with the progress of the threads of [kernel_thread_ping_pong], which
+/// should have finished by the time this is called.
+fn report_stuck_ping_pong(_: usize) {
+    panic!(
+        "ping-pong threads are stuck after {} turns out of {}",
+        NUM_PING_PONG_TURNS.load(Ordering::SeqCst),
+        2 * NUM_PING_PONGS,
+    );
+}
+
+/// Makes two kernel threads take turns through a pair of semaphores, so that
+/// each of them blocks on a wait queue until the other one wakes it up.  A
+/// lost wakeup leaves both of them blocked forever, which trips a watchdog.
+fn kernel_thread_ping_pong() {
+    NUM_PING_PONG_TURNS.store(0, Ordering::SeqCst);
+
+    let watchdog = timer::after_ms(10_000, report_stuck_ping_pong, 0);
+    task_manager::spawn_kernel_thread("ping", ping_pong, 0);
+    task_manager::spawn_kernel_thread("pong", ping_pong, 1);
+    for _ in 0..2 {
+        PING_PONG_DONE.wait();
+    }
+    assert!(watchdog.cancel());
+
+    assert_eq!(
+        NUM_PING_PONG_TURNS.load(Ordering::SeqCst),
+        2 * NUM_PING_PONGS,
+    );
+}
+
+/// Number of the allocations made by [heap_mixed_align].
+const NUM_MIXED_ALLOCS: usize = 64;
+
+/// Layout of the allocation `idx` of [heap_mixed_align], with an alignment
+/// from 1 to 4096 bytes.
+fn mixed_layout(idx: usize) -> Layout {
+    Layout::from_size_align(300 + idx * 97 % 2000, 1 << (idx * 5 % 13)).unwrap()
+}
+
+/// Allocates chunks of mixed sizes and alignments with the tag allocator,
+/// frees and reallocates half of them, then frees all of them in a scrambled
+/// order, and checks that the free heap is back to its initial size.
+fn heap_mixed_align() {
+    // Nothing else may allocate in the meantime.
+    interrupts::with_disabled(|| unsafe {
+        // The coalescing is lazy, so the free chunks are joined before the
+        // sizes are compared.
+        heap::join_free_chunks();
+        let initial = heap::usage();
+
+        let mut ptrs = [ptr::null_mut(); NUM_MIXED_ALLOCS];
+        let alloc_filled = |idx: usize| {
+            let layout = mixed_layout(idx);
+            let ptr = heap::alloc_tagged(layout);
+            assert!(!ptr.is_null(), "allocation {} has failed", idx);
+            assert_eq!(ptr as usize % layout.align(), 0);
+            ptr.write_bytes(idx as u8, layout.size());
+            ptr
+        };
+        let free_checked = |idx: usize, ptr: *mut u8| {
+            let layout = mixed_layout(idx);
+            let data = slice::from_raw_parts(ptr, layout.size());
+            assert!(
+                data.iter().all(|&byte| byte == idx as u8),
+                "allocation {} has been overwritten",
+                idx,
+            );
+            heap::dealloc_tagged(ptr, layout);
+        };
+
+        for (idx, ptr) in ptrs.iter_mut().enumerate() {
+            *ptr = alloc_filled(idx);
+        }
+        for idx in (0..NUM_MIXED_ALLOCS).step_by(2) {
+            free_checked(idx, ptrs[idx]);
+        }
+        heap::check();
+        for idx in (0..NUM_MIXED_ALLOCS).step_by(2) {
+            ptrs[idx] = alloc_filled(idx);
+        }
+        // 29 is coprime with the number of allocations, so this frees each of
+        // them once.
+        for i in 0..NUM_MIXED_ALLOCS {
+            let idx = i * 29 % NUM_MIXED_ALLOCS;
+            free_checked(idx, ptrs[idx]);
+        }
+
+        heap::check();
+        heap::join_free_chunks();
+        let usage = heap::usage();
+        assert_eq!(usage.total, initial.total, "the heap has grown");
+        assert_eq!(usage.free, initial.free, "free heap is not restored");
+        assert_eq!(usage.used, initial.used);
+        assert_eq!(usage.allocations, initial.allocations);
+    });
+}
+
+/// Size of the chunks that [heap_coalescing] frees.
+const COALESCED_CHUNK_SIZE: usize = 1000;
+
+/// Allocates five adjacent chunks with the tag allocator, returns them and the
+/// allocations that have filled the holes in the heap before them.
+unsafe fn alloc_adjacent_chunks() -> ([*mut u8; 5], Vec<*mut u8>) {
+    let layout = Layout::from_size_align(COALESCED_CHUNK_SIZE, 1).unwrap();
+    let mut holes = Vec::new();
+    loop {
+        let mut ptrs = [ptr::null_mut(); 5];
+        for ptr in ptrs.iter_mut() {
+            *ptr = heap::alloc_tagged(layout);
+            assert!(!ptr.is_null());
+        }
+        // Some of the chunks may have been put in the holes left by the other
+        // allocations.  The first one is kept to fill its hole and the rest
+        // are allocated again.
+        let is_adjacent = ptrs.windows(2).all(|pair| {
+            heap::chunk_of(pair[0]).end == heap::chunk_of(pair[1]).start
+        });
+        if is_adjacent {
+            return (ptrs, holes);
+        }
+        holes.push(ptrs[0]);
+        for &ptr in ptrs[1..].iter() {
+            heap::dealloc_tagged(ptr, layout);
+        }
+    }
+}
+
+/// Frees three adjacent chunks in each of the six orders and checks that they
+/// end up as a single free chunk.  It is merged right away if the first chunk
+/// is freed last, otherwise by the pass that the allocator makes before it
+/// grows the heap.
+fn heap_coalescing() {
+    const ORDERS: [[usize; 3]; 6] = [
+        [0, 1, 2],
+        [0, 2, 1],
+        [1, 0, 2],
+        [1, 2, 0],
+        [2, 0, 1],
+        [2, 1, 0],
+    ];
+    let layout = Layout::from_size_align(COALESCED_CHUNK_SIZE, 1).unwrap();
+
+    for order in ORDERS.iter() {
+        // Nothing else may allocate in the meantime.
+        interrupts::with_disabled(|| unsafe {
+            // The chunks in the middle are freed, the outer ones keep them
+            // from being merged with the neighbouring free chunks.
+            let (ptrs, holes) = alloc_adjacent_chunks();
+            let first = heap::chunk_of(ptrs[1]);
+            let last = heap::chunk_of(ptrs[3]);
+            for &idx in order.iter() {
+                heap::dealloc_tagged(ptrs[1 + idx], layout);
+            }
+            if order[2] != 0 {
+                heap::join_free_chunks();
+            }
+            assert_eq!(
+                heap::free_chunk_at(first.start),
+                Some(first.start..last.end),
+                "chunks freed in the order {:?} are not merged",
+                order,
+            );
+
+            heap::dealloc_tagged(ptrs[0], layout);
+            heap::dealloc_tagged(ptrs[4], layout);
+            for hole in holes {
+                heap::dealloc_tagged(hole, layout);
+            }
+            heap::check();
+        });
+    }
+}
+
+/// Byte at `offset` in the buffers of [heap_realloc].
+fn realloc_pattern(offset: usize) -> u8 {
+    (offset % 251) as u8
+}
+
+/// Grows and shrinks buffers with alignments of 1, 8, 64 and 4096 bytes, which
+/// for the small alignments includes a move to the slab allocator and back, and
+/// checks their contents, their alignment and the heap after each step.
+fn heap_realloc() {
+    const SIZES: [usize; 8] = [300, 1000, 5000, 20_000, 2000, 100, 700, 300];
+    for &align in [1, 8, 64, 4096].iter() {
+        let mut layout = Layout::from_size_align(SIZES[0], align).unwrap();
+        unsafe {
+            let mut ptr = alloc(layout);
+            assert!(!ptr.is_null());
+            for offset in 0..layout.size() {
+                *ptr.add(offset) = realloc_pattern(offset);
+            }
+
+            for &new_size in SIZES[1..].iter() {
+                ptr = realloc(ptr, layout, new_size);
+                assert!(!ptr.is_null());
+                assert_eq!(
+                    ptr as usize % align,
+                    0,
+                    "realloc to {} bytes has lost the alignment {}",
+                    new_size,
+                    align,
+                );
+                let kept = layout.size().min(new_size);
+                for offset in 0..kept {
+                    assert_eq!(
+                        *ptr.add(offset),
+                        realloc_pattern(offset),
+                        "realloc from {} to {} bytes (align {}) has changed \
+                         offset {}",
+                        layout.size(),
+                        new_size,
+                        align,
+                        offset,
+                    );
+                }
+                for offset in kept..new_size {
+                    *ptr.add(offset) = realloc_pattern(offset);
+                }
+                layout = Layout::from_size_align(new_size, align).unwrap();
+                heap::check();
+            }
+            dealloc(ptr, layout);
+        }
+    }
+    heap::check();
+}
+
+/// Allocates chunks aligned at 8 KiB and 64 KiB, interleaved with small
+/// unaligned ones that shift the free space, and checks their alignment, that
+/// they do not overlap and the heap after each allocation and free.
+fn heap_large_align() {
+    const LAYOUTS: [(usize, usize); 8] = [
+        (100, 8192),
+        (5000, 65536),
+        (8192, 8192),
+        (70_000, 65536),
+        (1, 65536),
+        (12_000, 8192),
+        (65536, 65536),
+        (300, 8192),
+    ];
+    let small = Layout::from_size_align(333, 1).unwrap();
+    let mut allocs: Vec<(*mut u8, Layout, *mut u8)> = Vec::new();
+
+    for &(size, align) in LAYOUTS.iter() {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        unsafe {
+            let shift = alloc(small);
+            let ptr = alloc(layout);
+            assert!(!shift.is_null() && !ptr.is_null());
+            assert_eq!(
+                ptr as usize % align,
+                0,
+                "0x{:08X} is not aligned at {}",
+                ptr as usize,
+                align,
+            );
+            for &(other, other_layout, _) in allocs.iter() {
+                assert!(
+                    ptr as usize + size <= other as usize
+                        || other as usize + other_layout.size() <= ptr as usize,
+                    "0x{:08X} overlaps 0x{:08X}",
+                    ptr as usize,
+                    other as usize,
+                );
+            }
+            ptr.write_bytes(0xA5, size);
+            allocs.push((ptr, layout, shift));
+        }
+        heap::check();
+    }
+
+    for (ptr, layout, shift) in allocs {
+        unsafe {
+            let data = slice::from_raw_parts(ptr, layout.size());
+            assert!(data.iter().all(|&byte| byte == 0xA5));
+            dealloc(ptr, layout);
+            dealloc(shift, small);
+        }
+        heap::check();
+    }
+}
+
+/// Number of calls of [alloc_on_tick].
+static NUM_TICK_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+// Allocation kept by [alloc_on_tick] until the next tick, so that it is freed
+// while the interrupted code may be allocating too.  Locked with the
+// interrupts disabled.
+kernel_static! {
+    static ref TICK_ALLOC: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+}
+
+/// Allocates and frees memory of the sizes served by either allocator in the
+/// timer IRQ handler.
+fn alloc_on_tick(_: usize) {
+    let num_calls = NUM_TICK_ALLOCS.fetch_add(1, Ordering::SeqCst);
+    for &size in [24, 200, 1500].iter() {
+        let buf = vec![num_calls as u8; size];
+        assert!(buf.iter().all(|&byte| byte == num_calls as u8));
+    }
+    let kept = vec![num_calls as u8; 100 + num_calls % 3000];
+    if let Some(prev) = TICK_ALLOC.lock().replace(kept) {
+        assert!(prev.iter().all(|&byte| byte == prev[0]));
+    }
+}
+
+/// Allocates in a loop for two seconds while a timer event allocates on every
+/// tick, which deadlocks if an interrupt comes while the heap is locked.
+fn heap_irq_alloc() {
+    NUM_TICK_ALLOCS.store(0, Ordering::SeqCst);
+    let handle = timer::every_tick(alloc_on_tick, 0);
+
+    let end_ms = timer::uptime_ms() + 2000;
+    let mut num_loops = 0;
+    while timer::uptime_ms() < end_ms {
+        let mut bufs: Vec<Vec<u8>> = Vec::new();
+        for size in (8..4096).step_by(97 + num_loops % 13) {
+            bufs.push(vec![size as u8; size]);
+        }
+        for buf in bufs.iter() {
+            assert!(buf.iter().all(|&byte| byte == buf.len() as u8));
+        }
+        num_loops += 1;
+    }
+
+    assert!(handle.cancel());
+    interrupts::with_disabled(|| TICK_ALLOC.lock().take());
+    let num_tick_allocs = NUM_TICK_ALLOCS.load(Ordering::SeqCst);
+    println!(
+        "[SELFTEST] {} allocation loops, {} allocating ticks.",
+        num_loops, num_tick_allocs,
+    );
+    assert_ne!(num_tick_allocs, 0, "the timer event has not allocated");
+    heap::check();
+}
+
+/// Appends a tag made of a header and `payload` to a synthetic MBI and pads
+/// it to 8 bytes.
+fn push_mbi_tag(mbi: &mut Vec<u8>, tag_type: u32, payload: &[u8]) {
+    let tag_size = 8 + payload.len() as u32;
+    mbi.extend_from_slice(&tag_type.to_le_bytes());
+    mbi.extend_from_slice(&tag_size.to_le_bytes());
+    mbi.extend_from_slice(payload);
+    while mbi.len() % 8 != 0 {
+        mbi.push(0);
+    }
+}
+
+/// Returns a synthetic MBI containing `tags` and an end tag.
+fn build_mbi(tags: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut mbi = vec![0; 8];
+    for (tag_type, payload) in tags {
+        push_mbi_tag(&mut mbi, *tag_type, payload);
+    }
+    push_mbi_tag(&mut mbi, 0, &[]);
+    let total_size = mbi.len() as u32;
+    set_mbi_word(&mut mbi, 0, total_size);
+    mbi
+}
+
+fn set_mbi_word(mbi: &mut [u8], offset: usize, value: u32) {
+    mbi[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Returns the payload of a memory map tag with 24-byte entries.
+fn memory_map_payload(entries: &[(u64, u64, u32)]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&24u32.to_le_bytes());
+    payload.extend_from_slice(&0u32.to_le_bytes());
+    for &(base, length, region_type) in entries {
+        payload.extend_from_slice(&base.to_le_bytes());
+        payload.extend_from_slice(&length.to_le_bytes());
+        payload.extend_from_slice(&region_type.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+    }
+    payload
+}
+
+/// Returns the payload of a module tag.
+fn module_payload(start: u32, end: u32, cmdline: &str) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&start.to_le_bytes());
+    payload.extend_from_slice(&end.to_le_bytes());
+    payload.extend_from_slice(cmdline.as_bytes());
+    payload.push(0);
+    payload
+}
+
+/// Parses synthetic MBIs: a valid one, one with too many memory map entries,
+/// a misaligned one, one with an unpadded tag and several truncated ones.
+fn multiboot_parse() {
+    let memory_map = [
+        (0, 0x9F000, 1),
+        (0xF0000, 0x10000, 2),
+        (0x100000, 0x7F00000, 1),
+        (0xFFFF_0000, 0x2_0000, 1),
+        (0x1_0000_0000, 0x1000_0000, 1),
+    ];
+    let tags = [
+        (1, b"root=0 quiet\0".to_vec()),
+        (2, b"GRUB 2.04\0".to_vec()),
+        (6, memory_map_payload(&memory_map)),
+        (3, module_payload(0x200000, 0x210000, "initrd")),
+    ];
+    let mbi = build_mbi(&tags);
+
+    let info = multiboot::parse_bytes(&mbi).unwrap();
+    assert_eq!(info.cmdline, Some("root=0 quiet"));
+    assert_eq!(info.bootloader_name, Some("GRUB 2.04"));
+    assert_eq!(info.memory_map().len(), memory_map.len());
+    assert_eq!(info.num_ignored_memory_map_entries, 0);
+    let available: Vec<(usize, usize)> = info
+        .available_regions()
+        .map(|region| (region.start, region.end))
+        .collect();
+    assert_eq!(
+        available,
+        [
+            (0, 0x9F000),
+            (0x100000, 0x8000000),
+            (0xFFFF0000, 0xFFFFF000)
+        ],
+    );
+    assert_eq!(info.memory_above_4g(), 0x1001_0000);
+    assert_eq!(info.modules().len(), 1);
+    let module = &info.modules()[0];
+    assert_eq!(module.cmdline(), "initrd");
+    assert_eq!((module.phys.start, module.phys.end), (0x200000, 0x210000));
+    assert_eq!(info.reserved_regions().count(), 2);
+    assert_eq!(info.mbi_region.len(), mbi.len());
+
+    // The entries that do not fit are counted.
+    let many_entries: Vec<(u64, u64, u32)> = (0..70)
+        .map(|i| (i * 0x10_0000, 0x10_0000, 1 + i as u32 % 2))
+        .collect();
+    let mbi = build_mbi(&[(6, memory_map_payload(&many_entries))]);
+    let info = multiboot::parse_bytes(&mbi).unwrap();
+    assert_eq!(info.memory_map().len(), multiboot::MAX_MEMORY_MAP_ENTRIES);
+    assert_eq!(
+        info.num_ignored_memory_map_entries,
+        70 - multiboot::MAX_MEMORY_MAP_ENTRIES,
+    );
+
+    // The MBI must be aligned at 8 bytes.  The words keep the copies aligned.
+    let mbi = build_mbi(&tags);
+    let mut words = vec![0u64; mbi.len() / 8 + 1];
+    for offset in [0, 4].iter() {
+        unsafe {
+            let copy = (words.as_mut_ptr() as *mut u8).add(*offset);
+            ptr::copy_nonoverlapping(mbi.as_ptr(), copy, mbi.len());
+            let parsed = multiboot::parse(copy as *const multiboot::BootInfo);
+            if *offset == 0 {
+                assert_eq!(parsed.unwrap().cmdline, Some("root=0 quiet"));
+            } else {
+                assert!(matches!(parsed, Err(ParseErr::MisalignedMbi)));
+            }
+        }
+    }
+
+    // A tag that is not padded to 8 bytes hides the end tag after it.
+    let mut mbi = vec![0; 8];
+    mbi.extend_from_slice(&1u32.to_le_bytes());
+    mbi.extend_from_slice(&12u32.to_le_bytes());
+    mbi.extend_from_slice(b"abc\0");
+    mbi.extend_from_slice(&[0; 8]);
+    let total_size = mbi.len() as u32;
+    set_mbi_word(&mut mbi, 0, total_size);
+    let parsed = multiboot::parse_bytes(&mbi);
+    assert!(matches!(parsed, Err(ParseErr::NoEndTag)));
+
+    // The MBI is shorter than its header or its declared size.
+    let mbi = build_mbi(&tags);
+    let parsed = multiboot::parse_bytes(&mbi[..4]);
+    assert!(matches!(parsed, Err(ParseErr::TruncatedMbi)));
+    let parsed = multiboot::parse_bytes(&mbi[..mbi.len() - 8]);
+    assert!(matches!(parsed, Err(ParseErr::TruncatedMbi)));
+
+    // The end tag is missing or is not at the end.
+    let mut truncated = mbi[..mbi.len() - 8].to_vec();
+    set_mbi_word(&mut truncated, 0, mbi.len() as u32 - 8);
+    let parsed = multiboot::parse_bytes(&truncated);
+    assert!(matches!(parsed, Err(ParseErr::NoEndTag)));
+    let mut padded = mbi.clone();
+    padded.extend_from_slice(&[0; 8]);
+    set_mbi_word(&mut padded, 0, mbi.len() as u32 + 8);
+    let parsed = multiboot::parse_bytes(&padded);
+    assert!(matches!(parsed, Err(ParseErr::SizeMismatch { .. })));
+
+    // The first tag runs past the end of the MBI.
+    let mut overlong = mbi.clone();
+    set_mbi_word(&mut overlong, 12, mbi.len() as u32);
+    let parsed = multiboot::parse_bytes(&overlong);
+    assert!(matches!(parsed, Err(ParseErr::TruncatedTag(1))));
+
+    // The tags are shorter than their fixed fields or entries.
+    let short_module = build_mbi(&[(3, vec![0; 4])]);
+    let parsed = multiboot::parse_bytes(&short_module);
+    assert!(matches!(parsed, Err(ParseErr::TagTooSmall(3))));
+    let mut payload = memory_map_payload(&memory_map);
+    payload.truncate(payload.len() - 8);
+    let partial_entry = build_mbi(&[(6, payload)]);
+    let parsed = multiboot::parse_bytes(&partial_entry);
+    assert!(matches!(parsed, Err(ParseErr::TagTooSmall(6))));
+    let mut bad_header = build_mbi(&[]);
+    set_mbi_word(&mut bad_header, 12, 4);
+    let parsed = multiboot::parse_bytes(&bad_header);
+    assert!(matches!(parsed, Err(ParseErr::TagTooSmall(0))));
+}
+
+/// Key event of a [KEYMAP_CASES] case and what it must translate to.
+type KeymapStep = (Key, bool, Option<KeyInput>);
+
+const fn ch(ch: char) -> Option<KeyInput> {
+    Some(KeyInput::Char(ch))
+}
+
+const fn key(key: Key) -> Option<KeyInput> {
+    Some(KeyInput::Key(key))
+}
+
+/// Cases of [keymap_translate], each of them starts with a new [Keymap].
+const KEYMAP_CASES: &[(&str, LayoutId, &[KeymapStep])] = &[
+    (
+        "plain keys",
+        LayoutId::Us,
+        &[
+            (Key::A, true, ch('a')),
+            (Key::A, false, None),
+            (Key::One, true, ch('1')),
+            (Key::Enter, true, ch('\n')),
+            (Key::F1, true, key(Key::F1)),
+            (Key::UpArrow, true, key(Key::UpArrow)),
+        ],
+    ),
+    (
+        "shift",
+        LayoutId::Us,
+        &[
+            (Key::LeftShift, true, None),
+            (Key::A, true, ch('A')),
+            (Key::One, true, ch('!')),
+            (Key::Slash, true, ch('?')),
+            (Key::LeftShift, false, None),
+            (Key::A, true, ch('a')),
+            (Key::RightShift, true, None),
+            (Key::Backtick, true, ch('~')),
+            (Key::RightShift, false, None),
+            (Key::Backtick, true, ch('`')),
+        ],
+    ),
+    (
+        "caps lock",
+        LayoutId::Us,
+        &[
+            (Key::CapsLock, true, None),
+            (Key::A, true, ch('a')),
+            (Key::CapsLock, false, None),
+            (Key::A, true, ch('A')),
+            (Key::One, true, ch('1')),
+            (Key::LeftSquareBracket, true, ch('[')),
+            (Key::CapsLock, true, None),
+            (Key::CapsLock, false, None),
+            (Key::A, true, ch('a')),
+        ],
+    ),
+    (
+        "shift with caps lock",
+        LayoutId::Us,
+        &[
+            (Key::CapsLock, true, None),
+            (Key::CapsLock, false, None),
+            (Key::LeftShift, true, None),
+            (Key::A, true, ch('a')),
+            (Key::One, true, ch('!')),
+            (Key::LeftShift, false, None),
+            (Key::A, true, ch('A')),
+            (Key::One, true, ch('1')),
+        ],
+    ),
+    (
+        "shift with caps lock, de",
+        LayoutId::De,
+        &[
+            (Key::CapsLock, true, None),
+            (Key::CapsLock, false, None),
+            (Key::Semicolon, true, ch('Ö')),
+            (Key::RightShift, true, None),
+            (Key::Semicolon, true, ch('ö')),
+            (Key::Minus, true, ch('?')),
+            (Key::RightShift, false, None),
+            (Key::Minus, true, ch('ß')),
+        ],
+    ),
+    (
+        "ctrl",
+        LayoutId::Us,
+        &[
+            (Key::LeftCtrl, true, None),
+            (Key::C, true, ch('\x03')),
+            (Key::A, true, ch('\x01')),
+            (Key::LeftShift, true, None),
+            (Key::Z, true, ch('\x1A')),
+            (Key::LeftShift, false, None),
+            (Key::One, true, ch('1')),
+            (Key::LeftCtrl, false, None),
+            (Key::C, true, ch('c')),
+            (Key::RightCtrl, true, None),
+            (Key::LeftSquareBracket, true, ch('\x1B')),
+            (Key::D, true, ch('\x04')),
+            (Key::RightCtrl, false, None),
+        ],
+    ),
+    (
+        "num lock",
+        LayoutId::Us,
+        &[
+            (Key::NumpadSeven, true, ch('7')),
+            (Key::NumpadPeriod, true, ch('.')),
+            (Key::NumLock, true, None),
+            (Key::NumLock, false, None),
+            (Key::NumpadSeven, true, key(Key::Home)),
+            (Key::NumpadTwo, true, key(Key::DownArrow)),
+            (Key::NumpadFive, true, None),
+            (Key::NumpadPeriod, true, key(Key::Delete)),
+            (Key::NumpadZero, true, key(Key::Insert)),
+            (Key::NumpadPlus, true, ch('+')),
+            (Key::NumpadEnter, true, ch('\n')),
+            (Key::NumLock, true, None),
+            (Key::NumLock, false, None),
+            (Key::NumpadOne, true, ch('1')),
+        ],
+    ),
+    (
+        "alt gr, de",
+        LayoutId::De,
+        &[
+            (Key::Y, true, ch('z')),
+            (Key::NumpadPeriod, true, ch(',')),
+            (Key::RightAlt, true, None),
+            (Key::Q, true, ch('@')),
+            (Key::Seven, true, ch('{')),
+            (Key::A, true, key(Key::A)),
+            (Key::RightAlt, false, None),
+            (Key::Q, true, ch('q')),
+        ],
+    ),
+];
+
+/// Feeds the key events of [KEYMAP_CASES] to keymaps and checks what they
+/// translate to.
+fn keymap_translate() {
+    for &(name, layout, steps) in KEYMAP_CASES {
+        let mut keymap = Keymap::new();
+        for (i, &(key, pressed, expected)) in steps.iter().enumerate() {
+            let input = keymap.translate_with(layout, &Event { key, pressed });
+            assert_eq!(input, expected, "{}, step {}: {:?}", name, i, key);
+        }
+    }
+}
+
+/// Waits for `num_ticks` timer ticks, starting right after a tick, and returns
+/// how long they have taken by the TSC in milliseconds.
+fn measure_ticks_ms(num_ticks: u64) -> u64 {
+    let start_tick = timer::ticks() + 1;
+    while timer::ticks() < start_tick {
+        interrupts::wait_for_interrupt();
+    }
+    let start_ns = tsc::now_ns();
+    while timer::ticks() < start_tick + num_ticks {
+        interrupts::wait_for_interrupt();
+    }
+    (tsc::now_ns() - start_ns) / 1_000_000
+}
+
+/// Changes the timer period and checks the tick rate and the scheduler's
+/// quantum, then changes it back.
+fn timer_period() {
+    let old_period_ms = timer::ms_per_tick();
+    let old_quantum_ticks = task_manager::quantum_ticks();
+    let old_uptime_ms = timer::uptime_ms();
+
+    for &period_ms in [2, 25, 1].iter() {
+        timer::set_period_ms(period_ms);
+        assert_eq!(timer::ms_per_tick(), period_ms as u64);
+        // 50 ms rounded to whole ticks.
+        let expected_quantum = match period_ms {
+            1 => 50,
+            2 => 25,
+            _ => 2,
+        };
+        assert_eq!(task_manager::quantum_ticks(), expected_quantum);
+
+        let num_ticks = 200 / period_ms as u64;
+        let elapsed_ms = measure_ticks_ms(num_ticks);
+        println!(
+            "[SELFTEST] {} ticks of {} ms have taken {} ms.",
+            num_ticks, period_ms, elapsed_ms,
+        );
+        // The TSC rate is only measured roughly, and may change if it is not
+        // invariant.
+        if !tsc::is_approximate() {
+            assert!(
+                (150..=250).contains(&elapsed_ms),
+                "ticks of {} ms have come at a wrong rate",
+                period_ms,
+            );
+        }
+    }
+
+    timer::set_period_ms(old_period_ms as usize);
+    assert_eq!(task_manager::quantum_ticks(), old_quantum_ticks);
+    assert!(timer::uptime_ms() >= old_uptime_ms + 600);
+}
+
+/// Overwrites the frame pointer saved in its frame with `garbage`, walks the
+/// stack and restores the frame pointer.
+#[inline(never)]
+fn walk_scribbled_frame(garbage: impl Fn(u32) -> u32) -> StackTrace {
+    let ebp = stack_trace::frame_pointer();
+    let saved = ebp as *mut u32;
+    unsafe {
+        let orig = saved.read_volatile();
+        saved.write_volatile(garbage(ebp));
+        let trace = StackTrace::walk_and_get();
+        saved.write_volatile(orig);
+        trace
+    }
+}
+
+/// Walks the stack with an overwritten frame and a synthetic one with a trap
+/// frame from the usermode.
+fn stack_trace_walk() {
+    // The walk goes through walk_and_get and walk_scribbled_frame, then stops
+    // at the overwritten frame pointer.
+    let garbage_makers: [(fn(u32) -> u32, bool); 5] = [
+        (|_| 0xDEAD_BEEF, true),
+        (|ebp| ebp + 2, true),
+        (|ebp| ebp - 16, true),
+        (|_| 0x0804_8000, true),
+        (|_| 0, false),
+    ];
+    for &(garbage, is_bad) in garbage_makers.iter() {
+        let trace = walk_scribbled_frame(garbage);
+        assert_eq!(trace.length, 2);
+        assert!(trace.iter().all(|addr| addr as usize >= KERNEL_VIRT_BASE));
+        assert_eq!(trace.bad_frame.is_some(), is_bad);
+    }
+
+    // A frame of an interrupt stub that holds the usermode %ebp and %eip ends
+    // the walk quietly.
+    let trace = walk_synthetic_frames(0x0804_8123);
+    assert_eq!(trace.length, 2);
+    assert_eq!(trace.addresses[1], 0x0804_8123);
+    assert!(trace.bad_frame.is_none());
+
+    // A kernel frame pointing outside the stack is still reported.
+    let trace = walk_synthetic_frames(stack_trace_walk as fn() as usize as u32);
+    assert_eq!(trace.length, 2);
+    assert_eq!(trace.bad_frame, Some(0xBFFF_F000));
+}
+
+/// Walks a synthetic stack of two frames, the inner one of a kernel function
+/// and the outer one with the %ebp of the usermode and `eip`.
+fn walk_synthetic_frames(eip: u32) -> StackTrace {
+    let mut frames = [0u32; 8];
+    let base = frames.as_mut_ptr();
+    unsafe {
+        base.write(base.add(4) as u32);
+        base.add(1).write(stack_trace_walk as fn() as usize as u32);
+        base.add(4).write(0xBFFF_F000);
+        base.add(5).write(eip);
+        let stack = Region::from_start_len(base as usize, 32);
+        StackTrace::walk(base as u32, &stack)
+    }
+}
+
+/// Overwrites the frame pointer saved in its frame and panics.  The trace
+/// printed by the panic handler must end with this function and the invalid
+/// frame pointer instead of a fault.
+#[inline(never)]
+fn stack_scribble_panic() {
+    let saved = stack_trace::frame_pointer() as *mut u32;
+    unsafe {
+        saved.write_volatile(0xDEAD_BEEF);
+    }
+    panic!(
+        "frame at 0x{:08X} is overwritten on purpose",
+        saved as usize
+    );
+}
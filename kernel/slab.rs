@@ -0,0 +1,384 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Slab allocator for small kernel objects.
+//!
+//! The allocations that fit in one of the [size classes](SLOT_SIZES) are
+//! served from slabs, i.e. page-sized chunks of the kernel heap carved into
+//! fixed-size slots.  Each slab keeps its free slots in an intrusive list and
+//! is returned to the heap once all of its slots are free.
+//!
+//! The slabs are not aligned at their size, so that the tag allocator does not
+//! have to pad them.  The slab of a slot is found through the table of the
+//! slabs that start in each page of the heap (see [SLAB_STARTS]).
+//!
+//! The free slots are filled with [POISON] past the list link, and a slot is
+//! checked to still be poisoned when it is allocated again, which catches the
+//! writes through dangling pointers.
+
+use core::alloc::Layout;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::heap;
+use crate::kernel_static::Mutex;
+use crate::KERNEL_INFO;
+
+/// Slot sizes of the size classes.
+///
+/// A tag allocator chunk costs a 16-byte tag and a 4-byte tag address on top of
+/// the allocation, which doubles the size of the most frequent kernel objects:
+/// the file names and the other short strings (16 to 64 bytes), the `Rc` boxes
+/// of the VFS nodes (64 bytes) and the small vectors.  Past 256 bytes the
+/// overhead is below 8% and a slab would hold only 15 slots.  [Heap::stats]
+/// prints how many used chunks fall in each class, which is how the classes
+/// are checked against a workload (see the `slab_fragmentation` self-test).
+///
+/// [Heap::stats]: crate::heap::Heap::stats
+pub const SLOT_SIZES: [usize; 5] = [16, 32, 64, 128, 256];
+
+const SLAB_SIZE: usize = 4096;
+
+/// Number of heap pages tracked in [SLAB_STARTS].
+const NUM_HEAP_PAGES: usize = heap::KERNEL_HEAP_MAX_SIZE / 4096;
+
+/// Slab that starts in each page of the kernel heap, or a null pointer.
+///
+/// A slab is page-sized, so no two slabs start in the same page and a slot is
+/// in the slab that starts either in its page or in the previous one.  The
+/// table is guarded by the [SIZE_CLASSES] lock.
+static mut SLAB_STARTS: [*mut Slab; NUM_HEAP_PAGES] =
+    [ptr::null_mut(); NUM_HEAP_PAGES];
+
+/// Whether the new allocations are served by the slabs, see [set_enabled].
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Byte that the free slots are filled with after the free list link.
+pub const POISON: u8 = 0x6B;
+
+/// Header at the start of each slab.
+#[repr(C)]
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free_slots: *mut FreeSlot,
+    num_used: usize,
+    slot_size: usize,
+}
+
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+impl FreeSlot {
+    /// Fills the slot of `slot_size` bytes with [POISON] after the link.
+    unsafe fn poison(slot: *mut FreeSlot, slot_size: usize) {
+        let payload = (slot as *mut u8).add(size_of::<FreeSlot>());
+        ptr::write_bytes(payload, POISON, slot_size - size_of::<FreeSlot>());
+    }
+
+    /// Returns the offset of the first byte after the link that is not
+    /// [POISON], i.e. that has been written to since the slot was freed.
+    unsafe fn find_unpoisoned(
+        slot: *const FreeSlot,
+        slot_size: usize,
+    ) -> Option<usize> {
+        let bytes = slice::from_raw_parts(slot as *const u8, slot_size);
+        bytes[size_of::<FreeSlot>()..]
+            .iter()
+            .position(|&byte| byte != POISON)
+            .map(|idx| size_of::<FreeSlot>() + idx)
+    }
+}
+
+/// Slabs of the same slot size.
+#[derive(Clone, Copy)]
+struct SizeClass {
+    slot_size: usize,
+    /// List of slabs with at least one free slot.
+    partial: *mut Slab,
+    num_slabs: usize,
+}
+
+impl SizeClass {
+    const fn new(slot_size: usize) -> Self {
+        SizeClass {
+            slot_size,
+            partial: ptr::null_mut(),
+            num_slabs: 0,
+        }
+    }
+
+    /// Allocates a slab on the heap and adds it to the partial list, returns
+    /// `false` if the heap is exhausted.
+    unsafe fn add_slab(&mut self) -> bool {
+        let slab = heap::alloc_tagged(slab_layout()) as *mut Slab;
+        if slab.is_null() {
+            return false;
+        }
+
+        // The first slot follows the header and is aligned at its size.
+        let first_slot = (slab as usize + size_of::<Slab>() + self.slot_size
+            - 1)
+            & !(self.slot_size - 1);
+        let slots_end = slab as usize + SLAB_SIZE - self.slot_size + 1;
+        let mut free_slots: *mut FreeSlot = ptr::null_mut();
+        for addr in (first_slot..slots_end).step_by(self.slot_size).rev() {
+            let slot = addr as *mut FreeSlot;
+            (*slot).next = free_slots;
+            FreeSlot::poison(slot, self.slot_size);
+            free_slots = slot;
+        }
+
+        *slab = Slab {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free_slots,
+            num_used: 0,
+            slot_size: self.slot_size,
+        };
+        register_slab(slab);
+        self.push_partial(slab);
+        self.num_slabs += 1;
+        true
+    }
+
+    unsafe fn push_partial(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.partial;
+        if !self.partial.is_null() {
+            (*self.partial).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn remove_partial(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.partial = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+
+    unsafe fn alloc(&mut self) -> *mut u8 {
+        if self.partial.is_null() && !self.add_slab() {
+            return ptr::null_mut();
+        }
+
+        let slab = self.partial;
+        let slot = (*slab).free_slots;
+        if let Some(offset) = FreeSlot::find_unpoisoned(slot, self.slot_size) {
+            panic!(
+                "slab::alloc: free slot 0x{:08X} was written to at offset {}",
+                slot as usize, offset,
+            );
+        }
+        let next = (*slot).next;
+        assert!(
+            next.is_null()
+                || (slab as usize..slab as usize + SLAB_SIZE)
+                    .contains(&(next as usize)),
+            "slab::alloc: free slot 0x{:08X} links outside its slab",
+            slot as usize,
+        );
+        (*slab).free_slots = next;
+        (*slab).num_used += 1;
+        if (*slab).free_slots.is_null() {
+            // The slab is full.
+            self.remove_partial(slab);
+        }
+        slot as *mut u8
+    }
+
+    /// Frees the slot at `ptr` in `slab`, which must be of this size class.
+    unsafe fn dealloc(&mut self, slab: *mut Slab, ptr: *mut u8) {
+        assert_ne!(
+            (*slab).num_used,
+            0,
+            "slab::dealloc: double free of 0x{:08X}",
+            ptr as usize,
+        );
+
+        let was_full = (*slab).free_slots.is_null();
+        let slot = ptr as *mut FreeSlot;
+        (*slot).next = (*slab).free_slots;
+        FreeSlot::poison(slot, self.slot_size);
+        (*slab).free_slots = slot;
+        (*slab).num_used -= 1;
+
+        if was_full {
+            self.push_partial(slab);
+        }
+        if (*slab).num_used == 0 {
+            // Return the slab to the heap.
+            self.remove_partial(slab);
+            unregister_slab(slab);
+            (*slab).slot_size = 0;
+            heap::dealloc_tagged(slab as *mut u8, slab_layout());
+            self.num_slabs -= 1;
+        }
+    }
+}
+
+kernel_static! {
+    static ref SIZE_CLASSES: Mutex<[SizeClass; SLOT_SIZES.len()]> = Mutex::new([
+        SizeClass::new(SLOT_SIZES[0]),
+        SizeClass::new(SLOT_SIZES[1]),
+        SizeClass::new(SLOT_SIZES[2]),
+        SizeClass::new(SLOT_SIZES[3]),
+        SizeClass::new(SLOT_SIZES[4]),
+    ]);
+}
+
+fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, align_of::<Slab>()).unwrap()
+}
+
+/// Returns the index of the heap page containing `addr` in [SLAB_STARTS], or
+/// `None` if `addr` is outside the heap.
+fn heap_page_idx(addr: usize) -> Option<usize> {
+    let heap_start = unsafe { KERNEL_INFO.arch.heap_region.start };
+    let idx = addr.checked_sub(heap_start)? / 4096;
+    if idx < NUM_HEAP_PAGES {
+        Some(idx)
+    } else {
+        None
+    }
+}
+
+/// Adds `slab` to [SLAB_STARTS], the [SIZE_CLASSES] lock must be held.
+unsafe fn register_slab(slab: *mut Slab) {
+    let idx = heap_page_idx(slab as usize).expect("slab is outside the heap");
+    assert!(
+        SLAB_STARTS[idx].is_null(),
+        "two slabs start in the same page"
+    );
+    SLAB_STARTS[idx] = slab;
+}
+
+/// Removes `slab` from [SLAB_STARTS], the [SIZE_CLASSES] lock must be held.
+unsafe fn unregister_slab(slab: *mut Slab) {
+    let idx = heap_page_idx(slab as usize).expect("slab is outside the heap");
+    SLAB_STARTS[idx] = ptr::null_mut();
+}
+
+/// Returns the slab containing `addr`, or `None` if it is not in a slab.  The
+/// [SIZE_CLASSES] lock must be held.
+unsafe fn slab_of(addr: usize) -> Option<*mut Slab> {
+    let idx = heap_page_idx(addr)?;
+    let in_page = SLAB_STARTS[idx];
+    if !in_page.is_null() && in_page as usize <= addr {
+        return Some(in_page);
+    }
+    let in_prev_page = SLAB_STARTS[idx.checked_sub(1)?];
+    if !in_prev_page.is_null() && addr < in_prev_page as usize + SLAB_SIZE {
+        Some(in_prev_page)
+    } else {
+        None
+    }
+}
+
+/// Returns the index of the size class that serves `layout`, or `None` if it
+/// must be served by the tag allocator.
+fn class_of(layout: Layout) -> Option<usize> {
+    // A slot is aligned at its size.
+    let size = layout.size().max(layout.align());
+    SLOT_SIZES.iter().position(|&slot_size| size <= slot_size)
+}
+
+/// Returns the size of the slots that serve `layout`, or `None` if it must be
+/// served by the tag allocator.
+pub fn slot_size_of(layout: Layout) -> Option<usize> {
+    if ENABLED.load(Ordering::Relaxed) {
+        class_of(layout).map(|idx| SLOT_SIZES[idx])
+    } else {
+        None
+    }
+}
+
+/// Checks if the allocations with `layout` are served by the slab allocator.
+pub fn serves(layout: Layout) -> bool {
+    slot_size_of(layout).is_some()
+}
+
+/// Returns the size of the slot at `ptr`, or `None` if `ptr` is not in a slab.
+pub fn slot_size_at(ptr: *const u8) -> Option<usize> {
+    let _size_classes = SIZE_CLASSES.lock();
+    unsafe { slab_of(ptr as usize).map(|slab| (*slab).slot_size) }
+}
+
+/// Enables or disables serving the new allocations from the slabs.
+///
+/// The slots allocated so far can still be freed, since the slot of a pointer
+/// is found by its address.  This is meant for comparing the slabs with the tag
+/// allocator.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Allocates a slot for `layout`, returns a null pointer if the heap is
+/// exhausted.
+///
+/// # Panics
+/// This function panics if `layout` is not [served](serves) by the slab
+/// allocator.
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+    let idx = class_of(layout).expect("layout is too large for a slab");
+    SIZE_CLASSES.lock()[idx].alloc()
+}
+
+/// Frees a slot allocated by [alloc].
+///
+/// # Panics
+/// This function panics if `ptr` is not in a slab.
+pub unsafe fn dealloc(ptr: *mut u8) {
+    let mut size_classes = SIZE_CLASSES.lock();
+    let slab = slab_of(ptr as usize).unwrap_or_else(|| {
+        panic!("slab::dealloc: 0x{:08X} is not in a slab", ptr as usize)
+    });
+    let idx = SLOT_SIZES
+        .iter()
+        .position(|&slot_size| slot_size == (*slab).slot_size)
+        .unwrap();
+    size_classes[idx].dealloc(slab, ptr);
+}
+
+/// Checks if the free slot `ptr` for `layout` is still filled with [POISON],
+/// i.e. if its next [alloc] will not panic.
+///
+/// # Safety
+/// `ptr` must be a free slot in a slab that has not been returned to the heap.
+pub unsafe fn is_poisoned(ptr: *const u8, layout: Layout) -> bool {
+    let idx = class_of(layout).expect("layout is too large for a slab");
+    FreeSlot::find_unpoisoned(ptr as *const FreeSlot, SLOT_SIZES[idx]).is_none()
+}
+
+#[allow(dead_code)]
+pub fn stats() {
+    for class in SIZE_CLASSES.lock().iter() {
+        println!(
+            "[SLAB] Slot size: {}, slabs: {}.",
+            class.slot_size, class.num_slabs,
+        );
+    }
+}
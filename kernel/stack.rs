@@ -23,7 +23,8 @@ use crate::memory_region::Region;
 
 pub struct Stack<T> {
     layout: Layout,
-    max_top: *mut T,
+    on_heap: bool,
+    pub max_top: *mut T,
     pub top: *mut T,
     pub bottom: *mut T,
 }
@@ -56,20 +57,27 @@ impl<T> Stack<T> {
         );
         Stack {
             layout,
+            on_heap: false,
             max_top: region.start as *mut T,
             top: region.end as *mut T,
             bottom: region.end as *mut T,
         }
     }
 
+    /// Allocates a stack on the heap.
+    ///
+    /// Unlike the stacks constructed with [from_region](Self::from_region),
+    /// the memory is deallocated when the stack is dropped.
     pub fn with_layout(layout: Layout) -> Self {
         unsafe {
             let top = alloc(layout) as usize;
             let bottom = top + layout.size();
-            Self::from_region(Region {
+            let mut stack = Self::from_region(Region {
                 start: top,
                 end: bottom,
-            })
+            });
+            stack.on_heap = true;
+            stack
         }
     }
 
@@ -110,8 +118,10 @@ pub enum PopErr {
 
 impl<T> Drop for Stack<T> {
     fn drop(&mut self) {
-        unsafe {
-            dealloc(self.max_top.cast(), self.layout);
+        if self.on_heap {
+            unsafe {
+                dealloc(self.max_top.cast(), self.layout);
+            }
         }
     }
 }
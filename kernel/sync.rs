@@ -0,0 +1,193 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Sleeping locks.
+//!
+//! Unlike [kernel_static::Mutex](crate::kernel_static::Mutex), which spins,
+//! these put the contending task on a [WaitQueue] and let the other tasks run,
+//! so they suit the locks that are held for long, e.g. across disk I/O.  They
+//! cannot be used in the interrupt handlers, which must not block.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+use crate::arch;
+use crate::kernel_static;
+use crate::task_manager::{WaitQueue, TASK_MANAGER};
+
+/// Panics if called in an interrupt handler, where the running task must not
+/// block.
+fn assert_can_block(what: &str) {
+    assert!(
+        !arch::interrupts::in_interrupt(),
+        "cannot {} in an interrupt handler",
+        what,
+    );
+}
+
+struct MutexState {
+    locked: bool,
+    /// ID of the task holding the lock, `None` before the scheduler starts.
+    owner_id: Option<usize>,
+}
+
+/// A mutual exclusion lock that blocks the contending tasks.
+pub struct Mutex<T> {
+    state: kernel_static::Mutex<MutexState>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    pub fn new(data: T) -> Self {
+        Mutex {
+            state: kernel_static::Mutex::new(MutexState {
+                locked: false,
+                owner_id: None,
+            }),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquires the lock, blocking the running task while another one holds
+    /// it.
+    ///
+    /// # Panics
+    /// This method panics if called in an interrupt handler, or if the running
+    /// task already holds the lock.
+    pub fn lock(&self) -> MutexGuard<T> {
+        assert_can_block("lock a sleeping mutex");
+        let task_id =
+            unsafe { TASK_MANAGER.running_task().map(|task| task.id) };
+        while !self.try_acquire(task_id) {
+            self.waiters.wait();
+        }
+        if task_id.is_some() {
+            unsafe {
+                TASK_MANAGER.this_task().held_locks += 1;
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    fn try_acquire(&self, task_id: Option<usize>) -> bool {
+        arch::interrupts::with_disabled(|| {
+            let mut state = self.state.lock();
+            if !state.locked {
+                state.locked = true;
+                state.owner_id = task_id;
+                true
+            } else {
+                assert!(
+                    task_id.is_none() || state.owner_id != task_id,
+                    "task ID {:?} locks a sleeping mutex it holds",
+                    task_id,
+                );
+                false
+            }
+        })
+    }
+
+    fn unlock(&self) {
+        let owner_id = arch::interrupts::with_disabled(|| {
+            let mut state = self.state.lock();
+            state.locked = false;
+            state.owner_id.take()
+        });
+        if owner_id.is_some() {
+            unsafe {
+                TASK_MANAGER.this_task().held_locks -= 1;
+            }
+        }
+        self.waiters.wake_one();
+    }
+}
+
+unsafe impl<T> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+/// A counting semaphore.
+///
+/// [wait](Self::wait) blocks, so it cannot be used in the interrupt handlers,
+/// but [post](Self::post) can.
+pub struct Semaphore {
+    count: kernel_static::Mutex<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Self {
+        Semaphore {
+            count: kernel_static::Mutex::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Decrements the count, blocking the running task while it is zero.
+    ///
+    /// # Panics
+    /// This method panics if called in an interrupt handler.
+    pub fn wait(&self) {
+        assert_can_block("wait on a semaphore");
+        while !self.try_wait() {
+            self.waiters.wait();
+        }
+    }
+
+    /// Decrements the count unless it is zero, returns `false` if it is.
+    pub fn try_wait(&self) -> bool {
+        arch::interrupts::with_disabled(|| {
+            let mut count = self.count.lock();
+            if *count != 0 {
+                *count -= 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Increments the count and unblocks a waiting task.
+    pub fn post(&self) {
+        arch::interrupts::with_disabled(|| {
+            *self.count.lock() += 1;
+        });
+        self.waiters.wake_one();
+    }
+}
@@ -15,50 +15,169 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::mem::size_of;
 
+use crate::dev::console;
+use crate::dev::timer;
+use crate::errno::Errno;
 use crate::fs::VFS_ROOT;
+use crate::heap;
+use crate::task_manager;
 use crate::task_manager::TASK_MANAGER;
+use crate::usercopy;
 
+use crate::arch::pmm_stack;
+use crate::arch::task::{MapErr, UnmapErr};
+use crate::ffi::cstring::CString;
 use crate::fs;
-use crate::task::OpenFileErr;
+use crate::task::{FileMode, LoadErr, OpenFileErr, PRIORITY_IDLE};
+
+/// Maximum length of a path passed to a syscall in bytes.
+pub const PATH_MAX: usize = 4096;
+
+// The values match the O_* constants of newlib.
+bitflags_new! {
+    pub struct OpenFlags: u32 {
+        const WRONLY = 0x0001;
+        const RDWR = 0x0002;
+        const APPEND = 0x0008;
+        const CREAT = 0x0200;
+        const TRUNC = 0x0400;
+    }
+}
+
+/// Opens the file `pathname` with the lowest free file descriptor, creating it
+/// if it does not exist and `flags` has `CREAT`.  Without `WRONLY` or `RDWR`
+/// the file is opened for reading only.
+pub fn open(pathname: &str, flags: OpenFlags) -> Result<i32, OpenErr> {
+    println!("[SYS OPEN] pathname = {:?}, flags = {:?}", pathname, flags,);
+    let known = OpenFlags::WRONLY
+        | OpenFlags::RDWR
+        | OpenFlags::APPEND
+        | OpenFlags::CREAT
+        | OpenFlags::TRUNC;
+    if flags.bits() & !known.bits() != 0
+        || flags.contains(OpenFlags::WRONLY | OpenFlags::RDWR)
+    {
+        return Err(OpenErr::InvalidFlags);
+    }
+    let mode = FileMode {
+        readable: !flags.contains(OpenFlags::WRONLY),
+        writable: flags.contains(OpenFlags::WRONLY)
+            || flags.contains(OpenFlags::RDWR),
+        append: flags.contains(OpenFlags::APPEND),
+    };
+
+    let lookup = VFS_ROOT.lock().as_mut().unwrap().lookup(pathname);
+    let node = match lookup {
+        Ok(node) => node,
+        Err(fs::LookupErr::NotFound) if flags.contains(OpenFlags::CREAT) => {
+            create(pathname)?
+        }
+        Err(err) => {
+            println!("[SYS OPEN] Could not look up the path: {:?}.", err);
+            return Err(err.into());
+        }
+    };
+
+    if flags.contains(OpenFlags::TRUNC)
+        && mode.writable
+        && node.0.borrow()._type == fs::NodeType::RegularFile
+    {
+        let id_in_fs = node.0.borrow().id_in_fs.unwrap();
+        node.fs()
+            .truncate_file(id_in_fs)
+            .map_err(|_| OpenErr::ReadOnlyFs)?;
+    }
 
-pub fn open(pathname: &str) -> Result<i32, OpenErr> {
-    println!("[SYS OPEN] pathname = {:?}", pathname);
     let this_task = unsafe { TASK_MANAGER.this_task() };
-    let maybe_node = VFS_ROOT.lock().as_mut().unwrap().path(pathname);
-    if let Some(node) = maybe_node {
-        match this_task.open_file_by_node(node) {
-            Ok(fd) => {
-                println!("[SYS OPEN] fd = {} for pid {}", fd, this_task.id);
-                Ok(fd)
-            }
-            Err(err) => {
-                println!("[SYS OPEN] Could not open the node: {:?}.", err);
-                Err(err.into())
-            }
+    match this_task.open_file_by_node(node, mode) {
+        Ok(fd) => {
+            println!("[SYS OPEN] fd = {} for pid {}", fd, this_task.id);
+            Ok(fd)
+        }
+        Err(err) => {
+            println!("[SYS OPEN] Could not open the node: {:?}.", err);
+            Err(err.into())
         }
-    } else {
-        println!("[SYS OPEN] Node not found.");
-        Err(OpenErr::NotFound)
     }
 }
 
+/// Creates an empty regular file at `pathname`, whose parent directory must
+/// exist.
+fn create(pathname: &str) -> Result<fs::Node, OpenErr> {
+    let (dir_path, name) = match pathname.rfind('/') {
+        Some(idx) => (&pathname[..idx + 1], &pathname[idx + 1..]),
+        None => ("", pathname),
+    };
+    if name.is_empty() {
+        return Err(OpenErr::IsDir);
+    }
+    let mut dir = VFS_ROOT.lock().as_mut().unwrap().lookup(dir_path)?;
+    if !dir.is_dir() {
+        return Err(OpenErr::NotDir);
+    }
+    dir.create_child(name).map_err(|err| {
+        println!("[SYS OPEN] Could not create the file: {:?}.", err);
+        match err {
+            fs::CreateFileErr::ReadOnly => OpenErr::ReadOnlyFs,
+            fs::CreateFileErr::Exists => OpenErr::Exists,
+            fs::CreateFileErr::NameTooLong => OpenErr::NameTooLong,
+        }
+    })
+}
+
 #[derive(Debug)]
 pub enum OpenErr {
     NotFound,
+    NotDir,
+    NameTooLong,
     MaxOpenedFiles,
-    UnsupportedFileType,
+    IsDir,
+    Exists,
+    ReadOnlyFs,
+    InvalidFlags,
+    TooManyLinks,
 }
 
 impl From<OpenFileErr> for OpenErr {
     fn from(err: OpenFileErr) -> Self {
         match err {
             OpenFileErr::MaxOpenedFiles => OpenErr::MaxOpenedFiles,
-            OpenFileErr::UnsupportedFileType => OpenErr::UnsupportedFileType,
+            OpenFileErr::IsDir => OpenErr::IsDir,
         }
     }
 }
 
+impl From<fs::LookupErr> for OpenErr {
+    fn from(err: fs::LookupErr) -> Self {
+        match err {
+            fs::LookupErr::NotFound => OpenErr::NotFound,
+            fs::LookupErr::NotDir => OpenErr::NotDir,
+            fs::LookupErr::NameTooLong => OpenErr::NameTooLong,
+            fs::LookupErr::TooManyLinks => OpenErr::TooManyLinks,
+        }
+    }
+}
+
+/// Closes the file descriptor `fd`.  The file stays open for the other file
+/// descriptors and tasks that share it.
+pub fn close(fd: i32) -> Result<(), CloseErr> {
+    println!("[SYS CLOSE] fd = {}", fd);
+    let this_task = unsafe { TASK_MANAGER.this_task() };
+    if this_task.close_file(fd) {
+        Ok(())
+    } else {
+        Err(CloseErr::BadFd)
+    }
+}
+
+#[derive(Debug)]
+pub enum CloseErr {
+    BadFd,
+}
+
 pub fn write(fd: i32, buf: &[u8]) -> Result<usize, WriteErr> {
     let this_task = unsafe { TASK_MANAGER.this_task() };
 
@@ -72,15 +191,74 @@ pub fn write(fd: i32, buf: &[u8]) -> Result<usize, WriteErr> {
             fd, this_task.id,
         );
         Err(WriteErr::BadFd)
+    } else if !this_task.opened_file(fd).mode.writable {
+        println!("[SYS WRITE] File descriptor {} is not writable.", fd);
+        Err(WriteErr::BadFd)
     } else {
-        let n = this_task.opened_file(fd).write(&buf);
-        Ok(n)
+        // A write to a pipe blocks until all of the buffer is written.
+        let mut written = 0;
+        while written < buf.len() {
+            match this_task.opened_file(fd).write(&buf[written..]) {
+                Ok(n) => written += n,
+                Err(fs::WriteFileErr::Block(queue)) => unsafe {
+                    queue.wait();
+                    TASK_MANAGER.terminate_this_task_if_killed();
+                },
+                Err(fs::WriteFileErr::BrokenPipe) if written == 0 => {
+                    return Err(WriteErr::BrokenPipe);
+                }
+                Err(fs::WriteFileErr::BrokenPipe) => break,
+                Err(fs::WriteFileErr::NotWritable) => {
+                    return Err(WriteErr::NotWritable);
+                }
+            }
+        }
+        Ok(written)
     }
 }
 
 #[derive(Debug)]
 pub enum WriteErr {
     BadFd,
+    NotWritable,
+    /// The pipe has no readers.
+    BrokenPipe,
+}
+
+/// Creates a pipe, returns the file descriptors of its read end and write
+/// end.
+pub fn pipe() -> Result<(i32, i32), PipeErr> {
+    let this_task = unsafe { TASK_MANAGER.this_task() };
+    if !this_task.has_free_fds(2) {
+        return Err(PipeErr::MaxOpenedFiles);
+    }
+    let (read_end, write_end) = fs::pipefs::new_pipe();
+    let read_fd = this_task.open_file_by_node(read_end, FileMode::READ_ONLY)?;
+    let write_fd = this_task
+        .open_file_by_node(write_end, FileMode::WRITE_ONLY)
+        .map_err(|err| {
+            this_task.close_file(read_fd);
+            err
+        })?;
+    println!(
+        "[SYS PIPE] fds = {}, {} for task ID {}",
+        read_fd, write_fd, this_task.id,
+    );
+    Ok((read_fd, write_fd))
+}
+
+#[derive(Debug)]
+pub enum PipeErr {
+    MaxOpenedFiles,
+}
+
+impl From<OpenFileErr> for PipeErr {
+    fn from(err: OpenFileErr) -> Self {
+        match err {
+            OpenFileErr::MaxOpenedFiles => PipeErr::MaxOpenedFiles,
+            OpenFileErr::IsDir => unreachable!("a pipe end is not a directory"),
+        }
+    }
 }
 
 pub fn read(fd: i32, buf: &mut [u8]) -> Result<usize, ReadErr> {
@@ -97,17 +275,26 @@ pub fn read(fd: i32, buf: &mut [u8]) -> Result<usize, ReadErr> {
                 fd, this_task.id,
             );
             return Err(ReadErr::BadFd);
+        } else if !this_task.opened_file(fd).mode.readable {
+            println!("[SYS READ] File descriptor {} is not readable.", fd);
+            return Err(ReadErr::BadFd);
+        } else if this_task.opened_file(fd).node.is_dir() {
+            return Err(ReadErr::IsDir);
         } else {
             match this_task.opened_file(fd).read(buf) {
                 Ok(n) => return Ok(n),
                 Err(err) => match err {
-                    fs::ReadFileErr::Block => unsafe {
-                        TASK_MANAGER.block_this_task();
+                    fs::ReadFileErr::Block(queue) => unsafe {
+                        queue.wait();
+                        TASK_MANAGER.terminate_this_task_if_killed();
                     },
                     fs::ReadFileErr::NotReadable => {
                         return Err(ReadErr::NotReadable);
                     }
-                    other => unimplemented!("FIXME: handle {:?}", other),
+                    other => {
+                        println!("[SYS READ] Could not read: {:?}.", other);
+                        return Err(ReadErr::IoError);
+                    }
                 },
             }
         }
@@ -118,35 +305,187 @@ pub fn read(fd: i32, buf: &mut [u8]) -> Result<usize, ReadErr> {
 pub enum ReadErr {
     BadFd,
     NotReadable,
+    IsDir,
+    IoError,
 }
 
+/// Variant of [lseek] with an unsigned offset for the older syscalls.
 pub fn seek(variant: Seek, fd: i32, offset: usize) -> Result<usize, SeekErr> {
+    lseek(fd, offset as i64, variant)
+}
+
+/// Moves the offset of `fd` to `offset` relative to the start, the current
+/// offset or the end of the file, returns the new offset.
+///
+/// The offset may go past the end of the file, a read from there returns 0
+/// bytes.  `offset` is 64-bit so that a 64-bit variant of the syscall can use
+/// this too.
+pub fn lseek(fd: i32, offset: i64, whence: Seek) -> Result<usize, SeekErr> {
+    println!(
+        "[SYS LSEEK] fd = {}, offset = {}, whence = {:?}",
+        fd, offset, whence,
+    );
     let this_task = unsafe { TASK_MANAGER.this_task() };
     if !this_task.check_fd(fd) {
-        println!(
-            "[SYS SEEK] Invalid file descriptor {} for PID {}.",
-            fd, this_task.id,
-        );
-        Err(SeekErr::BadFd)
-    } else {
-        Ok(match variant {
-            Seek::Abs => this_task.opened_file(fd).seek_abs(offset),
-            Seek::Rel => this_task.opened_file(fd).seek_rel(offset),
-        })
+        return Err(SeekErr::BadFd);
     }
+    let file = this_task.opened_file(fd);
+    if !file.is_seekable() {
+        return Err(SeekErr::NotSeekable);
+    }
+    let base = match whence {
+        Seek::Abs => 0,
+        Seek::Rel => file.offset(),
+        Seek::End => {
+            let id_in_fs = file.node.0.borrow().id_in_fs.unwrap();
+            file.node
+                .fs()
+                .file_size_bytes(id_in_fs)
+                .map_err(|_| SeekErr::NotSeekable)?
+        }
+    };
+    let new_offset = (base as i64)
+        .checked_add(offset)
+        .filter(|&new_offset| new_offset >= 0 && new_offset <= i32::MAX as i64)
+        .ok_or(SeekErr::InvalidOffset)?;
+    Ok(file.seek_abs(new_offset as usize))
 }
 
+/// Whence of a seek: the start of the file, the current offset or the end of
+/// the file.
 #[derive(Debug)]
 pub enum Seek {
     Abs,
     Rel,
+    End,
 }
 
 #[derive(Debug)]
 pub enum SeekErr {
     BadFd,
+    NotSeekable,
+    /// The resulting offset is negative or does not fit in the return value.
+    InvalidOffset,
+}
+
+/// File information returned by the stat syscalls.
+///
+/// NOTE: this is ABI, the layout must not change.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct Stat {
+    /// Together with `node_id`, identifies the node across the mounted file
+    /// systems.
+    pub fs_id: u32,
+    pub node_id: u32,
+    /// File type (`S_IFMT` bits) and permissions.
+    pub mode: u32,
+    pub hard_links: u32,
+    pub user_id: u32,
+    pub group_id: u32,
+    pub size: u64,
+    /// Times in seconds since the Unix epoch, 0 if unknown.
+    pub access_time: u32,
+    pub modification_time: u32,
+    pub change_time: u32,
+    _reserved: u32,
+}
+
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFIFO: u32 = 0o010000;
+
+impl Stat {
+    fn of_node(node: &fs::Node) -> Result<Self, StatErr> {
+        let metadata = node.metadata().map_err(|err| {
+            println!("[SYS STAT] Could not read the metadata: {:?}.", err);
+            StatErr::IoError
+        })?;
+        let file_type = match node.0.borrow()._type {
+            fs::NodeType::RegularFile => S_IFREG,
+            fs::NodeType::Dir | fs::NodeType::MountPoint(_) => S_IFDIR,
+            fs::NodeType::CharDevice => S_IFCHR,
+            fs::NodeType::BlockDevice => S_IFBLK,
+            fs::NodeType::Symlink => S_IFLNK,
+            fs::NodeType::Fifo => S_IFIFO,
+        };
+        Ok(Stat {
+            fs_id: node.fs_id() as u32,
+            node_id: node.0.borrow().id_in_fs.unwrap() as u32,
+            mode: file_type | metadata.permissions as u32,
+            hard_links: metadata.hard_links as u32,
+            user_id: metadata.user_id as u32,
+            group_id: metadata.group_id as u32,
+            size: metadata.size as u64,
+            access_time: metadata.access_time,
+            modification_time: metadata.modification_time,
+            change_time: metadata.change_time,
+            _reserved: 0,
+        })
+    }
+}
+
+/// Returns the information about the file at `pathname`, following the
+/// symbolic links.
+pub fn stat(pathname: &str) -> Result<Stat, StatErr> {
+    println!("[SYS STAT] pathname = {:?}", pathname);
+    let node = VFS_ROOT.lock().as_mut().unwrap().lookup(pathname)?;
+    Stat::of_node(&node)
+}
+
+/// Same as [stat], but if `pathname` is a symbolic link, returns the
+/// information about the link itself.
+pub fn lstat(pathname: &str) -> Result<Stat, StatErr> {
+    println!("[SYS LSTAT] pathname = {:?}", pathname);
+    let node = VFS_ROOT
+        .lock()
+        .as_mut()
+        .unwrap()
+        .lookup_no_follow(pathname)?;
+    Stat::of_node(&node)
+}
+
+/// Returns the information about the file opened as `fd`.
+pub fn fstat(fd: i32) -> Result<Stat, StatErr> {
+    println!("[SYS FSTAT] fd = {}", fd);
+    let this_task = unsafe { TASK_MANAGER.this_task() };
+    if !this_task.check_fd(fd) {
+        return Err(StatErr::BadFd);
+    }
+    Stat::of_node(&this_task.opened_file(fd).node)
+}
+
+#[derive(Debug)]
+pub enum StatErr {
+    BadFd,
+    NotFound,
+    NotDir,
+    NameTooLong,
+    TooManyLinks,
+    IoError,
+}
+
+impl From<fs::LookupErr> for StatErr {
+    fn from(err: fs::LookupErr) -> Self {
+        match err {
+            fs::LookupErr::NotFound => StatErr::NotFound,
+            fs::LookupErr::NotDir => StatErr::NotDir,
+            fs::LookupErr::NameTooLong => StatErr::NameTooLong,
+            fs::LookupErr::TooManyLinks => StatErr::TooManyLinks,
+        }
+    }
 }
 
+/// Maps `len` bytes of zeroed memory and returns the address of the mapping.
+///
+/// Only private anonymous mappings are supported, so `fd` and `offset` are
+/// ignored.  `addr` is ignored too unless [MemMapFlags::FIXED] is given, in
+/// which case the mapping is placed exactly there and must not overlap the
+/// existing ones.  [MemMapProt::NONE] reserves the pages but makes them
+/// inaccessible until [mem_protect] is called.
 pub fn mem_map(
     addr: usize,
     len: usize,
@@ -160,22 +499,60 @@ pub fn mem_map(
         addr, len, prot, flags, fd, offset,
     );
 
-    if addr != 0 {
-        unimplemented!("syscall mem_map: addr is not 0");
+    let known_prot = MemMapProt::NONE
+        | MemMapProt::READ
+        | MemMapProt::WRITE
+        | MemMapProt::EXEC;
+    let known_flags = MemMapFlags::PRIVATE
+        | MemMapFlags::ANONYMOUS
+        | MemMapFlags::SHARED
+        | MemMapFlags::FIXED;
+    if prot.bits() & !known_prot.bits() != 0
+        || flags.bits() & !known_flags.bits() != 0
+    {
+        return Err(MemMapErr::InvalidArgs);
     }
-    if fd != -1 {
-        unimplemented!("syscall mem_map: fd is not -1");
+    if !flags.contains(MemMapFlags::PRIVATE | MemMapFlags::ANONYMOUS)
+        || flags.contains(MemMapFlags::SHARED)
+    {
+        return Err(MemMapErr::NotSupported);
     }
-    if offset != 0 {
-        println!("[SYS MEM_MAP] non-zero offset (0x{:X}) is ignored", offset);
-    }
-
-    assert_eq!(prot, MemMapProt::READ | MemMapProt::WRITE);
-    assert_eq!(flags, MemMapFlags::PRIVATE | MemMapFlags::ANONYMOUS);
 
-    let mapping = unsafe { TASK_MANAGER.this_task().mem_map(len) };
+    let this_task = unsafe { TASK_MANAGER.this_task() };
+    let fixed_addr = if flags.contains(MemMapFlags::FIXED) {
+        Some(addr)
+    } else {
+        None
+    };
+    let region = match this_task.mem_map(fixed_addr, len) {
+        Ok(mapping) => mapping.region.clone(),
+        Err(err) => {
+            println!("[SYS MEM_MAP] Could not map: {:?}.", err);
+            return Err(match err {
+                MapErr::InvalidArgs => MemMapErr::InvalidArgs,
+                MapErr::Conflict | MapErr::NoSpace => MemMapErr::NoMemory,
+            });
+        }
+    };
+
+    let writable = prot.contains(MemMapProt::WRITE);
+    let user = !prot.contains(MemMapProt::NONE);
+    if !writable || !user {
+        unsafe {
+            this_task
+                .process()
+                .vas
+                .set_protection(
+                    region.start as u32,
+                    region.end as u32,
+                    writable,
+                    user,
+                )
+                .unwrap();
+        }
+    }
 
-    Ok(mapping.region.start as usize)
+    Ok(region.start)
 }
 
 bitflags_new! {
@@ -197,17 +574,101 @@ bitflags_new! {
 }
 
 #[derive(Debug)]
-pub enum MemMapErr {}
+pub enum MemMapErr {
+    /// Unknown prot or flags, zero length, or a misaligned or out-of-range
+    /// fixed address.
+    InvalidArgs,
+    /// A file or shared mapping was requested.
+    NotSupported,
+    /// There is no room for the mapping, or the fixed address is taken.
+    NoMemory,
+}
+
+pub fn mem_unmap(addr: usize, len: usize) -> Result<(), UnmapErr> {
+    println!("[SYS MEM_UNMAP] addr = 0x{:08X}, len = 0x{:08X}", addr, len);
+    let _mem_lock = usercopy::lock_mem();
+    unsafe { TASK_MANAGER.this_task().mem_unmap(addr, len) }
+}
+
+/// Sets the program break and returns the new one, or the old one on failure.
+pub fn brk(new_end: usize) -> usize {
+    println!("[SYS BRK] new_end = 0x{:08X}", new_end);
+    let _mem_lock = usercopy::lock_mem();
+    unsafe { TASK_MANAGER.this_task().set_program_break(new_end) }
+}
+
+/// Changes the access rights of the pages in the specified range.
+///
+/// The range must be page-aligned and lie within the task's program segments
+/// and memory mappings (including the usermode stack).  [MemMapProt::EXEC] is
+/// ignored since x86 without PAE cannot disable instruction fetches.
+pub fn mem_protect(
+    addr: usize,
+    len: usize,
+    prot: MemMapProt,
+) -> Result<(), MemProtectErr> {
+    println!(
+        "[SYS MEM_PROTECT] addr = 0x{:08X}, len = 0x{:08X}, prot = {:?}",
+        addr, len, prot,
+    );
+
+    if addr % 4096 != 0 || len % 4096 != 0 || addr.checked_add(len).is_none() {
+        return Err(MemProtectErr::InvalidArgs);
+    }
+
+    let _mem_lock = usercopy::lock_mem();
+    let process = unsafe { TASK_MANAGER.this_task().process() };
+    let is_covered =
+        |page: usize| {
+            process.program_segments.iter().any(|segment| {
+                segment.align_boundaries_at(4096).contains(&page)
+            }) || process
+                .mem_mappings
+                .iter()
+                .any(|mapping| mapping.region.contains(&page))
+        };
+    if !(addr..addr + len).step_by(4096).all(is_covered) {
+        return Err(MemProtectErr::NotMapped);
+    }
+
+    let writable = prot.contains(MemMapProt::WRITE);
+    let user = !prot.contains(MemMapProt::NONE);
+    unsafe {
+        process
+            .vas
+            .set_protection(addr as u32, (addr + len) as u32, writable, user)
+            .map_err(|_| MemProtectErr::NotMapped)
+    }
+}
+
+#[derive(Debug)]
+pub enum MemProtectErr {
+    InvalidArgs,
+    NotMapped,
+}
 
-pub fn set_tls(ptr: usize) {
+/// Sets the base of the TLS segment of the calling task.  The TLS ABI loads
+/// the thread pointer from `%gs:0`, so the base must point to readable memory,
+/// unless it is 0, which clears it.
+pub fn set_tls(ptr: usize) -> Result<(), SetTlsErr> {
     unsafe {
         let this_task = TASK_MANAGER.this_task();
-        this_task.set_tls(ptr);
         println!(
             "[SYS SET_TLS] tls_ptr = 0x{:08X} for task ID {}",
             ptr, this_task.id,
         );
+        if ptr != 0 {
+            usercopy::check_range(ptr, size_of::<u32>())
+                .map_err(|_| SetTlsErr::InvalidPointer)?;
+        }
+        this_task.set_tls(ptr);
     }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum SetTlsErr {
+    InvalidPointer,
 }
 
 pub fn debug_print_num(num: u32) {
@@ -218,10 +679,20 @@ pub fn debug_print_str(s: &str) {
     println!("[SYS DEBUG_PRINT_STR] {}", s);
 }
 
+/// Terminates the calling process, including all of its threads, with the
+/// exit status `status`.
 pub fn exit(status: i32) -> ! {
-    unsafe {
-        TASK_MANAGER.terminate_this_task(status);
+    task_manager::process_exit(status);
+}
+
+/// Performs a device-specific request on the char device opened as `fd`.
+pub fn ioctl(fd: i32, request: u32, arg: usize) -> Result<u32, Errno> {
+    let this_task = unsafe { TASK_MANAGER.this_task() };
+    if !this_task.check_fd(fd) {
+        return Err(Errno::EBADF);
     }
+    let node = this_task.opened_file(fd).node.clone();
+    node.ioctl(request, arg)
 }
 
 pub fn is_tty(fd: i32) -> Result<bool, IsTtyErr> {
@@ -251,6 +722,268 @@ pub enum IsTtyErr {
     BadFd,
 }
 
+/// Returns the ID of the calling task's process, which its threads share.
 pub fn get_pid() -> i32 {
+    unsafe { TASK_MANAGER.this_task().process_id() as i32 }
+}
+
+/// Returns the ID of the process that forked the calling task's process, or
+/// [the reaper's](task_manager::REAPER_TASK_ID) if it has exited.  Returns 0
+/// if there is no parent.
+pub fn get_ppid() -> i32 {
+    unsafe { TASK_MANAGER.this_task().parent_id.unwrap_or(0) as i32 }
+}
+
+/// Returns the ID of the calling task, which is distinct for each thread of a
+/// process.
+pub fn get_tid() -> i32 {
     unsafe { TASK_MANAGER.this_task().id as i32 }
 }
+
+/// Makes the task with the ID `task_id` the foreground task of the console,
+/// i.e. the one that Ctrl+C terminates.  Only the process that owns the
+/// console or is in the foreground may do that.
+pub fn set_foreground(task_id: usize) -> Result<(), SetForegroundErr> {
+    if !unsafe { TASK_MANAGER.has_task(task_id) } {
+        return Err(SetForegroundErr::NoSuchTask);
+    }
+    let caller_id = unsafe { TASK_MANAGER.this_task().process_id() };
+    console::with_console(|console| {
+        if console.may_set_foreground(caller_id) {
+            console.set_foreground_task(Some(task_id));
+            Ok(())
+        } else {
+            Err(SetForegroundErr::NotPermitted)
+        }
+    })
+}
+
+#[derive(Debug)]
+pub enum SetForegroundErr {
+    NoSuchTask,
+    NotPermitted,
+}
+
+/// Blocks the calling task for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u32) {
+    task_manager::sleep_ms(ms as u64);
+}
+
+/// Time interval for [nanosleep].
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Timespec {
+    pub secs: i64,
+    pub nanos: i32,
+}
+
+impl Timespec {
+    fn from_ms(ms: u64) -> Self {
+        Timespec {
+            secs: (ms / 1000) as i64,
+            nanos: (ms % 1000) as i32 * 1_000_000,
+        }
+    }
+}
+
+/// Blocks the calling task for at least `req`, rounded up to whole timer
+/// periods.  A request shorter than the period sleeps for at least one period.
+pub fn nanosleep(req: Timespec) -> Result<(), NanosleepErr> {
+    if req.secs < 0 || !(0..1_000_000_000).contains(&req.nanos) {
+        return Err(NanosleepErr::InvalidTime);
+    }
+    let ms = (req.secs as u64)
+        .saturating_mul(1000)
+        .saturating_add((req.nanos as u64 + 999_999) / 1_000_000);
+    let ms_per_tick = timer::ms_per_tick().max(1);
+    // The uptime advances a tick at a time and the current tick may be almost
+    // over, so one more tick is slept to never wake up early.
+    let ticks = ms / ms_per_tick + (ms % ms_per_tick != 0) as u64 + 1;
+    match task_manager::sleep_ms(ticks.saturating_mul(ms_per_tick)) {
+        0 => Ok(()),
+        left_ms => Err(NanosleepErr::Interrupted(Timespec::from_ms(
+            left_ms.min(ms),
+        ))),
+    }
+}
+
+#[derive(Debug)]
+pub enum NanosleepErr {
+    /// The seconds are negative or the nanoseconds are out of range.
+    InvalidTime,
+    /// The task is woken up early, with the remaining time.
+    Interrupted(Timespec),
+}
+
+/// Sets the scheduling priority of the task with the ID `task_id`, from 0
+/// (highest) to 7 (idle).
+pub fn set_priority(
+    task_id: usize,
+    priority: u32,
+) -> Result<(), SetPriorityErr> {
+    if priority > PRIORITY_IDLE as u32 {
+        return Err(SetPriorityErr::InvalidPriority);
+    }
+    if unsafe { TASK_MANAGER.set_priority(task_id, priority as u8) } {
+        Ok(())
+    } else {
+        Err(SetPriorityErr::NoSuchTask)
+    }
+}
+
+#[derive(Debug)]
+pub enum SetPriorityErr {
+    NoSuchTask,
+    InvalidPriority,
+}
+
+/// Blocks the calling task until one of its children exits, returns the ID
+/// and the exit status of the child.
+pub fn wait() -> Result<(usize, i32), WaitErr> {
+    loop {
+        unsafe {
+            // A child that has just exited may not have been reaped yet.
+            TASK_MANAGER.reap_terminated_tasks();
+            if TASK_MANAGER.this_task().process().child_ids.is_empty() {
+                return Err(WaitErr::NoChildren);
+            }
+            if let Some(exited) = TASK_MANAGER.take_exited_child() {
+                return Ok(exited);
+            }
+            task_manager::CHILD_EXITS.wait();
+            TASK_MANAGER.terminate_this_task_if_killed();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WaitErr {
+    NoChildren,
+}
+
+/// Terminates the task with the ID `task_id`, which may be the calling task
+/// itself.
+pub fn kill(task_id: usize) -> Result<(), KillErr> {
+    println!("[SYS KILL] task_id = {}", task_id);
+    if task_manager::kill(task_id, task_manager::STATUS_KILLED) {
+        Ok(())
+    } else {
+        Err(KillErr::NoSuchTask)
+    }
+}
+
+#[derive(Debug)]
+pub enum KillErr {
+    NoSuchTask,
+}
+
+/// Thread creation flag: the thread cannot be joined and its exit value is
+/// discarded.
+pub const THREAD_DETACHED: u32 = 0b0001;
+
+/// Blocks the calling thread until the thread `thread_id` of the same process
+/// exits, returns its exit value.
+///
+/// A thread can be joined only once and only if it has not been created
+/// detached.
+pub fn thread_join(thread_id: usize) -> Result<i32, ThreadJoinErr> {
+    println!("[SYS THREAD_JOIN] thread_id = {}", thread_id);
+    loop {
+        unsafe {
+            if thread_id == TASK_MANAGER.this_task().id {
+                return Err(ThreadJoinErr::JoinSelf);
+            }
+            // A thread that has just exited may not have been reaped yet.
+            TASK_MANAGER.reap_terminated_tasks();
+            if let Some(value) = TASK_MANAGER.take_exited_thread(thread_id) {
+                return Ok(value);
+            }
+            if !TASK_MANAGER.is_joinable_thread(thread_id) {
+                return Err(ThreadJoinErr::NoSuchThread);
+            }
+            task_manager::THREAD_EXITS.wait();
+            TASK_MANAGER.terminate_this_task_if_killed();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ThreadJoinErr {
+    NoSuchThread,
+    JoinSelf,
+}
+
+/// Renames the calling task, see [Task::name](crate::task::Task::name).
+pub fn set_task_name(name: &str) {
+    println!("[SYS SET_TASK_NAME] name = {:?}", name);
+    unsafe {
+        TASK_MANAGER.this_task().set_name(name);
+    }
+}
+
+/// Terminates the calling thread with the exit value `value`, which is
+/// returned to the thread that joins it.  The other threads keep running.
+pub fn thread_exit(value: i32) -> ! {
+    task_manager::task_exit(value);
+}
+
+/// Replaces the program of the calling task with the executable `pathname`.
+/// Returns only if it cannot be executed.
+pub fn execve(
+    pathname: &str,
+    argv: Vec<CString>,
+    environ: Vec<CString>,
+) -> ExecveErr {
+    println!("[SYS EXECVE] pathname = {:?}", pathname);
+    let this_task = unsafe { TASK_MANAGER.this_task() };
+    match unsafe { this_task.exec(pathname, argv, environ) } {
+        LoadErr::OpenFailed(OpenErr::NotFound)
+        | LoadErr::OpenFailed(OpenErr::NotDir)
+        | LoadErr::OpenFailed(OpenErr::NameTooLong) => ExecveErr::NotFound,
+        LoadErr::OpenFailed(OpenErr::MaxOpenedFiles) => {
+            ExecveErr::MaxOpenedFiles
+        }
+        LoadErr::OpenFailed(_)
+        | LoadErr::NotExecutable(_)
+        | LoadErr::InvalidSegment(_)
+        | LoadErr::NotRelocatable(_)
+        | LoadErr::ReadFailed
+        | LoadErr::NoRoomForTls => ExecveErr::NotExecutable,
+    }
+}
+
+#[derive(Debug)]
+pub enum ExecveErr {
+    NotFound,
+    MaxOpenedFiles,
+    NotExecutable,
+}
+
+/// Kernel memory usage returned by [mem_stats].
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MemStats {
+    /// Current size of the kernel heap.
+    pub heap_total: u32,
+    /// Bytes requested by the live allocations of the tag allocator, which
+    /// include the slabs.
+    pub heap_used: u32,
+    /// Number of the live allocations of the tag allocator.
+    pub heap_allocations: u32,
+    pub total_pages: u32,
+    pub free_pages: u32,
+}
+
+/// Returns the kernel heap usage and the number of free physical pages, e.g.
+/// to check for leaks.
+pub fn mem_stats() -> MemStats {
+    let heap_usage = heap::usage();
+    let pmm_stats = pmm_stack::stats();
+    MemStats {
+        heap_total: heap_usage.total as u32,
+        heap_used: heap_usage.used as u32,
+        heap_allocations: heap_usage.allocations as u32,
+        total_pages: pmm_stats.total_pages as u32,
+        free_pages: pmm_stats.free_pages as u32,
+    }
+}
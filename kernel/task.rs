@@ -14,43 +14,248 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use alloc::alloc::{alloc, Layout};
+use alloc::alloc::Layout;
 use alloc::boxed::Box;
+use alloc::rc::Rc;
 use alloc::vec;
 use alloc::vec::Vec;
+use core::cell::{Cell, UnsafeCell};
+use core::cmp;
+use core::mem::{self, size_of};
+use core::ptr;
 use core::slice;
+use core::str;
 
-use crate::arch::pmm_stack::PMM_STACK;
 use crate::arch::vas::USERMODE_REGION;
 use crate::dev::console::CONSOLE;
+use crate::dev::timer;
 
+use crate::arch::fpu::FpuState;
 use crate::arch::task::{MemMapping, TaskControlBlock};
-use crate::arch::vas::{Table, VirtAddrSpace};
-use crate::elf::{ElfObj, ProgSegmentType};
+use crate::arch::vas::VirtAddrSpace;
+use crate::elf::{
+    DynamicErr, ElfObj, ElfObjErr, ProgSegment, ProgSegmentType, Relocation,
+    SymbolTable,
+};
 use crate::feeder::Feeder;
 use crate::fs;
 use crate::memory_region::Region;
 use crate::stack::Stack;
+use crate::sync::Mutex;
 use crate::syscall;
 
+/// Initial usermode stack region.  The stack grows down on demand (see
+/// [USERMODE_STACK_MAX_SIZE]).
 pub const USERMODE_STACK_REGION: Region<usize> = Region {
-    start: 3 * 1024 * 1024 * 1024,      // 3 GiB
-    end: 3 * 1024 * 1024 * 1024 + 4096, // 3 GiB + 4 KiB
+    start: USERMODE_REGION.end - 4096, // 3 GiB - 4 KiB
+    end: USERMODE_REGION.end,          // 3 GiB
+};
+
+/// Maximum size the usermode stack may grow to.
+pub const USERMODE_STACK_MAX_SIZE: usize = 8 * 1024 * 1024; // 8 MiB
+
+/// Region the usermode stack may grow within.  No other mappings are placed
+/// here.
+pub const USERMODE_STACK_LIMIT_REGION: Region<usize> = Region {
+    start: USERMODE_STACK_REGION.end - USERMODE_STACK_MAX_SIZE,
+    end: USERMODE_STACK_REGION.end,
 };
 
+/// Where a position-independent executable is loaded, its segments are linked
+/// starting at 0.
+pub const PIE_LOAD_BIAS: usize = 0x1000_0000;
+
 pub const MAX_OPENED_FILES: usize = 32;
 
-pub struct Task {
-    pub id: usize,
+/// Maximum length of a [task name](Task::name) in bytes.
+pub const TASK_NAME_LEN: usize = 16;
+
+/// Task priorities, a lower value is a higher priority.
+pub const PRIORITY_HIGHEST: u8 = 0;
+pub const PRIORITY_DEFAULT: u8 = 4;
+/// Priority of the tasks that run only when there is nothing else to run.
+pub const PRIORITY_IDLE: u8 = 7;
+
+/// State of a task, each one has its own list in the [task manager].
+///
+/// [task manager]: crate::task_manager::TaskManager
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Runnable,
+    /// Waiting for an event, e.g. on a [WaitQueue].
+    ///
+    /// [WaitQueue]: crate::task_manager::WaitQueue
+    Blocked,
+    /// Waiting for the uptime to reach [Task::wake_ms].
+    Sleeping,
+    /// Terminated, but not reaped yet.
+    Zombie,
+}
+
+impl TaskState {
+    pub fn name(self) -> &'static str {
+        match self {
+            TaskState::Running => "running",
+            TaskState::Runnable => "runnable",
+            TaskState::Blocked => "blocked",
+            TaskState::Sleeping => "sleeping",
+            TaskState::Zombie => "zombie",
+        }
+    }
+
+    /// Checks if a task may go from this state to `to`.
+    fn can_become(self, to: TaskState) -> bool {
+        use TaskState::*;
+        matches!(
+            (self, to),
+            (Runnable, Running)
+                | (Running, Runnable)
+                | (Running, Blocked)
+                | (Running, Sleeping)
+                | (Running, Zombie)
+                | (Blocked, Runnable)
+                | (Blocked, Zombie)
+                | (Sleeping, Runnable)
+                | (Sleeping, Zombie)
+        )
+    }
+}
+
+/// Size of the thread control block at the thread pointer, which only holds
+/// the thread pointer itself for `%gs:0` loads.
+const TLS_TCB_SIZE: usize = size_of::<u32>();
+
+/// Maximum size of the TLS block set up for the TLS segment.
+const MAX_TLS_BLOCK_SIZE: usize = 1024 * 1024;
 
+/// Number of scheduler quanta a runnable task is passed over for before its
+/// [effective priority](Task::effective_priority) is raised by one level.
+pub const AGING_QUANTA: usize = 8;
+
+/// The parts of a task that its [threads](Task::thread) share: the memory,
+/// the opened files and the children.  The VAS is destroyed when the last
+/// thread is dropped.
+///
+/// Task and process IDs are allocated from the same counter by
+/// [TaskManager::allocate_task_id]: a fork allocates a task ID for the first
+/// thread of the new process, which becomes the process ID as well, while a
+/// thread gets a task ID of its own and keeps the process ID.
+///
+/// [TaskManager::allocate_task_id]: crate::task_manager::TaskManager::allocate_task_id
+pub struct Process {
+    /// Process ID, the task ID of the first thread.
+    pub id: usize,
     pub vas: VirtAddrSpace,
     pub program_segments: Vec<Region<usize>>,
     pub mem_mappings: Vec<MemMapping>,
+    /// Start of the program break heap, right after the program segments.
+    pub heap_start: usize,
+    /// Current program break (see [`Task::set_program_break()`]).
+    pub heap_end: usize,
+    /// Held while the kernel accesses the usermode memory on behalf of a
+    /// thread (see [usercopy](crate::usercopy)) and while the pages are
+    /// unmapped or reprotected, so that a sibling thread cannot pull a checked
+    /// buffer from under the copy.
+    pub mem_lock: Mutex<()>,
+
+    /// Indexed by the file descriptors, `None` for the closed ones.
+    opened_files: Vec<Option<OpenedFile>>,
+    /// IDs of the forked processes that have not been waited for yet.
+    pub child_ids: Vec<usize>,
+    /// Function symbols of the program, `None` if it is stripped.
+    pub symbols: Option<Rc<SymbolTable>>,
+    /// Exit status of a process-wide exit (see [process_exit]), which
+    /// overrides that of the last thread.
+    ///
+    /// [process_exit]: crate::task_manager::process_exit
+    pub exit_status: Option<i32>,
+}
+
+impl Process {
+    fn new(id: usize, vas: VirtAddrSpace) -> Self {
+        Process {
+            id,
+            vas,
+            program_segments: Vec::new(),
+            mem_mappings: Vec::new(),
+            heap_start: 0,
+            heap_end: 0,
+            mem_lock: Mutex::new(()),
+
+            opened_files: Vec::new(),
+            child_ids: Vec::new(),
+            symbols: None,
+            exit_status: None,
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // The kernel threads share the kernel VAS.
+        if self.vas.is_usermode() {
+            unsafe {
+                self.vas.destroy();
+            }
+        }
+    }
+}
+
+pub struct Task {
+    /// Task ID, which the threads of a process do not share (see
+    /// [Process]).
+    pub id: usize,
+    /// See [Task::name].
+    name: [u8; TASK_NAME_LEN],
+    /// Uptime in milliseconds at which the task was created.
+    pub created_ms: u64,
+
+    /// Shared with the other threads of the process.  Only the running task
+    /// accesses it, so there are no other references to it while it does.
+    process: Rc<UnsafeCell<Process>>,
     pub kernel_stack: Stack<u32>,
     pub usermode_stack: Option<Stack<u32>>,
     pub tls: u32,
+    /// FPU and SSE registers, saved when the task is switched from.
+    pub fpu_state: Box<FpuState>,
 
-    opened_files: Vec<OpenedFile>,
+    /// Exit status to terminate the task with once it reaches a point where
+    /// it can be terminated safely (see [TaskManager::kill_task]).
+    ///
+    /// [TaskManager::kill_task]: crate::task_manager::TaskManager::kill_task
+    pub kill_status: Option<i32>,
+
+    /// Scheduling priority, from [PRIORITY_HIGHEST] to [PRIORITY_IDLE].
+    pub priority: u8,
+    /// Number of times the task has been passed over by the scheduler since
+    /// it last ran, see [Task::effective_priority].
+    pub skipped_quanta: usize,
+    /// Milliseconds of the timer periods the task has been running for.  The
+    /// idle task's are counted separately (see [TaskManager::idle_ms]).
+    ///
+    /// [TaskManager::idle_ms]: crate::task_manager::TaskManager::idle_ms
+    pub cpu_ms: u64,
+    /// Number of IRQ handlers the task was in when it was switched from.
+    pub irq_depth: u32,
+    /// Number of [sleeping locks](crate::sync::Mutex) the task holds.  A task
+    /// that holds any is not terminated while it waits, see
+    /// [TaskManager::kill_task].
+    ///
+    /// [TaskManager::kill_task]: crate::task_manager::TaskManager::kill_task
+    pub held_locks: usize,
+
+    state: TaskState,
+    /// ID of the process that forked the task's process, `None` for the
+    /// kernel tasks and the orphans left without a
+    /// [reaper](crate::task_manager::REAPER_TASK_ID).
+    pub parent_id: Option<usize>,
+    /// Set for the threads that are not detached, whose exit value is kept
+    /// until another thread of the process joins them.
+    pub joinable: bool,
+    /// Uptime in milliseconds at which a [sleeping](TaskState::Sleeping) task
+    /// is woken up.
+    pub wake_ms: u64,
 
     pub tcb: TaskControlBlock,
 }
@@ -63,23 +268,8 @@ impl Task {
     /// task switch to be successful, there must be certain items on the task's
     /// kernel stack (see [`crate::arch::task::Task::with_filled_stack()`]).
     pub fn with_empty_stack(id: usize, vas: VirtAddrSpace) -> Self {
-        let kernel_stack_layout = Layout::from_size_align(65536, 4096).unwrap();
-        let kernel_stack = Stack::with_layout(kernel_stack_layout);
-
-        let mut task = Task {
-            id,
-
-            vas,
-            mem_mappings: Vec::new(),
-            program_segments: Vec::new(),
-            kernel_stack,
-            usermode_stack: None,
-            tls: 0x00000000,
-
-            opened_files: Vec::new(),
-
-            tcb: TaskControlBlock::default(),
-        };
+        let process = Rc::new(UnsafeCell::new(Process::new(id, vas)));
+        let mut task = Self::with_process(id, process);
 
         // Open stdin, stdout, stderr.
         assert!(CONSOLE.lock().is_some());
@@ -101,69 +291,397 @@ impl Task {
             .unwrap()
             .path("/dev/chr0")
             .unwrap();
-        assert_eq!(task.open_file_by_node(stdin).unwrap(), 0);
-        assert_eq!(task.open_file_by_node(stdout).unwrap(), 1);
-        assert_eq!(task.open_file_by_node(stderr).unwrap(), 2);
+        let mode = FileMode::READ_WRITE;
+        assert_eq!(task.open_file_by_node(stdin, mode).unwrap(), 0);
+        assert_eq!(task.open_file_by_node(stdout, mode).unwrap(), 1);
+        assert_eq!(task.open_file_by_node(stderr, mode).unwrap(), 2);
 
         task
     }
 
+    /// Creates a task of `process` with an empty kernel stack.
+    fn with_process(id: usize, process: Rc<UnsafeCell<Process>>) -> Self {
+        // The lowest page of the kernel stack is a guard page.
+        let kernel_stack_layout =
+            Layout::from_size_align(65536 + 4096, 4096).unwrap();
+        let kernel_stack = Stack::with_layout(kernel_stack_layout);
+
+        let mut task = Task {
+            id,
+            name: [0; TASK_NAME_LEN],
+            created_ms: timer::uptime_ms(),
+
+            process,
+            kernel_stack,
+            usermode_stack: None,
+            tls: 0x00000000,
+            fpu_state: Box::new(FpuState::new()),
+
+            kill_status: None,
+
+            priority: PRIORITY_DEFAULT,
+            skipped_quanta: 0,
+            cpu_ms: 0,
+            irq_depth: 0,
+            held_locks: 0,
+
+            state: TaskState::Runnable,
+            parent_id: None,
+            joinable: false,
+            wake_ms: 0,
+
+            tcb: TaskControlBlock::default(),
+        };
+        unsafe {
+            task.paint_kernel_stack();
+            task.place_kernel_stack_guard();
+        }
+        task
+    }
+
+    /// Returns the name of the task for the logs and dumps, which is the
+    /// basename of its executable unless it has been renamed.  It is empty if
+    /// the task has not been named.
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(TASK_NAME_LEN);
+        str::from_utf8(&self.name[..len]).unwrap()
+    }
+
+    /// Sets the name of the task, truncated to [TASK_NAME_LEN] bytes.
+    pub fn set_name(&mut self, name: &str) {
+        let mut len = cmp::min(name.len(), TASK_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.name = [0; TASK_NAME_LEN];
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+
+    /// Prints the function of the program that the usermode address `addr`
+    /// belongs to, e.g. `in function main+0x41 of /bin/test-fork`, if it is
+    /// known.
+    pub fn print_user_symbol(&self, addr: usize) {
+        let symbols = match self.process().symbols.as_ref() {
+            Some(symbols) => symbols,
+            None => return,
+        };
+        if let Some((name, offset)) = symbols.symbolize(addr) {
+            println!(
+                " in function {}+0x{:X} of {}",
+                name, offset, symbols.path,
+            );
+        }
+    }
+
+    /// Returns the process the task is a thread of.
+    pub fn process(&self) -> &Process {
+        unsafe { &*self.process.get() }
+    }
+
+    pub fn process_mut(&mut self) -> &mut Process {
+        unsafe { &mut *self.process.get() }
+    }
+
+    /// Returns the ID of the task's process.
+    pub fn process_id(&self) -> usize {
+        self.process().id
+    }
+
+    /// Checks if the task is the only thread of its process.
+    pub fn is_only_thread(&self) -> bool {
+        Rc::strong_count(&self.process) == 1
+    }
+
+    /// Replaces the process of the task with a new one in `vas`, which
+    /// inherits the opened files and the children.  The other threads keep
+    /// the old one.
+    pub fn replace_process(&mut self, vas: VirtAddrSpace) {
+        let mut process = Process::new(self.process().id, vas);
+        process.opened_files = self.process().opened_files.clone();
+        process.child_ids = mem::take(&mut self.process_mut().child_ids);
+        self.process = Rc::new(UnsafeCell::new(process));
+    }
+
     /// Reads loadable ELF segments into memory from an executable.
+    ///
+    /// # Panics
+    /// This method panics if the executable cannot be loaded.
     pub unsafe fn load_from_file(&mut self, pathname: &str) -> ElfObj {
+        let (fd, elf) = self.open_executable(pathname).unwrap();
+        let loaded = self.load_executable(pathname, fd, &elf);
+        self.close_file(fd);
+        loaded.unwrap();
+        elf
+    }
+
+    /// Opens the executable `pathname` and parses its ELF headers, returns
+    /// the file descriptor and the parsed object.  Nothing is loaded yet, but
+    /// the task is renamed after the executable.
+    pub unsafe fn open_executable(
+        &mut self,
+        pathname: &str,
+    ) -> Result<(i32, ElfObj), LoadErr> {
         // FIXME: no syscalls here
 
         println!("[TASK] Loading from file {}.", pathname);
 
-        let fd = syscall::open(pathname).unwrap();
-        let elf = ElfObj::from(self.opened_file(fd)).unwrap();
+        let fd = syscall::open(pathname, syscall::OpenFlags::empty())
+            .map_err(LoadErr::OpenFailed)?;
+        let elf = match self.parse_executable(fd) {
+            Ok(elf) => elf,
+            Err(err) => {
+                println!("[TASK] Cannot execute {}: {:?}.", pathname, err);
+                syscall::close(fd).unwrap();
+                return Err(err);
+            }
+        };
+        self.set_name(pathname.rsplit('/').next().unwrap());
+        Ok((fd, elf))
+    }
+
+    /// Parses the ELF headers of the executable opened as `fd` and checks that
+    /// it can be loaded.  A position-independent one is placed at
+    /// [PIE_LOAD_BIAS].
+    unsafe fn parse_executable(&mut self, fd: i32) -> Result<ElfObj, LoadErr> {
+        let mut elf = ElfObj::from(self.opened_file(fd))
+            .map_err(LoadErr::NotExecutable)?;
+        if elf.position_independent {
+            elf.relocate(PIE_LOAD_BIAS)
+                .map_err(LoadErr::NotRelocatable)?;
+        }
+
+        let file = self.opened_file(fd);
+        let id_in_fs = file.node.0.borrow().id_in_fs.unwrap();
+        let file_size = file
+            .node
+            .fs()
+            .file_size_bytes(id_in_fs)
+            .map_err(|_| LoadErr::ReadFailed)?;
+        check_segments(&elf, file_size).map_err(LoadErr::InvalidSegment)?;
+
+        // Only checked here, they are applied once the segments are loaded.
+        elf.for_each_relocation(self.opened_file(fd), |_| {})
+            .map_err(LoadErr::NotRelocatable)?;
+        Ok(elf)
+    }
+
+    /// Reads the loadable segments of `elf` opened from `pathname` as `fd` into
+    /// memory, along with its function symbols.
+    pub unsafe fn load_executable(
+        &mut self,
+        pathname: &str,
+        fd: i32,
+        elf: &ElfObj,
+    ) -> Result<(), LoadErr> {
+        let node = self.opened_file(fd).node.clone();
 
         for segment in &elf.program_segments {
             let mem_reg =
                 Region::from_start_len(segment.in_mem_at, segment.in_mem_size);
 
-            self.program_segments.push(mem_reg);
+            self.process_mut().program_segments.push(mem_reg);
 
             if segment._type != ProgSegmentType::Load {
                 continue;
             }
 
-            assert!(mem_reg.is_in(&USERMODE_REGION));
-            assert!(!mem_reg.conflicts_with(&USERMODE_STACK_REGION));
-            // FIXME: check for conflicting with other regions?
-
-            if self.vas.pgtbl_virt_of(mem_reg.start as u32).is_null() {
-                let pde_idx = mem_reg.start >> 22;
-                let pgtbl_virt =
-                    alloc(Layout::from_size_align(4096, 4096).unwrap())
-                        as *mut Table;
-                pgtbl_virt.write_bytes(0, 1);
-                self.vas.set_pde_virt(pde_idx, pgtbl_virt);
+            // The segment is mapped starting with the page that contains its
+            // first byte, so the file offset must be shifted accordingly.
+            // check_segments() has made sure that they are congruent.
+            let head = segment.in_mem_at % 4096;
+            let mut map_start = segment.in_mem_at - head;
+            let mut file_offset = segment.in_file_at - head;
+            let mut file_size = head + segment.in_file_size;
+            let map_end = (mem_reg.end + 0xFFF) & !0xFFF;
+
+            // If the first page is shared with the previous segment, it is
+            // already mapped.  Read this segment's part of it right away, after
+            // committing the page so that the read does not fault.
+            if self.mem_mapping_at(map_start).is_some() {
+                self.commit_lazy_page(map_start as u32);
+                let shared_end = cmp::min(map_start + 4096, mem_reg.end);
+                let in_file = cmp::min(
+                    shared_end - segment.in_mem_at,
+                    segment.in_file_size,
+                );
+                let buf = slice::from_raw_parts_mut(
+                    segment.in_mem_at as *mut u8,
+                    in_file,
+                );
+                syscall::seek(syscall::Seek::Abs, fd, segment.in_file_at)
+                    .unwrap();
+                syscall::read(fd, buf).map_err(|_| LoadErr::ReadFailed)?;
+                ((segment.in_mem_at + in_file) as *mut u8)
+                    .write_bytes(0, shared_end - segment.in_mem_at - in_file);
+
+                map_start += 4096;
+                file_offset += 4096;
+                file_size = file_size.saturating_sub(4096);
+            }
+
+            if map_start < map_end {
+                let len = map_end - map_start;
+                self.mem_map_file_at(
+                    map_start,
+                    len,
+                    node.clone(),
+                    file_offset,
+                    cmp::min(file_size, len),
+                    true,
+                );
             }
 
-            for virt_page in
-                mem_reg.align_boundaries_at(4096).range().step_by(4096)
+            // Zero .bss in the page where the file content ends.  The page is
+            // committed right away, so that the zeroing does not depend on how
+            // much of the page the file read fills in.  The pages past it are
+            // zeroed when they are committed.
+            let file_end = segment.in_mem_at + segment.in_file_size;
+            if file_end < mem_reg.end
+                && file_end % 4096 != 0
+                && file_end >= map_start
             {
-                if self.vas.virt_to_phys(virt_page as u32).is_none() {
-                    let phys_page = PMM_STACK.lock().pop_page();
-                    self.vas.map_page(virt_page as u32, phys_page);
-                    (virt_page as *mut u8).write_bytes(0, 4096);
-                }
+                self.commit_lazy_page(file_end as u32 & !0xFFF);
+                let zero_end =
+                    cmp::min((file_end + 0xFFF) & !0xFFF, mem_reg.end);
+                (file_end as *mut u8).write_bytes(0, zero_end - file_end);
             }
+        }
 
-            let buf = slice::from_raw_parts_mut(
-                mem_reg.start as *mut u8,
-                segment.in_file_size as usize,
+        // The program break starts right after the highest program segment.
+        let process = self.process_mut();
+        let segments_end = process
+            .program_segments
+            .iter()
+            .map(|segment| segment.end)
+            .max()
+            .unwrap_or(USERMODE_REGION.start);
+        process.heap_start = (segments_end + 0xFFF) & !0xFFF;
+        process.heap_end = process.heap_start;
+
+        let mut num_relocations = 0;
+        elf.for_each_relocation(self.opened_file(fd), |relocation| {
+            num_relocations += 1;
+            match relocation {
+                Relocation::Add { at, value } => {
+                    let word = at as *mut u32;
+                    word.write_unaligned(
+                        word.read_unaligned().wrapping_add(value as u32),
+                    );
+                }
+                Relocation::Set { at, value } => {
+                    (at as *mut u32).write_unaligned(value as u32);
+                }
+            }
+        })
+        .map_err(LoadErr::NotRelocatable)?;
+        if num_relocations != 0 {
+            println!(
+                "[TASK] Applied {} relocations at 0x{:08X}.",
+                num_relocations, elf.load_bias,
             );
-            syscall::seek(syscall::Seek::Abs, fd, segment.in_file_at).unwrap();
-            syscall::read(fd, buf).unwrap();
         }
 
+        let tls = elf
+            .program_segments
+            .iter()
+            .find(|segment| segment._type == ProgSegmentType::Tls);
+        if let Some(tls) = tls {
+            self.set_up_tls_block(tls)?;
+        }
+
+        // The segments are filled in and relocated, so the read-only ones can
+        // be protected now.
+        self.protect_read_only_segments(elf);
+
+        let symbols = SymbolTable::read(elf, self.opened_file(fd), pathname);
+        if symbols.is_none() {
+            println!("[TASK] No function symbols in {}.", pathname);
+        }
+        self.process_mut().symbols = symbols.map(Rc::new);
+
         println!(
             "[TASK] Program entry point is at 0x{:08X}.",
             elf.entry_point,
         );
 
-        elf
+        Ok(())
+    }
+
+    /// Makes the pages of the loadable segments without PF_W read-only.  A
+    /// page shared with a writable segment, e.g. where .rodata ends and .data
+    /// starts, stays writable.
+    unsafe fn protect_read_only_segments(&mut self, elf: &ElfObj) {
+        let pages_of = |segment: &ProgSegment| {
+            Region::from_start_len(segment.in_mem_at, segment.in_mem_size)
+                .align_boundaries_at(4096)
+        };
+        let loaded = elf.program_segments.iter().filter(|segment| {
+            segment._type == ProgSegmentType::Load && segment.in_mem_size != 0
+        });
+        let writable_pages: Vec<Region<usize>> = loaded
+            .clone()
+            .filter(|segment| segment.writable)
+            .map(pages_of)
+            .collect();
+
+        let vas = &self.process().vas;
+        for segment in loaded.filter(|segment| !segment.writable) {
+            for page in pages_of(segment).range().step_by(4096) {
+                if writable_pages.iter().any(|pages| pages.contains(&page)) {
+                    println!(
+                        "[TASK] Page 0x{:08X} is shared with a writable \
+                         segment, leaving it writable.",
+                        page,
+                    );
+                    continue;
+                }
+                vas.set_protection(
+                    page as u32,
+                    page as u32 + 4096,
+                    false,
+                    true,
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    /// Maps the initial TLS block of the program described by its PT_TLS
+    /// segment `tls` and points the TLS segment to it.
+    ///
+    /// The block is laid out as on i386 (variant II): the TLS data ends at the
+    /// thread pointer, which points to a thread control block that starts with
+    /// the thread pointer itself.
+    unsafe fn set_up_tls_block(
+        &mut self,
+        tls: &ProgSegment,
+    ) -> Result<(), LoadErr> {
+        let (data_len, len) =
+            tls_block_layout(tls).map_err(LoadErr::InvalidSegment)?;
+
+        // The anonymous mapping is zeroed, which takes care of .tbss.
+        let start = self
+            .mem_map(None, len)
+            .map_err(|_| LoadErr::NoRoomForTls)?
+            .region
+            .start;
+        ptr::copy_nonoverlapping(
+            tls.in_mem_at as *const u8,
+            start as *mut u8,
+            tls.in_file_size,
+        );
+        let thread_ptr = start + data_len;
+        (thread_ptr as *mut u32).write(thread_ptr as u32);
+        self.set_tls(thread_ptr);
+        println!(
+            "[TASK] TLS block is at 0x{:08X}, thread pointer 0x{:08X}.",
+            start, thread_ptr,
+        );
+        Ok(())
     }
 
     /// Clones the task.
@@ -172,87 +690,354 @@ impl Task {
     /// * virtual address space layout (physical memory is copied),
     /// * program segments,
     /// * memory mappings,
+    /// * program break,
     /// * usermode stack,
+    /// * thread local storage pointer,
     /// * opened files.
     ///
     /// What is not cloned:
-    /// * task ID,
-    /// * guard pages,
+    /// * task and process IDs, the clone's are both `clone_id`,
     /// * kernel stack,
-    /// * thread local storage pointer.
+    /// * child processes, the task's process becomes the parent of the clone
+    ///   instead.
+    ///
+    /// The opened files share their offsets with the originals.  The kernel
+    /// stack of the clone is empty, it must be
+    /// [filled](Task::fill_kernel_stack) before the clone is scheduled.
     ///
-    /// # Safety
-    /// See [`Task::with_filled_stack()`].
-    pub fn clone(
-        &self,
-        clone_id: usize,
-        entry: u32,
-        entry_args: &[u32],
-    ) -> Self {
+    /// Returns `None` if there is not enough physical memory to copy the VAS.
+    pub fn clone(&self, clone_id: usize) -> Option<Self> {
         print!("[TASK] Copying VAS...");
-        let vas = unsafe { self.vas.copy() };
+        let process = self.process();
+        let vas = match unsafe { process.vas.copy() } {
+            Some(vas) => vas,
+            None => {
+                println!("failed");
+                return None;
+            }
+        };
         println!("done");
 
+        let copy = Process {
+            id: clone_id,
+            vas,
+            program_segments: process.program_segments.clone(),
+            mem_mappings: process.mem_mappings.clone(),
+            heap_start: process.heap_start,
+            heap_end: process.heap_end,
+            mem_lock: Mutex::new(()),
+
+            opened_files: process.opened_files.clone(),
+            child_ids: Vec::new(),
+            symbols: process.symbols.clone(),
+            exit_status: None,
+        };
         let mut clone =
-            Self::with_filled_stack(clone_id, vas, entry, entry_args);
-        clone.mem_mappings = self.mem_mappings.clone();
-        clone
+            Self::with_process(clone_id, Rc::new(UnsafeCell::new(copy)));
+        clone.name = self.name;
+        clone.tls = self.tls;
+        // The task is the running one, so its FPU state is in the registers.
+        clone.fpu_state.save();
+        clone.priority = self.priority;
+        clone.parent_id = Some(process.id);
+        Some(clone)
     }
 
+    /// Creates a thread of the task's process.  It shares the memory and the
+    /// opened files with the task, but has its own kernel stack and no TLS
+    /// block until it sets one.  The usermode stack is up to the caller.
+    ///
+    /// The kernel stack of the thread is empty, it must be
+    /// [filled](Task::fill_kernel_stack) before the thread is scheduled.
+    pub fn thread(&self, thread_id: usize) -> Self {
+        let mut thread =
+            Self::with_process(thread_id, Rc::clone(&self.process));
+        thread.name = self.name;
+        thread.priority = self.priority;
+        thread.parent_id = self.parent_id;
+        thread
+    }
+
+    /// Returns the priority the scheduler picks the task with.
+    ///
+    /// A task gets one level higher every [AGING_QUANTA] times it is passed
+    /// over, so that it is not starved by the tasks of higher priorities.
+    /// The idle tasks do not age, they run only when nothing else can.
+    pub fn effective_priority(&self) -> u8 {
+        if self.priority == PRIORITY_IDLE {
+            return PRIORITY_IDLE;
+        }
+        let boost = (self.skipped_quanta / AGING_QUANTA).min(u8::MAX as usize);
+        self.priority.saturating_sub(boost as u8)
+    }
+
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+
+    /// Changes the state of the task.  Only the task manager does this, when
+    /// it moves the task to the list of the new state.
+    ///
+    /// # Panics
+    /// This function panics if the task cannot go to `state` from its current
+    /// state.
+    pub fn set_state(&mut self, state: TaskState) {
+        assert!(
+            self.state.can_become(state),
+            "task ID {} cannot go from {:?} to {:?}",
+            self.id,
+            self.state,
+            state,
+        );
+        self.state = state;
+    }
+
+    /// Opens `node` with the lowest free file descriptor and returns it.
+    ///
+    /// A directory can be opened only for reading, and cannot actually be
+    /// read or written.
     pub fn open_file_by_node(
         &mut self,
         node: fs::Node,
+        mode: FileMode,
     ) -> Result<i32, OpenFileErr> {
-        let file_type = node.0.borrow()._type.clone();
-        if file_type == fs::NodeType::RegularFile
-            || file_type == fs::NodeType::BlockDevice
-            || file_type == fs::NodeType::CharDevice
-        {
-            if self.opened_files.len() == MAX_OPENED_FILES {
-                return Err(OpenFileErr::MaxOpenedFiles);
+        if node.is_dir() && mode.writable {
+            return Err(OpenFileErr::IsDir);
+        }
+        let seekable = node.0.borrow()._type.is_seekable();
+        let opened_files = &mut self.process_mut().opened_files;
+        let file = Some(OpenedFile::new(node, mode, seekable));
+        match opened_files.iter().position(|file| file.is_none()) {
+            Some(fd) => {
+                opened_files[fd] = file;
+                Ok(fd as i32)
             }
-            let fd = self.opened_files.len() as i32;
-            self.opened_files
-                .push(OpenedFile::new(node.clone(), file_type.is_seekable()));
-            Ok(fd)
-        } else {
-            Err(OpenFileErr::UnsupportedFileType)
+            None if opened_files.len() < MAX_OPENED_FILES => {
+                opened_files.push(file);
+                Ok(opened_files.len() as i32 - 1)
+            }
+            None => Err(OpenFileErr::MaxOpenedFiles),
         }
     }
 
+    /// Checks if `count` more files can be opened.
+    pub fn has_free_fds(&self, count: usize) -> bool {
+        let opened_files = &self.process().opened_files;
+        let closed = opened_files.iter().filter(|file| file.is_none()).count();
+        closed + MAX_OPENED_FILES - opened_files.len() >= count
+    }
+
+    /// Closes the file descriptor `fd`, returns `false` if it is not open.
+    pub fn close_file(&mut self, fd: i32) -> bool {
+        if !self.check_fd(fd) {
+            return false;
+        }
+        let opened_files = &mut self.process_mut().opened_files;
+        opened_files[fd as usize] = None;
+        while let Some(None) = opened_files.last() {
+            opened_files.pop();
+        }
+        true
+    }
+
+    /// Returns the file opened as `fd`.
+    ///
+    /// # Panics
+    /// This method panics if `fd` is not open, see [Task::check_fd].
     pub fn opened_file(&mut self, fd: i32) -> &mut OpenedFile {
-        &mut self.opened_files[fd as usize]
+        self.process_mut().opened_files[fd as usize]
+            .as_mut()
+            .expect("fd is not open")
     }
 
     pub fn check_fd(&self, fd: i32) -> bool {
-        return 0 <= fd && fd < self.opened_files.len() as i32;
+        let opened_files = &self.process().opened_files;
+        0 <= fd
+            && (fd as usize) < opened_files.len()
+            && opened_files[fd as usize].is_some()
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        unsafe {
+            self.remove_kernel_stack_guard();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadErr {
+    OpenFailed(syscall::OpenErr),
+    NotExecutable(ElfObjErr),
+    InvalidSegment(ElfLoadErr),
+    NotRelocatable(DynamicErr),
+    ReadFailed,
+    /// There is no free region for the TLS block.
+    NoRoomForTls,
+}
+
+/// Why the program segments of an executable cannot be loaded.
+#[derive(Debug)]
+pub enum ElfLoadErr {
+    /// A segment does not lie within [USERMODE_REGION].
+    OutsideUsermode,
+    /// A segment has more bytes in the file than in memory.
+    FileSizeExceedsMemSize,
+    /// A segment extends past the end of the file.
+    BeyondFileEnd,
+    /// A segment's file offset and address differ modulo the page size.
+    MisalignedOffset,
+    /// A loadable segment overlaps another one or
+    /// [USERMODE_STACK_LIMIT_REGION].
+    Overlapping,
+    /// The TLS segment's alignment is not a power of two or is above the page
+    /// size.
+    InvalidTlsAlignment,
+    /// The TLS block would be larger than [MAX_TLS_BLOCK_SIZE].
+    TlsTooLarge,
+}
+
+/// Returns the length of the TLS data in the TLS block for the segment `tls`
+/// and the page-aligned length of the block.
+fn tls_block_layout(tls: &ProgSegment) -> Result<(usize, usize), ElfLoadErr> {
+    let align = cmp::max(tls.align, size_of::<u32>());
+    if !align.is_power_of_two() || align > 4096 {
+        return Err(ElfLoadErr::InvalidTlsAlignment);
+    }
+    if tls.in_mem_size > MAX_TLS_BLOCK_SIZE {
+        return Err(ElfLoadErr::TlsTooLarge);
+    }
+    let data_len = (tls.in_mem_size + align - 1) & !(align - 1);
+    let len = (data_len + TLS_TCB_SIZE + 0xFFF) & !0xFFF;
+    if len > MAX_TLS_BLOCK_SIZE {
+        return Err(ElfLoadErr::TlsTooLarge);
+    }
+    Ok((data_len, len))
+}
+
+/// Checks the program segments of `elf`, whose file is `file_size` bytes long,
+/// against the usermode memory layout, so that a malformed executable is
+/// rejected before anything is mapped.
+fn check_segments(elf: &ElfObj, file_size: usize) -> Result<(), ElfLoadErr> {
+    let mut loaded: Vec<Region<usize>> = Vec::new();
+    for segment in &elf.program_segments {
+        let mem_end = segment
+            .in_mem_at
+            .checked_add(segment.in_mem_size)
+            .ok_or(ElfLoadErr::OutsideUsermode)?;
+        let mem_reg = Region {
+            start: segment.in_mem_at,
+            end: mem_end,
+        };
+        if !mem_reg.is_in(&USERMODE_REGION) {
+            return Err(ElfLoadErr::OutsideUsermode);
+        }
+        if segment.in_file_size > segment.in_mem_size {
+            return Err(ElfLoadErr::FileSizeExceedsMemSize);
+        }
+        match segment.in_file_at.checked_add(segment.in_file_size) {
+            Some(file_end) if file_end <= file_size => {}
+            _ => return Err(ElfLoadErr::BeyondFileEnd),
+        }
+
+        if segment._type == ProgSegmentType::Tls {
+            tls_block_layout(segment)?;
+        }
+
+        // The TLS initialization image lies within a loadable segment.
+        if segment._type != ProgSegmentType::Load
+            || mem_reg.start == mem_reg.end
+        {
+            continue;
+        }
+        if segment.in_file_at % 4096 != segment.in_mem_at % 4096 {
+            return Err(ElfLoadErr::MisalignedOffset);
+        }
+        if mem_reg.conflicts_with(&USERMODE_STACK_LIMIT_REGION)
+            || loaded.iter().any(|other| mem_reg.conflicts_with(other))
+        {
+            return Err(ElfLoadErr::Overlapping);
+        }
+        loaded.push(mem_reg);
     }
+    Ok(())
 }
 
 #[derive(Debug)]
 pub enum OpenFileErr {
     MaxOpenedFiles,
-    UnsupportedFileType,
+    IsDir,
+}
+
+/// How an [OpenedFile] may be used.
+#[derive(Clone, Copy, Debug)]
+pub struct FileMode {
+    pub readable: bool,
+    pub writable: bool,
+    /// Every write goes to the end of the file.
+    pub append: bool,
 }
 
-#[derive(Clone)]
+impl FileMode {
+    pub const READ_ONLY: FileMode = FileMode {
+        readable: true,
+        writable: false,
+        append: false,
+    };
+    pub const WRITE_ONLY: FileMode = FileMode {
+        readable: false,
+        writable: true,
+        append: false,
+    };
+    pub const READ_WRITE: FileMode = FileMode {
+        readable: true,
+        writable: true,
+        append: false,
+    };
+}
+
+/// File opened by a task.  A clone of it, e.g. in a forked task, shares the
+/// offset with it.
+///
+/// The file system is told when the file is opened, cloned and dropped (see
+/// [FileSystem::file_opened]).
+///
+/// [FileSystem::file_opened]: fs::FileSystem::file_opened
 pub struct OpenedFile {
     pub node: fs::Node,
-    offset: Option<usize>,
+    pub mode: FileMode,
+    offset: Option<Rc<Cell<usize>>>,
 }
 
 impl OpenedFile {
-    fn new(node: fs::Node, seekable: bool) -> Self {
+    fn new(node: fs::Node, mode: FileMode, seekable: bool) -> Self {
+        node.fs().file_opened(node.0.borrow().id_in_fs.unwrap());
         OpenedFile {
             node,
-            offset: if seekable { Some(0) } else { None },
+            mode,
+            offset: if seekable {
+                Some(Rc::new(Cell::new(0)))
+            } else {
+                None
+            },
         }
     }
 
+    /// Returns the current offset, which is always 0 if the file is not
+    /// [seekable](Self::is_seekable).
+    pub fn offset(&self) -> usize {
+        self.offset.as_ref().map_or(0, |offset| offset.get())
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        self.offset.is_some()
+    }
+
     pub fn seek_abs(&mut self, new_offset: usize) -> usize {
-        if let Some(offset) = self.offset.as_mut() {
-            *offset = new_offset;
-            return *offset;
+        if let Some(offset) = self.offset.as_ref() {
+            offset.set(new_offset);
+            return new_offset;
         } else {
             // FIXME: error 'not seekable'.
             return 0;
@@ -260,30 +1045,74 @@ impl OpenedFile {
     }
 
     pub fn seek_rel(&mut self, add_offset: usize) -> usize {
-        if let Some(offset) = self.offset.as_mut() {
-            *offset += add_offset;
-            return *offset;
+        if let Some(offset) = self.offset.as_ref() {
+            offset.set(offset.get() + add_offset);
+            return offset.get();
         } else {
             // FIXME: error 'not seekable'.
             return 0;
         }
     }
 
+    /// Reads into `buf` from the current offset and advances it, returns the
+    /// number of bytes read.  A read from a regular file stops at its end, so
+    /// it may be short and returns 0 at the end.
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, fs::ReadFileErr> {
         let fs = self.node.fs();
         let id_in_fs = self.node.0.borrow().id_in_fs.unwrap();
-        let n = fs.read_file(id_in_fs, self.offset.unwrap_or(0), buf)?;
+        let offset = self.offset();
+        let mut len = buf.len();
+        if self.node.0.borrow()._type == fs::NodeType::RegularFile {
+            let size = fs.file_size_bytes(id_in_fs)?;
+            len = cmp::min(len, size.saturating_sub(offset));
+        }
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = fs.read_file(id_in_fs, offset, &mut buf[..len])?;
         self.seek_rel(n);
         Ok(n)
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> usize {
+    /// Writes `buf` at the current offset, or at the end of the file in the
+    /// [append](FileMode::append) mode, and advances it, returns the number of
+    /// bytes written.  A write to a pipe may be short.
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, fs::WriteFileErr> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
         let fs = self.node.fs();
         let id_in_fs = self.node.0.borrow().id_in_fs.unwrap();
-        fs.write_file(id_in_fs, self.offset.unwrap_or(0), buf)
-            .unwrap();
-        self.seek_rel(buf.len());
-        buf.len()
+        if self.mode.append {
+            let size = fs
+                .file_size_bytes(id_in_fs)
+                .map_err(|_| fs::WriteFileErr::NotWritable)?;
+            self.seek_abs(size);
+        }
+        let n = fs.write_file(id_in_fs, self.offset(), buf)?;
+        self.seek_rel(n);
+        Ok(n)
+    }
+}
+
+impl Clone for OpenedFile {
+    fn clone(&self) -> Self {
+        self.node
+            .fs()
+            .file_opened(self.node.0.borrow().id_in_fs.unwrap());
+        OpenedFile {
+            node: self.node.clone(),
+            mode: self.mode,
+            offset: self.offset.clone(),
+        }
+    }
+}
+
+impl Drop for OpenedFile {
+    fn drop(&mut self) {
+        self.node
+            .fs()
+            .file_closed(self.node.0.borrow().id_in_fs.unwrap());
     }
 }
 
@@ -15,26 +15,70 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use alloc::collections::vec_deque::VecDeque;
+use alloc::vec::Vec;
+use core::cmp;
+use core::fmt;
+use core::mem;
 use core::sync::atomic::{AtomicU32, Ordering};
 
 use crate::arch::task::default_entry_point;
+use crate::dev::console;
+use crate::dev::timer;
 use crate::dev::timer::TIMER;
 
 use crate::arch;
+use crate::arch::task::TaskControlBlock;
 use crate::arch::vas::VirtAddrSpace;
-use crate::task::Task;
+use crate::kernel_static::Mutex;
+use crate::task::{Task, TaskState, PRIORITY_IDLE};
 
 /// A counter used by the scheduler to count the number of tasks that want the
 /// interrupts to be disabled in order to perform their critical stuff.
 pub static NO_SCHED_COUNTER: AtomicU32 = AtomicU32::new(0);
 
+/// Exit status of a task interrupted with Ctrl+C.
+pub const STATUS_INTERRUPTED: i32 = 130;
+/// Exit status of a task killed with Ctrl+\\.
+pub const STATUS_KILLED: i32 = 137;
+
+/// ID of the process that the orphaned processes are handed over to, the
+/// first usermode program.  It is also the ID of its first thread.
+pub const REAPER_TASK_ID: usize = 1;
+
+/// Exit status of a reaped process that its parent has not waited for yet.
+struct ExitedTask {
+    /// Process ID.
+    id: usize,
+    /// Parent process ID.
+    parent_id: usize,
+    status: i32,
+}
+
+/// Exit value of a reaped joinable thread that has not been joined yet.
+struct ExitedThread {
+    id: usize,
+    process_id: usize,
+    value: i32,
+}
+
 pub struct TaskManager {
     counter_ms: u64,
 
     running_task: Option<Task>,
     runnable_tasks: Option<VecDeque<Task>>,
     blocked_tasks: Option<VecDeque<Task>>,
+    /// Tasks sleeping in [sleep_ms], the earliest to wake up first.
+    sleeping_tasks: Vec<Task>,
     terminated_tasks: Option<VecDeque<(Task, i32)>>,
+    exited_tasks: Vec<ExitedTask>,
+    exited_threads: Vec<ExitedThread>,
+
+    /// ID of the task that runs only when no other task is runnable.
+    idle_task_id: Option<usize>,
+    /// The idle task while it is not running, it is never in the other lists.
+    idle_task: Option<Task>,
+    /// Milliseconds of the timer periods the idle task has been running for.
+    idle_ms: u64,
 
     new_task_id: usize,
 }
@@ -47,7 +91,14 @@ impl TaskManager {
             running_task: None,
             runnable_tasks: None,
             blocked_tasks: None,
+            sleeping_tasks: Vec::new(),
             terminated_tasks: None,
+            exited_tasks: Vec::new(),
+            exited_threads: Vec::new(),
+
+            idle_task_id: None,
+            idle_task: None,
+            idle_ms: 0,
 
             new_task_id: 0,
         }
@@ -62,6 +113,8 @@ impl TaskManager {
         self.terminated_tasks = Some(VecDeque::new());
     }
 
+    /// Allocates an ID for a new task, which is also the process ID if the task
+    /// is the first thread of a process (see [Process](crate::task::Process)).
     pub fn allocate_task_id(&mut self) -> usize {
         let id = self.new_task_id;
         self.new_task_id += 1;
@@ -72,49 +125,599 @@ impl TaskManager {
         self.running_task.as_mut().unwrap()
     }
 
-    pub fn run_task(&mut self, task: Task) {
+    /// Returns the running task, or `None` if the task manager has not been
+    /// initialized yet.
+    pub fn running_task(&mut self) -> Option<&mut Task> {
+        self.running_task.as_mut()
+    }
+
+    pub fn run_task(&mut self, mut task: Task) {
+        task.set_state(TaskState::Running);
         unsafe {
             task.load_tls();
         }
+        task.fpu_state.restore();
+        arch::interrupts::set_irq_depth(task.irq_depth);
         self.running_task = Some(task);
     }
 
+    /// Makes the running task the idle task.  It gets the idle priority and is
+    /// switched to only when there is no other runnable task.
+    pub fn set_idle_task(&mut self) {
+        assert!(self.idle_task_id.is_none(), "there is an idle task already");
+        let task = self.this_task();
+        task.set_name("idle");
+        task.priority = PRIORITY_IDLE;
+        self.idle_task_id = Some(task.id);
+    }
+
+    /// Checks if the task with the ID `task_id` is the idle task.
+    pub fn is_idle_task(&self, task_id: usize) -> bool {
+        self.idle_task_id == Some(task_id)
+    }
+
+    fn is_idle_running(&self) -> bool {
+        self.running_task
+            .as_ref()
+            .map_or(false, |task| self.is_idle_task(task.id))
+    }
+
+    /// Adds the timer period of `ms` milliseconds to the CPU time of the
+    /// running task.
+    pub fn account_cpu_time(&mut self, ms: u64) {
+        if self.is_idle_running() {
+            self.idle_ms += ms;
+        } else if let Some(task) = self.running_task.as_mut() {
+            task.cpu_ms += ms;
+        }
+    }
+
+    /// Returns the number of milliseconds the CPU has been idle for.
+    pub fn idle_ms(&self) -> u64 {
+        self.idle_ms
+    }
+
     pub fn add_runnable_task(&mut self, task: Task) {
         self.runnable_tasks.as_mut().unwrap().push_back(task);
+        self.reserve_for_unblocking();
+    }
+
+    /// Makes room in the runnable queue for every blocked and sleeping task,
+    /// so that [unblock_task](Self::unblock_task) does not allocate in an
+    /// interrupt handler.
+    fn reserve_for_unblocking(&mut self) {
+        let num_waiting = self.blocked_tasks.as_ref().unwrap().len()
+            + self.sleeping_tasks.len();
+        self.runnable_tasks.as_mut().unwrap().reserve(num_waiting);
+    }
+
+    /// Returns the index of the runnable task of the highest effective
+    /// priority, the longest waiting one among the equal ones.
+    fn best_runnable_idx(&self) -> Option<usize> {
+        let runnable_tasks = self.runnable_tasks.as_ref().unwrap();
+        let mut best: Option<(usize, u8)> = None;
+        for (idx, task) in runnable_tasks.iter().enumerate() {
+            let priority = task.effective_priority();
+            if best.map_or(true, |(_, best_priority)| priority < best_priority)
+            {
+                best = Some((idx, priority));
+            }
+        }
+        best.map(|(idx, _)| idx)
     }
 
+    /// Takes the runnable task of the highest effective priority.  The other
+    /// runnable tasks age.  If there are none, takes the idle task.
     pub fn next_runnable_task(&mut self) -> Task {
-        self.runnable_tasks.as_mut().unwrap().pop_front().unwrap()
+        let idx = match self.best_runnable_idx() {
+            Some(idx) => idx,
+            None => return self.idle_task.take().expect("no task to run"),
+        };
+        let runnable_tasks = self.runnable_tasks.as_mut().unwrap();
+        let mut task = runnable_tasks.remove(idx).unwrap();
+        task.skipped_quanta = 0;
+        for other in runnable_tasks.iter_mut() {
+            other.skipped_quanta += 1;
+        }
+        task
+    }
+
+    /// Checks if the running task has a higher priority than any runnable
+    /// one, i.e. if it should keep running instead of being preempted.
+    fn running_task_has_priority(&self) -> bool {
+        let running = match self.running_task.as_ref() {
+            Some(task) => task.effective_priority(),
+            None => return false,
+        };
+        if self.is_idle_running() {
+            return self.best_runnable_idx().is_none();
+        }
+        let runnable_tasks = self.runnable_tasks.as_ref().unwrap();
+        match self.best_runnable_idx() {
+            Some(idx) => running < runnable_tasks[idx].effective_priority(),
+            None => true,
+        }
+    }
+
+    /// Sets the priority of the task with the ID `task_id`, returns `false`
+    /// if there is no such task or it is the idle task.
+    ///
+    /// # Panics
+    /// This method panics if `priority` is not a valid priority.
+    pub fn set_priority(&mut self, task_id: usize, priority: u8) -> bool {
+        assert!(priority <= PRIORITY_IDLE, "invalid priority");
+        if self.is_idle_task(task_id) {
+            return false;
+        }
+        match self.find_task(task_id) {
+            Some(task) => {
+                task.priority = priority;
+                true
+            }
+            None => false,
+        }
     }
 
+    /// Blocks the running task until it is unblocked.
+    ///
+    /// If there is no task to switch to, e.g. the scheduler has not started
+    /// yet, this waits for an interrupt instead and returns, so the caller must
+    /// check again whether it still has to wait.
     pub fn block_this_task(&mut self) {
-        self.schedule(0, false);
+        if self.can_switch() {
+            self.switch_from_this_task(TaskState::Blocked);
+        } else {
+            arch::interrupts::wait_for_interrupt();
+        }
     }
 
-    pub fn unblock_task(&mut self, task_id: usize) {
-        let idx = self
-            .blocked_tasks
-            .as_ref()
+    /// Checks if the running task can be switched from.
+    fn can_switch(&self) -> bool {
+        self.running_task.is_some()
+            && NO_SCHED_COUNTER.load(Ordering::SeqCst) == 0
+            && self.has_next_task()
+    }
+
+    /// Checks if there is a task to switch to, which is always the case once
+    /// the idle task exists, unless it is the running one.
+    fn has_next_task(&self) -> bool {
+        self.idle_task.is_some()
+            || self
+                .runnable_tasks
+                .as_ref()
+                .map_or(false, |tasks| !tasks.is_empty())
+    }
+
+    /// Lets the next runnable task run, the running task stays runnable.
+    pub fn yield_this_task(&mut self) {
+        if self.running_task.is_some() {
+            self.schedule(0, true);
+        }
+    }
+
+    /// Blocks the running task until the uptime reaches `wake_ms`.  Returns
+    /// early if the task is killed.
+    fn sleep_this_task(&mut self, wake_ms: u64) {
+        while timer::uptime_ms() < wake_ms
+            && self.this_task().kill_status.is_none()
+        {
+            if self.can_switch() {
+                self.this_task().wake_ms = wake_ms;
+                self.switch_from_this_task(TaskState::Sleeping);
+            } else {
+                arch::interrupts::wait_for_interrupt();
+            }
+        }
+    }
+
+    /// Makes the sleeping tasks whose wake-up time has come runnable.
+    pub fn wake_sleeping_tasks(&mut self) {
+        let now = timer::uptime_ms();
+        while self
+            .sleeping_tasks
+            .first()
+            .map_or(false, |task| task.wake_ms <= now)
+        {
+            let mut task = self.sleeping_tasks.remove(0);
+            task.set_state(TaskState::Runnable);
+            self.runnable_tasks.as_mut().unwrap().push_front(task);
+        }
+    }
+
+    /// Returns the task with the ID `task_id` unless it has terminated.
+    fn find_task(&mut self, task_id: usize) -> Option<&mut Task> {
+        self.running_task
+            .iter_mut()
+            .chain(self.runnable_tasks.iter_mut().flatten())
+            .chain(self.blocked_tasks.iter_mut().flatten())
+            .chain(self.sleeping_tasks.iter_mut())
+            .chain(self.idle_task.iter_mut())
+            .find(|task| task.id == task_id)
+    }
+
+    /// Returns all tasks, including the terminated ones.
+    pub fn tasks(&self) -> impl Iterator<Item = &Task> {
+        self.running_task
+            .iter()
+            .chain(self.runnable_tasks.iter().flatten())
+            .chain(self.blocked_tasks.iter().flatten())
+            .chain(self.sleeping_tasks.iter())
+            .chain(self.idle_task.iter())
+            .chain(self.terminated_tasks.iter().flatten().map(|(task, _)| task))
+    }
+
+    /// Writes a line per task with its ID, process ID, name, parent process ID,
+    /// state, priority, CPU time, saved kernel ESP and EIP and the kernel stack
+    /// usage, then the idle time.
+    ///
+    /// This does not lock or allocate anything, so it can be used in an
+    /// interrupt handler, but the lists may be in the middle of a change then.
+    pub fn write_tasks(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            w,
+            " TID   PID  NAME              PPID  STATE     PRIO  CPU ms  \
+             ESP       EIP       STACK",
+        )?;
+        for task in self.tasks() {
+            let state = task.state();
+            write!(
+                w,
+                "{:>4}  {:>4}  {:<16}",
+                task.id,
+                task.process_id(),
+                task.name(),
+            )?;
+            match task.parent_id {
+                Some(parent_id) => write!(w, "  {:>4}", parent_id)?,
+                None => write!(w, "  {:>4}", "-")?,
+            }
+            write!(
+                w,
+                "  {:<8}  {:>4}  {:>6}",
+                state.name(),
+                task.priority,
+                task.cpu_ms,
+            )?;
+            // The running task's registers are not saved on its stack.
+            if state == TaskState::Running {
+                write!(w, "  {:<8}  {:<8}", "-", "-")?;
+            } else {
+                write!(
+                    w,
+                    "  {:08X}  {:08X}",
+                    task.kernel_stack.top as usize,
+                    unsafe { task.saved_eip() },
+                )?;
+            }
+            let (used, size) = task.kernel_stack_usage();
+            writeln!(w, "  {}/{}", used, size)?;
+        }
+        writeln!(w, "Idle: {} ms", self.idle_ms)
+    }
+
+    /// Prints the [task list](Self::write_tasks) without waiting for the
+    /// screen lock.
+    pub fn dump(&self) {
+        struct TryPrinter;
+
+        impl fmt::Write for TryPrinter {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                try_print!("{}", s);
+                Ok(())
+            }
+        }
+
+        let _ = self.write_tasks(&mut TryPrinter);
+    }
+
+    /// Checks if the task with the ID `task_id` exists and has not terminated.
+    pub fn has_task(&mut self, task_id: usize) -> bool {
+        self.find_task(task_id).is_some()
+    }
+
+    /// Removes a blocked or sleeping task from its list.
+    fn take_waiting_task(&mut self, task_id: usize) -> Option<Task> {
+        let blocked_tasks = self.blocked_tasks.as_mut()?;
+        if let Some(idx) = blocked_tasks.iter().position(|x| x.id == task_id) {
+            blocked_tasks.remove(idx)
+        } else {
+            let idx =
+                self.sleeping_tasks.iter().position(|x| x.id == task_id)?;
+            Some(self.sleeping_tasks.remove(idx))
+        }
+    }
+
+    /// Makes a blocked or sleeping task runnable, returns `false` if the task
+    /// is neither, e.g. if it has not had a task to switch to when blocking or
+    /// has been killed.
+    pub fn unblock_task(&mut self, task_id: usize) -> bool {
+        match self.take_waiting_task(task_id) {
+            Some(mut task) => {
+                task.set_state(TaskState::Runnable);
+                self.runnable_tasks.as_mut().unwrap().push_front(task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Terminates the task with the ID `task_id` with `status`, returns
+    /// `false` if there is no such task.
+    ///
+    /// How soon the task terminates depends on where it is:
+    ///
+    /// * A blocked or sleeping task is terminated right away on its behalf.
+    ///   Tasks only wait in [WaitQueue::wait] and [sleep_ms], where they must
+    ///   not hold any spinlocks (see [assert_no_locks_held]), so there is
+    ///   nothing for them to release and their kernel stack can be discarded.
+    ///   Unless they hold a [sleeping lock](crate::sync::Mutex), then they are
+    ///   only marked like the runnable tasks.
+    /// * A runnable task may have been preempted in the middle of anything, so
+    ///   it is only marked.  It terminates itself at the next safe point, that
+    ///   is on its way back to the usermode once the scheduler picks it (see
+    ///   [terminate_this_task_if_killed]).
+    /// * The running task is only marked too, because this may be called from
+    ///   an interrupt handler.  A syscall can use [kill] to exit directly.
+    ///
+    /// The kernel threads, including the idle task, cannot be killed, since
+    /// they never return to the usermode.  They are treated as if they did not
+    /// exist.
+    ///
+    /// [assert_no_locks_held]: Self::assert_no_locks_held
+    /// [terminate_this_task_if_killed]: Self::terminate_this_task_if_killed
+    pub fn kill_task(&mut self, task_id: usize, status: i32) -> bool {
+        match self.find_task(task_id) {
+            Some(task) if !task.is_kernel_thread() => {
+                if task.kill_status.is_none() {
+                    task.kill_status = Some(status);
+                }
+                if task.held_locks != 0 {
+                    return true;
+                }
+            }
+            _ => return false,
+        }
+        if let Some(task) = self.take_waiting_task(task_id) {
+            self.terminate_waiting_task(task, status);
+        }
+        true
+    }
+
+    /// Terminates a task taken out of the blocked or sleeping list.  Its stale
+    /// ID in a wait queue is skipped by the next wakeup.
+    fn terminate_waiting_task(&mut self, mut task: Task, status: i32) {
+        self.reparent_children_if_last_thread(&mut task);
+        task.set_state(TaskState::Zombie);
+
+        println!(
+            "[TASKMGR] Terminated waiting task ID {} with status {}",
+            task.id, status,
+        );
+
+        self.terminated_tasks
+            .as_mut()
             .unwrap()
+            .push_back((task, status));
+        CHILD_EXITS.wake_all();
+        THREAD_EXITS.wake_all();
+    }
+
+    /// Checks that the running task does not hold any of the global kernel
+    /// locks before it blocks or sleeps.
+    ///
+    /// A waiting task may be [killed](Self::kill_task) and never run again, so
+    /// a lock held across a blocking point would never be released.  The
+    /// locks are spinlocks, so the next task to take it would also spin with
+    /// the interrupts disabled forever.  The same goes for `RefCell` borrows
+    /// of shared devices and files, which cannot be checked here.
+    fn assert_no_locks_held(&self) {
+        let locks = [
+            ("KERNEL_HEAP", crate::heap::KERNEL_HEAP.is_locked()),
+            ("KERNEL_VAS", arch::vas::KERNEL_VAS.is_locked()),
+            ("PMM_STACK", arch::pmm_stack::PMM_STACK.is_locked()),
+            ("VFS_ROOT", crate::fs::VFS_ROOT.is_locked()),
+            ("CONSOLE", crate::dev::console::CONSOLE.is_locked()),
+            ("KEYBOARD", arch::dev::keyboard::KEYBOARD.is_locked()),
+            (
+                "CHAR_DEVICES",
+                crate::dev::char_device::CHAR_DEVICES.is_locked(),
+            ),
+        ];
+        for (name, is_locked) in locks.iter() {
+            assert!(!is_locked, "{} is held across a blocking point", name);
+        }
+    }
+
+    /// Terminates the running task if it has been [killed](Self::kill_task).
+    ///
+    /// This must only be called when the task does not hold any kernel
+    /// resources, e.g. right before it returns to the usermode.
+    pub fn terminate_this_task_if_killed(&mut self) {
+        let status = match self.running_task() {
+            Some(task) => task.kill_status,
+            None => return,
+        };
+        if let Some(status) = status {
+            task_exit(status);
+        }
+    }
+
+    /// Frees the resources of the terminated tasks.
+    ///
+    /// This must not be called by a terminated task itself, since it still runs
+    /// on its own kernel stack and in its own VAS.  Nor in an interrupt
+    /// handler that may have interrupted the kernel in the middle of an
+    /// allocation.
+    pub fn reap_terminated_tasks(&mut self) {
+        while let Some((task, status)) =
+            self.terminated_tasks.as_mut().unwrap().pop_front()
+        {
+            // The VAS is destroyed with the process when the last thread is
+            // dropped.
+            println!(
+                "[TASKMGR] Reaped task ID {} (exit status {}).",
+                task.id, status,
+            );
+            // The process exits with its last thread, which is the only one
+            // left holding it once the others have been reaped.
+            let process_id = task.process_id();
+            if task.is_only_thread() {
+                console::with_console(|console| {
+                    console.process_exited(process_id, task.parent_id)
+                });
+                if let Some(parent_id) = task.parent_id {
+                    self.exited_tasks.push(ExitedTask {
+                        id: process_id,
+                        parent_id,
+                        status: task.process().exit_status.unwrap_or(status),
+                    });
+                }
+                // Nobody is left to join the threads of the process.
+                self.exited_threads
+                    .retain(|exited| exited.process_id != process_id);
+            } else if task.joinable {
+                self.exited_threads.push(ExitedThread {
+                    id: task.id,
+                    process_id,
+                    value: status,
+                });
+            }
+            // Dropping the task frees its kernel stack and memory mappings and
+            // closes its files.
+        }
+    }
+
+    /// Takes the exit status of an exited child of the running task's
+    /// process, returns `None` if none of its children has exited.
+    pub fn take_exited_child(&mut self) -> Option<(usize, i32)> {
+        let parent_id = self.this_task().process_id();
+        let idx = self
+            .exited_tasks
+            .iter()
+            .position(|exited| exited.parent_id == parent_id)?;
+        let exited = self.exited_tasks.remove(idx);
+        self.this_task()
+            .process_mut()
+            .child_ids
+            .retain(|&id| id != exited.id);
+        Some((exited.id, exited.status))
+    }
+
+    /// Checks if `thread_id` is a joinable thread of the running task's
+    /// process that has not exited yet.
+    pub fn is_joinable_thread(&mut self, thread_id: usize) -> bool {
+        let process_id = self.this_task().process().id;
+        match self.find_task(thread_id) {
+            Some(thread) => {
+                thread.joinable && thread.process().id == process_id
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the exit value of the exited thread `thread_id` of the running
+    /// task's process, returns `None` if it has not exited or is not such a
+    /// thread.
+    pub fn take_exited_thread(&mut self, thread_id: usize) -> Option<i32> {
+        let process_id = self.this_task().process().id;
+        let idx = self.exited_threads.iter().position(|exited| {
+            exited.id == thread_id && exited.process_id == process_id
+        })?;
+        Some(self.exited_threads.remove(idx).value)
+    }
+
+    /// Hands the children of the exiting task's process over to the
+    /// [reaper](REAPER_TASK_ID) unless the process has other threads left.
+    ///
+    /// The task must have been taken out of the task lists.
+    fn reparent_children_if_last_thread(&mut self, task: &mut Task) {
+        let process_id = task.process_id();
+        let has_other_threads = self
+            .running_task
             .iter()
-            .position(|x| x.id == task_id)
-            .unwrap();
-        let task = self.runnable_tasks.as_mut().unwrap().remove(idx).unwrap();
-        self.runnable_tasks.as_mut().unwrap().push_front(task);
+            .chain(self.runnable_tasks.iter().flatten())
+            .chain(self.blocked_tasks.iter().flatten())
+            .chain(self.sleeping_tasks.iter())
+            .any(|other| other.process_id() == process_id);
+        if !has_other_threads {
+            let child_ids = mem::take(&mut task.process_mut().child_ids);
+            self.reparent_children(process_id, child_ids);
+        }
+    }
+
+    /// Hands the children of the exiting process `parent_id` over to the
+    /// [reaper](REAPER_TASK_ID), or orphans them if there is none.
+    fn reparent_children(&mut self, parent_id: usize, child_ids: Vec<usize>) {
+        let new_parent_id = if parent_id != REAPER_TASK_ID
+            && self.find_task(REAPER_TASK_ID).is_some()
+        {
+            Some(REAPER_TASK_ID)
+        } else {
+            None
+        };
+
+        for &child_id in &child_ids {
+            // All threads of the child process, including the terminated ones.
+            let child_threads = self
+                .running_task
+                .iter_mut()
+                .chain(self.runnable_tasks.iter_mut().flatten())
+                .chain(self.blocked_tasks.iter_mut().flatten())
+                .chain(self.sleeping_tasks.iter_mut())
+                .chain(
+                    self.terminated_tasks
+                        .iter_mut()
+                        .flatten()
+                        .map(|(task, _)| task),
+                )
+                .filter(|task| task.process_id() == child_id);
+            for child in child_threads {
+                child.parent_id = new_parent_id;
+            }
+
+            match new_parent_id {
+                Some(new_parent_id) => {
+                    for exited in self.exited_tasks.iter_mut() {
+                        if exited.id == child_id {
+                            exited.parent_id = new_parent_id;
+                        }
+                    }
+                }
+                None => {
+                    self.exited_tasks.retain(|exited| exited.id != child_id)
+                }
+            }
+        }
+
+        if let Some(new_parent_id) = new_parent_id {
+            let reaper = self.find_task(new_parent_id).unwrap();
+            reaper.process_mut().child_ids.extend(child_ids);
+        }
     }
 
     pub fn terminate_this_task(&mut self, status: i32) -> ! {
-        assert_ne!(
-            self.runnable_tasks.as_ref().unwrap().len(),
-            0,
-            "cannot terminate the last task",
-        );
-        let from_task = self.running_task.take().unwrap();
+        // The previously terminated tasks are not running anymore, so they can
+        // be safely reaped here.
+        self.reap_terminated_tasks();
+
+        assert!(!self.is_idle_running(), "the idle task cannot terminate");
+        let held_locks = self.this_task().held_locks;
+        assert_eq!(held_locks, 0, "cannot terminate a task holding locks");
+        assert!(self.has_next_task(), "cannot terminate the last task");
+
+        let mut this_task = self.running_task.take().unwrap();
+        self.reparent_children_if_last_thread(&mut this_task);
+        self.running_task = Some(this_task);
+        CHILD_EXITS.wake_all();
+        THREAD_EXITS.wake_all();
+
+        let mut from_task = self.running_task.take().unwrap();
         let to_task = self.next_runnable_task();
 
         let from_id = from_task.id;
         let to_id = to_task.id;
 
+        from_task.set_state(TaskState::Zombie);
         self.run_task(to_task);
 
         println!(
@@ -146,35 +749,77 @@ impl TaskManager {
         unreachable!();
     }
 
-    pub fn schedule(&mut self, add_count_ms: u64, keep_runnable: bool) {
-        self.counter_ms += add_count_ms;
-        if NO_SCHED_COUNTER.load(Ordering::SeqCst) == 0
-            && self.runnable_tasks.as_ref().unwrap().len() > 0
-        {
-            let from_task = self.running_task.take().unwrap();
-            let to_task = self.next_runnable_task();
+    /// Switches to the next runnable task, the running task goes to the list
+    /// of `state`.  Returns when the task runs again.
+    fn switch_from_this_task(&mut self, state: TaskState) {
+        if state != TaskState::Runnable {
+            assert!(!self.is_idle_running(), "the idle task cannot wait");
+            self.assert_no_locks_held();
+        }
 
-            let from_id = from_task.id;
-            let to_id = to_task.id;
+        let mut from_task = self.running_task.take().unwrap();
+        let to_task = self.next_runnable_task();
 
-            self.run_task(to_task);
+        let from_id = from_task.id;
+        let to_id = to_task.id;
 
-            let where_from_goes = if keep_runnable {
-                self.runnable_tasks.as_mut().unwrap()
-            } else {
+        from_task.set_state(state);
+        from_task.irq_depth = arch::interrupts::irq_depth();
+        from_task.fpu_state.save();
+        self.run_task(to_task);
+
+        let from_tcb: *const TaskControlBlock = match state {
+            TaskState::Runnable if self.is_idle_task(from_id) => {
+                self.idle_task.insert(from_task).raw_tcb()
+            }
+            TaskState::Runnable => {
+                let runnable_tasks = self.runnable_tasks.as_mut().unwrap();
+                runnable_tasks.push_back(from_task);
+                runnable_tasks.back_mut().unwrap().raw_tcb()
+            }
+            TaskState::Blocked => {
                 println!("[TASKMGR] Blocking task ID {}", from_id);
-                self.blocked_tasks.as_mut().unwrap()
-            };
-            where_from_goes.push_back(from_task);
+                let blocked_tasks = self.blocked_tasks.as_mut().unwrap();
+                blocked_tasks.push_back(from_task);
+                blocked_tasks.back_mut().unwrap().raw_tcb()
+            }
+            TaskState::Sleeping => {
+                let idx = self
+                    .sleeping_tasks
+                    .iter()
+                    .position(|task| task.wake_ms > from_task.wake_ms)
+                    .unwrap_or(self.sleeping_tasks.len());
+                self.sleeping_tasks.insert(idx, from_task);
+                self.sleeping_tasks[idx].raw_tcb()
+            }
+            _ => unreachable!("cannot switch from a {:?} task", state),
+        };
+        let to_tcb = self.this_task().raw_tcb();
+        if state != TaskState::Runnable {
+            // Does not move the waiting task, so from_tcb stays valid.
+            self.reserve_for_unblocking();
+        }
 
-            let from_tcb = where_from_goes.back_mut().unwrap().raw_tcb();
-            let to_tcb = self.this_task().raw_tcb();
+        println!("[TASKMGR] id {} -> id {}", from_id, to_id);
 
-            println!("[TASKMGR] id {} -> id {}", from_id, to_id);
+        unsafe {
+            self.switch_tasks(from_tcb, to_tcb);
+        }
+    }
 
-            unsafe {
-                self.switch_tasks(from_tcb, to_tcb);
+    pub fn schedule(&mut self, add_count_ms: u64, keep_runnable: bool) {
+        self.counter_ms += add_count_ms;
+        if keep_runnable && self.running_task_has_priority() {
+            // The running task stays, the runnable ones are passed over.
+            for task in self.runnable_tasks.as_mut().unwrap().iter_mut() {
+                task.skipped_quanta += 1;
             }
+        } else if self.can_switch() {
+            self.switch_from_this_task(if keep_runnable {
+                TaskState::Runnable
+            } else {
+                TaskState::Blocked
+            });
         } else {
             if self.counter_ms % 10000 == 0 {
                 println!(
@@ -189,6 +834,206 @@ impl TaskManager {
 
 pub static mut TASK_MANAGER: TaskManager = TaskManager::new();
 
+// Tasks waiting for one of their children to exit.
+kernel_static! {
+    pub static ref CHILD_EXITS: WaitQueue = WaitQueue::new();
+}
+
+// Tasks waiting for a thread to exit to join it.
+kernel_static! {
+    pub static ref THREAD_EXITS: WaitQueue = WaitQueue::new();
+}
+
+/// Tasks waiting for an event, e.g. for input to arrive.
+///
+/// A task checks whether it has to wait and then calls [wait](Self::wait),
+/// an interrupt handler calls [wake_one](Self::wake_one) or
+/// [wake_all](Self::wake_all) when the event happens.  A wakeup that comes
+/// when there is no one waiting is remembered and consumed by the next
+/// [wait](Self::wait), so that it is not lost if it arrives between the check
+/// and the wait.  This means that a wait may return early, the callers must
+/// check again whether they still have to wait.
+///
+/// The queue is only accessed with the interrupts disabled and the wake side
+/// does not allocate, so it can be used in interrupt handlers.
+pub struct WaitQueue {
+    inner: Mutex<WaitQueueInner>,
+}
+
+struct WaitQueueInner {
+    /// IDs of the waiting tasks, the longest waiting one first.
+    task_ids: Vec<usize>,
+    wake_pending: bool,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue {
+            inner: Mutex::new(WaitQueueInner {
+                task_ids: Vec::new(),
+                wake_pending: false,
+            }),
+        }
+    }
+
+    /// Blocks the running task until it is woken up or killed, returns right
+    /// away if there is a pending wakeup.
+    ///
+    /// Before the scheduler starts this waits for an interrupt instead.
+    pub fn wait(&self) {
+        arch::interrupts::with_disabled(|| unsafe {
+            let task_id = match TASK_MANAGER.running_task() {
+                Some(task) => task.id,
+                None => return arch::interrupts::wait_for_interrupt(),
+            };
+
+            {
+                let mut inner = self.inner.lock();
+                if inner.wake_pending {
+                    inner.wake_pending = false;
+                    return;
+                }
+                inner.task_ids.push(task_id);
+            }
+
+            TASK_MANAGER.block_this_task();
+
+            // The task is still in the queue if it has been killed or there
+            // has been no task to switch to.
+            let mut inner = self.inner.lock();
+            inner.task_ids.retain(|&id| id != task_id);
+        });
+    }
+
+    /// Unblocks the longest waiting task.
+    pub fn wake_one(&self) {
+        arch::interrupts::with_disabled(|| {
+            let mut inner = self.inner.lock();
+            // The IDs of the killed tasks are skipped.
+            while !inner.task_ids.is_empty() {
+                let task_id = inner.task_ids.remove(0);
+                if unsafe { TASK_MANAGER.unblock_task(task_id) } {
+                    return;
+                }
+            }
+            inner.wake_pending = true;
+        });
+    }
+
+    /// Unblocks all the waiting tasks.
+    pub fn wake_all(&self) {
+        arch::interrupts::with_disabled(|| {
+            let mut inner = self.inner.lock();
+            let mut woken = false;
+            for task_id in inner.task_ids.drain(..) {
+                woken |= unsafe { TASK_MANAGER.unblock_task(task_id) };
+            }
+            if !woken {
+                inner.wake_pending = true;
+            }
+        });
+    }
+}
+
+impl fmt::Debug for WaitQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("WaitQueue")
+    }
+}
+
+/// Terminates the running task with the exit status `status`.
+///
+/// The task becomes a zombie and is never switched to again, so this can be
+/// used where the task cannot run any more of its own code, e.g. in an
+/// exception handler.  Its resources are freed by the next task that reaches a
+/// safe point (see [TaskManager::reap_terminated_tasks]).
+pub fn task_exit(status: i32) -> ! {
+    unsafe { TASK_MANAGER.terminate_this_task(status) }
+}
+
+/// Terminates the running task's process with the exit status `status`.
+///
+/// The other threads of the process are [killed](TaskManager::kill_task) with
+/// the same status, and the process exits when the last of them is reaped.
+pub fn process_exit(status: i32) -> ! {
+    arch::interrupts::with_disabled(|| unsafe {
+        let this_task = TASK_MANAGER.this_task();
+        let this_id = this_task.id;
+        let process_id = this_task.process_id();
+        this_task.process_mut().exit_status.get_or_insert(status);
+
+        let thread_ids: Vec<usize> = TASK_MANAGER
+            .tasks()
+            .filter(|task| {
+                task.process_id() == process_id
+                    && task.id != this_id
+                    && task.state() != TaskState::Zombie
+            })
+            .map(|task| task.id)
+            .collect();
+        for thread_id in thread_ids {
+            TASK_MANAGER.kill_task(thread_id, status);
+        }
+    });
+    task_exit(status);
+}
+
+/// Runs `f(arg)` in a new kernel thread named `name`, returns its ID.  The
+/// thread exits with the status 0 when `f` returns.
+pub fn spawn_kernel_thread(name: &str, f: fn(usize), arg: usize) -> usize {
+    arch::interrupts::with_disabled(|| unsafe {
+        let task_id = TASK_MANAGER.allocate_task_id();
+        let mut task = Task::kernel_thread(task_id, f, arg);
+        task.set_name(name);
+        TASK_MANAGER.add_runnable_task(task);
+        println!(
+            "[TASKMGR] Spawned kernel thread {:?} with ID {}.",
+            name, task_id,
+        );
+        task_id
+    })
+}
+
+/// Terminates the task with the ID `task_id` with `status`, returns `false` if
+/// there is no such task.  If it is the running task, this exits directly and
+/// does not return, so it must not be called in an interrupt handler.
+///
+/// See [TaskManager::kill_task] for when the other tasks terminate.
+pub fn kill(task_id: usize, status: i32) -> bool {
+    unsafe {
+        if TASK_MANAGER.running_task().map(|task| task.id) == Some(task_id) {
+            task_exit(status);
+        }
+        TASK_MANAGER.kill_task(task_id, status)
+    }
+}
+
+/// Blocks the running task for at least `ms` milliseconds.  Sleeping for 0 ms
+/// lets the other tasks run.  Returns the number of milliseconds left if the
+/// task is woken up early, i.e. killed, 0 otherwise.
+///
+/// Before the scheduler starts there is nothing to switch to, so this waits
+/// for the timer instead.
+pub fn sleep_ms(ms: u64) -> u64 {
+    arch::interrupts::with_disabled(|| unsafe {
+        if TASK_MANAGER.running_task().is_none() {
+            log_warn!(
+                "[TASKMGR] Sleeping for {} ms before the scheduler starts.",
+                ms,
+            );
+            timer::busy_wait_ms(ms);
+            0
+        } else if ms == 0 {
+            TASK_MANAGER.yield_this_task();
+            0
+        } else {
+            let wake_ms = timer::uptime_ms() + ms;
+            TASK_MANAGER.sleep_this_task(wake_ms);
+            wake_ms.saturating_sub(timer::uptime_ms())
+        }
+    })
+}
+
 pub fn init() -> ! {
     unsafe {
         TASK_MANAGER.init_vecs();
@@ -196,23 +1041,39 @@ pub fn init() -> ! {
 
     arch::task_manager::init();
 
+    // The boot task becomes the idle task once it has nothing else to do (see
+    // init_entry_point).
     unsafe {
-        TIMER.as_mut().unwrap().set_callback(schedule);
+        TASK_MANAGER.set_idle_task();
     }
 
+    set_timer_period_ms(timer::ms_per_tick());
+    timer::every_tick(schedule, 0);
+
     init_entry_point();
 }
 
+/// Time that a task runs before the others are given a chance.
 const SCHEDULING_PERIOD_MS: u64 = 50;
 
-static mut COUNTER_MS: u64 = 0;
+/// Number of timer ticks in a quantum, i.e. [SCHEDULING_PERIOD_MS] rounded to
+/// whole ticks, and the number of them used up by the running task.
+/// Accessed with the interrupts disabled.
+static mut QUANTUM_TICKS: u64 = 1;
+static mut QUANTUM_TICKS_USED: u64 = 0;
+
 pub static mut TEMP_SPAWNER_ON: bool = false;
 static mut NUM_SPAWNED: usize = 0;
 
-pub fn schedule() {
+/// Wakes up the sleeping tasks, accounts the CPU time and switches tasks once
+/// the running one has used up its quantum.  Called on every timer tick.
+fn schedule(_: usize) {
     unsafe {
+        TASK_MANAGER.wake_sleeping_tasks();
+
         let period_ms = TIMER.as_ref().unwrap().period_ms() as u64;
-        COUNTER_MS += period_ms;
+        TASK_MANAGER.account_cpu_time(period_ms);
+        QUANTUM_TICKS_USED += 1;
 
         if TEMP_SPAWNER_ON && NUM_SPAWNED < 1 {
             let task_id = TASK_MANAGER.allocate_task_id();
@@ -225,17 +1086,47 @@ pub fn schedule() {
             TASK_MANAGER.add_runnable_task(task);
             println!("[TASKMGR] Created a task with ID {}.", task_id);
             NUM_SPAWNED += 1;
+            // The first usermode program has taken REAPER_TASK_ID by now.
+            crate::selftest::init();
         }
 
-        if COUNTER_MS >= SCHEDULING_PERIOD_MS {
-            COUNTER_MS = 0;
-            TASK_MANAGER.schedule(SCHEDULING_PERIOD_MS, true);
+        if QUANTUM_TICKS_USED >= QUANTUM_TICKS {
+            QUANTUM_TICKS_USED = 0;
+            TASK_MANAGER.schedule(QUANTUM_TICKS * period_ms, true);
         }
     }
 }
 
+/// Recomputes the quantum in ticks of the timer period `period_ms`.  Called
+/// whenever the timer period changes.
+pub fn set_timer_period_ms(period_ms: u64) {
+    let ticks = (SCHEDULING_PERIOD_MS + period_ms / 2) / period_ms;
+    arch::interrupts::with_disabled(|| unsafe {
+        QUANTUM_TICKS = cmp::max(ticks, 1);
+        QUANTUM_TICKS_USED = 0;
+    });
+}
+
+/// Returns the number of timer ticks in a quantum.
+pub fn quantum_ticks() -> u64 {
+    arch::interrupts::with_disabled(|| unsafe { QUANTUM_TICKS })
+}
+
 fn init_entry_point() -> ! {
     println!("[INIT] Init process entry point.");
     println!("[INIT] End of init process.");
-    loop {}
+    idle_loop();
+}
+
+/// Body of the idle task: lets the other tasks run and halts the CPU until the
+/// next interrupt when there are none.
+fn idle_loop() -> ! {
+    loop {
+        arch::interrupts::with_disabled(|| unsafe {
+            TASK_MANAGER.yield_this_task();
+            // The interrupts stay disabled until the HLT, so a task woken up
+            // after the check does not wait for the interrupt after next.
+            arch::interrupts::wait_for_interrupt();
+        });
+    }
 }
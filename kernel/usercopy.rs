@@ -0,0 +1,169 @@
+// ytret's OS - hobby operating system
+// Copyright (C) 2020, 2021  Yuri Tretyakov (ytretyakov18@gmail.com)
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Copying data between the kernel and the usermode memory of the running task.
+//!
+//! The pointers passed to the syscalls must not be dereferenced directly: they
+//! may point to the kernel or to an unmapped page.  The helpers here check that
+//! the whole range lies in the usermode region and is mapped, committing the
+//! lazily allocated pages, and return [EFAULT](Errno::EFAULT) otherwise.  The
+//! ranges written to must also be writable by the task, since the kernel itself
+//! can write to the read-only pages.
+//!
+//! The process's [memory lock](crate::task::Process::mem_lock) is held from
+//! the check to the end of the copy, so that another thread of the process
+//! cannot unmap the range in between and fault the kernel.
+
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr;
+use core::slice;
+
+use crate::errno::Errno;
+use crate::ffi::cstring::CString;
+use crate::sync::MutexGuard;
+use crate::task_manager::TASK_MANAGER;
+
+/// Checks that the `len` bytes at `user_ptr` can be read on behalf of the
+/// running task.  An empty range is always valid.
+pub fn check_range(user_ptr: usize, len: usize) -> Result<(), Errno> {
+    let _mem_lock = lock_mem();
+    check_access(user_ptr, len, false)
+}
+
+/// Checks that the `len` bytes at `user_ptr` can be written on behalf of the
+/// running task.  An empty range is always valid.
+pub fn check_writable(user_ptr: usize, len: usize) -> Result<(), Errno> {
+    let _mem_lock = lock_mem();
+    check_access(user_ptr, len, true)
+}
+
+/// Locks the [memory](crate::task::Process::mem_lock) of the running task's
+/// process.
+pub fn lock_mem() -> MutexGuard<'static, ()> {
+    unsafe { TASK_MANAGER.this_task().process().mem_lock.lock() }
+}
+
+fn check_access(user_ptr: usize, len: usize, write: bool) -> Result<(), Errno> {
+    let is_valid = len == 0
+        || unsafe {
+            TASK_MANAGER
+                .this_task()
+                .check_user_buf(user_ptr, len, write)
+        };
+    if is_valid {
+        Ok(())
+    } else {
+        Err(Errno::EFAULT)
+    }
+}
+
+/// Copies the `len` bytes at `user_ptr` to the start of `dst`.
+///
+/// # Panics
+/// This function panics if `dst` is shorter than `len`.
+pub fn copy_from_user(
+    dst: &mut [u8],
+    user_ptr: usize,
+    len: usize,
+) -> Result<(), Errno> {
+    assert!(dst.len() >= len, "copy_from_user: destination is too short");
+    let _mem_lock = lock_mem();
+    check_access(user_ptr, len, false)?;
+    unsafe {
+        ptr::copy_nonoverlapping(user_ptr as *const u8, dst.as_mut_ptr(), len);
+    }
+    Ok(())
+}
+
+/// Copies `src` to `user_ptr`.
+pub fn copy_to_user(user_ptr: usize, src: &[u8]) -> Result<(), Errno> {
+    let _mem_lock = lock_mem();
+    check_access(user_ptr, src.len(), true)?;
+    unsafe {
+        ptr::copy_nonoverlapping(src.as_ptr(), user_ptr as *mut u8, src.len());
+    }
+    Ok(())
+}
+
+/// Copies the `len` bytes at `user_ptr` to a new vector.
+pub fn bytes_from_user(user_ptr: usize, len: usize) -> Result<Vec<u8>, Errno> {
+    let _mem_lock = lock_mem();
+    check_access(user_ptr, len, false)?;
+    let mut bytes = Vec::with_capacity(len);
+    unsafe {
+        bytes.extend_from_slice(slice::from_raw_parts(
+            user_ptr as *const u8,
+            len,
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Reads a value of type `T` at `user_ptr`, which need not be aligned.
+pub fn read_from_user<T: Copy>(user_ptr: usize) -> Result<T, Errno> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let _mem_lock = lock_mem();
+    check_access(user_ptr, size_of::<T>(), false)?;
+    unsafe {
+        ptr::copy_nonoverlapping(
+            user_ptr as *const u8,
+            value.as_mut_ptr() as *mut u8,
+            size_of::<T>(),
+        );
+        Ok(value.assume_init())
+    }
+}
+
+/// Writes `value` to `user_ptr`, which need not be aligned.
+pub fn write_to_user<T: Copy>(user_ptr: usize, value: T) -> Result<(), Errno> {
+    let _mem_lock = lock_mem();
+    check_access(user_ptr, size_of::<T>(), true)?;
+    unsafe {
+        (user_ptr as *mut T).write_unaligned(value);
+    }
+    Ok(())
+}
+
+/// Copies the NUL-terminated string at `user_ptr`.  Returns
+/// [ENAMETOOLONG](Errno::ENAMETOOLONG) if there is no NUL byte among the first
+/// `max_len` bytes.
+pub fn str_from_user(
+    user_ptr: usize,
+    max_len: usize,
+) -> Result<CString, Errno> {
+    let mut bytes = Vec::new();
+    let mut addr = user_ptr;
+    let _mem_lock = lock_mem();
+    while bytes.len() < max_len {
+        // The string is checked a page at a time, since it may end right
+        // before an unmapped page.
+        let page_end =
+            (addr & !0xFFF).checked_add(4096).ok_or(Errno::EFAULT)?;
+        let len = (page_end - addr).min(max_len - bytes.len());
+        check_access(addr, len, false)?;
+        let chunk = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+        match chunk.iter().position(|&byte| byte == 0) {
+            Some(nul_idx) => {
+                bytes.extend_from_slice(&chunk[..nul_idx]);
+                return Ok(CString::new(bytes).unwrap());
+            }
+            None => bytes.extend_from_slice(chunk),
+        }
+        addr = page_end;
+    }
+    Err(Errno::ENAMETOOLONG)
+}
@@ -1,5 +1,71 @@
 #include <stdio.h>
 #include <stdint.h>
+#include <string.h>
+
+#define SYSCALL_EXECVE 21
+#define USERMODE_END 0xC0000000
+
+// Enough to take more than the initial 4 KiB page of the stack.
+#define LONG_ARGC 64
+#define LONG_ARG_LEN 1000
+
+static int sys_execve(const char *pathname, char **argv, char **environ) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_EXECVE), "b"(pathname), "c"(strlen(pathname)),
+                   "d"(argv), "S"(environ)
+                 : "memory");
+    return ret;
+}
+
+static int check_on_stack(const char *what, int idx, const char *str,
+                          const void *stack) {
+    if ((uintptr_t)str < (uintptr_t)stack || (uintptr_t)str >= USERMODE_END) {
+        printf("%s[%d] at %p is not on the usermode stack\n", what, idx,
+               (void *)str);
+        return 1;
+    }
+    return 0;
+}
+
+static char long_args[LONG_ARGC][LONG_ARG_LEN + 1];
+
+static void fill_long_arg(char *arg, int idx) {
+    memset(arg, 'a' + idx % 26, LONG_ARG_LEN);
+    arg[LONG_ARG_LEN] = '\0';
+}
+
+static int check_long_args(int argc, char **argv) {
+    if (argc != LONG_ARGC + 2) {
+        printf("argc is %d, expected %d\n", argc, LONG_ARGC + 2);
+        return 1;
+    }
+    for (int i = 0; i < LONG_ARGC; i++) {
+        fill_long_arg(long_args[i], i);
+        if (strcmp(argv[i + 2], long_args[i]) != 0) {
+            printf("argv[%d] was not passed intact\n", i + 2);
+            return 1;
+        }
+    }
+    printf("OK\n");
+    return 0;
+}
+
+static int exec_long_args(const char *pathname) {
+    static char *argv[LONG_ARGC + 3];
+    static char *environ[] = {"LONG_ARGS=1", NULL};
+    argv[0] = (char *)pathname;
+    argv[1] = "--long";
+    for (int i = 0; i < LONG_ARGC; i++) {
+        fill_long_arg(long_args[i], i);
+        argv[i + 2] = long_args[i];
+    }
+    argv[LONG_ARGC + 2] = NULL;
+    int ret = sys_execve(pathname, argv, environ);
+    printf("execve with long arguments returned %d\n", ret);
+    return 1;
+}
 
 int main(int argc, char **argv, char **environ) {
     setvbuf(stdout, NULL, _IONBF, 0);
@@ -8,7 +74,12 @@ int main(int argc, char **argv, char **environ) {
 
     int i;
     for (i = 0; i < argc; i++) {
-        printf("argv[%d] = %s\n", i, argv[i]);
+        if (argc <= 8) {
+            printf("argv[%d] = %s\n", i, argv[i]);
+        }
+        if (check_on_stack("argv", i, argv[i], &argc)) {
+            return 1;
+        }
     }
     printf("argv[%d] = NULL\n", i);
 
@@ -19,9 +90,15 @@ int main(int argc, char **argv, char **environ) {
         int j;
         for (j = 0; environ[j] != NULL; j++) {
             printf("environ[%d] = %s\n", j, environ[j]);
+            if (check_on_stack("environ", j, environ[j], &argc)) {
+                return 1;
+            }
         }
         printf("environ[%d] = NULL\n", j);
     }
 
-    return 0;
+    if (argc > 1 && strcmp(argv[1], "--long") == 0) {
+        return check_long_args(argc, argv);
+    }
+    return exec_long_args("/bin/test-arg-env");
 }
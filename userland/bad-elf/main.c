@@ -0,0 +1,39 @@
+#include <stdio.h>
+#include <string.h>
+
+#define SYSCALL_EXECVE 21
+#define ENOEXEC 8
+
+static int sys_execve(const char *pathname) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_EXECVE), "b"(pathname), "c"(strlen(pathname)),
+                   "d"(0), "S"(0)
+                 : "memory");
+    return ret;
+}
+
+static const char *bad_elfs[] = {
+    "/bin/bad-elf-outside",    "/bin/bad-elf-wrapping",
+    "/bin/bad-elf-filesz",     "/bin/bad-elf-beyond-file",
+    "/bin/bad-elf-misaligned", "/bin/bad-elf-overlap",
+    "/bin/bad-elf-stack",
+};
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    for (size_t i = 0; i < sizeof(bad_elfs) / sizeof(bad_elfs[0]); i++) {
+        int ret = sys_execve(bad_elfs[i]);
+        if (ret != -ENOEXEC) {
+            printf("execve of %s returned %d, expected %d\n", bad_elfs[i],
+                   ret, -ENOEXEC);
+            return 1;
+        }
+    }
+
+    // Still running the old program.
+    printf("OK\n");
+    return 0;
+}
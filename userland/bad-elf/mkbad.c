@@ -0,0 +1,112 @@
+// Writes an i386 executable with a malformed program header table, which the
+// kernel must refuse to execute.  Runs on the build host.
+
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+
+#define FILE_SIZE 4096
+#define ENTRY 0x08048000
+#define PT_LOAD 1
+
+struct elf_header {
+    unsigned char ident[16];
+    uint16_t type;
+    uint16_t machine;
+    uint32_t version;
+    uint32_t entry;
+    uint32_t phoff;
+    uint32_t shoff;
+    uint32_t flags;
+    uint16_t ehsize;
+    uint16_t phentsize;
+    uint16_t phnum;
+    uint16_t shentsize;
+    uint16_t shnum;
+    uint16_t shstrndx;
+};
+
+struct prog_header {
+    uint32_t type;
+    uint32_t offset;
+    uint32_t vaddr;
+    uint32_t paddr;
+    uint32_t filesz;
+    uint32_t memsz;
+    uint32_t flags;
+    uint32_t align;
+};
+
+struct bad_elf {
+    const char *name;
+    int phnum;
+    // offset, vaddr, filesz, memsz of each segment.
+    uint32_t segments[2][4];
+};
+
+static const struct bad_elf bad_elfs[] = {
+    // Below the usermode region.
+    {"outside", 1, {{0, 0x1000, 0, 0x1000}}},
+    // The end of the segment wraps around.
+    {"wrapping", 1, {{0, 0xFFFFF000, 0, 0x2000}}},
+    {"filesz", 1, {{0, ENTRY, 0x200, 0x100}}},
+    {"beyond-file", 1, {{0, ENTRY, 0x10000, 0x10000}}},
+    {"misaligned", 1, {{0x100, ENTRY, 0x100, 0x100}}},
+    {"overlap", 2, {{0, ENTRY, 0, 0x2000}, {0, ENTRY + 0x1000, 0, 0x1000}}},
+    // Within the region reserved for the stack.
+    {"stack", 1, {{0, 0xBFFFF000, 0, 0x1000}}},
+};
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s KIND OUTPUT\n", argv[0]);
+        return 1;
+    }
+
+    const struct bad_elf *bad = NULL;
+    for (size_t i = 0; i < sizeof(bad_elfs) / sizeof(bad_elfs[0]); i++) {
+        if (strcmp(bad_elfs[i].name, argv[1]) == 0) {
+            bad = &bad_elfs[i];
+        }
+    }
+    if (bad == NULL) {
+        fprintf(stderr, "unknown kind %s\n", argv[1]);
+        return 1;
+    }
+
+    static unsigned char image[FILE_SIZE];
+    struct elf_header eh = {
+        .ident = {0x7F, 'E', 'L', 'F', 1, 1, 1},
+        .type = 2,
+        .machine = 3,
+        .version = 1,
+        .entry = ENTRY,
+        .phoff = sizeof(eh),
+        .ehsize = sizeof(eh),
+        .phentsize = sizeof(struct prog_header),
+        .phnum = bad->phnum,
+        .shentsize = 40,
+    };
+    memcpy(image, &eh, sizeof(eh));
+    for (int i = 0; i < bad->phnum; i++) {
+        struct prog_header ph = {
+            .type = PT_LOAD,
+            .offset = bad->segments[i][0],
+            .vaddr = bad->segments[i][1],
+            .paddr = bad->segments[i][1],
+            .filesz = bad->segments[i][2],
+            .memsz = bad->segments[i][3],
+            .flags = 5,
+            .align = 0x1000,
+        };
+        memcpy(image + sizeof(eh) + i * sizeof(ph), &ph, sizeof(ph));
+    }
+
+    FILE *file = fopen(argv[2], "wb");
+    if (file == NULL || fwrite(image, 1, sizeof(image), file) != sizeof(image)
+        || fclose(file) != 0) {
+        perror(argv[2]);
+        return 1;
+    }
+    return 0;
+}
@@ -0,0 +1,38 @@
+#include <stdio.h>
+
+#define BIG_LEN (1024 * 1024 + 123)
+
+// .data right before .bss, so that they share a page.
+int data_var = 42;
+int bss_var;
+static unsigned char big[BIG_LEN];
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    printf("data_var at %p, bss_var at %p, big at %p\n", (void *)&data_var,
+           (void *)&bss_var, (void *)big);
+    if (data_var != 42) {
+        printf("data_var is %d, expected 42\n", data_var);
+        return 1;
+    }
+    if (bss_var != 0) {
+        printf("bss_var is %d, expected 0\n", bss_var);
+        return 1;
+    }
+    for (int i = 0; i < BIG_LEN; i++) {
+        if (big[i] != 0) {
+            printf("big[%d] is %d, expected 0\n", i, big[i]);
+            return 1;
+        }
+    }
+
+    // Dirty the memory, so that a rerun would catch the reuse of the frames.
+    for (int i = 0; i < BIG_LEN; i++) {
+        big[i] = 0xAA;
+    }
+    bss_var = -1;
+
+    printf("OK\n");
+    return 0;
+}
@@ -0,0 +1,45 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define SYSCALL_WAIT 20
+#define PAGE_FAULT_EXIT_STATUS -1
+
+static pid_t sys_wait(int *status) {
+    pid_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WAIT), "b"(status)
+                 : "memory");
+    return ret;
+}
+
+// The kernel log should name this function when the child is killed.
+__attribute__((noinline)) static void crash_here(volatile int *ptr) {
+    *ptr = 42;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    pid_t child = fork();
+    if (child == 0) {
+        crash_here((volatile int *)0x10);
+        printf("Child survived the write to a wild pointer\n");
+        exit(1);
+    }
+
+    int status;
+    if (sys_wait(&status) != child) {
+        printf("wait failed\n");
+        return 1;
+    }
+    if (status != PAGE_FAULT_EXIT_STATUS) {
+        printf("Child exited with status %d, expected %d\n", status,
+               PAGE_FAULT_EXIT_STATUS);
+        return 1;
+    }
+    printf("Child was killed, the kernel log names crash_here\n");
+    printf("OK\n");
+    return 0;
+}
@@ -0,0 +1,96 @@
+#include <stdio.h>
+
+#define SYSCALL_OPEN 0
+#define SYSCALL_WRITE 1
+#define SYSCALL_READ 2
+#define SYSCALL_SEEK_ABS 3
+#define SYSCALL_SEEK_REL 4
+#define SYSCALL_MEM_MAP 5
+#define SYSCALL_SET_TLS 6
+#define SYSCALL_WAIT 20
+#define SYSCALL_CLOSE 27
+#define SYSCALL_STAT 30
+#define SYSCALL_FSTAT 31
+#define SYSCALL_PIPE 35
+#define SYSCALL_BOGUS 1000
+
+#define EBADF 9
+#define EFAULT 14
+#define EINVAL 22
+#define ENOSYS 88
+
+#define KERNEL_ADDR 0xC0000000
+
+static int syscall3(int num, int arg1, int arg2, int arg3) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(num), "b"(arg1), "c"(arg2), "d"(arg3)
+                 : "memory");
+    return ret;
+}
+
+static int check(const char *what, int ret, int expected) {
+    printf("%s returned %d, expected %d\n", what, ret, expected);
+    return ret == expected;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    int ok = 1;
+    char buf[4];
+
+    ok &= check("bogus syscall", syscall3(SYSCALL_BOGUS, 0, 0, 0), -ENOSYS);
+
+    int fds[2];
+    if (syscall3(SYSCALL_PIPE, (int)fds, 0, 0) != 0) {
+        printf("pipe failed\n");
+        return 1;
+    }
+    syscall3(SYSCALL_CLOSE, fds[0], 0, 0);
+    syscall3(SYSCALL_CLOSE, fds[1], 0, 0);
+    ok &= check("read on a closed fd",
+                syscall3(SYSCALL_READ, fds[0], (int)buf, sizeof(buf)), -EBADF);
+    ok &= check("close on a closed fd",
+                syscall3(SYSCALL_CLOSE, fds[0], 0, 0), -EBADF);
+
+    // Offsets that do not fit in an off_t.
+    static const char self_path[] = "/bin/test-errno";
+    int fd = syscall3(SYSCALL_OPEN, (int)self_path, sizeof(self_path) - 1, 0);
+    if (fd < 0) {
+        printf("open returned %d\n", fd);
+        return 1;
+    }
+    ok &= check("seek_abs", syscall3(SYSCALL_SEEK_ABS, fd, 0x80000000, 0),
+                -EINVAL);
+    ok &= check("seek_abs", syscall3(SYSCALL_SEEK_ABS, fd, 1, 0), 1);
+    ok &= check("seek_rel", syscall3(SYSCALL_SEEK_REL, fd, 0xFFFFFFFF, 0),
+                -EINVAL);
+    syscall3(SYSCALL_CLOSE, fd, 0, 0);
+
+    // Pointers into the kernel and null pointers.
+    int bad_ptrs[] = {KERNEL_ADDR, 0};
+    for (unsigned int i = 0; i < sizeof(bad_ptrs) / sizeof(bad_ptrs[0]); i++) {
+        int ptr = bad_ptrs[i];
+        printf("Pointer 0x%08X:\n", ptr);
+        ok &= check("read", syscall3(SYSCALL_READ, 0, ptr, 4), -EFAULT);
+        ok &= check("write", syscall3(SYSCALL_WRITE, 1, ptr, 4), -EFAULT);
+        ok &= check("open", syscall3(SYSCALL_OPEN, ptr, 4, 0), -EFAULT);
+        ok &= check("stat", syscall3(SYSCALL_STAT, ptr, 4, ptr), -EFAULT);
+        ok &= check("fstat", syscall3(SYSCALL_FSTAT, 1, ptr, 0), -EFAULT);
+        ok &= check("pipe", syscall3(SYSCALL_PIPE, ptr, 0, 0), -EFAULT);
+        ok &= check("mem_map", syscall3(SYSCALL_MEM_MAP, ptr, 0, 0), -EFAULT);
+    }
+    ok &= check("wait", syscall3(SYSCALL_WAIT, KERNEL_ADDR, 0, 0), -EFAULT);
+    ok &= check("set_tls", syscall3(SYSCALL_SET_TLS, KERNEL_ADDR, 0, 0),
+                -EFAULT);
+    ok &= check("set_tls", syscall3(SYSCALL_SET_TLS, 0xFFFFFFFE, 0, 0),
+                -EFAULT);
+
+    if (!ok) {
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
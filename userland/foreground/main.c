@@ -0,0 +1,116 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define SYSCALL_SET_FOREGROUND 17
+#define SYSCALL_SLEEP_MS 18
+#define SYSCALL_WAIT 20
+
+#define EPERM 1
+#define ESRCH 3
+
+// Must be run as the init program, which owns the console.
+
+static int sys_set_foreground(pid_t pid) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_SET_FOREGROUND), "b"(pid)
+                 : "memory");
+    return ret;
+}
+
+static void sys_sleep_ms(int ms) {
+    asm volatile("int $0x88" : : "a"(SYSCALL_SLEEP_MS), "b"(ms) : "memory");
+}
+
+static pid_t sys_wait(int *status) {
+    pid_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WAIT), "b"(status)
+                 : "memory");
+    return ret;
+}
+
+static int expect(const char *what, int ret, int expected) {
+    if (ret != expected) {
+        printf("%s returned %d instead of %d\n", what, ret, expected);
+        return 0;
+    }
+    return 1;
+}
+
+// Runs in a child of the owner, which makes it the foreground task after the
+// first check.
+static int child_main(void) {
+    pid_t self = getpid();
+    if (!expect("set_foreground by a background task",
+                sys_set_foreground(self), -EPERM)) {
+        return 1;
+    }
+    // Wait for the owner to make this task the foreground one.
+    sys_sleep_ms(200);
+
+    pid_t grandchild = fork();
+    if (grandchild == 0) {
+        sys_sleep_ms(100);
+        exit(0);
+    }
+    if (!expect("set_foreground by the foreground task",
+                sys_set_foreground(grandchild), 0)) {
+        return 1;
+    }
+    if (!expect("set_foreground by the former foreground task",
+                sys_set_foreground(self), -EPERM)) {
+        return 1;
+    }
+
+    int status;
+    if (sys_wait(&status) != grandchild) {
+        printf("wait for the grandchild failed\n");
+        return 1;
+    }
+    // The exited grandchild has handed the foreground back to this task.
+    if (!expect("set_foreground after the foreground task exited",
+                sys_set_foreground(self), 0)) {
+        return 1;
+    }
+    return 0;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    if (!expect("set_foreground by the owner", sys_set_foreground(getpid()),
+                0)) {
+        printf("Run this program as init.\n");
+        return 1;
+    }
+    if (!expect("set_foreground of a missing task", sys_set_foreground(99999),
+                -ESRCH)) {
+        return 1;
+    }
+
+    pid_t child = fork();
+    if (child == 0) {
+        exit(child_main());
+    }
+    sys_sleep_ms(100);
+    if (!expect("set_foreground of the child by the owner",
+                sys_set_foreground(child), 0)) {
+        return 1;
+    }
+
+    int status;
+    if (sys_wait(&status) != child) {
+        printf("wait for the child failed\n");
+        return 1;
+    }
+    if (status != 0) {
+        printf("FAILED: child exited with %d\n", status);
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
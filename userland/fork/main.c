@@ -1,12 +1,66 @@
 #include <stdio.h>
+#include <stdlib.h>
 #include <unistd.h>
 
+#define SYSCALL_WAIT 20
+#define SYSCALL_GETPPID 33
+#define SYSCALL_GETTID 34
+#define CHILD_EXIT_STATUS 42
+
+static pid_t sys_wait(int *status) {
+    pid_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WAIT), "b"(status)
+                 : "memory");
+    return ret;
+}
+
+static pid_t sys_getppid(void) {
+    pid_t ret;
+    asm volatile("int $0x88" : "=a"(ret) : "a"(SYSCALL_GETPPID) : "memory");
+    return ret;
+}
+
+static pid_t sys_gettid(void) {
+    pid_t ret;
+    asm volatile("int $0x88" : "=a"(ret) : "a"(SYSCALL_GETTID) : "memory");
+    return ret;
+}
+
 int main(void) {
     setvbuf(stdout, NULL, _IONBF, 0);
-    if (fork() == 0) {
+
+    pid_t parent = getpid();
+    pid_t child = fork();
+    if (child == 0) {
         printf("Child\n");
-    } else {
-        printf("Parent\n");
+        printf("PID: %d, PPID: %d\n", getpid(), sys_getppid());
+        if (sys_getppid() != parent || sys_gettid() != getpid()) {
+            printf("getppid or gettid returned a wrong ID\n");
+            exit(1);
+        }
+        exit(CHILD_EXIT_STATUS);
     }
+
+    printf("Parent\n");
     printf("PID: %d\n", getpid());
+
+    int status;
+    pid_t waited = sys_wait(&status);
+    if (waited != child) {
+        printf("wait returned %d, expected %d\n", waited, child);
+        return 1;
+    }
+    printf("Child %d exited with status %d\n", waited, status);
+    if (status != CHILD_EXIT_STATUS) {
+        return 1;
+    }
+
+    if (sys_wait(&status) >= 0) {
+        printf("wait succeeded with no children left\n");
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
 }
@@ -0,0 +1,62 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define SYSCALL_WAIT 20
+#define NUM_ITERATIONS 50000000
+
+static pid_t sys_wait(int *status) {
+    pid_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WAIT), "b"(status)
+                 : "memory");
+    return ret;
+}
+
+// Long enough to be preempted many times, with the intermediate values kept
+// in the FPU registers.
+static double compute(double seed) {
+    double x = seed;
+    double sum = 0.0;
+    for (int i = 0; i < NUM_ITERATIONS; i++) {
+        x = x * 1.0000001 + 0.25;
+        if (x > 1000.0) {
+            x -= 999.0;
+        }
+        sum += x / (i + 1);
+    }
+    return sum;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    // Nothing else uses the FPU yet, so these are right.
+    double expected_parent = compute(1.0);
+    double expected_child = compute(2.0);
+
+    pid_t child = fork();
+    if (child == 0) {
+        double got = compute(2.0);
+        if (got != expected_child) {
+            printf("Child got %f, expected %f\n", got, expected_child);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    double got = compute(1.0);
+    if (got != expected_parent) {
+        printf("Parent got %f, expected %f\n", got, expected_parent);
+        return 1;
+    }
+
+    int status;
+    if (sys_wait(&status) != child || status != 0) {
+        printf("Child failed\n");
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
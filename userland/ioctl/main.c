@@ -0,0 +1,97 @@
+#include <stdint.h>
+#include <stdio.h>
+
+#define SYSCALL_CLOSE 27
+#define SYSCALL_PIPE 35
+#define SYSCALL_IOCTL 38
+
+#define TCGETS 0x5401
+#define TCSETS 0x5402
+#define TIOCGWINSZ 0x5413
+
+#define ICANON 0002
+#define ECHO 0010
+
+#define EBADF 9
+#define EFAULT 14
+#define ENOTTY 25
+
+#define KERNEL_ADDR 0xC0000000
+
+struct termios_ {
+    uint32_t local_modes;
+};
+
+struct winsize_ {
+    uint16_t rows;
+    uint16_t cols;
+    uint16_t x_pixels;
+    uint16_t y_pixels;
+};
+
+static int sys_ioctl(int fd, unsigned int request, void *arg) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_IOCTL), "b"(fd), "c"(request), "d"(arg)
+                 : "memory");
+    return ret;
+}
+
+static int sys_pipe(int fds[2]) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_PIPE), "b"(fds)
+                 : "memory");
+    return ret;
+}
+
+static int check(const char *what, int ret, int expected) {
+    printf("%s returned %d, expected %d\n", what, ret, expected);
+    return ret == expected;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    int ok = 1;
+
+    struct winsize_ ws = {0};
+    ok &= check("TIOCGWINSZ", sys_ioctl(1, TIOCGWINSZ, &ws), 0);
+    printf("Terminal size: %u x %u\n", ws.cols, ws.rows);
+    ok &= ws.cols != 0 && ws.rows != 0;
+
+    struct termios_ saved;
+    ok &= check("TCGETS", sys_ioctl(0, TCGETS, &saved), 0);
+    printf("Local modes: 0%o\n", saved.local_modes);
+    ok &= saved.local_modes == (ICANON | ECHO);
+
+    struct termios_ termios = {ICANON};
+    ok &= check("TCSETS without ECHO", sys_ioctl(0, TCSETS, &termios), 0);
+    ok &= check("TCGETS", sys_ioctl(0, TCGETS, &termios), 0);
+    ok &= termios.local_modes == ICANON;
+    ok &= check("TCSETS restore", sys_ioctl(0, TCSETS, &saved), 0);
+
+    ok &= check("unknown request", sys_ioctl(0, 0x1234, NULL), -ENOTTY);
+    ok &= check("TCGETS into a kernel address",
+                sys_ioctl(0, TCGETS, (void *)KERNEL_ADDR), -EFAULT);
+    ok &= check("TCSETS from a null pointer", sys_ioctl(0, TCSETS, NULL),
+                -EFAULT);
+
+    int fds[2];
+    if (sys_pipe(fds) != 0) {
+        printf("pipe failed\n");
+        return 1;
+    }
+    ok &= check("TCGETS on a pipe", sys_ioctl(fds[0], TCGETS, &termios),
+                -ENOTTY);
+    ok &= check("TCGETS on a bad fd", sys_ioctl(100, TCGETS, &termios),
+                -EBADF);
+
+    if (!ok) {
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
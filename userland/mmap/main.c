@@ -0,0 +1,111 @@
+#include <stdint.h>
+#include <stdio.h>
+
+#define SYSCALL_MEM_MAP 5
+#define SYSCALL_MEM_UNMAP 15
+#define PAGE_SIZE 4096
+#define EINVAL 22
+#define MAP_SIZE (64 * PAGE_SIZE)
+
+#define PROT_NONE 0x1
+#define PROT_READ 0x2
+#define PROT_WRITE 0x4
+
+#define MAP_PRIVATE 0x1
+#define MAP_ANONYMOUS 0x2
+#define MAP_SHARED 0x4
+#define MAP_FIXED 0x8
+
+/* Error numbers are small negative numbers, mapping addresses are
+ * page-aligned. */
+#define IS_ERR(ret) ((uintptr_t)(ret) > (uintptr_t)-PAGE_SIZE)
+
+static void *sys_mmap(void *addr, size_t len, int prot, int flags) {
+    uint32_t args[6] = {(uintptr_t)addr, len, prot, flags, -1, 0};
+    void *ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_MEM_MAP), "b"(args)
+                 : "memory");
+    return ret;
+}
+
+static int sys_munmap(void *addr, size_t len) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_MEM_UNMAP), "b"(addr), "c"(len)
+                 : "memory");
+    return ret;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    uint8_t *mem = sys_mmap(NULL, MAP_SIZE - 1, PROT_READ | PROT_WRITE,
+                            MAP_PRIVATE | MAP_ANONYMOUS);
+    if (IS_ERR(mem) || (uintptr_t)mem % PAGE_SIZE != 0) {
+        printf("mmap returned %p\n", mem);
+        return 1;
+    }
+    printf("Mapping: %p-%p\n", mem, mem + MAP_SIZE);
+    for (size_t i = 0; i < MAP_SIZE; i += PAGE_SIZE) {
+        if (mem[i] != 0) {
+            printf("Page at %p is not zeroed\n", &mem[i]);
+            return 1;
+        }
+        mem[i] = 0xAA;
+    }
+
+    if (!IS_ERR(sys_mmap(mem, PAGE_SIZE, PROT_READ | PROT_WRITE,
+                         MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED))) {
+        printf("MAP_FIXED succeeded over an existing mapping\n");
+        return 1;
+    }
+    if (!IS_ERR(sys_mmap(NULL, 0, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS))
+        || !IS_ERR(sys_mmap(NULL, PAGE_SIZE, PROT_READ, MAP_SHARED))
+        || !IS_ERR(sys_mmap(NULL, PAGE_SIZE, PROT_READ | 0x100,
+                            MAP_PRIVATE | MAP_ANONYMOUS))
+        || !IS_ERR(sys_mmap(NULL, 0xF0000000, PROT_READ,
+                            MAP_PRIVATE | MAP_ANONYMOUS))) {
+        printf("mmap succeeded with bad arguments\n");
+        return 1;
+    }
+
+    /* Rounding the length up to the page size must not wrap around. */
+    if (sys_munmap(mem, 0xFFFFFFFF) != -EINVAL) {
+        printf("munmap with a huge length did not fail with EINVAL\n");
+        return 1;
+    }
+
+    /* Punch a hole and map it again at the same address. */
+    uint8_t *hole = mem + 8 * PAGE_SIZE;
+    if (sys_munmap(hole, PAGE_SIZE) != 0) {
+        printf("munmap failed\n");
+        return 1;
+    }
+    uint8_t *fixed = sys_mmap(hole, PAGE_SIZE, PROT_READ | PROT_WRITE,
+                              MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED);
+    if (fixed != hole || fixed[0] != 0 || mem[9 * PAGE_SIZE] != 0xAA) {
+        printf("MAP_FIXED into the hole returned %p\n", fixed);
+        return 1;
+    }
+
+    uint8_t *none = sys_mmap(NULL, PAGE_SIZE, PROT_NONE,
+                             MAP_PRIVATE | MAP_ANONYMOUS);
+    if (IS_ERR(none)) {
+        printf("mmap with PROT_NONE returned %p\n", none);
+        return 1;
+    }
+
+    /* The hole split the mapping in three. */
+    if (sys_munmap(mem, 8 * PAGE_SIZE) != 0
+        || sys_munmap(fixed, PAGE_SIZE) != 0
+        || sys_munmap(hole + PAGE_SIZE, MAP_SIZE - 9 * PAGE_SIZE) != 0
+        || sys_munmap(none, PAGE_SIZE) != 0) {
+        printf("munmap of the whole mappings failed\n");
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
@@ -0,0 +1,149 @@
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define SYSCALL_WRITE 1
+#define SYSCALL_READ 2
+#define SYSCALL_WAIT 20
+#define SYSCALL_CLOSE 27
+#define SYSCALL_PIPE 35
+
+#define EPIPE -32
+
+#define TOTAL_SIZE (100 * 1024)
+#define CHUNK_SIZE 1000
+
+static int sys_pipe(int fds[2]) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_PIPE), "b"(fds)
+                 : "memory");
+    return ret;
+}
+
+static int sys_write(int fd, const void *buf, unsigned int len) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WRITE), "b"(fd), "c"(buf), "d"(len)
+                 : "memory");
+    return ret;
+}
+
+static int sys_read(int fd, void *buf, unsigned int len) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_READ), "b"(fd), "c"(buf), "d"(len)
+                 : "memory");
+    return ret;
+}
+
+static int sys_close(int fd) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_CLOSE), "b"(fd)
+                 : "memory");
+    return ret;
+}
+
+static pid_t sys_wait(int *status) {
+    pid_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WAIT), "b"(status)
+                 : "memory");
+    return ret;
+}
+
+static uint8_t byte_at(uint32_t idx) {
+    return (uint8_t)(idx * 31 + idx / 251);
+}
+
+/* Reads the whole stream until the end of file and checks it. */
+static int reader(int fd) {
+    static uint8_t buf[777];
+    uint32_t total = 0;
+    uint32_t checksum = 0;
+    int n;
+    while ((n = sys_read(fd, buf, sizeof(buf))) > 0) {
+        for (int i = 0; i < n; ++i) {
+            if (buf[i] != byte_at(total + i)) {
+                printf("byte %u is wrong\n", total + i);
+                return 1;
+            }
+            checksum = checksum * 33 + buf[i];
+        }
+        total += n;
+    }
+    if (n < 0 || total != TOTAL_SIZE) {
+        printf("read %u bytes, last read returned %d\n", total, n);
+        return 1;
+    }
+    printf("Child read %u bytes, checksum %08X\n", total, checksum);
+    return 0;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    int fds[2];
+    if (sys_pipe(fds) != 0) {
+        printf("pipe failed\n");
+        return 1;
+    }
+    printf("Pipe: read end %d, write end %d\n", fds[0], fds[1]);
+    char c;
+    if (sys_write(fds[0], "x", 1) >= 0 || sys_read(fds[1], &c, 1) >= 0) {
+        printf("the pipe ends work the wrong way\n");
+        return 1;
+    }
+
+    pid_t child = fork();
+    if (child == 0) {
+        sys_close(fds[1]);
+        exit(reader(fds[0]));
+    }
+    sys_close(fds[0]);
+
+    /* The writer gets ahead of the reader until the pipe is full. */
+    static uint8_t chunk[CHUNK_SIZE];
+    for (uint32_t sent = 0; sent < TOTAL_SIZE; sent += CHUNK_SIZE) {
+        uint32_t len = TOTAL_SIZE - sent;
+        if (len > CHUNK_SIZE) {
+            len = CHUNK_SIZE;
+        }
+        for (uint32_t i = 0; i < len; ++i) {
+            chunk[i] = byte_at(sent + i);
+        }
+        if (sys_write(fds[1], chunk, len) != (int)len) {
+            printf("write failed at %u\n", sent);
+            return 1;
+        }
+    }
+    sys_close(fds[1]);
+
+    int status;
+    if (sys_wait(&status) != child || status != 0) {
+        printf("the reader failed\n");
+        return 1;
+    }
+
+    /* A write with no readers fails. */
+    if (sys_pipe(fds) != 0) {
+        printf("pipe failed\n");
+        return 1;
+    }
+    sys_close(fds[0]);
+    if (sys_write(fds[1], "x", 1) != EPIPE) {
+        printf("write succeeded with no readers\n");
+        return 1;
+    }
+    sys_close(fds[1]);
+
+    printf("OK\n");
+    return 0;
+}
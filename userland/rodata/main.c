@@ -0,0 +1,108 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define SYSCALL_WRITE 1
+#define SYSCALL_READ 2
+#define SYSCALL_WAIT 20
+#define SYSCALL_CLOSE 27
+#define SYSCALL_PIPE 35
+#define PAGE_FAULT_EXIT_STATUS -1
+#define EFAULT 14
+
+static int syscall3(int num, int arg1, int arg2, int arg3) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(num), "b"(arg1), "c"(arg2), "d"(arg3)
+                 : "memory");
+    return ret;
+}
+
+static pid_t sys_wait(int *status) {
+    return syscall3(SYSCALL_WAIT, (int)status, 0, 0);
+}
+
+// .data, which stays writable.
+static int data_var = 1;
+
+// .rodata, which the syscalls must not write to.
+static const int const_status = 7;
+static const int const_fds[2] = {-1, -1};
+static const char const_buf[8] = "rodata";
+
+static int check(const char *what, int ret, int expected) {
+    if (ret != expected) {
+        printf("%s returned %d, expected %d\n", what, ret, expected);
+        return 0;
+    }
+    return 1;
+}
+
+// The kernel can write to the read-only pages, so it must check the pointers
+// itself.
+static int check_syscalls_writing_rodata(void) {
+    int ok = 1;
+
+    int fds[2];
+    if (syscall3(SYSCALL_PIPE, (int)fds, 0, 0) != 0) {
+        printf("pipe failed\n");
+        return 0;
+    }
+    syscall3(SYSCALL_WRITE, fds[1], (int)"XXXX", 4);
+    ok &= check("read into .rodata",
+                syscall3(SYSCALL_READ, fds[0], (int)const_buf, 4), -EFAULT);
+    syscall3(SYSCALL_CLOSE, fds[0], 0, 0);
+    syscall3(SYSCALL_CLOSE, fds[1], 0, 0);
+
+    ok &= check("pipe into .rodata",
+                syscall3(SYSCALL_PIPE, (int)const_fds, 0, 0), -EFAULT);
+    ok &= check("wait into .rodata", sys_wait((int *)&const_status), -EFAULT);
+
+    volatile const char *buf = const_buf;
+    volatile const int *status = &const_status;
+    volatile const int *rfds = const_fds;
+    if (buf[0] != 'r' || *status != 7 || rfds[0] != -1 || rfds[1] != -1) {
+        printf(".rodata was changed by a syscall\n");
+        ok = 0;
+    }
+    return ok;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    data_var++;
+
+    pid_t child = fork();
+    if (child == 0) {
+        // String literals are in .rodata, which must be read-only.
+        volatile char *literal = (volatile char *)"read-only";
+        literal[0] = 'X';
+        printf("Child wrote into a string literal\n");
+        exit(1);
+    }
+
+    // The child is waited for after the checks, so that wait has something
+    // to report.
+    if (!check_syscalls_writing_rodata()) {
+        return 1;
+    }
+
+    int status;
+    if (sys_wait(&status) != child) {
+        printf("wait failed\n");
+        return 1;
+    }
+    if (status != PAGE_FAULT_EXIT_STATUS) {
+        printf("Child exited with status %d, expected %d\n", status,
+               PAGE_FAULT_EXIT_STATUS);
+        return 1;
+    }
+    if (data_var != 2) {
+        printf("data_var is %d, expected 2\n", data_var);
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
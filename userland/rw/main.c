@@ -0,0 +1,251 @@
+#include <stdio.h>
+#include <string.h>
+
+#define SYSCALL_OPEN 0
+#define SYSCALL_WRITE 1
+#define SYSCALL_READ 2
+#define SYSCALL_CLOSE 27
+#define SYSCALL_LSEEK 28
+#define SYSCALL_STAT 30
+#define SYSCALL_FSTAT 31
+
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
+
+#define O_RDONLY 0x0000
+#define O_WRONLY 0x0001
+#define O_RDWR 0x0002
+#define O_CREAT 0x0200
+
+#define S_IFMT 0170000
+#define S_IFREG 0100000
+#define S_IFDIR 0040000
+#define S_IFCHR 0020000
+
+struct kstat {
+    unsigned int fs_id;
+    unsigned int node_id;
+    unsigned int mode;
+    unsigned int hard_links;
+    unsigned int user_id;
+    unsigned int group_id;
+    unsigned long long size;
+    unsigned int access_time;
+    unsigned int modification_time;
+    unsigned int change_time;
+    unsigned int reserved;
+};
+
+static int sys_open(const char *pathname, int flags) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_OPEN), "b"(pathname), "c"(strlen(pathname)),
+                   "d"(flags)
+                 : "memory");
+    return ret;
+}
+
+static int sys_close(int fd) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_CLOSE), "b"(fd)
+                 : "memory");
+    return ret;
+}
+
+static int sys_write(int fd, const void *buf, unsigned int len) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WRITE), "b"(fd), "c"(buf), "d"(len)
+                 : "memory");
+    return ret;
+}
+
+static int sys_read(int fd, void *buf, unsigned int len) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_READ), "b"(fd), "c"(buf), "d"(len)
+                 : "memory");
+    return ret;
+}
+
+static int sys_lseek(int fd, int offset, int whence) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_LSEEK), "b"(fd), "c"(offset), "d"(whence)
+                 : "memory");
+    return ret;
+}
+
+static int sys_stat(const char *pathname, struct kstat *buf) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_STAT), "b"(pathname), "c"(strlen(pathname)),
+                   "d"(buf)
+                 : "memory");
+    return ret;
+}
+
+static int sys_fstat(int fd, struct kstat *buf) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_FSTAT), "b"(fd), "c"(buf)
+                 : "memory");
+    return ret;
+}
+
+static int test_stat(void) {
+    struct kstat st, fst;
+    if (sys_stat("/bin/test-rw", &st) != 0 || (st.mode & S_IFMT) != S_IFREG
+        || st.size == 0 || st.hard_links == 0) {
+        printf("stat of the executable failed\n");
+        return 1;
+    }
+    int fd = sys_open("/bin/test-rw", O_RDONLY);
+    if (sys_fstat(fd, &fst) != 0 || fst.fs_id != st.fs_id
+        || fst.node_id != st.node_id || fst.size != st.size) {
+        printf("fstat does not match stat\n");
+        return 1;
+    }
+    sys_close(fd);
+
+    if (sys_stat("/bin", &st) != 0 || (st.mode & S_IFMT) != S_IFDIR
+        || sys_stat("/dev/chr0", &st) != 0
+        || (st.mode & S_IFMT) != S_IFCHR) {
+        printf("stat of a directory or a device failed\n");
+        return 1;
+    }
+    if (sys_stat("/no-such-file", &st) >= 0
+        || sys_stat("/bin", (struct kstat *)0xC0100000) >= 0
+        || sys_fstat(42, &st) >= 0) {
+        printf("stat succeeded with a bad path, buffer or fd\n");
+        return 1;
+    }
+    return 0;
+}
+
+static int test_lseek(void) {
+    int fd = sys_open("/bin/test-rw", O_RDONLY);
+    if (fd < 0) {
+        printf("open of the executable returned %d\n", fd);
+        return 1;
+    }
+    int size = sys_lseek(fd, 0, SEEK_END);
+    char magic[4];
+    if (size <= 0 || sys_lseek(fd, 1, SEEK_SET) != 1
+        || sys_lseek(fd, -1, SEEK_CUR) != 0
+        || sys_read(fd, magic, 4) != 4 || memcmp(magic, "\x7f" "ELF", 4)) {
+        printf("lseek within the file failed\n");
+        return 1;
+    }
+    if (sys_lseek(fd, 10, SEEK_END) != size + 10
+        || sys_read(fd, magic, 4) != 0) {
+        printf("lseek or read past the end failed\n");
+        return 1;
+    }
+    if (sys_lseek(fd, -1, SEEK_SET) >= 0 || sys_lseek(fd, 0, 3) >= 0) {
+        printf("lseek succeeded with a bad offset or whence\n");
+        return 1;
+    }
+    sys_close(fd);
+
+    if (sys_lseek(0, 0, SEEK_SET) >= 0) {
+        printf("lseek succeeded on the console\n");
+        return 1;
+    }
+    return 0;
+}
+
+static char line_buf[1];
+
+int main(void) {
+    static const char hello[] = "Hello from write()\n";
+    int n = sys_write(1, hello, strlen(hello));
+    if (n != (int)strlen(hello)) {
+        printf("write returned %d\n", n);
+        return 1;
+    }
+
+    if (sys_write(1, (void *)0xC0100000, 16) >= 0) {
+        printf("write succeeded with a kernel pointer\n");
+        return 1;
+    }
+    if (sys_read(0, (void *)0x10, 16) >= 0) {
+        printf("read succeeded with a wild pointer\n");
+        return 1;
+    }
+    if (sys_write(1, hello, 0xFFFFFFF0) >= 0) {
+        printf("write succeeded with a huge length\n");
+        return 1;
+    }
+    if (sys_write(42, hello, strlen(hello)) >= 0) {
+        printf("write succeeded with a bad fd\n");
+        return 1;
+    }
+    if (sys_write(1, hello, 0) != 0) {
+        printf("empty write failed\n");
+        return 1;
+    }
+
+    int fd = sys_open("/dev/chr0", O_RDWR);
+    if (fd < 0) {
+        printf("open returned %d\n", fd);
+        return 1;
+    }
+    if (sys_close(fd) != 0 || sys_close(fd) >= 0) {
+        printf("close did not fail the second time\n");
+        return 1;
+    }
+    if (sys_open("/dev/chr0", O_RDONLY) != fd) {
+        printf("open did not reuse the lowest free fd %d\n", fd);
+        return 1;
+    }
+    if (sys_write(fd, hello, strlen(hello)) >= 0) {
+        printf("write succeeded on a read-only fd\n");
+        return 1;
+    }
+    sys_close(fd);
+
+    if (sys_open("/no-such-file", O_RDONLY) >= 0
+        || sys_open("/dev/chr0/x", O_RDONLY) >= 0
+        || sys_open("/bin/new-file", O_WRONLY | O_CREAT) >= 0
+        || sys_open("/dev/chr0", 0x80000000) >= 0) {
+        printf("open succeeded with a bad path or flags\n");
+        return 1;
+    }
+
+    int dir = sys_open("/bin", O_RDONLY);
+    if (dir < 0) {
+        printf("open of a directory returned %d\n", dir);
+        return 1;
+    }
+    if (sys_read(dir, line_buf, 1) >= 0 || sys_open("/bin", O_RDWR) >= 0) {
+        printf("a directory could be read or opened for writing\n");
+        return 1;
+    }
+    sys_close(dir);
+
+    if (test_lseek() != 0 || test_stat() != 0) {
+        return 1;
+    }
+
+    char line[64];
+    sys_write(1, "Type a line: ", 13);
+    n = sys_read(0, line, sizeof(line) - 1);
+    if (n < 0) {
+        printf("read returned %d\n", n);
+        return 1;
+    }
+    line[n] = '\0';
+    printf("Read %d bytes: %s\n", n, line);
+    printf("OK\n");
+    return 0;
+}
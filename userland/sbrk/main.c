@@ -0,0 +1,56 @@
+#include <stdint.h>
+#include <stdio.h>
+
+#define SYSCALL_BRK 16
+#define PAGE_SIZE 4096
+#define HEAP_SIZE (1024 * 1024)
+
+static uintptr_t sys_brk(uintptr_t new_end) {
+    uintptr_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_BRK), "b"(new_end)
+                 : "memory");
+    return ret;
+}
+
+static void *my_sbrk(intptr_t increment) {
+    uintptr_t old_end = sys_brk(0);
+    uintptr_t new_end = sys_brk(old_end + increment);
+    if (new_end != old_end + increment) {
+        return (void *)-1;
+    }
+    return (void *)old_end;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    uint8_t *heap = my_sbrk(HEAP_SIZE);
+    if (heap == (void *)-1) {
+        printf("sbrk failed\n");
+        return 1;
+    }
+    printf("Heap: %p-%p\n", heap, heap + HEAP_SIZE);
+
+    for (size_t i = 0; i < HEAP_SIZE; i += PAGE_SIZE) {
+        if (heap[i] != 0) {
+            printf("Page at %p is not zeroed\n", &heap[i]);
+            return 1;
+        }
+        heap[i] = 0xAA;
+    }
+    for (size_t i = 0; i < HEAP_SIZE; i += PAGE_SIZE) {
+        if (heap[i] != 0xAA) {
+            printf("Page at %p lost its content\n", &heap[i]);
+            return 1;
+        }
+    }
+
+    if (my_sbrk(-HEAP_SIZE) == (void *)-1) {
+        printf("Shrinking the heap failed\n");
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
@@ -0,0 +1,91 @@
+#include <stdint.h>
+#include <stdio.h>
+
+#define SYSCALL_NANOSLEEP 37
+
+#define EFAULT 14
+#define EINVAL 22
+
+#define KERNEL_ADDR 0xC0000000
+
+struct timespec_ {
+    int64_t secs;
+    int32_t nanos;
+};
+
+static int sys_nanosleep(const struct timespec_ *req, struct timespec_ *rem) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_NANOSLEEP), "b"(req), "c"(rem)
+                 : "memory");
+    return ret;
+}
+
+static uint64_t rdtsc(void) {
+    uint32_t low, high;
+    asm volatile("rdtsc" : "=a"(low), "=d"(high));
+    return (uint64_t)high << 32 | low;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    // There is no gettime syscall yet, so the TSC deltas are printed for
+    // comparison: they should be about the same.
+    struct timespec_ req = {0, 100 * 1000 * 1000};
+    uint64_t min_cycles = UINT64_MAX;
+    for (int i = 0; i < 3; i++) {
+        uint64_t start = rdtsc();
+        int ret = sys_nanosleep(&req, NULL);
+        uint64_t cycles = rdtsc() - start;
+        printf("Slept 100 ms: returned %d, %u Mcycles\n", ret,
+               (unsigned int)(cycles / 1000000));
+        if (ret != 0) {
+            return 1;
+        }
+        if (cycles < min_cycles) {
+            min_cycles = cycles;
+        }
+    }
+
+    // Shorter than a tick.  A tick is at least 1 ms and a 100 ms sleep lasts
+    // less than 200 ms even with the longest timer period of 50 ms, so a sleep
+    // of at least one tick takes more than 1/200 of the cycles of the 100 ms
+    // one.
+    struct timespec_ short_req = {0, 1};
+    uint64_t start = rdtsc();
+    if (sys_nanosleep(&short_req, NULL) != 0) {
+        printf("1 ns sleep failed\n");
+        return 1;
+    }
+    uint64_t short_cycles = rdtsc() - start;
+    printf("Slept 1 ns: %u Kcycles\n", (unsigned int)(short_cycles / 1000));
+    if (short_cycles * 200 < min_cycles) {
+        printf("1 ns sleep has not slept for a tick\n");
+        return 1;
+    }
+
+    struct timespec_ bad_req = {0, 1000 * 1000 * 1000};
+    if (sys_nanosleep(&bad_req, NULL) != -EINVAL) {
+        printf("nanosleep accepted 1e9 nanoseconds\n");
+        return 1;
+    }
+    bad_req.secs = -1;
+    bad_req.nanos = 0;
+    if (sys_nanosleep(&bad_req, NULL) != -EINVAL) {
+        printf("nanosleep accepted negative seconds\n");
+        return 1;
+    }
+    if (sys_nanosleep((void *)KERNEL_ADDR, NULL) != -EFAULT) {
+        printf("nanosleep accepted a kernel pointer\n");
+        return 1;
+    }
+    if (sys_nanosleep(&req, (void *)KERNEL_ADDR) != -EFAULT) {
+        printf("nanosleep accepted a kernel pointer for rem\n");
+        return 1;
+    }
+
+    printf("OK\n");
+    return 0;
+}
@@ -0,0 +1,154 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define SYSCALL_SLEEP_MS 18
+#define SYSCALL_WAIT 20
+#define SYSCALL_THREAD_CREATE 23
+#define SYSCALL_MEM_STATS 39
+
+#define THREAD_DETACHED 1
+
+#define NUM_WARMUP_ITERS 50
+#define NUM_ITERS 3000
+
+// Slack for the allocations made by the other tasks in the meantime.  A leak
+// of even a single allocation or page per iteration exceeds it by far.
+#define MAX_HEAP_ALLOCS_DELTA 16
+#define MAX_HEAP_USED_DELTA (16 * 1024)
+#define MAX_PAGES_DELTA 8
+
+struct mem_stats {
+    unsigned heap_total;
+    unsigned heap_used;
+    unsigned heap_allocations;
+    unsigned total_pages;
+    unsigned free_pages;
+};
+
+static char thread_stack[4096] __attribute__((aligned(16)));
+
+static pid_t sys_wait(int *status) {
+    pid_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WAIT), "b"(status)
+                 : "memory");
+    return ret;
+}
+
+static int sys_thread_create(void (*entry)(int), void *stack_top, int arg,
+                             int flags) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_THREAD_CREATE), "b"(entry), "c"(stack_top),
+                   "d"(arg), "S"(flags)
+                 : "memory");
+    return ret;
+}
+
+static void sys_sleep_ms(int ms) {
+    asm volatile("int $0x88" : : "a"(SYSCALL_SLEEP_MS), "b"(ms) : "memory");
+}
+
+static int sys_mem_stats(struct mem_stats *stats) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_MEM_STATS), "b"(stats)
+                 : "memory");
+    return ret;
+}
+
+static void sleeping_thread(int arg) {
+    (void)arg;
+    for (;;) {
+        sys_sleep_ms(1000);
+    }
+}
+
+// Forks a child that exits with a status depending on `iter`.  Every other
+// child has a second thread that its exit has to terminate.  Returns 0 if it
+// is waited for with that status.
+static int spawn_and_exit(int iter) {
+    int expected = iter & 0x7F;
+    pid_t child = fork();
+    if (child < 0) {
+        printf("fork failed with %d at iteration %d\n", child, iter);
+        return 1;
+    }
+    if (child == 0) {
+        void *stack_top = thread_stack + sizeof(thread_stack);
+        if (iter % 2 == 0 && sys_thread_create(sleeping_thread, stack_top, 0,
+                                               THREAD_DETACHED) < 0) {
+            exit(0xFF);
+        }
+        exit(expected);
+    }
+
+    int status;
+    pid_t waited = sys_wait(&status);
+    if (waited != child || status != expected) {
+        printf("iteration %d: wait returned %d with status %d, expected %d "
+               "with status %d\n",
+               iter, waited, status, child, expected);
+        return 1;
+    }
+    return 0;
+}
+
+static void print_stats(const char *when, const struct mem_stats *stats) {
+    printf("%s: heap %u/%u bytes in %u allocations, %u/%u free pages\n", when,
+           stats->heap_used, stats->heap_total, stats->heap_allocations,
+           stats->free_pages, stats->total_pages);
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    // The kernel's own vectors reach their steady capacity first.
+    for (int iter = 0; iter < NUM_WARMUP_ITERS; ++iter) {
+        if (spawn_and_exit(iter)) {
+            return 1;
+        }
+    }
+
+    struct mem_stats before, after;
+    if (sys_mem_stats(&before) != 0) {
+        printf("mem_stats failed\n");
+        return 1;
+    }
+    for (int iter = 0; iter < NUM_ITERS; ++iter) {
+        if (spawn_and_exit(iter)) {
+            return 1;
+        }
+    }
+    if (sys_mem_stats(&after) != 0) {
+        printf("mem_stats failed\n");
+        return 1;
+    }
+    print_stats("Before", &before);
+    print_stats("After", &after);
+
+    if ((int)(after.heap_allocations - before.heap_allocations) >
+        MAX_HEAP_ALLOCS_DELTA) {
+        printf("heap allocations leak over %d iterations\n", NUM_ITERS);
+        return 1;
+    }
+    if ((int)(after.heap_used - before.heap_used) > MAX_HEAP_USED_DELTA) {
+        printf("heap memory leaks over %d iterations\n", NUM_ITERS);
+        return 1;
+    }
+    if ((int)(before.free_pages - after.free_pages) > MAX_PAGES_DELTA) {
+        printf("physical pages leak over %d iterations\n", NUM_ITERS);
+        return 1;
+    }
+    if (sys_mem_stats((void *)0xC0000000) >= 0) {
+        printf("mem_stats accepted a kernel pointer\n");
+        return 1;
+    }
+
+    printf("OK\n");
+    return 0;
+}
@@ -0,0 +1,42 @@
+#include <stdio.h>
+#include <unistd.h>
+
+#define SYSCALL_SET_FOREGROUND 17
+
+static int sys_set_foreground(pid_t pid) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_SET_FOREGROUND), "b"(pid)
+                 : "memory");
+    return ret;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    pid_t child = fork();
+    if (child == 0) {
+        printf("Child is spinning, press Ctrl+C to interrupt it.\n");
+        for (;;) {
+        }
+    }
+
+    // Like a shell, let Ctrl+C go to the child.  The parent keeps running and
+    // reads the console once the child is gone.
+    if (sys_set_foreground(child) != 0) {
+        printf("set_foreground failed\n");
+        return 1;
+    }
+
+    char buf[64];
+    printf("Parent is waiting for a line.\n");
+    int nread = read(STDIN_FILENO, buf, sizeof(buf) - 1);
+    if (nread < 0) {
+        perror("read");
+        return 1;
+    }
+    buf[nread] = 0;
+    printf("Parent got: \"%s\"\n", buf);
+    return 0;
+}
@@ -0,0 +1,58 @@
+#include <stdio.h>
+#include <string.h>
+
+#define SYSCALL_WRITE 1
+#define SYSCALL_READ 2
+#define SYSCALL_PIPE 35
+
+#define STACK_BUF_SIZE (64 * 1024)
+#define MESSAGE "hello from the pipe"
+
+static int syscall3(int num, int arg1, int arg2, int arg3) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(num), "b"(arg1), "c"(arg2), "d"(arg3)
+                 : "memory");
+    return ret;
+}
+
+/* Reads from the pipe into an array on a part of the stack that has not been
+ * touched yet, so that the kernel is the first to access it.  The syscall is
+ * made right here, since a call would push its arguments below the array.
+ * Returns 0 if the data is wrong. */
+static __attribute__((noinline)) int read_to_fresh_stack(int fd) {
+    char buf[STACK_BUF_SIZE];
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_READ), "b"(fd), "c"(buf), "d"(sizeof(buf))
+                 : "memory");
+    if (ret != sizeof(MESSAGE) - 1) {
+        return ret;
+    }
+    return memcmp(buf, MESSAGE, ret) == 0 ? ret : 0;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    int fds[2];
+    if (syscall3(SYSCALL_PIPE, (int)fds, 0, 0) != 0) {
+        printf("pipe failed\n");
+        return 1;
+    }
+    int n = syscall3(SYSCALL_WRITE, fds[1], (int)MESSAGE, sizeof(MESSAGE) - 1);
+    if (n != sizeof(MESSAGE) - 1) {
+        printf("write returned %d\n", n);
+        return 1;
+    }
+
+    n = read_to_fresh_stack(fds[0]);
+    if (n != sizeof(MESSAGE) - 1) {
+        printf("read into a fresh stack array returned %d\n", n);
+        return 1;
+    }
+    printf("OK\n");
+    return 0;
+}
@@ -88,6 +88,7 @@ open_console:
     movl $0, %eax
     movl $console_pathname, %ebx
     movl $9, %ecx
+    movl $2, %edx               // O_RDWR
     int $0x88
 
     popl %ebp
@@ -0,0 +1,127 @@
+#include <stdio.h>
+#include <string.h>
+
+#define SYSCALL_WRITE 1
+#define SYSCALL_GETPID 12
+#define SYSCALL_FORK 13
+#define SYSCALL_WAIT 20
+#define SYSCALL_GET_FEATURES 36
+#define SYSCALL_BOGUS 1000
+
+#define FEATURE_SYSENTER 1
+
+#define ENOSYS 88
+
+static int int88_syscall(int num, int arg1, int arg2, int arg3) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(num), "b"(arg1), "c"(arg2), "d"(arg3)
+                 : "memory");
+    return ret;
+}
+
+// The kernel returns to %edi with %esp = %ebp, and sysexit clobbers %ecx and
+// %edx.
+static int sysenter_syscall(int num, int arg1, int arg2, int arg3) {
+    int ret;
+    asm volatile("pushl %%ebp\n"
+                 "pushl %%edi\n"
+                 "movl %%esp, %%ebp\n"
+                 "movl $1f, %%edi\n"
+                 "sysenter\n"
+                 "1: popl %%edi\n"
+                 "popl %%ebp\n"
+                 : "=a"(ret), "+c"(arg2), "+d"(arg3)
+                 : "a"(num), "b"(arg1)
+                 : "memory", "cc");
+    return ret;
+}
+
+#define EFLAGS_TF (1 << 8)
+#define EFLAGS_NT (1 << 14)
+#define EFLAGS_AC (1 << 18)
+
+// Like sysenter_syscall, but with the flags in `set_flags` set.  TF must not
+// make the kernel panic on the single-step trap in its entry code, and the
+// flags must not leak into the kernel.  Returns the flags after the syscall.
+static unsigned int sysenter_with_flags(int num, unsigned int set_flags,
+                                        int *ret) {
+    unsigned int flags;
+    int ecx = 0, edx = 0;
+    asm volatile("pushl %%ebp\n"
+                 "pushl %%edi\n"
+                 "pushfl\n"
+                 "orl %[set], (%%esp)\n"
+                 "movl %%esp, %%ebp\n"
+                 "addl $4, %%ebp\n"
+                 "movl $1f, %%edi\n"
+                 "popfl\n"
+                 "sysenter\n"
+                 "1: pushfl\n"
+                 "popl %%ebx\n"
+                 "popl %%edi\n"
+                 "popl %%ebp\n"
+                 : "=a"(*ret), "=b"(flags), "+c"(ecx), "+d"(edx)
+                 : "a"(num), [set] "r"(set_flags)
+                 : "memory", "cc");
+    return flags;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    int features = int88_syscall(SYSCALL_GET_FEATURES, 0, 0, 0);
+    printf("Features: 0x%X\n", features);
+    if (!(features & FEATURE_SYSENTER)) {
+        printf("sysenter is not supported, skipping the test\n");
+        return 0;
+    }
+
+    int pid = int88_syscall(SYSCALL_GETPID, 0, 0, 0);
+    if (sysenter_syscall(SYSCALL_GETPID, 0, 0, 0) != pid) {
+        printf("getpid via sysenter returned a wrong ID\n");
+        return 1;
+    }
+
+    const char *msg = "Hello from sysenter\n";
+    int len = strlen(msg);
+    if (sysenter_syscall(SYSCALL_WRITE, 1, (int)msg, len) != len) {
+        printf("write via sysenter failed\n");
+        return 1;
+    }
+
+    int ret = sysenter_syscall(SYSCALL_BOGUS, 0, 0, 0);
+    if (ret != -ENOSYS) {
+        printf("bogus syscall via sysenter returned %d\n", ret);
+        return 1;
+    }
+
+    // The single-step trap right after sysenter is swallowed, TF is lost.
+    unsigned int flags = sysenter_with_flags(SYSCALL_GETPID, EFLAGS_TF, &ret);
+    if (ret != pid || (flags & EFLAGS_TF)) {
+        printf("sysenter with TF: ret %d, flags 0x%X\n", ret, flags);
+        return 1;
+    }
+    // The kernel must not run with these, but the usermode keeps them.
+    flags = sysenter_with_flags(SYSCALL_GETPID, EFLAGS_NT | EFLAGS_AC, &ret);
+    if (ret != pid || !(flags & EFLAGS_NT) || !(flags & EFLAGS_AC)) {
+        printf("sysenter with NT and AC: ret %d, flags 0x%X\n", ret, flags);
+        return 1;
+    }
+
+    // The child returns from sysenter through iret.
+    int child = sysenter_syscall(SYSCALL_FORK, 0, 0, 0);
+    if (child == 0) {
+        sysenter_syscall(SYSCALL_WRITE, 1, (int)"Child\n", 6);
+        return 0;
+    }
+    int waited = sysenter_syscall(SYSCALL_WAIT, 0, 0, 0);
+    if (waited != child) {
+        printf("wait returned %d, expected %d\n", waited, child);
+        return 1;
+    }
+
+    printf("OK\n");
+    return 0;
+}
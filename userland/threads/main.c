@@ -0,0 +1,215 @@
+#include <stdio.h>
+#include <stdlib.h>
+#include <unistd.h>
+
+#define SYSCALL_SLEEP_MS 18
+#define SYSCALL_WAIT 20
+#define SYSCALL_THREAD_CREATE 23
+#define SYSCALL_THREAD_JOIN 24
+#define SYSCALL_THREAD_EXIT 25
+#define SYSCALL_GETTID 34
+
+#define THREAD_DETACHED 1
+
+#define NUM_THREADS 8
+#define STACK_SIZE 4096
+
+#define WORKER_EXIT_STATUS 42
+#define PROCESS_EXIT_STATUS 7
+
+static volatile int counters[NUM_THREADS];
+static volatile int detached_done;
+static volatile int spinner_started;
+static volatile int thread_tids[NUM_THREADS];
+static int main_pid;
+static char stacks[NUM_THREADS + 1][STACK_SIZE] __attribute__((aligned(16)));
+
+static int sys_thread_create(void (*entry)(int), void *stack_top, int arg,
+                             int flags) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_THREAD_CREATE), "b"(entry), "c"(stack_top),
+                   "d"(arg), "S"(flags)
+                 : "memory");
+    return ret;
+}
+
+static int sys_thread_join(int tid, int *value) {
+    int ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_THREAD_JOIN), "b"(tid), "c"(value)
+                 : "memory");
+    return ret;
+}
+
+static void sys_thread_exit(int value) {
+    asm volatile("int $0x88"
+                 :
+                 : "a"(SYSCALL_THREAD_EXIT), "b"(value)
+                 : "memory");
+    __builtin_unreachable();
+}
+
+static void sys_sleep_ms(int ms) {
+    asm volatile("int $0x88" : : "a"(SYSCALL_SLEEP_MS), "b"(ms) : "memory");
+}
+
+static int sys_gettid(void) {
+    int ret;
+    asm volatile("int $0x88" : "=a"(ret) : "a"(SYSCALL_GETTID) : "memory");
+    return ret;
+}
+
+static pid_t sys_wait(int *status) {
+    pid_t ret;
+    asm volatile("int $0x88"
+                 : "=a"(ret)
+                 : "a"(SYSCALL_WAIT), "b"(status)
+                 : "memory");
+    return ret;
+}
+
+static void thread_main(int idx) {
+    for (int i = 0; i < 1000; ++i) {
+        counters[idx] += 1;
+    }
+    // The threads share the process ID but have task IDs of their own.
+    thread_tids[idx] = getpid() == main_pid ? sys_gettid() : -1;
+    sys_thread_exit(idx);
+}
+
+static void detached_main(int arg) {
+    detached_done = arg;
+    sys_thread_exit(0);
+}
+
+static void outliving_main(int status) {
+    // The main thread has exited by the time this wakes up.
+    sys_sleep_ms(100);
+    exit(status);
+}
+
+static void spinner_main(int arg) {
+    (void)arg;
+    spinner_started = 1;
+    for (;;) {
+        sys_sleep_ms(10);
+    }
+}
+
+// Forks a child that runs `entry` in a second thread and then either exits
+// the process with `exit_status` or, if it is negative, only its main thread.
+// Returns the exit status that the child is waited for with.
+static int run_child(void (*entry)(int), int arg, int exit_status) {
+    pid_t child = fork();
+    if (child == 0) {
+        if (sys_thread_create(entry, stacks[1] - 16, arg, THREAD_DETACHED) <
+            0) {
+            exit(1);
+        }
+        if (exit_status < 0) {
+            sys_thread_exit(0);
+        }
+        while (!spinner_started) {
+            sys_sleep_ms(10);
+        }
+        exit(exit_status);
+    }
+
+    int status;
+    pid_t waited = sys_wait(&status);
+    if (waited != child) {
+        printf("wait returned %d, expected %d\n", waited, child);
+        return -1;
+    }
+    return status;
+}
+
+// Checks that a process exits with its last thread rather than its first one,
+// and that exit() terminates the other threads.
+static int check_process_exit(void) {
+    int status = run_child(outliving_main, WORKER_EXIT_STATUS, -1);
+    if (status != WORKER_EXIT_STATUS) {
+        printf("process whose main thread exited first exited with %d\n",
+               status);
+        return 1;
+    }
+    status = run_child(spinner_main, 0, PROCESS_EXIT_STATUS);
+    if (status != PROCESS_EXIT_STATUS) {
+        printf("process with a running thread exited with %d\n", status);
+        return 1;
+    }
+    return 0;
+}
+
+int main(void) {
+    setvbuf(stdout, NULL, _IONBF, 0);
+
+    main_pid = getpid();
+    if (sys_gettid() != main_pid) {
+        printf("the main thread's ID is not the process ID\n");
+        return 1;
+    }
+
+    int tids[NUM_THREADS];
+    for (int i = 0; i < NUM_THREADS; ++i) {
+        tids[i] = sys_thread_create(thread_main, stacks[i + 1] - 16, i, 0);
+        if (tids[i] < 0) {
+            printf("thread_create failed with %d\n", tids[i]);
+            return 1;
+        }
+        printf("Created thread %d\n", tids[i]);
+    }
+
+    if (sys_thread_create(thread_main, (void *)0xC0100000, 0, 0) >= 0) {
+        printf("thread_create succeeded with a stack in the kernel\n");
+        return 1;
+    }
+
+    // Some of the threads have exited by now, some have not.
+    for (int i = 0; i < NUM_THREADS; ++i) {
+        int value;
+        if (sys_thread_join(tids[i], &value) < 0) {
+            printf("thread_join %d failed\n", tids[i]);
+            return 1;
+        }
+        if (value != i || counters[i] != 1000 || thread_tids[i] != tids[i]) {
+            printf("thread %d returned %d with counter %d\n", tids[i], value,
+                   counters[i]);
+            return 1;
+        }
+        printf("Joined thread %d\n", tids[i]);
+    }
+
+    if (sys_thread_join(tids[0], NULL) >= 0) {
+        printf("thread_join succeeded twice\n");
+        return 1;
+    }
+    if (sys_thread_join(sys_gettid(), NULL) >= 0) {
+        printf("thread_join succeeded on itself\n");
+        return 1;
+    }
+
+    int detached = sys_thread_create(detached_main, stacks[NUM_THREADS + 1],
+                                     42, THREAD_DETACHED);
+    if (detached < 0) {
+        printf("thread_create failed with %d\n", detached);
+        return 1;
+    }
+    while (detached_done != 42) {
+        sys_sleep_ms(10);
+    }
+    if (sys_thread_join(detached, NULL) >= 0) {
+        printf("thread_join succeeded on a detached thread\n");
+        return 1;
+    }
+
+    if (check_process_exit()) {
+        return 1;
+    }
+
+    printf("OK\n");
+    return 0;
+}
@@ -5,18 +5,25 @@
 #include <errno.h>
 
 int main(void) {
-    printf("Enter something:\n");
-    printf("> ");
-    fflush(stdout);
+    printf("Enter lines, Ctrl+D on an empty line to stop.\n");
 
-    char buf[3];
-    int nread = read(STDIN_FILENO, buf, sizeof(buf) - 1);
-    if (nread <= 0) {
-        perror("read");
-        exit(EXIT_FAILURE);
-    }
+    char buf[64];
+    for (;;) {
+        printf("> ");
+        fflush(stdout);
+
+        // The task is blocked until a line is entered.
+        int nread = read(STDIN_FILENO, buf, sizeof(buf) - 1);
+        if (nread < 0) {
+            perror("read");
+            exit(EXIT_FAILURE);
+        } else if (nread == 0) {
+            printf("\nEnd of file.\n");
+            break;
+        }
 
-    printf("nread: %d\n", nread);
-    buf[nread] = 0;
-    printf("buf: \"%s\"\n", buf);
+        printf("nread: %d\n", nread);
+        buf[nread] = 0;
+        printf("buf: \"%s\"\n", buf);
+    }
 }